@@ -8,7 +8,7 @@ use std::env;
 use std::path::Path;
 use libc::ENOENT;
 use time::Timespec;
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+use fuse::{DEFAULT_BLKSIZE, FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
 
@@ -28,6 +28,7 @@ const HELLO_DIR_ATTR: FileAttr = FileAttr {
     uid: 501,
     gid: 20,
     rdev: 0,
+    blksize: DEFAULT_BLKSIZE,
     flags: 0,
 };
 
@@ -47,6 +48,7 @@ const HELLO_TXT_ATTR: FileAttr = FileAttr {
     uid: 501,
     gid: 20,
     rdev: 0,
+    blksize: DEFAULT_BLKSIZE,
     flags: 0,
 };
 
@@ -61,7 +63,7 @@ impl Filesystem for HelloFS {
         }
     }
 
-    fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    fn getattr (&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         match ino {
             1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
             2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
@@ -69,22 +71,22 @@ impl Filesystem for HelloFS {
         }
     }
 
-    fn read (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, _size: u32, reply: ReplyData) {
+    fn read (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, size: u32, _flags: u32, _lock_owner: Option<u64>, reply: ReplyData) {
         if ino == 2 {
-            reply.data(&HELLO_TXT_CONTENT.as_bytes()[offset as usize..]);
+            reply.data_at(HELLO_TXT_CONTENT.as_bytes(), offset, size);
         } else {
             reply.error(ENOENT);
         }
     }
 
-    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
+    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, reply: ReplyDirectory) {
         if ino == 1 {
-            if offset == 0 {
-                reply.add(1, 0, FileType::Directory, ".");
-                reply.add(1, 1, FileType::Directory, "..");
-                reply.add(2, 2, FileType::RegularFile, "hello.txt");
-            }
-            reply.ok();
+            let entries = [
+                (1, FileType::Directory, "."),
+                (1, FileType::Directory, ".."),
+                (2, FileType::RegularFile, "hello.txt"),
+            ];
+            reply.entries(offset, entries.iter().cloned());
         } else {
             reply.error(ENOENT);
         }
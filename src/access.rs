@@ -0,0 +1,121 @@
+//!
+//! Permission helpers: checks for filesystems that don't leave them to the
+//! kernel (i.e. aren't mounted with the default_permissions option) and
+//! implement access themselves, and the permissions of newly created files.
+//!
+
+use libc::{R_OK, W_OK, X_OK};
+use {FileAttr, FileType};
+
+/// Check the given access mask (a combination of R_OK, W_OK and X_OK, or F_OK to
+/// only check for existence) of a caller with the given uid and gid against the
+/// owner, group and permissions of a file, like the standard POSIX permission
+/// check. Only the class that applies to the caller (owner, group or other) is
+/// checked. Root (uid 0) may read and write anything and may execute a file if
+/// anyone may execute it (directories can always be searched). Since the kernel
+/// only tells the primary group of the caller, supplementary groups are not
+/// taken into account. Meant to be used in Filesystem::access:
+///
+/// ```ignore
+/// fn access (&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+///     if fuse::check_access(&self.attr(ino), req.uid(), req.gid(), mask) {
+///         reply.ok();
+///     } else {
+///         reply.error(EACCES);
+///     }
+/// }
+/// ```
+pub fn check_access (attr: &FileAttr, req_uid: u32, req_gid: u32, mask: u32) -> bool {
+    let mask = mask & (R_OK | W_OK | X_OK) as u32;
+    let perm = attr.perm as u32;
+    if req_uid == 0 {
+        // Root needs an execute bit for executing, but not for searching directories
+        return mask & X_OK as u32 == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+    }
+    let granted = if req_uid == attr.uid {
+        perm >> 6
+    } else if req_gid == attr.gid {
+        perm >> 3
+    } else {
+        perm
+    } & 0o7;
+    mask & granted == mask
+}
+
+/// Returns the permissions of a new file with the given mode and umask of create.
+/// Without FUSE_DONT_MASK, the kernel already applied the umask to the mode, with
+/// it the filesystem must do so (the kernel leaves it to the filesystem because
+/// a default ACL of the parent directory replaces the umask, a filesystem that
+/// implements default ACLs must not use this then). Since applying the umask twice
+/// doesn't change the result, this gives the right permissions in both cases, e.g.
+/// 0o644 for a mode of 0o666 and a umask of 0o022. Only the permission bits of the
+/// mode are returned (without the file type).
+pub fn apply_umask (mode: u32, umask: u32) -> u16 {
+    (mode & !umask & 0o7777) as u16
+}
+
+
+#[cfg(test)]
+mod test {
+    use libc::{F_OK, R_OK, W_OK, X_OK};
+    use FileAttr;
+    use super::{check_access, apply_umask};
+
+    /// Attributes of a file with the given permissions, owned by uid 1000 and gid 100
+    fn file (perm: u16) -> FileAttr {
+        let mut attr = FileAttr::for_file(2, 0);
+        attr.perm = perm;
+        attr.uid = 1000;
+        attr.gid = 100;
+        attr
+    }
+
+    #[test]
+    fn access_owner () {
+        let attr = file(0o640);
+        assert!(check_access(&attr, 1000, 100, R_OK as u32));
+        assert!(check_access(&attr, 1000, 100, (R_OK | W_OK) as u32));
+        assert!(!check_access(&attr, 1000, 100, X_OK as u32));
+        // Only the owner class applies to the owner, even if others may do more
+        assert!(!check_access(&file(0o077), 1000, 100, R_OK as u32));
+    }
+
+    #[test]
+    fn access_group () {
+        let attr = file(0o640);
+        assert!(check_access(&attr, 1001, 100, R_OK as u32));
+        assert!(!check_access(&attr, 1001, 100, W_OK as u32));
+        assert!(!check_access(&file(0o606), 1001, 100, R_OK as u32));
+    }
+
+    #[test]
+    fn access_other () {
+        let attr = file(0o645);
+        assert!(check_access(&attr, 1001, 101, (R_OK | X_OK) as u32));
+        assert!(!check_access(&attr, 1001, 101, W_OK as u32));
+        assert!(!check_access(&file(0o640), 1001, 101, R_OK as u32));
+        // Existence can always be checked
+        assert!(check_access(&file(0o000), 1001, 101, F_OK as u32));
+    }
+
+    #[test]
+    fn access_root () {
+        let attr = file(0o000);
+        assert!(check_access(&attr, 0, 0, (R_OK | W_OK) as u32));
+        assert!(!check_access(&attr, 0, 0, X_OK as u32));
+        assert!(check_access(&file(0o001), 0, 0, X_OK as u32));
+        let mut dir = FileAttr::for_dir(3);
+        dir.perm = 0o000;
+        assert!(check_access(&dir, 0, 0, X_OK as u32));
+    }
+
+    #[test]
+    fn umask () {
+        // Without FUSE_DONT_MASK, the umask is already applied
+        assert_eq!(apply_umask(0o100644, 0o022), 0o644);
+        // With FUSE_DONT_MASK, the filesystem applies it
+        assert_eq!(apply_umask(0o100666, 0o022), 0o644);
+        assert_eq!(apply_umask(0o104777, 0o077), 0o4700);
+        assert_eq!(apply_umask(0o100666, 0), 0o666);
+    }
+}
@@ -0,0 +1,718 @@
+//!
+//! Asynchronous filesystems, whose operations return futures instead of replying
+//! right away. An AsyncDispatcher runs the operations on an executor: the session
+//! loop receives a request, calls the filesystem to get the future of its result
+//! and spawns a task that replies once the future resolves, so the session loop
+//! can go on with the next request while the operation waits for I/O (e.g. of
+//! the network a filesystem is backed by).
+//!
+
+use std::{cmp, thread, u32};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use futures::{future, Future};
+use futures::future::{Either, Executor};
+use futures::sync::oneshot;
+use libc::{c_int, ENOSYS, ETIMEDOUT};
+use time::{self, Timespec};
+use {ConnectionInfo, FileAttr, FileType, Filesystem, Request, StatFs};
+use {Reply, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen, ReplyWrite, ReplyStatfs, ReplyCreate, ReplyDirectory};
+
+/// Future of the result of an operation of an AsyncFilesystem. An error is the error
+/// code to reply with.
+pub type FsFuture<T> = Box<Future<Item=T, Error=c_int> + Send>;
+
+/// Task that an AsyncDispatcher spawns for an operation (it replies when it's done)
+pub type Task = Box<Future<Item=(), Error=()> + Send>;
+
+/// Returns a future that fails with the given error code right away
+pub fn failed<T: Send + 'static> (err: c_int) -> FsFuture<T> {
+    Box::new(future::err(err))
+}
+
+/// Returns a future that succeeds with the given value right away
+pub fn done<T: Send + 'static> (value: T) -> FsFuture<T> {
+    Box::new(future::ok(value))
+}
+
+/// Details of the request of an operation. Unlike Request, it doesn't borrow the
+/// request buffer, so it can be moved into a future.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestInfo {
+    /// Unique id of the request
+    pub unique: u64,
+    /// Uid of the process that caused the request
+    pub uid: u32,
+    /// Gid of the process that caused the request
+    pub gid: u32,
+    /// Pid of the process that caused the request
+    pub pid: u32,
+}
+
+impl RequestInfo {
+    /// Details of the given request
+    pub fn of (req: &Request) -> RequestInfo {
+        RequestInfo { unique: req.unique(), uid: req.uid(), gid: req.gid(), pid: req.pid() }
+    }
+}
+
+/// Result of an operation that replies with an entry (see ReplyEntry::entry)
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// Time the entry and its attributes may be cached
+    pub ttl: Timespec,
+    /// Attributes of the entry
+    pub attr: FileAttr,
+    /// Generation number of the inode
+    pub generation: u64,
+}
+
+/// Result of an operation that replies with attributes (see ReplyAttr::attr)
+#[derive(Clone, Copy, Debug)]
+pub struct Attr {
+    /// Time the attributes may be cached
+    pub ttl: Timespec,
+    /// Attributes
+    pub attr: FileAttr,
+}
+
+/// Result of opening a file or directory (see ReplyOpen::opened)
+#[derive(Clone, Copy, Debug)]
+pub struct Opened {
+    /// File handle
+    pub fh: u64,
+    /// Open flags (FOPEN_*)
+    pub flags: u32,
+}
+
+/// Result of creating and opening a file (see ReplyCreate::created)
+#[derive(Clone, Copy, Debug)]
+pub struct Created {
+    /// Entry of the new file
+    pub entry: Entry,
+    /// Open file handle and flags
+    pub opened: Opened,
+}
+
+/// Entry of a directory listing (see ReplyDirectory::add)
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Offset of the next entry
+    pub offset: u64,
+    /// Kind of file
+    pub kind: FileType,
+    /// Name of the entry
+    pub name: OsString,
+}
+
+/// Filesystem whose operations return futures. The methods are like the ones of
+/// Filesystem, but take shared references (operations run concurrently) and owned
+/// arguments (futures outlive the request buffer), and return the future of the
+/// result to reply with instead of getting a reply to send it. Operations that
+/// aren't part of this trait are replied with ENOSYS. The default implementations
+/// give the same answers as the ones of Filesystem.
+pub trait AsyncFilesystem: Send + Sync + 'static {
+    /// Initialize filesystem (see Filesystem::init). Runs in the session loop before
+    /// any other operation, so it's the only method that isn't asynchronous.
+    fn init (&self, _req: RequestInfo, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+        conn.want_recommended();
+        Ok(())
+    }
+
+    /// Clean up filesystem (see Filesystem::destroy). Runs in the session loop, so
+    /// operations that are still running may finish after it.
+    fn destroy (&self, _req: RequestInfo) {
+    }
+
+    /// Look up a directory entry by name and get its attributes
+    fn lookup (&self, _req: RequestInfo, _parent: u64, _name: PathBuf) -> FsFuture<Entry> {
+        failed(ENOSYS)
+    }
+
+    /// Forget about an inode (see Filesystem::forget). Since there's no reply,
+    /// it isn't asynchronous.
+    fn forget (&self, _req: RequestInfo, _ino: u64, _nlookup: u64) {
+    }
+
+    /// Get file attributes
+    fn getattr (&self, _req: RequestInfo, _ino: u64, _fh: Option<u64>) -> FsFuture<Attr> {
+        failed(ENOSYS)
+    }
+
+    /// Set file attributes
+    fn setattr (&self, _req: RequestInfo, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>) -> FsFuture<Attr> {
+        failed(ENOSYS)
+    }
+
+    /// Read symbolic link
+    fn readlink (&self, _req: RequestInfo, _ino: u64) -> FsFuture<Vec<u8>> {
+        failed(ENOSYS)
+    }
+
+    /// Create file node
+    fn mknod (&self, _req: RequestInfo, _parent: u64, _name: PathBuf, _mode: u32, _rdev: u32) -> FsFuture<Entry> {
+        failed(ENOSYS)
+    }
+
+    /// Create a directory
+    fn mkdir (&self, _req: RequestInfo, _parent: u64, _name: PathBuf, _mode: u32) -> FsFuture<Entry> {
+        failed(ENOSYS)
+    }
+
+    /// Remove a file
+    fn unlink (&self, _req: RequestInfo, _parent: u64, _name: PathBuf) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Remove a directory
+    fn rmdir (&self, _req: RequestInfo, _parent: u64, _name: PathBuf) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Create a symbolic link
+    fn symlink (&self, _req: RequestInfo, _parent: u64, _name: PathBuf, _link: PathBuf) -> FsFuture<Entry> {
+        failed(ENOSYS)
+    }
+
+    /// Rename a file
+    fn rename (&self, _req: RequestInfo, _parent: u64, _name: PathBuf, _newparent: u64, _newname: PathBuf) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Create a hard link
+    fn link (&self, _req: RequestInfo, _ino: u64, _newparent: u64, _newname: PathBuf) -> FsFuture<Entry> {
+        failed(ENOSYS)
+    }
+
+    /// Open a file (see Filesystem::open)
+    fn open (&self, _req: RequestInfo, _ino: u64, _flags: u32) -> FsFuture<Opened> {
+        done(Opened { fh: 0, flags: 0 })
+    }
+
+    /// Read data (see Filesystem::read)
+    fn read (&self, _req: RequestInfo, _ino: u64, _fh: u64, _offset: u64, _size: u32, _flags: u32, _lock_owner: Option<u64>) -> FsFuture<Vec<u8>> {
+        failed(ENOSYS)
+    }
+
+    /// Write data (see Filesystem::write). Resolves to the number of bytes written.
+    fn write (&self, _req: RequestInfo, _ino: u64, _fh: u64, _offset: u64, _data: Vec<u8>, _write_flags: u32, _flags: u32) -> FsFuture<u32> {
+        failed(ENOSYS)
+    }
+
+    /// Flush method (see Filesystem::flush)
+    fn flush (&self, _req: RequestInfo, _ino: u64, _fh: u64, _lock_owner: u64) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Release an open file (see Filesystem::release)
+    fn release (&self, _req: RequestInfo, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> FsFuture<()> {
+        done(())
+    }
+
+    /// Synchronize file contents
+    fn fsync (&self, _req: RequestInfo, _ino: u64, _fh: u64, _datasync: bool) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Open a directory
+    fn opendir (&self, _req: RequestInfo, _ino: u64, _flags: u32) -> FsFuture<Opened> {
+        done(Opened { fh: 0, flags: 0 })
+    }
+
+    /// Read directory (see Filesystem::readdir). Resolves to the entries starting at
+    /// the given offset, the ones that don't fit into the reply are dropped (and
+    /// read again by the kernel starting at the offset of the first dropped one).
+    fn readdir (&self, _req: RequestInfo, _ino: u64, _fh: u64, _offset: u64) -> FsFuture<Vec<DirEntry>> {
+        failed(ENOSYS)
+    }
+
+    /// Release an open directory
+    fn releasedir (&self, _req: RequestInfo, _ino: u64, _fh: u64, _flags: u32) -> FsFuture<()> {
+        done(())
+    }
+
+    /// Synchronize directory contents
+    fn fsyncdir (&self, _req: RequestInfo, _ino: u64, _fh: u64, _datasync: bool) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Get file system statistics
+    fn statfs (&self, _req: RequestInfo, _ino: u64) -> FsFuture<StatFs> {
+        done(StatFs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 0 })
+    }
+
+    /// Check file access permissions
+    fn access (&self, _req: RequestInfo, _ino: u64, _mask: u32) -> FsFuture<()> {
+        failed(ENOSYS)
+    }
+
+    /// Create and open a file (see Filesystem::create)
+    fn create (&self, _req: RequestInfo, _parent: u64, _name: PathBuf, _mode: u32, _umask: u32, _flags: u32) -> FsFuture<Created> {
+        failed(ENOSYS)
+    }
+}
+
+/// Filesystem that runs the operations of an AsyncFilesystem on an executor (e.g.
+/// the one of a Tokio runtime). Every operation becomes a task that replies once
+/// the future of the operation resolves. Since the session loop only waits for the
+/// futures to be created, many operations can be in flight at once. Mount it like
+/// any other filesystem:
+///
+/// ```ignore
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// fuse::mount(fuse::async_fs::AsyncDispatcher::new(NetFs::new(), runtime.executor()), &mountpoint, &[]);
+/// ```
+///
+/// If the executor refuses a task (e.g. because it's shut down), the operation is
+/// replied with EIO.
+///
+/// If the session has an operation timeout (see Session::operation_timeout), an
+/// operation whose future doesn't resolve before the deadline of its request is
+/// replied with ETIMEDOUT, and its future is dropped (which cancels whatever it
+/// waits for), so a hanging backend doesn't leave the caller hanging as well.
+pub struct AsyncDispatcher<FS: AsyncFilesystem, E: Executor<Task>> {
+    /// The asynchronous filesystem
+    filesystem: Arc<FS>,
+    /// Executor to run the operations on
+    executor: E,
+    /// Timer of the deadlines of operations
+    deadlines: Deadlines,
+}
+
+impl<FS: AsyncFilesystem, E: Executor<Task>> AsyncDispatcher<FS, E> {
+    /// Create a dispatcher that runs the operations of the given filesystem on the
+    /// given executor
+    pub fn new (filesystem: FS, executor: E) -> AsyncDispatcher<FS, E> {
+        AsyncDispatcher { filesystem: Arc::new(filesystem), executor: executor, deadlines: Deadlines::new() }
+    }
+
+    /// Returns the asynchronous filesystem
+    pub fn filesystem (&self) -> &Arc<FS> {
+        &self.filesystem
+    }
+
+    /// Spawn a task that replies with the result of the given future using the given
+    /// function (or with its error). If the request has a deadline, the task replies
+    /// with ETIMEDOUT once it passed.
+    fn spawn<T, R, F> (&self, req: &Request, result: FsFuture<T>, reply: R, f: F) where
+        T: Send + 'static, R: Reply + Send + 'static, F: FnOnce(R, T) + Send + 'static
+    {
+        let result = match req.deadline() {
+            Some(deadline) => self.deadlines.limit(req.unique(), req.log_target(), deadline, result),
+            None => result,
+        };
+        let task = result.then(move |result| {
+            reply.respond(result, f);
+            Ok(())
+        });
+        if let Err(err) = self.executor.execute(Box::new(task)) {
+            // Dropping the task drops the reply, which replies with EIO
+            error!(target: req.log_target(), "Failed to spawn operation: {:?}", err.kind());
+        }
+    }
+}
+
+impl<FS: AsyncFilesystem, E: Executor<Task>> Filesystem for AsyncDispatcher<FS, E> {
+    fn init (&mut self, req: &Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+        self.filesystem.init(RequestInfo::of(req), conn)
+    }
+
+    fn destroy (&mut self, req: &Request) {
+        self.filesystem.destroy(RequestInfo::of(req));
+    }
+
+    fn lookup (&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        let result = self.filesystem.lookup(RequestInfo::of(req), parent, name.to_path_buf());
+        self.spawn(req, result, reply, reply_entry);
+    }
+
+    fn forget (&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.filesystem.forget(RequestInfo::of(req), ino, nlookup);
+    }
+
+    fn getattr (&mut self, req: &Request, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        let result = self.filesystem.getattr(RequestInfo::of(req), ino, fh);
+        self.spawn(req, result, reply, reply_attr);
+    }
+
+    fn setattr (&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>, fh: Option<u64>, crtime: Option<Timespec>, chgtime: Option<Timespec>, bkuptime: Option<Timespec>, flags: Option<u32>, reply: ReplyAttr) {
+        let result = self.filesystem.setattr(RequestInfo::of(req), ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags);
+        self.spawn(req, result, reply, reply_attr);
+    }
+
+    fn readlink (&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        let result = self.filesystem.readlink(RequestInfo::of(req), ino);
+        self.spawn(req, result, reply, |reply, data| reply.data(&data));
+    }
+
+    fn mknod (&mut self, req: &Request, parent: u64, name: &Path, mode: u32, rdev: u32, reply: ReplyEntry) {
+        let result = self.filesystem.mknod(RequestInfo::of(req), parent, name.to_path_buf(), mode, rdev);
+        self.spawn(req, result, reply, reply_entry);
+    }
+
+    fn mkdir (&mut self, req: &Request, parent: u64, name: &Path, mode: u32, reply: ReplyEntry) {
+        let result = self.filesystem.mkdir(RequestInfo::of(req), parent, name.to_path_buf(), mode);
+        self.spawn(req, result, reply, reply_entry);
+    }
+
+    fn unlink (&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        let result = self.filesystem.unlink(RequestInfo::of(req), parent, name.to_path_buf());
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn rmdir (&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        let result = self.filesystem.rmdir(RequestInfo::of(req), parent, name.to_path_buf());
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn symlink (&mut self, req: &Request, parent: u64, name: &Path, link: &Path, reply: ReplyEntry) {
+        let result = self.filesystem.symlink(RequestInfo::of(req), parent, name.to_path_buf(), link.to_path_buf());
+        self.spawn(req, result, reply, reply_entry);
+    }
+
+    fn rename (&mut self, req: &Request, parent: u64, name: &Path, newparent: u64, newname: &Path, reply: ReplyEmpty) {
+        let result = self.filesystem.rename(RequestInfo::of(req), parent, name.to_path_buf(), newparent, newname.to_path_buf());
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn link (&mut self, req: &Request, ino: u64, newparent: u64, newname: &Path, reply: ReplyEntry) {
+        let result = self.filesystem.link(RequestInfo::of(req), ino, newparent, newname.to_path_buf());
+        self.spawn(req, result, reply, reply_entry);
+    }
+
+    fn open (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let result = self.filesystem.open(RequestInfo::of(req), ino, flags);
+        self.spawn(req, result, reply, reply_opened);
+    }
+
+    fn read (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, size: u32, flags: u32, lock_owner: Option<u64>, reply: ReplyData) {
+        let result = self.filesystem.read(RequestInfo::of(req), ino, fh, offset, size, flags, lock_owner);
+        self.spawn(req, result, reply, |reply, data| reply.data(&data));
+    }
+
+    fn write (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], write_flags: u32, flags: u32, reply: ReplyWrite) {
+        let result = self.filesystem.write(RequestInfo::of(req), ino, fh, offset, data.to_vec(), write_flags, flags);
+        self.spawn(req, result, reply, |reply, size| reply.written(size));
+    }
+
+    fn flush (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let result = self.filesystem.flush(RequestInfo::of(req), ino, fh, lock_owner);
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn release (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        let result = self.filesystem.release(RequestInfo::of(req), ino, fh, flags, lock_owner, flush);
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn fsync (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let result = self.filesystem.fsync(RequestInfo::of(req), ino, fh, datasync);
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn opendir (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let result = self.filesystem.opendir(RequestInfo::of(req), ino, flags);
+        self.spawn(req, result, reply, reply_opened);
+    }
+
+    fn readdir (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
+        let result = self.filesystem.readdir(RequestInfo::of(req), ino, fh, offset);
+        self.spawn(req, result, reply, |mut reply, entries| {
+            for entry in entries {
+                if reply.add(entry.ino, entry.offset, entry.kind, &entry.name) {
+                    break;
+                }
+            }
+            reply.ok();
+        });
+    }
+
+    fn releasedir (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        let result = self.filesystem.releasedir(RequestInfo::of(req), ino, fh, flags);
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn fsyncdir (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let result = self.filesystem.fsyncdir(RequestInfo::of(req), ino, fh, datasync);
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn statfs (&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        let result = self.filesystem.statfs(RequestInfo::of(req), ino);
+        self.spawn(req, result, reply, |reply, st| reply.stat(&st));
+    }
+
+    fn access (&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let result = self.filesystem.access(RequestInfo::of(req), ino, mask);
+        self.spawn(req, result, reply, reply_empty);
+    }
+
+    fn create (&mut self, req: &Request, parent: u64, name: &Path, mode: u32, umask: u32, flags: u32, reply: ReplyCreate) {
+        let result = self.filesystem.create(RequestInfo::of(req), parent, name.to_path_buf(), mode, umask, flags);
+        self.spawn(req, result, reply, |reply, created| {
+            let Created { entry, opened } = created;
+            reply.created(&entry.ttl, &entry.attr, entry.generation, opened.fh, opened.flags);
+        });
+    }
+}
+
+/// Pending deadlines, by deadline (and a sequence number to tell apart the ones of
+/// the same time), with the senders to fire them
+struct DeadlineState {
+    pending: BTreeMap<(Timespec, u64), oneshot::Sender<()>>,
+    next: u64,
+    started: bool,
+    ended: bool,
+}
+
+/// Timer that fires the deadlines of operations. It runs in a thread that is
+/// started with the first deadline and ends when the timer is dropped.
+struct Deadlines {
+    state: Arc<(Mutex<DeadlineState>, Condvar)>,
+}
+
+impl Deadlines {
+    fn new () -> Deadlines {
+        let state = DeadlineState { pending: BTreeMap::new(), next: 0, started: false, ended: false };
+        Deadlines { state: Arc::new((Mutex::new(state), Condvar::new())) }
+    }
+
+    /// Returns a future that resolves like the given one, or fails with ETIMEDOUT
+    /// (and drops the given one) once the given deadline passed. The timeout is
+    /// logged to the given target.
+    fn limit<T: Send + 'static> (&self, unique: u64, log_target: &str, deadline: Timespec, result: FsFuture<T>) -> FsFuture<T> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let &(ref lock, ref cvar) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            let key = (deadline, state.next);
+            state.next += 1;
+            state.pending.insert(key, sender);
+            if !state.started {
+                state.started = true;
+                let timer = self.state.clone();
+                thread::spawn(move || run_deadlines(timer));
+            }
+            cvar.notify_one();
+        }
+        let log_target = log_target.to_string();
+        let timeout = receiver.then(move |fired| match fired {
+            Ok(()) => {
+                warn!(target: &log_target, "Operation {} timed out, replying with ETIMEDOUT", unique);
+                Either::A(future::err(ETIMEDOUT))
+            },
+            // The timer ended, the operation has as much time as it takes then
+            Err(_) => Either::B(future::empty()),
+        });
+        Box::new(result.select(timeout).then(|result| match result {
+            Ok((value, _)) => Ok(value),
+            Err((err, _)) => Err(err),
+        }))
+    }
+}
+
+impl Drop for Deadlines {
+    fn drop (&mut self) {
+        let &(ref lock, ref cvar) = &*self.state;
+        lock.lock().unwrap().ended = true;
+        cvar.notify_one();
+    }
+}
+
+/// Fire the deadlines that passed and wait for the next one, until the timer ended
+fn run_deadlines (timer: Arc<(Mutex<DeadlineState>, Condvar)>) {
+    let &(ref lock, ref cvar) = &*timer;
+    let mut state = lock.lock().unwrap();
+    while !state.ended {
+        let now = time::get_time();
+        // Deadlines that passed fire, the ones of operations that already finished
+        // (and dropped the receiving end) are forgotten right away
+        let done: Vec<(Timespec, u64)> = state.pending.iter()
+            .filter(|&(&(deadline, _), sender)| deadline <= now || sender.is_canceled())
+            .map(|(&key, _)| key).collect();
+        for key in done {
+            if let Some(sender) = state.pending.remove(&key) {
+                let _ = sender.send(());
+            }
+        }
+        let next = state.pending.keys().next().map(|&(deadline, _)| (deadline - now).num_milliseconds());
+        state = match next {
+            Some(ms) => cvar.wait_timeout_ms(state, cmp::min(cmp::max(ms, 1), u32::MAX as i64) as u32).unwrap().0,
+            None => cvar.wait(state).unwrap(),
+        };
+    }
+}
+
+/// Reply with the given entry
+fn reply_entry (reply: ReplyEntry, entry: Entry) {
+    reply.entry(&entry.ttl, &entry.attr, entry.generation);
+}
+
+/// Reply with the given attributes
+fn reply_attr (reply: ReplyAttr, attr: Attr) {
+    reply.attr(&attr.ttl, &attr.attr);
+}
+
+/// Reply with the given file handle and flags
+fn reply_opened (reply: ReplyOpen, opened: Opened) {
+    reply.opened(opened.fh, opened.flags);
+}
+
+/// Reply successfully
+fn reply_empty (reply: ReplyEmpty, _: ()) {
+    reply.ok();
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{mem, slice};
+    use std::sync::{Arc, Mutex};
+    use futures::{future, Future};
+    use futures::future::{Executor, ExecuteError, ExecuteErrorKind};
+    use libc::{c_int, c_void, size_t, EIO, ENOENT, ENOSYS, ETIMEDOUT};
+    use time::{self, Duration, Timespec};
+    use channel::Channel;
+    use fuse::{FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION, fuse_in_header, fuse_out_header};
+    use fuse::fuse_opcode::{FUSE_GETATTR, FUSE_READLINK};
+    use session::{BUFFER_SIZE, Mount, Session};
+    use FileAttr;
+    use super::{done, failed, AsyncFilesystem, AsyncDispatcher, Attr, FsFuture, RequestInfo, Task};
+
+    struct NetFs;
+
+    impl AsyncFilesystem for NetFs {
+        fn getattr (&self, _req: RequestInfo, ino: u64, _fh: Option<u64>) -> FsFuture<Attr> {
+            if ino == 0x22 {
+                done(Attr { ttl: Timespec::new(1, 0), attr: FileAttr::for_file(ino, 0x33) })
+            } else if ino == 0x66 {
+                // A backend that never answers
+                Box::new(future::empty())
+            } else {
+                failed(ENOENT)
+            }
+        }
+    }
+
+    /// Executor that runs tasks right away
+    struct Immediate;
+
+    impl Executor<Task> for Immediate {
+        fn execute (&self, task: Task) -> Result<(), ExecuteError<Task>> {
+            task.wait().unwrap();
+            Ok(())
+        }
+    }
+
+    /// Executor that keeps tasks until they're run explicitly
+    #[derive(Clone)]
+    struct Deferred(Arc<Mutex<Vec<Task>>>);
+
+    impl Executor<Task> for Deferred {
+        fn execute (&self, task: Task) -> Result<(), ExecuteError<Task>> {
+            self.0.lock().unwrap().push(task);
+            Ok(())
+        }
+    }
+
+    /// Executor that is shut down
+    struct Refusing;
+
+    impl Executor<Task> for Refusing {
+        fn execute (&self, task: Task) -> Result<(), ExecuteError<Task>> {
+            Err(ExecuteError::new(ExecuteErrorKind::Shutdown, task))
+        }
+    }
+
+    /// Create an initialized session of a dispatcher of NetFs that uses the given
+    /// executor. Returns the session and the fd of the other end of its channel.
+    fn session<E: Executor<Task>> (executor: E) -> (Session<AsyncDispatcher<NetFs, E>>, c_int) {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(AsyncDispatcher::new(NetFs, executor), ch);
+        se.proto_major = FUSE_KERNEL_VERSION;
+        se.proto_minor = FUSE_KERNEL_MINOR_VERSION;
+        se.initialized = true;
+        (se, fd)
+    }
+
+    /// Send a request with the given opcode and node id (with room for the getattr
+    /// argument of newer ABIs) and let the session dispatch it
+    fn dispatch<E: Executor<Task>> (se: &mut Session<AsyncDispatcher<NetFs, E>>, fd: c_int, opcode: u32, nodeid: u64) {
+        let len = mem::size_of::<fuse_in_header>() + 16;
+        let header = fuse_in_header { len: len as u32, opcode: opcode, unique: 0xdeadbeef, nodeid: nodeid, uid: 0, gid: 0, pid: 0, padding: 0 };
+        let mut buffer: Vec<u8> = Vec::with_capacity(len);
+        unsafe {
+            buffer.push_all(slice::from_raw_parts(&header as *const fuse_in_header as *const u8, mem::size_of::<fuse_in_header>()));
+            buffer.push_all(&[0u8; 16]);
+            ::libc::write(fd, buffer.as_ptr() as *const c_void, len as size_t);
+        }
+        assert!(Mount::receive_and_dispatch(se, &mut vec![0; BUFFER_SIZE]).unwrap());
+    }
+
+    /// Receive a reply from the channel at the other end of fd
+    fn receive (fd: c_int) -> Vec<u8> {
+        let mut buffer = vec![0u8; 4096];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        assert!(rc >= mem::size_of::<fuse_out_header>() as isize, "failed to receive reply");
+        buffer.truncate(rc as usize);
+        buffer
+    }
+
+    /// Returns the error of the given reply
+    fn error_of (reply: &[u8]) -> i32 {
+        let header: &fuse_out_header = unsafe { mem::transmute(reply.as_ptr()) };
+        -header.error
+    }
+
+    #[test]
+    fn reply_when_resolved () {
+        let tasks = Arc::new(Mutex::new(Vec::new()));
+        let (mut se, fd) = session(Deferred(tasks.clone()));
+        dispatch(&mut se, fd, FUSE_GETATTR as u32, 0x22);
+        // The operation is spawned, but not replied before it runs
+        let pending: Vec<Task> = tasks.lock().unwrap().drain(..).collect();
+        assert_eq!(pending.len(), 1);
+        for task in pending {
+            task.wait().unwrap();
+        }
+        let reply = receive(fd);
+        assert_eq!(error_of(&reply), 0);
+        // The attributes follow the valid time of the attr reply
+        assert_eq!(&reply[32..40], &[0x22u8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..]);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn reply_errors () {
+        let (mut se, fd) = session(Immediate);
+        dispatch(&mut se, fd, FUSE_GETATTR as u32, 0x44);
+        assert_eq!(error_of(&receive(fd)), ENOENT);
+        // Operations the filesystem doesn't implement
+        dispatch(&mut se, fd, FUSE_READLINK as u32, 0x22);
+        assert_eq!(error_of(&receive(fd)), ENOSYS);
+        unsafe { ::libc::close(fd); }
+        // Tasks the executor refuses
+        let (mut se, fd) = session(Refusing);
+        dispatch(&mut se, fd, FUSE_GETATTR as u32, 0x22);
+        assert_eq!(error_of(&receive(fd)), EIO);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn reply_timed_out () {
+        let (mut se, fd) = session(Immediate);
+        se.operation_timeout = Some(Duration::milliseconds(50));
+        let start = time::get_time();
+        dispatch(&mut se, fd, FUSE_GETATTR as u32, 0x66);
+        assert_eq!(error_of(&receive(fd)), ETIMEDOUT);
+        assert!(time::get_time() - start < Duration::seconds(5));
+        // Operations that finish in time aren't affected
+        dispatch(&mut se, fd, FUSE_GETATTR as u32, 0x22);
+        assert_eq!(error_of(&receive(fd)), 0);
+        unsafe { ::libc::close(fd); }
+    }
+}
@@ -0,0 +1,96 @@
+//!
+//! Attribution of requests to the cgroups of the calling processes (Linux only),
+//! e.g. to tell which container on a host caused a request. The kernel doesn't
+//! send cgroup information with requests, but the cgroups of a process can be
+//! looked up in /proc/<pid>/cgroup by the pid of the request (see Request::pid
+//! about the pid namespace that pid is in).
+//!
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Membership of a process in a cgroup hierarchy, as listed in /proc/<pid>/cgroup
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cgroup {
+    /// Id of the hierarchy, 0 for the unified hierarchy of cgroup v2
+    pub hierarchy: u32,
+    /// Controllers bound to the hierarchy (e.g. "cpu", "memory"), empty for the
+    /// unified hierarchy and named v1 hierarchies list their name (e.g. "name=systemd")
+    pub controllers: Vec<String>,
+    /// Path of the cgroup of the process, relative to the root of the hierarchy
+    /// (as seen from the cgroup namespace of the filesystem)
+    pub path: PathBuf,
+}
+
+impl Cgroup {
+    /// Returns the cgroups of the process with the given pid. The pid must be one of
+    /// the pid namespace the filesystem runs in (the pid of a request is, if the
+    /// filesystem runs in the namespace that mounted it). Fails with NotFound if
+    /// there's no such process (e.g. because it already exited, or the pid is 0).
+    pub fn of_process (pid: u32) -> io::Result<Vec<Cgroup>> {
+        if pid == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "process not visible"));
+        }
+        let mut contents = String::new();
+        try!(File::open(format!("/proc/{}/cgroup", pid)).and_then(|mut file| file.read_to_string(&mut contents)));
+        Ok(parse_cgroups(&contents))
+    }
+
+    /// Returns the path of the cgroup of the unified (cgroup v2) hierarchy in the
+    /// given cgroups, which on most current systems identifies the container (or
+    /// systemd unit) of a process
+    pub fn unified (cgroups: &[Cgroup]) -> Option<&Path> {
+        cgroups.iter().find(|cgroup| cgroup.hierarchy == 0 && cgroup.controllers.is_empty()).map(|cgroup| cgroup.path.as_path())
+    }
+}
+
+/// Parse the contents of /proc/<pid>/cgroup, which has a line of the hierarchy id,
+/// the controllers (separated by commas) and the path (which may contain colons,
+/// but not newlines) per hierarchy, separated by colons. Malformed lines are skipped.
+fn parse_cgroups (contents: &str) -> Vec<Cgroup> {
+    contents.lines().filter_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        match (fields.next().and_then(|id| id.parse::<u32>().ok()), fields.next(), fields.next()) {
+            (Some(hierarchy), Some(controllers), Some(path)) => Some(Cgroup {
+                hierarchy: hierarchy,
+                controllers: controllers.split(',').filter(|c| !c.is_empty()).map(|c| c.to_string()).collect(),
+                path: PathBuf::from(path),
+            }),
+            _ => None,
+        }
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use super::{Cgroup, parse_cgroups};
+
+    const CGROUP: &'static str = "\
+12:cpu,cpuacct:/docker/0123abcd
+3:name=systemd:/docker/0123abcd
+garbage
+0::/system.slice/docker-0123abcd.scope:with:colons
+";
+
+    #[test]
+    fn parse () {
+        let cgroups = parse_cgroups(CGROUP);
+        assert_eq!(cgroups.len(), 3);
+        assert_eq!(cgroups[0], Cgroup { hierarchy: 12, controllers: vec!["cpu".to_string(), "cpuacct".to_string()], path: PathBuf::from("/docker/0123abcd") });
+        assert_eq!(cgroups[1].controllers, vec!["name=systemd".to_string()]);
+        assert!(cgroups[2].controllers.is_empty());
+        assert_eq!(Cgroup::unified(&cgroups), Some(Path::new("/system.slice/docker-0123abcd.scope:with:colons")));
+        assert_eq!(Cgroup::unified(&cgroups[..2]), None);
+    }
+
+    #[test]
+    fn of_process () {
+        let cgroups = Cgroup::of_process(unsafe { ::libc::getpid() } as u32).unwrap();
+        assert!(!cgroups.is_empty());
+        assert!(Cgroup::of_process(0).is_err());
+    }
+}
@@ -2,12 +2,20 @@
 //! Raw communication channel to the FUSE kernel driver.
 //!
 
-use std::io;
+use std::{cmp, env, fs, i32, io};
 use std::ffi::{CString, CStr, OsStr};
+#[cfg(not(target_os = "macos"))]
+use std::io::Read;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{PathBuf, Path};
-use libc::{c_char, c_int, c_void, size_t};
+#[cfg(target_os = "linux")]
+use std::process::Command;
+use libc::{c_char, c_int, c_void, size_t, EBUSY};
+use time::Duration;
 use fuse::{fuse_args, fuse_mount_compat25};
+#[cfg(target_os = "linux")]
+use fuse::{fuse_backing_map, FUSE_DEV_IOC_BACKING_OPEN, FUSE_DEV_IOC_BACKING_CLOSE};
+use reply::ReplySender;
 
 // Libc provides iovec based I/O using readv and writev functions
 #[allow(dead_code, non_camel_case_types)]
@@ -29,16 +37,158 @@ mod libc {
 
         pub fn realpath (file_name: *const c_char, resolved_name: *mut c_char) -> *const c_char;
 
-        #[cfg(target_os = "macos")]
+        #[cfg(not(target_os = "linux"))]
         pub fn unmount(dir: *const c_char, flags: c_int) -> c_int;
-        #[cfg(not(target_os = "macos"))]
+        #[cfg(target_os = "linux")]
         pub fn umount(dir: *const c_char) -> c_int;
+        #[cfg(target_os = "linux")]
+        pub fn umount2(dir: *const c_char, flags: c_int) -> c_int;
+
+        pub fn socketpair (domain: c_int, typ: c_int, protocol: c_int, sv: *mut c_int) -> c_int;
+
+        pub fn fcntl (fd: c_int, cmd: c_int, ...) -> c_int;
+
+        #[cfg(target_os = "linux")]
+        pub fn ioctl (fd: c_int, request: ::libc::c_ulong, ...) -> c_int;
+
+        pub fn poll (fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int;
+    }
+
+    /// Type of the number of file descriptors to poll
+    #[cfg(target_os = "macos")]
+    pub type nfds_t = ::libc::c_uint;
+    /// Type of the number of file descriptors to poll
+    #[cfg(not(target_os = "macos"))]
+    pub type nfds_t = ::libc::c_ulong;
+
+    /// File descriptor to wait for with poll
+    #[repr(C)]
+    pub struct pollfd {
+        pub fd: c_int,
+        pub events: i16,
+        pub revents: i16,
     }
 
+    /// Poll event for data to read
+    pub const POLLIN: i16 = 0x1;
+
+    /// Socket address family and type for local socket pairs
+    pub const AF_UNIX: c_int = 1;
+    pub const SOCK_DGRAM: c_int = 2;
+
+    /// File status flag commands for fcntl
+    pub const F_GETFL: c_int = 3;
+    pub const F_SETFL: c_int = 4;
+
+    /// Unmount flags (OS X and the BSDs have the same MNT_FORCE, but no MNT_DETACH)
+    #[cfg(not(target_os = "linux"))]
+    pub const MNT_FORCE: c_int = 0x80000;
+    #[cfg(target_os = "linux")]
+    pub const MNT_FORCE: c_int = 1;
+    #[cfg(target_os = "linux")]
+    pub const MNT_DETACH: c_int = 2;
+
     /// Max length for path names. 4096 should be reasonable safe (OS X uses 1024, Linux uses 4096)
     pub const PATH_MAX: usize = 4096;
 }
 
+/// Mount helper that libfuse runs to mount filesystems for unprivileged users,
+/// the directories it looks for it (besides PATH) and what to install to get it
+#[cfg(not(target_os = "macos"))]
+const MOUNT_HELPER: (&'static str, &'static [&'static str], &'static str) =
+    ("fusermount", &["/bin", "/usr/bin"], "install the fuse package of your distribution");
+#[cfg(target_os = "macos")]
+const MOUNT_HELPER: (&'static str, &'static [&'static str], &'static str) =
+    ("mount_osxfuse", &["/Library/Filesystems/osxfuse.fs/Contents/Resources"], "install OSXFUSE");
+
+/// Look for the mount helper with the given name in the given directories and in
+/// the directories of the given PATH value. Returns the path of the helper if found.
+fn find_mount_helper (name: &str, dirs: &[&str], path: Option<&OsStr>) -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = dirs.iter().map(|dir| Path::new(dir).join(name)).collect();
+    if let Some(path) = path {
+        candidates.extend(env::split_paths(path).map(|dir| dir.join(name)));
+    }
+    candidates.into_iter().find(|candidate| {
+        fs::metadata(candidate).map(|metadata| metadata.is_file()).unwrap_or(false)
+    })
+}
+
+/// Returns the error for a mount that failed because the mount helper with the
+/// given name is missing
+fn missing_mount_helper (name: &str, hint: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("mount helper {} not found, {}", name, hint))
+}
+
+/// Path of the fuse device, which must be accessible to mount
+#[cfg(not(target_os = "macos"))]
+const FUSE_DEVICE: &'static str = "/dev/fuse";
+
+/// Returns the error for a mount that failed because the fuse device can't be
+/// used, if that's the case. Takes the error of opening the device (None if it
+/// could be opened). A missing device means that the fuse kernel module isn't
+/// loaded, a device without access usually that the user isn't in its group.
+#[cfg(not(target_os = "macos"))]
+fn fuse_device_error (open_err: Option<io::Error>) -> Option<io::Error> {
+    open_err.and_then(|err| match err.kind() {
+        io::ErrorKind::NotFound => Some(io::Error::new(io::ErrorKind::NotFound,
+            format!("{} not found, is the fuse kernel module loaded (modprobe fuse)?", FUSE_DEVICE))),
+        io::ErrorKind::PermissionDenied => Some(io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("permission denied accessing {}, is the user in the 'fuse' group / is the fuse module loaded?", FUSE_DEVICE))),
+        _ => None,
+    })
+}
+
+/// Returns the error for a mount that failed because the user may not mount on
+/// the given mountpoint, if that's the case. Takes the error of checking for
+/// write access to the mountpoint (None if it's writable). Users other than root
+/// can only mount on directories they can write to.
+fn mountpoint_error (mountpoint: &Path, access_err: Option<io::Error>) -> Option<io::Error> {
+    access_err.and_then(|err| match err.kind() {
+        io::ErrorKind::PermissionDenied => Some(io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("permission denied accessing mountpoint {}, the user needs write access to it", mountpoint.display()))),
+        _ => None,
+    })
+}
+
+/// Returns the options of the -o arguments in the given mount arguments
+#[cfg(not(target_os = "macos"))]
+fn mount_option_list (options: &[&OsStr]) -> Vec<String> {
+    let mut list = vec![];
+    let mut iter = options.iter().map(|option| option.to_string_lossy().into_owned());
+    while let Some(arg) = iter.next() {
+        let value = if arg == "-o" {
+            match iter.next() { Some(value) => value, None => break }
+        } else if arg.starts_with("-o") {
+            arg[2..].to_string()
+        } else {
+            continue;
+        };
+        list.extend(value.split(',').filter(|option| !option.is_empty()).map(|option| option.to_string()));
+    }
+    list
+}
+
+/// Returns true if the given contents of /etc/fuse.conf allow unprivileged users
+/// to use allow_other and allow_root
+#[cfg(not(target_os = "macos"))]
+fn user_allow_other (fuse_conf: &str) -> bool {
+    fuse_conf.lines().any(|line| line.split('#').next().unwrap().trim() == "user_allow_other")
+}
+
+/// Returns the error for a mount with the given options that failed because
+/// the user isn't allowed to use allow_other or allow_root, if that's the case.
+/// Only root can use them, unless /etc/fuse.conf (whose contents are given)
+/// contains user_allow_other.
+#[cfg(not(target_os = "macos"))]
+fn privileged_option_error (options: &[&OsStr], root: bool, fuse_conf: Option<&str>) -> Option<io::Error> {
+    if root || fuse_conf.map(user_allow_other).unwrap_or(false) {
+        return None;
+    }
+    mount_option_list(options).into_iter().find(|option| option == "allow_other" || option == "allow_root").map(|option| {
+        io::Error::new(io::ErrorKind::PermissionDenied, format!("{} can only be used by root, unless user_allow_other is set in /etc/fuse.conf", option))
+    })
+}
+
 /// Wrapper around libc's realpath.  Returns the errno value if the real path cannot be obtained.
 /// FIXME: Use Rust's realpath method once available in std (see also https://github.com/mozilla/rust/issues/11857)
 fn real_path (path: &CStr) -> io::Result<CString> {
@@ -74,6 +224,15 @@ impl Channel {
     /// given path. The kernel driver will delegate filesystem operations of
     /// the given path to the channel. If the channel is dropped, the path is
     /// unmounted.
+    ///
+    /// If mounting fails and the mount helper (fusermount, or mount_osxfuse on OS X)
+    /// can't be found, an error of kind NotFound names the missing helper instead
+    /// of the (less helpful) error of the mount. Likewise, a missing /dev/fuse (the
+    /// fuse kernel module isn't loaded) is a NotFound error that says so, and no
+    /// access to /dev/fuse or no write access to the mountpoint are PermissionDenied
+    /// errors that name what can't be accessed. If it fails because allow_other or
+    /// allow_root were given by a user that isn't allowed to use them (see
+    /// /etc/fuse.conf), an error of kind PermissionDenied explains this.
     pub fn new (mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
         let mnt = try!(mountpoint.as_os_str().to_cstring().ok_or(
                 io::Error::new(io::ErrorKind::InvalidInput, "invalid path")));
@@ -81,7 +240,34 @@ impl Channel {
             with_fuse_args(options, |args| {
                 let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
                 if fd < 0 {
-                    Err(io::Error::last_os_error())
+                    let err = io::Error::last_os_error();
+                    let (helper, dirs, hint) = MOUNT_HELPER;
+                    if find_mount_helper(helper, dirs, env::var_os("PATH").as_ref().map(|path| path.as_os_str())).is_none() {
+                        return Err(missing_mount_helper(helper, hint));
+                    }
+                    #[cfg(not(target_os = "macos"))] #[inline]
+                    fn check_device () -> Option<io::Error> {
+                        fuse_device_error(fs::OpenOptions::new().read(true).write(true).open(FUSE_DEVICE).err())
+                    }
+                    #[cfg(target_os = "macos")] #[inline]
+                    fn check_device () -> Option<io::Error> { None }
+                    if let Some(err) = check_device() {
+                        return Err(err);
+                    }
+                    let writable = unsafe { ::libc::access(mnt.as_ptr(), ::libc::W_OK) } == 0;
+                    if let Some(err) = mountpoint_error(mountpoint, if writable { None } else { Some(io::Error::last_os_error()) }) {
+                        return Err(err);
+                    }
+                    #[cfg(not(target_os = "macos"))] #[inline]
+                    fn check_privileged_options (options: &[&OsStr]) -> Option<io::Error> {
+                        let mut fuse_conf = String::new();
+                        let found = fs::File::open("/etc/fuse.conf").and_then(|mut file| file.read_to_string(&mut fuse_conf)).is_ok();
+                        let root = unsafe { ::libc::getuid() } == 0;
+                        privileged_option_error(options, root, if found { Some(&fuse_conf) } else { None })
+                    }
+                    #[cfg(target_os = "macos")] #[inline]
+                    fn check_privileged_options (_options: &[&OsStr]) -> Option<io::Error> { None }
+                    Err(check_privileged_options(options).unwrap_or(err))
                 } else {
                     let mountpoint = PathBuf::from(<OsStr as OsStrExt>::from_bytes(mnt.as_bytes()));
                     Ok(Channel { mountpoint: mountpoint, fd: fd })
@@ -90,6 +276,50 @@ impl Channel {
         })
     }
 
+    /// Create a new communication channel to the CUSE driver. Nothing is mounted,
+    /// the character device is created by the init handshake over the channel and
+    /// removed when the channel is dropped.
+    #[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+    pub fn cuse () -> io::Result<Channel> {
+        let path = CString::new("/dev/cuse").unwrap();
+        let fd = unsafe { ::libc::open(path.as_ptr(), ::libc::O_RDWR, 0) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Channel { mountpoint: PathBuf::new(), fd: fd })
+        }
+    }
+
+    /// Create a new communication channel that is connected to a local socket
+    /// instead of the kernel driver. Returns the channel and the fd of the other
+    /// end of the socket, which can be used to send requests to the channel and
+    /// to receive replies from it. Nothing is mounted, so nothing is unmounted
+    /// if the channel is dropped.
+    pub fn pair () -> io::Result<(Channel, c_int)> {
+        let mut fds: [c_int; 2] = [0; 2];
+        let rc = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok((Channel { mountpoint: PathBuf::new(), fd: fds[0] }, fds[1]))
+        }
+    }
+
+    /// Switch the channel to non-blocking mode, so that sending to a full
+    /// channel fails with EAGAIN instead of blocking. This also applies to the
+    /// senders and duplicates of its file descriptor (see owned_sender). Receiving
+    /// then fails with EAGAIN if no request is waiting, so wait for requests first
+    /// (see wait and poll_readable).
+    pub fn set_nonblocking (&self) -> io::Result<()> {
+        set_nonblocking(self.fd)
+    }
+
+    /// Returns the file descriptor of the channel, e.g. to wait for requests of
+    /// several channels at once (see poll_readable)
+    pub fn fd (&self) -> c_int {
+        self.fd
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint (&self) -> &Path {
         &self.mountpoint
@@ -106,6 +336,31 @@ impl Channel {
         }
     }
 
+    /// Receives data into the given buffer (can block). Returns the part of the
+    /// buffer that was filled, i.e. exactly the received request. Errors carry
+    /// the errno of the failed read, so temporary failures can be told apart.
+    pub fn receive_into<'a> (&self, buffer: &'a mut [u8]) -> io::Result<&'a [u8]> {
+        let rc = unsafe { ::libc::read(self.fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(&buffer[..rc as usize])
+        }
+    }
+
+    /// Wait until a request can be received, but at most for the given timeout.
+    /// Returns false if the timeout expired.
+    pub fn wait (&self, timeout: Duration) -> io::Result<bool> {
+        let mut fds = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let ms = cmp::min(cmp::max(timeout.num_milliseconds(), 0), i32::MAX as i64) as c_int;
+        let rc = unsafe { libc::poll(&mut fds, 1, ms) };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc > 0)
+        }
+    }
+
     /// Returns a sender object for this channel. The sender object can be
     /// used to send to the channel. Multiple sender objects can be used
     /// and they can safely be sent to other threads.
@@ -116,6 +371,17 @@ impl Channel {
         // dropping the channel, it'll return an EBADF error.
         ChannelSender { fd: self.fd }
     }
+
+    /// Returns a sender that owns a duplicate of the channel's file descriptor
+    /// and therefore stays valid after the channel is dropped
+    pub fn owned_sender (&self) -> io::Result<OwnedSender> {
+        let fd = unsafe { ::libc::dup(self.fd) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(OwnedSender { sender: ChannelSender { fd: fd } })
+        }
+    }
 }
 
 impl Drop for Channel {
@@ -124,8 +390,11 @@ impl Drop for Channel {
         // Close the communication channel to the kernel driver
         // (closing it before unnmount prevents sync unmount deadlock)
         unsafe { ::libc::close(self.fd); }
-        // Unmount this channel's mount point
-        let _ = unmount(&self.mountpoint);
+        // Unmount this channel's mount point (channels without a mount point
+        // are connected to a local socket and have nothing to unmount)
+        if self.mountpoint != PathBuf::new() {
+            let _ = unmount(&self.mountpoint);
+        }
     }
 }
 
@@ -149,49 +418,195 @@ impl ChannelSender {
     }
 }
 
+/// A sender with its own file descriptor, which is closed when the sender is
+/// dropped. It can be used after the channel is dropped, but sending fails
+/// once the filesystem is unmounted. Since it keeps the connection to the
+/// kernel driver open, it shouldn't be kept around longer than needed.
+pub struct OwnedSender {
+    sender: ChannelSender,
+}
+
+impl OwnedSender {
+    /// Send all data in the slice of slice of bytes in a single write (can block).
+    pub fn send (&self, buffer: &[&[u8]]) -> io::Result<()> {
+        self.sender.send(buffer)
+    }
+
+    /// Register the given open file as a backing file for passthrough. Returns
+    /// the backing id the kernel assigned to it.
+    #[cfg(target_os = "linux")]
+    pub fn backing_open (&self, fd: c_int) -> io::Result<u32> {
+        let map = fuse_backing_map { fd: fd, flags: 0, padding: 0 };
+        let rc = unsafe { libc::ioctl(self.sender.fd, FUSE_DEV_IOC_BACKING_OPEN as ::libc::c_ulong, &map as *const fuse_backing_map) };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc as u32)
+        }
+    }
+
+    /// Unregister the backing file with the given backing id
+    #[cfg(target_os = "linux")]
+    pub fn backing_close (&self, backing_id: u32) -> io::Result<()> {
+        let rc = unsafe { libc::ioctl(self.sender.fd, FUSE_DEV_IOC_BACKING_CLOSE as ::libc::c_ulong, &backing_id as *const u32) };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for OwnedSender {
+    fn drop (&mut self) {
+        unsafe { ::libc::close(self.sender.fd); }
+    }
+}
+
+impl ReplySender for ChannelSender {
+    fn send (&self, data: &[&[u8]]) -> io::Result<()> {
+        ChannelSender::send(self, data)
+    }
+}
+
+/// Switch the given file descriptor to non-blocking mode
+pub fn set_nonblocking (fd: c_int) -> io::Result<()> {
+    let rc = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | ::libc::O_NONBLOCK)
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Wait until requests can be received from any of the given file descriptors,
+/// but at most for the given timeout. Returns which of them are ready (which
+/// includes file descriptors with errors, e.g. the channel of a filesystem that
+/// was unmounted). All are not ready if the timeout expired.
+pub fn poll_readable (fds: &[c_int], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds.iter().map(|&fd| libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 }).collect();
+    let ms = cmp::min(cmp::max(timeout.num_milliseconds(), 0), i32::MAX as i64) as c_int;
+    let rc = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, ms) };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(pollfds.iter().map(|pollfd| pollfd.revents != 0).collect())
+    }
+}
+
+/// How to unmount a filesystem
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnmountMode {
+    /// Unmount if the filesystem isn't busy. Fails with EBUSY if processes still
+    /// have files open in it or a directory in it as their current directory.
+    Normal,
+    /// Detach the filesystem from the directory tree right away (MNT_DETACH,
+    /// fusermount -u -z). Processes that use it can keep using it, the mount is
+    /// cleaned up and the session ends when it isn't busy anymore. Only supported
+    /// on Linux, elsewhere (OS X, the BSDs) this is the same as Normal.
+    Lazy,
+    /// Abort the connection to the filesystem before unmounting (MNT_FORCE), so
+    /// that processes waiting for it fail (with ENOTCONN) instead of hanging. On
+    /// Linux, the unmount itself can still fail with EBUSY, on OS X a busy filesystem
+    /// is unmounted anyway. Only root can force an unmount.
+    Force,
+}
+
 /// Unmount an arbitrary mount point
 pub fn unmount (mountpoint: &Path) -> io::Result<()> {
+    unmount_with_mode(mountpoint, UnmountMode::Normal)
+}
+
+/// Unmount an arbitrary mount point in the given way
+pub fn unmount_with_mode (mountpoint: &Path, mode: UnmountMode) -> io::Result<()> {
     // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
     // it attempts to call realpath, which in turn calls into the filesystem. So
     // if the filesystem returns an error, the unmount does not take place, with
     // no indication of the error available to the caller. So we call unmount
     // directly, which is what osxfuse does anyway, since we already converted
-    // to the real path when we first mounted.
+    // to the real path when we first mounted. Users that aren't allowed to
+    // unmount run the mount helper instead.
 
-    #[cfg(target_os = "macos")] #[inline]
-    fn libc_umount (mnt: &CStr) -> c_int { unsafe { libc::unmount(mnt.as_ptr(), 0) } }
+    #[cfg(not(target_os = "linux"))] #[inline]
+    fn libc_umount (mnt: &CStr, mode: UnmountMode) -> io::Result<()> {
+        let flags = if mode == UnmountMode::Force { libc::MNT_FORCE } else { 0 };
+        match unsafe { libc::unmount(mnt.as_ptr(), flags) } {
+            rc if rc < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
 
-    #[cfg(not(target_os = "macos"))] #[inline]
-    fn libc_umount (mnt: &CStr) -> c_int {
-        use fuse::fuse_unmount_compat22;
+    #[cfg(target_os = "linux")] #[inline]
+    fn libc_umount (mnt: &CStr, mode: UnmountMode) -> io::Result<()> {
         use std::io::ErrorKind::PermissionDenied;
 
-        let rc = unsafe { libc::umount(mnt.as_ptr()) };
-        if rc < 0 && io::Error::last_os_error().kind() == PermissionDenied {
-            // Linux always returns EPERM for non-root users.  We have to let the
-            // library go through the setuid-root "fusermount -u" to unmount.
-            unsafe { fuse_unmount_compat22(mnt.as_ptr()); }
-            0
-        } else {
-            rc
+        let rc = match mode {
+            UnmountMode::Normal => unsafe { libc::umount(mnt.as_ptr()) },
+            UnmountMode::Lazy => unsafe { libc::umount2(mnt.as_ptr(), libc::MNT_DETACH) },
+            UnmountMode::Force => unsafe { libc::umount2(mnt.as_ptr(), libc::MNT_FORCE) },
+        };
+        if rc >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != PermissionDenied {
+            return Err(err);
+        }
+        // Linux always returns EPERM for non-root users.  We have to go through
+        // the setuid-root "fusermount -u" to unmount, which can't force an unmount.
+        match mode {
+            UnmountMode::Force => Err(err),
+            _ => helper_unmount(OsStr::new(MOUNT_HELPER.0), <OsStr as OsStrExt>::from_bytes(mnt.to_bytes()), mode),
         }
     }
 
     let mnt = try!(mountpoint.as_os_str().to_cstring().ok_or(
             io::Error::new(io::ErrorKind::InvalidInput, "invalid path")));
-    let rc = libc_umount(&mnt);
-    if rc < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(())
+    libc_umount(&mnt, mode)
+}
+
+/// Unmount with the given mount helper (fusermount -u, with -z for a lazy unmount).
+/// The helper only tells that it failed, not why. Since a normal unmount fails if
+/// the filesystem is busy, its failure is reported as EBUSY.
+#[cfg(target_os = "linux")]
+fn helper_unmount (helper: &OsStr, mnt: &OsStr, mode: UnmountMode) -> io::Result<()> {
+    let mut command = Command::new(helper);
+    command.arg("-u");
+    if mode == UnmountMode::Lazy {
+        command.arg("-z");
+    }
+    let status = try!(command.arg(mnt).status());
+    match mode {
+        _ if status.success() => Ok(()),
+        UnmountMode::Normal => Err(io::Error::from_raw_os_error(EBUSY)),
+        _ => Err(io::Error::new(io::ErrorKind::Other, format!("{} -u -z failed with {}", helper.to_string_lossy(), status))),
+    }
+}
+
+/// Unmount with the given unmount function. If the filesystem is busy (a normal
+/// unmount fails with EBUSY), unmount it again in the given way, if any.
+pub fn unmount_busy<F: FnMut(UnmountMode) -> io::Result<()>> (mut unmount: F, busy_unmount: Option<UnmountMode>) -> io::Result<()> {
+    match (unmount(UnmountMode::Normal), busy_unmount) {
+        (Err(ref err), Some(mode)) if err.raw_os_error() == Some(EBUSY) => unmount(mode),
+        (res, _) => res,
     }
 }
 
 
 #[cfg(test)]
 mod test {
-    use super::with_fuse_args;
+    use super::{Channel, MOUNT_HELPER, find_mount_helper, missing_mount_helper, mountpoint_error, with_fuse_args};
+    #[cfg(target_os = "linux")]
+    use super::{UnmountMode, helper_unmount, unmount_busy};
+    #[cfg(not(target_os = "macos"))]
+    use super::{fuse_device_error, mount_option_list, user_allow_other, privileged_option_error};
+    use std::{env, fs, io};
     use std::ffi::{CStr, OsStr};
+    use std::path::Path;
+    use libc::{c_void, size_t, EACCES, EBUSY, ENOENT, EPERM, EROFS};
 
     #[test]
     fn fuse_args () {
@@ -202,4 +617,135 @@ mod test {
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(2)).to_bytes() }, b"bar");
         });
     }
+
+    #[test]
+    fn receive_into () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let data: [u8; 5] = [0xde, 0xad, 0xbe, 0xef, 0x01];
+        let rc = unsafe { ::libc::write(fd, data.as_ptr() as *const c_void, data.len() as size_t) };
+        assert_eq!(rc, 5);
+        let mut buffer = [0u8; 64];
+        assert_eq!(ch.receive_into(&mut buffer).unwrap(), &data[..]);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn mount_helper () {
+        let dir = env::temp_dir().join("fuse-mount-helper-test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = env::join_paths(&[Path::new("/nonexistent"), dir.as_path()]).unwrap();
+        // A missing helper isn't found anywhere
+        assert_eq!(find_mount_helper("fusermount-missing", &[], Some(path.as_os_str())), None);
+        assert_eq!(find_mount_helper("fusermount-missing", &["/nonexistent"], None), None);
+        // A helper in one of the PATH directories is found
+        fs::File::create(dir.join("fusermount-missing")).unwrap();
+        assert_eq!(find_mount_helper("fusermount-missing", &["/nonexistent"], Some(path.as_os_str())), Some(dir.join("fusermount-missing")));
+        fs::remove_file(dir.join("fusermount-missing")).unwrap();
+        fs::remove_dir(&dir).unwrap();
+        let (helper, _, hint) = MOUNT_HELPER;
+        let err = missing_mount_helper(helper, hint);
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), format!("mount helper {} not found, {}", helper, hint));
+    }
+
+    #[test]
+    fn mount_without_helper () {
+        // Root mounts without the helper, so its mounts never fail for lack of it
+        if unsafe { ::libc::getuid() } == 0 {
+            return;
+        }
+        // Only a system without the helper can show that it's missing
+        let (helper, dirs, hint) = MOUNT_HELPER;
+        if find_mount_helper(helper, dirs, env::var_os("PATH").as_ref().map(|path| path.as_os_str())).is_some() {
+            return;
+        }
+        match Channel::new(&env::temp_dir(), &[]) {
+            Err(err) => {
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                assert_eq!(err.to_string(), format!("mount helper {} not found, {}", helper, hint));
+            },
+            Ok(_) => panic!("mounted without the mount helper"),
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn fuse_device () {
+        let err = fuse_device_error(Some(io::Error::from_raw_os_error(ENOENT))).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "/dev/fuse not found, is the fuse kernel module loaded (modprobe fuse)?");
+        for &errno in [EACCES, EPERM].iter() {
+            let err = fuse_device_error(Some(io::Error::from_raw_os_error(errno))).unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(err.to_string(), "permission denied accessing /dev/fuse, is the user in the 'fuse' group / is the fuse module loaded?");
+        }
+        // Other errors of the device don't explain a failed mount
+        assert!(fuse_device_error(Some(io::Error::from_raw_os_error(EBUSY))).is_none());
+        assert!(fuse_device_error(None).is_none());
+    }
+
+    #[test]
+    fn mountpoint_access () {
+        let mountpoint = Path::new("/mnt/fuse");
+        for &errno in [EACCES, EPERM].iter() {
+            let err = mountpoint_error(mountpoint, Some(io::Error::from_raw_os_error(errno))).unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(err.to_string(), "permission denied accessing mountpoint /mnt/fuse, the user needs write access to it");
+        }
+        // A read-only filesystem isn't a permission problem
+        assert!(mountpoint_error(mountpoint, Some(io::Error::from_raw_os_error(EROFS))).is_none());
+        assert!(mountpoint_error(mountpoint, None).is_none());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn option_list () {
+        let options = [OsStr::new("-o"), OsStr::new("ro,allow_other"), OsStr::new("-f"), OsStr::new("-ofsname=foo")];
+        assert_eq!(mount_option_list(&options), ["ro", "allow_other", "fsname=foo"]);
+        assert!(mount_option_list(&[OsStr::new("-o")]).is_empty());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn fuse_conf () {
+        assert!(user_allow_other("# mount_max = 1000\nuser_allow_other\n"));
+        assert!(user_allow_other("  user_allow_other  # allow other users\n"));
+        assert!(!user_allow_other("#user_allow_other\n"));
+        assert!(!user_allow_other(""));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn privileged_options () {
+        let options = [OsStr::new("-o"), OsStr::new("ro,allow_other")];
+        let err = privileged_option_error(&options, false, None).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(err.to_string(), "allow_other can only be used by root, unless user_allow_other is set in /etc/fuse.conf");
+        assert!(privileged_option_error(&options, false, Some("#user_allow_other\n")).is_some());
+        assert!(privileged_option_error(&options, false, Some("user_allow_other\n")).is_none());
+        assert!(privileged_option_error(&options, true, None).is_none());
+        assert!(privileged_option_error(&[OsStr::new("-o"), OsStr::new("ro")], false, None).is_none());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn helper_unmount_busy () {
+        let mnt = OsStr::new("/nonexistent/mountpoint");
+        // The helper only tells that a normal unmount failed, which means it's busy
+        let err = helper_unmount(OsStr::new("false"), mnt, UnmountMode::Normal).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EBUSY));
+        assert!(helper_unmount(OsStr::new("true"), mnt, UnmountMode::Normal).is_ok());
+        // A busy filesystem is unmounted lazily, with the helper like unprivileged users do
+        let mut modes = Vec::new();
+        let res = unmount_busy(|mode| {
+            modes.push(mode);
+            let helper = if mode == UnmountMode::Lazy { "true" } else { "false" };
+            helper_unmount(OsStr::new(helper), mnt, mode)
+        }, Some(UnmountMode::Lazy));
+        assert!(res.is_ok());
+        assert_eq!(modes, [UnmountMode::Normal, UnmountMode::Lazy]);
+        // Without a busy unmount mode, it stays busy
+        let res = unmount_busy(|mode| helper_unmount(OsStr::new("false"), mnt, mode), None);
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(EBUSY));
+    }
 }
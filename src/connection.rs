@@ -0,0 +1,818 @@
+//!
+//! Connection parameters that are negotiated with the kernel driver when
+//! the filesystem is initialized. The filesystem gets a chance to inspect
+//! and adjust them in its init method.
+//!
+
+use std::{cmp, fmt};
+use std::ffi::OsStr;
+use fuse::FUSE_KERNEL_MINOR_VERSION;
+use fuse::consts::{FUSE_ATOMIC_O_TRUNC, FUSE_AUTO_INVAL_DATA, FUSE_BIG_WRITES, FUSE_DO_READDIRPLUS, FUSE_EXPORT_SUPPORT, FUSE_CACHE_SYMLINKS, FUSE_NO_OPEN_SUPPORT, FUSE_NO_OPENDIR_SUPPORT, FUSE_PARALLEL_DIROPS, FUSE_POSIX_ACL, FUSE_HANDLE_KILLPRIV, FUSE_HANDLE_KILLPRIV_V2};
+#[cfg(not(feature = "abi-7-21"))]
+use fuse::consts::FUSE_READDIRPLUS_AUTO;
+#[cfg(not(target_os = "macos"))]
+use fuse::consts::{FUSE_MAP_ALIGNMENT, FUSE_SETXATTR_EXT, FUSE_SUBMOUNTS, FUSE_DIRECT_IO_ALLOW_MMAP, FUSE_PASSTHROUGH};
+use fuse::fuse_opcode;
+use fuse::fuse_opcode::*;
+use session::MAX_WRITE_SIZE;
+
+/// Presets for how many background requests the kernel may have outstanding.
+/// Background requests are requests no process waits for directly, like readahead,
+/// asynchronous direct I/O and (with writeback caching) page writeback. When the
+/// number of them reaches the congestion threshold, the kernel marks the connection
+/// as congested and stops readahead and writeback from queueing more. The kernel
+/// limits both values for filesystems mounted by unprivileged users (see
+/// /proc/sys/fs/fuse/max_user_bgreq and max_user_congthresh).
+#[cfg(feature = "abi-7-13")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CongestionPolicy {
+    /// Kernel defaults (12 background requests, congested at 9)
+    Default,
+    /// Few background requests (4, congested at 3), so that requests processes
+    /// wait for don't queue up behind readahead and writeback. Good for interactive
+    /// use of slow (e.g. network) filesystems.
+    LowLatency,
+    /// Many background requests (64, congested at 48), so that a filesystem that
+    /// handles requests in parallel gets enough of them to stay busy. Good for
+    /// sequential reads and writes of large files.
+    HighThroughput,
+    /// Custom values. The congestion threshold should not exceed max_background.
+    Custom { max_background: u16, congestion_threshold: u16 },
+}
+
+#[cfg(feature = "abi-7-13")]
+impl CongestionPolicy {
+    /// Returns the max number of background requests and the congestion threshold
+    /// of this policy. A value of 0 means the kernel's default.
+    pub fn values (&self) -> (u16, u16) {
+        match *self {
+            CongestionPolicy::Default => (0, 0),
+            CongestionPolicy::LowLatency => (4, 3),
+            CongestionPolicy::HighThroughput => (64, 48),
+            CongestionPolicy::Custom { max_background, congestion_threshold } => (max_background, congestion_threshold),
+        }
+    }
+}
+
+/// Names of the capability flags (FUSE_* init flags) the kernel knows about,
+/// including the ones this library has no constants for
+const CAPABILITY_NAMES: &'static [(u32, &'static str)] = &[
+    (0, "FUSE_ASYNC_READ"), (1, "FUSE_POSIX_LOCKS"), (2, "FUSE_FILE_OPS"), (3, "FUSE_ATOMIC_O_TRUNC"),
+    (4, "FUSE_EXPORT_SUPPORT"), (5, "FUSE_BIG_WRITES"), (6, "FUSE_DONT_MASK"), (7, "FUSE_SPLICE_WRITE"),
+    (8, "FUSE_SPLICE_MOVE"), (9, "FUSE_SPLICE_READ"), (10, "FUSE_FLOCK_LOCKS"), (11, "FUSE_HAS_IOCTL_DIR"),
+    (12, "FUSE_AUTO_INVAL_DATA"), (13, "FUSE_DO_READDIRPLUS"), (14, "FUSE_READDIRPLUS_AUTO"), (15, "FUSE_ASYNC_DIO"),
+    (16, "FUSE_WRITEBACK_CACHE"), (17, "FUSE_NO_OPEN_SUPPORT"), (18, "FUSE_PARALLEL_DIROPS"), (19, "FUSE_HANDLE_KILLPRIV"),
+    (20, "FUSE_POSIX_ACL"), (21, "FUSE_ABORT_ERROR"), (22, "FUSE_MAX_PAGES"), (23, "FUSE_CACHE_SYMLINKS"),
+    (24, "FUSE_NO_OPENDIR_SUPPORT"), (25, "FUSE_EXPLICIT_INVAL_DATA"), (26, "FUSE_MAP_ALIGNMENT"), (27, "FUSE_SUBMOUNTS"),
+    (28, "FUSE_HANDLE_KILLPRIV_V2"),
+    #[cfg(not(target_os = "macos"))] (29, "FUSE_SETXATTR_EXT"),
+    #[cfg(not(target_os = "macos"))] (30, "FUSE_INIT_EXT"),
+    #[cfg(not(target_os = "macos"))] (32, "FUSE_SECURITY_CTX"),
+    #[cfg(not(target_os = "macos"))] (33, "FUSE_HAS_INODE_DAX"),
+    #[cfg(not(target_os = "macos"))] (34, "FUSE_CREATE_SUPP_GROUP"),
+    #[cfg(not(target_os = "macos"))] (35, "FUSE_HAS_EXPIRE_ONLY"),
+    #[cfg(not(target_os = "macos"))] (36, "FUSE_DIRECT_IO_ALLOW_MMAP"),
+    #[cfg(not(target_os = "macos"))] (37, "FUSE_PASSTHROUGH"),
+    #[cfg(not(target_os = "macos"))] (38, "FUSE_NO_EXPORT_SUPPORT"),
+    #[cfg(not(target_os = "macos"))] (39, "FUSE_HAS_RESEND"),
+    #[cfg(target_os = "macos")] (29, "FUSE_CASE_INSENSITIVE"),
+    #[cfg(target_os = "macos")] (30, "FUSE_VOL_RENAME"),
+    #[cfg(target_os = "macos")] (31, "FUSE_XTIMES"),
+];
+
+/// Capability flags of operations this library can't dispatch with the ABI version
+/// it was built for. They're never granted, even if the kernel is capable of them.
+#[cfg(not(feature = "abi-7-21"))]
+pub const UNSUPPORTED_FLAGS: u64 = FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO;
+#[cfg(feature = "abi-7-21")]
+pub const UNSUPPORTED_FLAGS: u64 = 0;
+
+/// A set of capability flags (FUSE_* init flags). Its Debug representation lists
+/// the flags by name (and unknown flags as a number), e.g. for logging what was
+/// negotiated with the kernel.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    bits: u64,
+}
+
+impl Capabilities {
+    /// Create a set of the given capability flags
+    pub fn new (bits: u64) -> Capabilities {
+        Capabilities { bits: bits }
+    }
+
+    /// Returns the capability flags
+    pub fn bits (&self) -> u64 {
+        self.bits
+    }
+
+    /// Returns true if all of the given capability flags are in the set
+    pub fn contains (&self, flags: u64) -> bool {
+        self.bits & flags == flags
+    }
+
+    /// Returns the names of the known capability flags in the set
+    pub fn names (&self) -> Vec<&'static str> {
+        CAPABILITY_NAMES.iter().filter(|&&(bit, _)| self.contains(1 << bit)).map(|&(_, name)| name).collect()
+    }
+}
+
+/// Returns the flags of all capabilities in CAPABILITY_NAMES
+fn known_capabilities () -> u64 {
+    CAPABILITY_NAMES.iter().fold(0u64, |known, &(bit, _)| known | 1 << bit)
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let known = known_capabilities();
+        let mut names: Vec<String> = self.names().iter().map(|name| name.to_string()).collect();
+        if self.bits & !known != 0 {
+            names.push(format!("{:#x}", self.bits & !known));
+        }
+        try!(write!(f, "Capabilities("));
+        for (i, name) in names.iter().enumerate() {
+            try!(write!(f, "{}{}", if i > 0 { " | " } else { "" }, name));
+        }
+        write!(f, ")")
+    }
+}
+
+/// Outcome of the negotiation with the kernel at init, e.g. to include in bug
+/// reports what a mount ended up with (see ConnectionInfo::negotiation). Its
+/// Display representation is a multi-line report of the versions, the sizes and
+/// every capability that was offered or requested, which the session logs at
+/// debug level after init.
+#[derive(Clone, Debug)]
+pub struct Negotiation {
+    /// FUSE protocol version of the kernel (major, minor)
+    pub kernel_version: (u32, u32),
+    /// FUSE protocol version this library was built for (see the abi-7-* features)
+    pub library_version: (u32, u32),
+    /// Capabilities the kernel was capable of
+    pub offered: Capabilities,
+    /// Capabilities the filesystem wanted (after Filesystem::init)
+    pub requested: Capabilities,
+    /// Capabilities that were granted (see ConnectionInfo::want about why wanted
+    /// capabilities may not be granted)
+    pub effective: Capabilities,
+    /// Max readahead size of the init reply
+    pub max_readahead: u32,
+    /// Max size of write requests of the init reply
+    pub max_write: u32,
+    /// Max number of outstanding background requests of the init reply, 0 (the
+    /// kernel's default) without ABI 7.13
+    pub max_background: u16,
+    /// Congestion threshold of the init reply, 0 (the kernel's default) without
+    /// ABI 7.13
+    pub congestion_threshold: u16,
+}
+
+impl fmt::Display for Negotiation {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn yes_no (flag: bool) -> &'static str { if flag { "yes" } else { "no" } }
+        try!(writeln!(f, "fuse {} (ABI {}.{}), kernel ABI {}.{}", env!("CARGO_PKG_VERSION"),
+            self.library_version.0, self.library_version.1, self.kernel_version.0, self.kernel_version.1));
+        try!(writeln!(f, "max_write {}, max_readahead {}, max_background {}, congestion_threshold {}",
+            self.max_write, self.max_readahead, self.max_background, self.congestion_threshold));
+        try!(write!(f, "{:<26} offered requested effective", "capability"));
+        let mentioned = self.offered.bits() | self.requested.bits();
+        let mut rows: Vec<(String, u64)> = CAPABILITY_NAMES.iter()
+            .filter(|&&(bit, _)| mentioned & 1 << bit != 0)
+            .map(|&(bit, name)| (name.to_string(), 1 << bit)).collect();
+        if mentioned & !known_capabilities() != 0 {
+            rows.push((format!("{:#x}", mentioned & !known_capabilities()), mentioned & !known_capabilities()));
+        }
+        for (name, flags) in rows {
+            try!(write!(f, "\n{:<26} {:<7} {:<9} {}", name, yes_no(self.offered.bits() & flags != 0),
+                yes_no(self.requested.bits() & flags != 0), yes_no(self.effective.bits() & flags != 0)));
+        }
+        Ok(())
+    }
+}
+
+/// Connection parameters negotiated during init
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// FUSE protocol major version supported by the kernel
+    pub proto_major: u32,
+    /// FUSE protocol minor version supported by the kernel
+    pub proto_minor: u32,
+    /// Capability flags (FUSE_* init flags) the kernel supports. The high 32 bits
+    /// are only offered by kernels with ABI 7.36 or later, which send them with
+    /// the extended init (FUSE_INIT_EXT, not on OS X). They can only be granted
+    /// with ABI 7.23 or later, since older init replies have no room for them.
+    pub capable: u64,
+    /// Capability flags the filesystem wants to use. Initially contains the
+    /// flags this library supports by default. Flags the kernel isn't capable
+    /// of are ignored. After init, contains the flags that were granted.
+    ///
+    /// FUSE_EXPORT_SUPPORT is wanted by default. It allows the filesystem to be
+    /// exported over NFS (and file handles to be used with open_by_handle_at). The
+    /// kernel then looks up "." (with parent being the inode itself) and ".." (the
+    /// parent directory of the directory given as parent) when an NFS client uses
+    /// a file handle the kernel doesn't have in its cache anymore. NFS file handles
+    /// are built from the inode and generation numbers, so these must be stable: an
+    /// inode number may only be reused with a different generation number, even
+    /// across restarts of the filesystem. A filesystem that can't guarantee this
+    /// should remove the flag.
+    ///
+    /// Optional capabilities a filesystem can add:
+    ///
+    /// * FUSE_NO_OPEN_SUPPORT: opens and releases of regular files are optional.
+    ///   If the filesystem replies ENOSYS to an open, the kernel treats it as a
+    ///   successful open and doesn't send any more opens or releases. The filesystem
+    ///   must then be stateless: read, write, flush and fsync get a file handle of 0
+    ///   and must work based on the inode alone, including the open flags (which the
+    ///   filesystem never sees).
+    /// * FUSE_NO_OPENDIR_SUPPORT: like FUSE_NO_OPEN_SUPPORT, but for opendir and
+    ///   releasedir. After an ENOSYS from opendir, readdir gets a file handle of 0,
+    ///   so this is only suitable for filesystems that don't keep directory stream
+    ///   state (e.g. read-only filesystems).
+    /// * FUSE_PARALLEL_DIROPS: the kernel doesn't serialize lookups and readdirs
+    ///   in a directory anymore, so they may arrive concurrently (e.g. with a
+    ///   dispatch mode that handles requests in parallel). The filesystem must
+    ///   cope with concurrent lookups of the same name under the same parent
+    ///   and with directories changing while they are being read.
+    /// * FUSE_POSIX_ACL: the kernel enforces POSIX ACLs. The filesystem only stores
+    ///   the ACLs in the system.posix_acl_access and system.posix_acl_default xattrs
+    ///   (as opaque values) and must return them with getxattr, the kernel does all
+    ///   permission checks and updates the file mode when an ACL is set. This implies
+    ///   default_permissions, which is set after the capability was granted.
+    /// * FUSE_ATOMIC_O_TRUNC: open gets the O_TRUNC flag, and the filesystem must
+    ///   truncate the file as part of the open. Without it, the kernel doesn't pass
+    ///   the flag and truncates with a separate setattr after the open, so another
+    ///   process may see the file before it's truncated.
+    /// * FUSE_CACHE_SYMLINKS: the kernel caches the targets of symlinks in the page
+    ///   cache, so readlink is only called once per symlink. Useful if symlinks never
+    ///   change (e.g. archives). If a symlink target changes without the kernel knowing,
+    ///   the filesystem must invalidate the inode with an inode invalidation notification.
+    /// * FUSE_AUTO_INVAL_DATA: the kernel drops cached data of a file when it sees
+    ///   that its mtime changed (in the attributes of a getattr or lookup reply), so the
+    ///   filesystem doesn't need to send inode invalidation notifications for changes
+    ///   of file contents. This only works if the filesystem updates the mtime on every
+    ///   change of the contents (and the new mtime differs from the old one, so the
+    ///   timestamp resolution matters), and the changes are only noticed as often as
+    ///   the kernel fetches attributes (after the attribute TTL expired).
+    /// * FUSE_DO_READDIRPLUS (ABI 7.21 or later in this library): the kernel reads
+    ///   directories with readdirplus instead of readdir, which also returns the
+    ///   attributes of the entries. The filesystem must implement readdirplus then.
+    ///   With FUSE_READDIRPLUS_AUTO in addition, the kernel only uses readdirplus
+    ///   when processes look at the attributes of the entries, and readdir otherwise,
+    ///   so the filesystem must implement both. Without ABI 7.21, both are never
+    ///   granted (see supports).
+    /// * FUSE_HANDLE_KILLPRIV or FUSE_HANDLE_KILLPRIV_V2: the filesystem instead of
+    ///   the kernel is responsible for clearing the suid and sgid bits (and security
+    ///   capabilities) when a file is written, truncated or its owner is changed.
+    ///   Without it, the kernel clears them by sending a setattr. **A filesystem that
+    ///   asks for this capability and doesn't clear the bits allows non-owners to
+    ///   modify a setuid executable that keeps its privileges (privilege escalation).**
+    ///   With FUSE_HANDLE_KILLPRIV_V2, the kernel tells when to clear the bits with the
+    ///   FUSE_WRITE_KILL_SUIDGID write flag and FATTR_KILL_SUIDGID setattr flag.
+    /// * FUSE_SETXATTR_EXT (not on OS X): setxattr gets setxattr flags from the kernel.
+    ///   With FUSE_SETXATTR_ACL_KILL_SGID, the filesystem must clear the sgid bit when
+    ///   it sets the system.posix_acl_access xattr (used with FUSE_POSIX_ACL).
+    /// * FUSE_SUBMOUNTS (not on OS X): directories that a lookup reply marks with
+    ///   ReplyEntry::submount become separate mounts of the same connection when they
+    ///   are crossed, e.g. to present the backends of a federating filesystem as mount
+    ///   boundaries. Needs ABI 7.32 (Linux 5.10) and ABI 7.9 or later in this library.
+    /// * FUSE_MAP_ALIGNMENT (not on OS X): setupmapping and removemapping are
+    ///   dispatched to the filesystem (they're answered with ENOSYS otherwise). Only
+    ///   virtiofs with DAX sends them. The alignment of the mapping offsets isn't
+    ///   restricted (map_alignment is 0 in the init reply).
+    /// * FUSE_DIRECT_IO_ALLOW_MMAP (not on OS X, in the high 32 bits): files opened
+    ///   with FOPEN_DIRECT_IO can be mmap'd shared (without it, only private mappings
+    ///   work). Reads and writes of the handle still bypass the page cache, but the
+    ///   mapping goes through it, so the two aren't coherent: data written through
+    ///   the handle (or changed by the filesystem) isn't seen by the mapping until
+    ///   its pages are dropped, and dirty pages of the mapping reach the filesystem
+    ///   as writes only when they're written back (msync or munmap). Needs ABI 7.39
+    ///   (Linux 6.6).
+    /// * FUSE_PASSTHROUGH (not on OS X, in the high 32 bits): open files may be
+    ///   backed by a file of another filesystem, which the kernel reads and writes
+    ///   directly (see BackingFiles and ReplyOpen::passthrough). The kernel only
+    ///   grants it if max_stack_depth is 1 or 2.
+    ///
+    /// FUSE_INIT_EXT is wanted by default if supported (see capable). Without it,
+    /// none of the capabilities in the high 32 bits are granted.
+    pub want: u64,
+    /// Max readahead size. Can be lowered by the filesystem.
+    pub max_readahead: u32,
+    /// Max size of write requests. Can be lowered by the filesystem.
+    pub max_write: u32,
+    /// Max number of outstanding background requests, 0 means the kernel's default.
+    /// Usually set with set_congestion_policy.
+    #[cfg(feature = "abi-7-13")]
+    pub max_background: u16,
+    /// Number of outstanding background requests at which the kernel considers
+    /// the connection as congested, 0 means the kernel's default. Usually set with
+    /// set_congestion_policy.
+    #[cfg(feature = "abi-7-13")]
+    pub congestion_threshold: u16,
+    /// Granularity of timestamps in nanoseconds, must be a power of ten between
+    /// 1 and 1e9. The kernel rounds timestamps to it, so filesystems that store
+    /// timestamps with a lower resolution (e.g. whole seconds) should set it.
+    #[cfg(feature = "abi-7-23")]
+    pub time_gran: u32,
+    /// Max number of stacked filesystems below backing files (with FUSE_PASSTHROUGH).
+    /// 1 allows backing files on regular filesystems, 2 also on filesystems that
+    /// are stacked themselves (like overlayfs). The filesystem itself is then
+    /// stacked one level higher, so it can't be used below another stacked filesystem.
+    /// The kernel allows at most 2 levels of stacking (which bounds the recursion of
+    /// operations through stacked filesystems), with another value it doesn't grant
+    /// FUSE_PASSTHROUGH, so it isn't granted then (see valid_max_stack_depth).
+    ///
+    /// Without passthrough, a filesystem isn't stacked in the kernel: its backend
+    /// (even on another FUSE mount) is accessed from user space, so the depth doesn't
+    /// limit it. A filesystem whose backend can be on its own mount (e.g. a union
+    /// filesystem that is given a branch below its own mountpoint) must refuse such
+    /// backends itself, otherwise its operations recurse into itself and deadlock.
+    #[cfg(feature = "abi-7-36")]
+    pub max_stack_depth: u32,
+    /// True if the filesystem was mounted with the default_permissions option
+    /// (or FUSE_POSIX_ACL was granted). The kernel then does permission checking
+    /// based on file modes, so the filesystem doesn't need to check permissions
+    /// in access or open.
+    pub default_permissions: bool,
+    /// True if the filesystem was mounted with the allow_other option, i.e.
+    /// users other than the one that mounted it can access it.
+    pub allow_other: bool,
+    /// True if the filesystem was mounted read-only (ro option). Operations that
+    /// modify the filesystem (and opens for writing) are then rejected with EROFS
+    /// without calling the filesystem.
+    pub read_only: bool,
+    /// True if the filesystem was mounted with the nosuid option. The kernel then
+    /// ignores the suid and sgid bits of executables in it. Mounts by unprivileged
+    /// users (through fusermount) always get nosuid and nodev, even if not given.
+    pub nosuid: bool,
+    /// True if the filesystem was mounted with the nodev option. Device nodes in it
+    /// can't be opened then (creating them with mknod still works).
+    pub nodev: bool,
+    /// True if the filesystem was mounted with the noexec option. Files in it can't
+    /// be executed then.
+    pub noexec: bool,
+    /// Outcome of the negotiation at init, set by the session once the filesystem's
+    /// init returned (see negotiation)
+    negotiation: Option<Negotiation>,
+    /// Operations the filesystem disabled (bit n is set for opcode n)
+    disabled_ops: u64,
+}
+
+impl ConnectionInfo {
+    /// Create connection parameters for a filesystem mounted with the given options
+    pub fn new (options: &[&OsStr]) -> ConnectionInfo {
+        ConnectionInfo {
+            proto_major: 0,
+            proto_minor: 0,
+            capable: 0,
+            want: 0,
+            max_readahead: 0,
+            max_write: 0,
+            #[cfg(feature = "abi-7-13")]
+            max_background: 0,
+            #[cfg(feature = "abi-7-13")]
+            congestion_threshold: 0,
+            #[cfg(feature = "abi-7-23")]
+            time_gran: 1,
+            #[cfg(feature = "abi-7-36")]
+            max_stack_depth: 1,
+            default_permissions: has_mount_option(options, "default_permissions"),
+            allow_other: has_mount_option(options, "allow_other"),
+            read_only: has_mount_option(options, "ro"),
+            nosuid: has_mount_option(options, "nosuid"),
+            nodev: has_mount_option(options, "nodev"),
+            noexec: has_mount_option(options, "noexec"),
+            negotiation: None,
+            disabled_ops: 0,
+        }
+    }
+
+    /// Returns true if the time granularity is a power of ten between 1 and 1e9
+    #[cfg(feature = "abi-7-23")]
+    pub fn valid_time_gran (&self) -> bool {
+        let mut gran = self.time_gran;
+        while gran > 1 && gran % 10 == 0 { gran /= 10; }
+        gran == 1 && self.time_gran <= 1_000_000_000
+    }
+
+    /// Returns true if the max stack depth is one the kernel accepts for passthrough
+    /// (1 or 2)
+    #[cfg(feature = "abi-7-36")]
+    pub fn valid_max_stack_depth (&self) -> bool {
+        self.max_stack_depth >= 1 && self.max_stack_depth <= 2
+    }
+
+    /// Set max_background and congestion_threshold according to the given policy
+    #[cfg(feature = "abi-7-13")]
+    pub fn set_congestion_policy (&mut self, policy: CongestionPolicy) {
+        let (max_background, congestion_threshold) = policy.values();
+        self.max_background = max_background;
+        self.congestion_threshold = congestion_threshold;
+    }
+
+    /// Ask for the capabilities and parameters that speed up any filesystem without
+    /// requiring anything of it. This is what the default Filesystem::init does, an
+    /// implementation of init can call it first and adjust the parameters afterwards.
+    /// Capabilities the kernel isn't capable of are left out. It asks for:
+    ///
+    /// * FUSE_BIG_WRITES: writes of more than a page at once (up to max_write), the
+    ///   kernel splits writes into single pages without it.
+    /// * max_write of MAX_WRITE_SIZE (16 MiB), the largest write a session can receive.
+    ///   The kernel limits it further (to 1 MiB at most for current kernels).
+    /// * FUSE_AUTO_INVAL_DATA: cached data of a file is dropped when its mtime changes,
+    ///   so changes made behind the kernel's back show up after the attribute TTL
+    ///   without notifications. Filesystems that never change files on their own don't
+    ///   notice it.
+    ///
+    /// FUSE_ASYNC_READ and FUSE_EXPORT_SUPPORT are wanted anyway (see want). Readdirplus
+    /// isn't asked for, since it only works with an implementation of readdirplus.
+    pub fn want_recommended (&mut self) {
+        self.want |= self.capable & (FUSE_BIG_WRITES | FUSE_AUTO_INVAL_DATA);
+        self.max_write = MAX_WRITE_SIZE as u32;
+    }
+
+    /// Disable the given operation, usually in init. Requests of it are then replied
+    /// with ENOSYS without calling the filesystem, even if it implements the operation.
+    /// For most optional operations (like the xattr operations, access, flush, fsync,
+    /// create, locks and bmap) the kernel remembers the ENOSYS and stops sending them.
+    /// Operations of the protocol itself (init, destroy, forget, interrupt and notify
+    /// replies) can't be disabled, they're ignored.
+    pub fn disable (&mut self, op: fuse_opcode) {
+        match op {
+            FUSE_INIT | FUSE_DESTROY | FUSE_FORGET | FUSE_INTERRUPT => (),
+            #[cfg(feature = "abi-7-15")]
+            FUSE_NOTIFY_REPLY => (),
+            #[cfg(feature = "abi-7-16")]
+            FUSE_BATCH_FORGET => (),
+            _ => self.disabled_ops |= 1 << op as u64,
+        }
+    }
+
+    /// Returns true if the given operation isn't disabled (see disable)
+    pub fn enabled (&self, op: fuse_opcode) -> bool {
+        self.disabled_ops & 1 << op as u64 == 0
+    }
+
+    /// Returns the minor version of the protocol that is used with the kernel: the
+    /// lower of the versions of the kernel (proto_minor) and of this library. The
+    /// kernel doesn't send operations or use fields of its version that are newer
+    /// than the one of this library, and this library doesn't use any that are newer
+    /// than the one of the kernel.
+    pub fn negotiated_minor (&self) -> u32 {
+        cmp::min(self.proto_minor, FUSE_KERNEL_MINOR_VERSION)
+    }
+
+    /// Returns the outcome of the negotiation with the kernel, once the filesystem's
+    /// init returned (None before, e.g. while in init)
+    pub fn negotiation (&self) -> Option<&Negotiation> {
+        self.negotiation.as_ref()
+    }
+
+    /// Returns true if all of the given capability flags can be granted: the kernel
+    /// is capable of them and this library supports them with the ABI version it was
+    /// built for. Unlike granted, this doesn't depend on whether the filesystem wants
+    /// them, so it can be used in init to decide what to want, e.g. to only implement
+    /// directory listings with attributes with FUSE_DO_READDIRPLUS. An older kernel
+    /// doesn't offer the flags of newer ABI versions, so they're unsupported then.
+    pub fn supports (&self, flags: u64) -> bool {
+        self.capable & !UNSUPPORTED_FLAGS & flags == flags
+    }
+
+    /// Returns the capabilities in effect: the flags that are wanted by the filesystem
+    /// and supported by the kernel. After init, these are the granted capabilities.
+    pub fn capabilities (&self) -> Capabilities {
+        Capabilities::new(self.want & self.capable)
+    }
+
+    /// Returns true if all of the given capability flags are wanted by the
+    /// filesystem and supported by the kernel. After init, this tells if
+    /// the capabilities were granted.
+    pub fn granted (&self, flags: u64) -> bool {
+        self.want & self.capable & flags == flags
+    }
+
+    /// Returns true if the filesystem can be exported over NFS (FUSE_EXPORT_SUPPORT
+    /// was granted)
+    pub fn export_support (&self) -> bool {
+        self.granted(FUSE_EXPORT_SUPPORT)
+    }
+
+    /// Returns true if the filesystem can reply ENOSYS to open to stop the kernel
+    /// from sending opens and releases (FUSE_NO_OPEN_SUPPORT was granted)
+    pub fn no_open_support (&self) -> bool {
+        self.granted(FUSE_NO_OPEN_SUPPORT)
+    }
+
+    /// Returns true if the filesystem can reply ENOSYS to opendir to stop the kernel
+    /// from sending opendirs and releasedirs (FUSE_NO_OPENDIR_SUPPORT was granted)
+    pub fn no_opendir_support (&self) -> bool {
+        self.granted(FUSE_NO_OPENDIR_SUPPORT)
+    }
+
+    /// Returns true if the kernel may send concurrent directory operations
+    /// (FUSE_PARALLEL_DIROPS was granted)
+    pub fn parallel_dirops (&self) -> bool {
+        self.granted(FUSE_PARALLEL_DIROPS)
+    }
+
+    /// Returns true if the kernel enforces POSIX ACLs (FUSE_POSIX_ACL was granted)
+    pub fn posix_acl (&self) -> bool {
+        self.granted(FUSE_POSIX_ACL)
+    }
+
+    /// Returns true if the kernel reads directories with readdirplus (FUSE_DO_READDIRPLUS
+    /// was granted)
+    pub fn readdirplus (&self) -> bool {
+        self.granted(FUSE_DO_READDIRPLUS)
+    }
+
+    /// Returns true if the kernel invalidates cached data when the mtime of a file
+    /// changes (FUSE_AUTO_INVAL_DATA was granted)
+    pub fn auto_inval_data (&self) -> bool {
+        self.granted(FUSE_AUTO_INVAL_DATA)
+    }
+
+    /// Returns true if open gets the O_TRUNC flag and must truncate the file
+    /// (FUSE_ATOMIC_O_TRUNC was granted)
+    pub fn atomic_o_trunc (&self) -> bool {
+        self.granted(FUSE_ATOMIC_O_TRUNC)
+    }
+
+    /// Returns true if the kernel caches symlink targets (FUSE_CACHE_SYMLINKS was granted)
+    pub fn cache_symlinks (&self) -> bool {
+        self.granted(FUSE_CACHE_SYMLINKS)
+    }
+
+    /// Returns true if setxattr requests carry setxattr flags (FUSE_SETXATTR_EXT was
+    /// granted). Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn setxattr_ext (&self) -> bool {
+        self.granted(FUSE_SETXATTR_EXT)
+    }
+
+    /// Returns true if setxattr requests carry setxattr flags (FUSE_SETXATTR_EXT was
+    /// granted). Always false on OS X.
+    #[cfg(target_os = "macos")]
+    pub fn setxattr_ext (&self) -> bool {
+        false
+    }
+
+    /// Returns true if directories marked as submounts become separate mounts
+    /// (FUSE_SUBMOUNTS was granted). Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn submounts (&self) -> bool {
+        self.granted(FUSE_SUBMOUNTS)
+    }
+
+    /// Returns true if directories marked as submounts become separate mounts
+    /// (FUSE_SUBMOUNTS was granted). Always false on OS X.
+    #[cfg(target_os = "macos")]
+    pub fn submounts (&self) -> bool {
+        false
+    }
+
+    /// Returns true if DAX mapping requests are dispatched to the filesystem
+    /// (FUSE_MAP_ALIGNMENT was granted). Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn dax_mappings (&self) -> bool {
+        self.granted(FUSE_MAP_ALIGNMENT)
+    }
+
+    /// Returns true if DAX mapping requests are dispatched to the filesystem
+    /// (FUSE_MAP_ALIGNMENT was granted). Always false on OS X.
+    #[cfg(target_os = "macos")]
+    pub fn dax_mappings (&self) -> bool {
+        false
+    }
+
+    /// Returns true if the kernel sends rename2 requests for renames with flags, which
+    /// it does if the negotiated ABI version is 7.23 or later. Otherwise, renames with
+    /// flags fail with EINVAL without reaching the filesystem. Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn rename2 (&self) -> bool {
+        self.negotiated_minor() >= 23
+    }
+
+    /// Returns true if the kernel sends rename2 requests for renames with flags, which
+    /// it does with ABI 7.23 or later. Always false on OS X.
+    #[cfg(target_os = "macos")]
+    pub fn rename2 (&self) -> bool {
+        false
+    }
+
+    /// Returns true if the kernel sends syncfs requests, which it does if it speaks
+    /// ABI 7.34 or later (it's not a capability the filesystem can ask for). Like the
+    /// capabilities in flags2, this depends on the kernel's version only, not on the
+    /// ABI version this library is built for. Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn syncfs (&self) -> bool {
+        self.proto_minor >= 34
+    }
+
+    /// Returns true if the kernel sends syncfs requests, which it does with ABI 7.34
+    /// or later (it's not a capability the filesystem can ask for). Always false on
+    /// OS X.
+    #[cfg(target_os = "macos")]
+    pub fn syncfs (&self) -> bool {
+        false
+    }
+
+    /// Returns true if direct_io files can be mmap'd shared (FUSE_DIRECT_IO_ALLOW_MMAP
+    /// was granted). Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn direct_io_mmap (&self) -> bool {
+        self.granted(FUSE_DIRECT_IO_ALLOW_MMAP)
+    }
+
+    /// Returns true if direct_io files can be mmap'd shared (FUSE_DIRECT_IO_ALLOW_MMAP
+    /// was granted). Always false on OS X.
+    #[cfg(target_os = "macos")]
+    pub fn direct_io_mmap (&self) -> bool {
+        false
+    }
+
+    /// Returns true if open files may be backed by backing files (FUSE_PASSTHROUGH
+    /// was granted). Always false on OS X.
+    #[cfg(not(target_os = "macos"))]
+    pub fn passthrough (&self) -> bool {
+        self.granted(FUSE_PASSTHROUGH)
+    }
+
+    /// Returns true if open files may be backed by backing files (FUSE_PASSTHROUGH
+    /// was granted). Always false on OS X.
+    #[cfg(target_os = "macos")]
+    pub fn passthrough (&self) -> bool {
+        false
+    }
+
+    /// Returns true if the filesystem must clear the suid and sgid bits itself on
+    /// write, truncate and chown (FUSE_HANDLE_KILLPRIV or FUSE_HANDLE_KILLPRIV_V2
+    /// was granted). The kernel doesn't clear them in this case, so a filesystem
+    /// that doesn't do it has a privilege escalation bug.
+    pub fn handle_killpriv (&self) -> bool {
+        self.granted(FUSE_HANDLE_KILLPRIV) || self.granted(FUSE_HANDLE_KILLPRIV_V2)
+    }
+}
+
+/// Record the outcome of the negotiation at init. Only the session sets it, so it
+/// isn't a method of ConnectionInfo.
+pub fn set_negotiation (conn: &mut ConnectionInfo, negotiation: Negotiation) {
+    conn.negotiation = Some(negotiation);
+}
+
+/// Check if the given mount option is set in the given mount arguments. Mount
+/// options are given to -o as a comma separated list, either as a separate
+/// argument (-o name) or directly appended (-oname).
+fn has_mount_option (options: &[&OsStr], name: &str) -> bool {
+    let mut iter = options.iter().filter_map(|opt| opt.to_str());
+    while let Some(arg) = iter.next() {
+        let list = if arg == "-o" {
+            match iter.next() {
+                Some(list) => list,
+                None => break,
+            }
+        } else if arg.starts_with("-o") {
+            &arg[2..]
+        } else {
+            continue;
+        };
+        if list.split(',').any(|opt| opt == name) {
+            return true;
+        }
+    }
+    false
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use fuse::consts::{FUSE_ASYNC_READ, FUSE_BIG_WRITES, FUSE_PARALLEL_DIROPS, FUSE_HANDLE_KILLPRIV, FUSE_HANDLE_KILLPRIV_V2};
+    use super::{Capabilities, ConnectionInfo, Negotiation, has_mount_option};
+    #[cfg(feature = "abi-7-13")]
+    use super::CongestionPolicy;
+
+    #[test]
+    fn mount_options () {
+        let options = [OsStr::new("-o"), OsStr::new("ro,default_permissions"), OsStr::new("-oallow_other")];
+        assert!(has_mount_option(&options, "ro"));
+        assert!(has_mount_option(&options, "default_permissions"));
+        assert!(has_mount_option(&options, "allow_other"));
+        assert!(!has_mount_option(&options, "allow_root"));
+        assert!(!has_mount_option(&[OsStr::new("default_permissions")], "default_permissions"));
+        assert!(!has_mount_option(&[OsStr::new("-o")], "default_permissions"));
+    }
+
+    #[test]
+    fn connection_info_mount_options () {
+        let conn = ConnectionInfo::new(&[OsStr::new("-o"), OsStr::new("default_permissions")]);
+        assert!(conn.default_permissions);
+        assert!(!conn.allow_other);
+        assert!(!conn.read_only);
+        let conn = ConnectionInfo::new(&[]);
+        assert!(!conn.default_permissions);
+        assert!(!conn.allow_other);
+        assert!(!conn.read_only);
+        assert!(!conn.nosuid);
+        assert!(!conn.nodev);
+        assert!(!conn.noexec);
+        let conn = ConnectionInfo::new(&[OsStr::new("-o"), OsStr::new("ro,allow_other")]);
+        assert!(conn.read_only);
+        assert!(conn.allow_other);
+        let conn = ConnectionInfo::new(&[OsStr::new("-o"), OsStr::new("nosuid"), OsStr::new("-onodev,noexec")]);
+        assert!(conn.nosuid);
+        assert!(conn.nodev);
+        assert!(conn.noexec);
+        assert!(!conn.read_only);
+    }
+
+    #[test]
+    fn connection_info_granted () {
+        let mut conn = ConnectionInfo::new(&[]);
+        conn.capable = FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS;
+        conn.want = FUSE_ASYNC_READ;
+        assert!(conn.granted(FUSE_ASYNC_READ));
+        assert!(!conn.parallel_dirops());
+        conn.want |= FUSE_PARALLEL_DIROPS;
+        assert!(conn.parallel_dirops());
+        conn.capable = FUSE_ASYNC_READ;
+        assert!(!conn.parallel_dirops());
+        assert!(!conn.granted(FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS));
+    }
+
+    #[test]
+    fn connection_info_capabilities () {
+        let mut conn = ConnectionInfo::new(&[]);
+        conn.capable = FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS | 1 << 16;
+        conn.want = FUSE_ASYNC_READ | 1 << 16 | FUSE_HANDLE_KILLPRIV;
+        let capabilities = conn.capabilities();
+        assert_eq!(capabilities.bits(), FUSE_ASYNC_READ | 1 << 16);
+        assert!(capabilities.contains(FUSE_ASYNC_READ));
+        assert!(!capabilities.contains(FUSE_PARALLEL_DIROPS));
+        assert_eq!(capabilities.names(), ["FUSE_ASYNC_READ", "FUSE_WRITEBACK_CACHE"]);
+        assert_eq!(format!("{:?}", capabilities), "Capabilities(FUSE_ASYNC_READ | FUSE_WRITEBACK_CACHE)");
+        assert_eq!(format!("{:?}", Capabilities::new(FUSE_BIG_WRITES | 1 << 50)), "Capabilities(FUSE_BIG_WRITES | 0x4000000000000)");
+        assert_eq!(format!("{:?}", Capabilities::new(0)), "Capabilities()");
+    }
+
+    #[test]
+    fn negotiation_report () {
+        let negotiation = Negotiation {
+            kernel_version: (7, 31),
+            library_version: (7, 23),
+            offered: Capabilities::new(FUSE_ASYNC_READ | FUSE_BIG_WRITES | 1 << 50),
+            requested: Capabilities::new(FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS),
+            effective: Capabilities::new(FUSE_ASYNC_READ),
+            max_readahead: 0x20000,
+            max_write: 0x1000000,
+            max_background: 12,
+            congestion_threshold: 9,
+        };
+        assert_eq!(negotiation.to_string(), format!("\
+fuse {} (ABI 7.23), kernel ABI 7.31
+max_write 16777216, max_readahead 131072, max_background 12, congestion_threshold 9
+capability                 offered requested effective
+FUSE_ASYNC_READ            yes     yes       yes
+FUSE_BIG_WRITES            yes     no        no
+FUSE_PARALLEL_DIROPS       no      yes       no
+0x4000000000000            yes     no        no", env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn connection_info_handle_killpriv () {
+        let mut conn = ConnectionInfo::new(&[]);
+        conn.capable = FUSE_HANDLE_KILLPRIV_V2;
+        assert!(!conn.handle_killpriv());
+        conn.want = FUSE_HANDLE_KILLPRIV;
+        assert!(!conn.handle_killpriv());
+        conn.want |= FUSE_HANDLE_KILLPRIV_V2;
+        assert!(conn.handle_killpriv());
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-23")]
+    fn connection_info_time_gran () {
+        let mut conn = ConnectionInfo::new(&[]);
+        assert!(conn.valid_time_gran());
+        for &gran in [10, 1000, 1_000_000_000].iter() {
+            conn.time_gran = gran;
+            assert!(conn.valid_time_gran());
+        }
+        for &gran in [0, 7, 1100, 2_000_000_000].iter() {
+            conn.time_gran = gran;
+            assert!(!conn.valid_time_gran());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-13")]
+    fn connection_info_congestion_policy () {
+        let mut conn = ConnectionInfo::new(&[]);
+        assert_eq!((conn.max_background, conn.congestion_threshold), (0, 0));
+        conn.set_congestion_policy(CongestionPolicy::HighThroughput);
+        assert_eq!((conn.max_background, conn.congestion_threshold), (64, 48));
+        conn.set_congestion_policy(CongestionPolicy::Custom { max_background: 20, congestion_threshold: 10 });
+        assert_eq!((conn.max_background, conn.congestion_threshold), (20, 10));
+        conn.set_congestion_policy(CongestionPolicy::Default);
+        assert_eq!((conn.max_background, conn.congestion_threshold), (0, 0));
+    }
+}
@@ -0,0 +1,149 @@
+//!
+//! Runtime control of a connection to the kernel driver (Linux only). The
+//! kernel exposes every connection in the fuse control filesystem, usually
+//! mounted at /sys/fs/fuse/connections, as a directory named by the device
+//! number of the mount. Some of the parameters that are negotiated during init
+//! can be read and changed there while the filesystem is mounted. Changing
+//! them needs write access to the files, which are owned by root.
+//!
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory of the connections in the fuse control filesystem
+const CONNECTIONS_DIR: &'static str = "/sys/fs/fuse/connections";
+
+/// Control files of the connection of a mounted filesystem
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionControl {
+    dir: PathBuf,
+}
+
+impl ConnectionControl {
+    /// Find the connection of the filesystem mounted at the given path (which
+    /// must be the real path of the mountpoint, see Session::mountpoint). The
+    /// connection is looked up by the device number of the mount in
+    /// /proc/self/mountinfo. Doesn't access the mountpoint itself, so it can be
+    /// used from within the filesystem.
+    pub fn for_mountpoint (mountpoint: &Path) -> io::Result<ConnectionControl> {
+        let mut mountinfo = String::new();
+        try!(File::open("/proc/self/mountinfo").and_then(|mut file| file.read_to_string(&mut mountinfo)));
+        match mount_device(&mountinfo, mountpoint) {
+            // The directory is named by the kernel's internal device number
+            Some((major, minor)) => Ok(ConnectionControl { dir: Path::new(CONNECTIONS_DIR).join(format!("{}", major << 20 | minor)) }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "mountpoint not found")),
+        }
+    }
+
+    /// Returns the directory of the connection in the fuse control filesystem
+    pub fn dir (&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the max number of background requests the kernel may have
+    /// outstanding (see CongestionPolicy)
+    pub fn max_background (&self) -> io::Result<u32> {
+        self.read("max_background")
+    }
+
+    /// Change the max number of background requests the kernel may have outstanding
+    pub fn set_max_background (&self, max_background: u32) -> io::Result<()> {
+        self.write("max_background", max_background)
+    }
+
+    /// Returns the number of background requests at which the kernel marks the
+    /// connection as congested
+    pub fn congestion_threshold (&self) -> io::Result<u32> {
+        self.read("congestion_threshold")
+    }
+
+    /// Change the number of background requests at which the kernel marks the
+    /// connection as congested. It should not exceed max_background.
+    pub fn set_congestion_threshold (&self, congestion_threshold: u32) -> io::Result<()> {
+        self.write("congestion_threshold", congestion_threshold)
+    }
+
+    /// Read the number in the given control file
+    fn read (&self, name: &str) -> io::Result<u32> {
+        let mut value = String::new();
+        try!(File::open(self.dir.join(name)).and_then(|mut file| file.read_to_string(&mut value)));
+        value.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid control file value"))
+    }
+
+    /// Write the given number to the given control file
+    fn write (&self, name: &str, value: u32) -> io::Result<()> {
+        let mut file = try!(File::create(self.dir.join(name)));
+        file.write_all(format!("{}\n", value).as_bytes())
+    }
+}
+
+/// Returns the major and minor device number of the last mount at the given
+/// mountpoint in the given contents of /proc/self/mountinfo
+fn mount_device (mountinfo: &str, mountpoint: &Path) -> Option<(u32, u32)> {
+    mountinfo.lines().filter_map(|line| {
+        // Fields are the mount id, parent id, major:minor, root and mount point
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 5 || Path::new(&unescape(fields[4])) != mountpoint {
+            return None;
+        }
+        let mut device = fields[2].split(':').map(|n| n.parse::<u32>().ok());
+        match (device.next(), device.next()) {
+            (Some(Some(major)), Some(Some(minor))) => Some((major, minor)),
+            _ => None,
+        }
+    }).last()
+}
+
+/// Unescape a path of /proc/self/mountinfo, where spaces, tabs, newlines and
+/// backslashes are escaped as octal numbers (e.g. \040 for a space)
+fn unescape (path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i+1..i+4].iter().all(|&b| b >= b'0' && b <= b'7') {
+            let value = bytes[i+1..i+4].iter().fold(0u32, |value, &b| value * 8 + (b - b'0') as u32);
+            unescaped.push(value as u8);
+            i += 4;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::{ConnectionControl, mount_device, unescape};
+
+    const MOUNTINFO: &'static str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+45 22 0:42 / /mnt/my\\040fs rw,nosuid,nodev,relatime shared:24 - fuse.memfs memfs rw,user_id=1000,group_id=1000
+46 22 0:300 / /mnt/other rw,nosuid,nodev,relatime shared:25 - fuse /dev/fuse rw,user_id=1000,group_id=1000
+";
+
+    #[test]
+    fn unescape_paths () {
+        assert_eq!(unescape("/mnt/my\\040fs"), "/mnt/my fs");
+        assert_eq!(unescape("/mnt/back\\134slash"), "/mnt/back\\slash");
+        assert_eq!(unescape("/mnt/plain"), "/mnt/plain");
+        assert_eq!(unescape("/mnt/short\\04"), "/mnt/short\\04");
+    }
+
+    #[test]
+    fn find_mount_device () {
+        assert_eq!(mount_device(MOUNTINFO, Path::new("/mnt/my fs")), Some((0, 42)));
+        assert_eq!(mount_device(MOUNTINFO, Path::new("/mnt/other")), Some((0, 300)));
+        assert_eq!(mount_device(MOUNTINFO, Path::new("/mnt/none")), None);
+    }
+
+    #[test]
+    fn mountpoint_not_found () {
+        assert!(ConnectionControl::for_mountpoint(Path::new("/nonexistent/mountpoint")).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//!
+//! CUSE (character device in userspace) lets a process implement a character
+//! device instead of a filesystem. It uses the same channel protocol, but has
+//! its own init handshake that creates the device (e.g. /dev/<name>) and only
+//! sends the operations of open device files.
+//!
+
+use std::sync::Arc;
+use libc::{c_int, ENOSYS, ENOTTY};
+use time::Duration;
+use channel::Channel;
+use notify::Notifier;
+use Error;
+use reply::{ReplyEmpty, ReplyData, ReplyOpen, ReplyWrite, ReplyIoctl, ReplyPoll};
+use request::{Inflight, Request, request, dispatch_cuse, destroy_cuse};
+use session::{BUFFER_SIZE, retry_receive};
+
+/// Character device operations. Like with Filesystem, all methods have a default
+/// implementation that replies with an error (or does nothing).
+pub trait CuseDevice {
+    /// Initialize the device. Called before any other method, when the kernel
+    /// starts the init handshake.
+    fn init (&mut self, _req: &Request) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    /// Clean up the device. Called when the device is removed.
+    fn destroy (&mut self, _req: &Request) {
+    }
+
+    /// Open the device. Like with Filesystem::open, the device may store a file
+    /// handle that is passed to the other operations of the open file.
+    fn open (&mut self, _req: &Request, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    /// Read data from the device
+    fn read (&mut self, _req: &Request, _fh: u64, _offset: u64, _size: u32, reply: ReplyData) {
+        reply.error(ENOSYS);
+    }
+
+    /// Write data to the device
+    fn write (&mut self, _req: &Request, _fh: u64, _offset: u64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
+        reply.error(ENOSYS);
+    }
+
+    /// Flush the device. Called on each close of a file descriptor of the open file.
+    fn flush (&mut self, _req: &Request, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Release an open file. Called when all file descriptors are closed.
+    fn release (&mut self, _req: &Request, _fh: u64, _flags: u32, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    /// Synchronize the device contents
+    fn fsync (&mut self, _req: &Request, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Device specific control. Only well-formed ioctls are supported: the kernel
+    /// takes the in and out sizes from the command (see _IOR/_IOW in <sys/ioctl.h>),
+    /// copies in the argument data and copies out the data of the reply. For
+    /// commands without data, arg is the value the caller passed.
+    fn ioctl (&mut self, _req: &Request, _fh: u64, _flags: u32, _cmd: u32, _arg: u64, _in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        reply.error(ENOTTY);
+    }
+
+    /// Poll for events. events are the requested events (with ABI 7.21 or later,
+    /// 0 otherwise). If FUSE_POLL_SCHEDULE_NOTIFY is set in flags, the kernel wants
+    /// to be notified with the given kh (Notifier::poll) when the events change. If this replies
+    /// ENOSYS, the kernel treats the device as always ready and stops polling it.
+    fn poll (&mut self, _req: &Request, _fh: u64, _kh: u64, _flags: u32, _events: u32, reply: ReplyPoll) {
+        reply.error(ENOSYS);
+    }
+}
+
+/// A CUSE session runs a character device implementation. The device is created
+/// when the session runs and removed when the session ends.
+pub struct CuseSession<D: CuseDevice> {
+    /// Device operation implementations
+    pub device: D,
+    /// Communication channel to the CUSE driver
+    ch: Channel,
+    /// Name of the device (the device file is /dev/<name>)
+    pub name: String,
+    /// Major device number, 0 lets the kernel choose one
+    pub dev_major: u32,
+    /// Minor device number
+    pub dev_minor: u32,
+    /// True if the device is initialized (init handshake done)
+    pub initialized: bool,
+    /// True if the device was destroyed
+    pub destroyed: bool,
+    /// Target of the log messages of this session, fuse::cuse::<name> by default
+    pub log_target: String,
+    /// Requests that were dispatched, but not replied yet
+    inflight: Arc<Inflight>,
+}
+
+impl<D: CuseDevice> CuseSession<D> {
+    /// Create a new session for the given device with the given name and device
+    /// numbers. Requires access to /dev/cuse (usually root).
+    pub fn new (device: D, name: &str, dev_major: u32, dev_minor: u32) -> CuseSession<D> {
+        info!(target: &format!("fuse::cuse::{}", name), "Creating CUSE device {}", name);
+        let ch = match Channel::cuse() {
+            Ok(ch) => ch,
+            Err(err) => panic!("Unable to open /dev/cuse. Error {}", err),
+        };
+        CuseSession::with_channel(device, name, dev_major, dev_minor, ch)
+    }
+
+    /// Create a new session for the given device that uses the given channel
+    /// (used for testing with an unmounted channel)
+    fn with_channel (device: D, name: &str, dev_major: u32, dev_minor: u32, ch: Channel) -> CuseSession<D> {
+        CuseSession {
+            device: device,
+            ch: ch,
+            name: name.to_string(),
+            dev_major: dev_major,
+            dev_minor: dev_minor,
+            initialized: false,
+            destroyed: false,
+            log_target: format!("fuse::cuse::{}", name),
+            inflight: Arc::new(Inflight::new()),
+        }
+    }
+
+    /// Returns a notifier for the device, e.g. to wake up processes that poll it
+    /// (see CuseDevice::poll and Notifier::poll). Like Session::notifier, this
+    /// switches the channel to non-blocking mode.
+    pub fn notifier (&self) -> Result<Notifier, Error> {
+        let ch = try!(self.ch.owned_sender());
+        try!(self.ch.set_nonblocking());
+        Ok(Notifier::new(ch))
+    }
+
+    /// Run the session loop that receives kernel requests and dispatches them to
+    /// the device. The loop ends when the connection to the kernel ends, after
+    /// that the device is destroyed.
+    pub fn run (&mut self) {
+        let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+        loop {
+            // Wait for the next request, the channel may be non-blocking (see notifier)
+            let res = match self.ch.wait(Duration::max_value()) {
+                Ok(_) => self.ch.receive_into(&mut buffer),
+                Err(err) => Err(err),
+            };
+            match res {
+                Ok(data) => match request(self.ch.sender(), data, None, &self.inflight, &self.log_target) {
+                    Some(req) => dispatch_cuse(&req, self),
+                    // Quit loop on illegal request
+                    None => break,
+                },
+                Err(ref err) => match retry_receive(err) {
+                    Some(true) => (),
+                    Some(false) => {
+                        info!(target: &self.log_target, "Connection to CUSE device ended: {}", err);
+                        break;
+                    },
+                    None => {
+                        error!(target: &self.log_target, "Lost connection to CUSE device: {}", err);
+                        break;
+                    },
+                },
+            }
+        }
+        let ch = self.ch.sender();
+        destroy_cuse(ch, self);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{mem, slice};
+    use libc::{c_int, c_void, size_t, EIO, ENOTTY};
+    use fuse::{FUSE_KERNEL_VERSION, fuse_in_header, fuse_out_header, fuse_read_in, fuse_ioctl_in, fuse_ioctl_out, cuse_init_in, cuse_init_out, CUSE_INIT};
+    use fuse::fuse_opcode::{FUSE_READ, FUSE_IOCTL};
+    use channel::Channel;
+    use {ReplyData, ReplyIoctl, Request};
+    use super::{CuseDevice, CuseSession};
+
+    #[derive(Default)]
+    struct EchoDevice {
+        initialized: bool,
+        destroyed: bool,
+    }
+
+    impl CuseDevice for EchoDevice {
+        fn init (&mut self, _req: &Request) -> Result<(), c_int> {
+            self.initialized = true;
+            Ok(())
+        }
+
+        fn destroy (&mut self, _req: &Request) {
+            self.destroyed = true;
+        }
+
+        fn read (&mut self, _req: &Request, _fh: u64, _offset: u64, size: u32, reply: ReplyData) {
+            reply.data(&b"hello"[..size as usize]);
+        }
+
+        fn ioctl (&mut self, _req: &Request, _fh: u64, _flags: u32, cmd: u32, _arg: u64, in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+            match cmd {
+                // Echo the argument data reversed
+                0x1234 => {
+                    let data: Vec<u8> = in_data.iter().rev().cloned().collect();
+                    reply.ioctl(7, &data);
+                },
+                _ => reply.error(ENOTTY),
+            }
+        }
+    }
+
+    /// Send a request with the given opcode, argument and data to the channel at the other end of fd
+    fn send_request<T> (fd: c_int, opcode: u32, arg: &T, extra: &[u8]) {
+        let len = mem::size_of::<fuse_in_header>() + mem::size_of::<T>() + extra.len();
+        let header = fuse_in_header { len: len as u32, opcode: opcode, unique: 0xdeadbeef, nodeid: 0, uid: 0, gid: 0, pid: 0, padding: 0 };
+        let mut buffer: Vec<u8> = Vec::with_capacity(len);
+        unsafe {
+            buffer.push_all(slice::from_raw_parts(&header as *const fuse_in_header as *const u8, mem::size_of::<fuse_in_header>()));
+            buffer.push_all(slice::from_raw_parts(arg as *const T as *const u8, mem::size_of::<T>()));
+            buffer.push_all(extra);
+            ::libc::write(fd, buffer.as_ptr() as *const c_void, len as size_t);
+        }
+    }
+
+    /// Receive a reply from the channel at the other end of fd
+    fn receive (fd: c_int) -> Vec<u8> {
+        let mut buffer = vec![0u8; 4096];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        assert!(rc >= mem::size_of::<fuse_out_header>() as isize, "failed to receive reply");
+        buffer.truncate(rc as usize);
+        buffer
+    }
+
+    /// Returns the error of the given reply
+    fn error_of (reply: &[u8]) -> i32 {
+        let header: &fuse_out_header = unsafe { mem::transmute(reply.as_ptr()) };
+        header.error
+    }
+
+    #[test]
+    fn cuse_session () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = CuseSession::with_channel(EchoDevice::default(), "echo", 0, 0, ch);
+        // Reads before the init handshake fail
+        let read: fuse_read_in = unsafe { mem::zeroed() };
+        send_request(fd, FUSE_READ as u32, &read, &[]);
+        send_request(fd, CUSE_INIT, &cuse_init_in { major: FUSE_KERNEL_VERSION, minor: 23, unused: 0, flags: 0 }, &[]);
+        let mut read: fuse_read_in = unsafe { mem::zeroed() };
+        read.size = 4;
+        send_request(fd, FUSE_READ as u32, &read, &[]);
+        let mut ioctl: fuse_ioctl_in = unsafe { mem::zeroed() };
+        ioctl.cmd = 0x1234;
+        ioctl.in_size = 3;
+        send_request(fd, FUSE_IOCTL as u32, &ioctl, &[1, 2, 3]);
+        ioctl.cmd = 0x5678;
+        ioctl.in_size = 0;
+        send_request(fd, FUSE_IOCTL as u32, &ioctl, &[]);
+        // A broken request ends the loop like a lost connection
+        unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        se.run();
+        assert!(se.device.initialized);
+        assert!(se.device.destroyed);
+        assert_eq!(error_of(&receive(fd)), -EIO);
+        // The init reply creates the device with the session's name
+        let reply = receive(fd);
+        assert_eq!(error_of(&reply), 0);
+        let init: &cuse_init_out = unsafe { mem::transmute(reply[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(init.major, FUSE_KERNEL_VERSION);
+        assert!(init.minor >= 11);
+        assert_eq!(&reply[mem::size_of::<fuse_out_header>() + mem::size_of::<cuse_init_out>()..], &b"DEVNAME=echo\0"[..]);
+        let reply = receive(fd);
+        assert_eq!(&reply[mem::size_of::<fuse_out_header>()..], &b"hell"[..]);
+        let reply = receive(fd);
+        let out: &fuse_ioctl_out = unsafe { mem::transmute(reply[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(out.result, 7);
+        assert_eq!(&reply[mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_ioctl_out>()..], &[3u8, 2, 1][..]);
+        assert_eq!(error_of(&receive(fd)), -ENOTTY);
+        unsafe { ::libc::close(fd); }
+    }
+}
@@ -0,0 +1,120 @@
+//!
+//! Errors of mounting, running and unmounting a filesystem. Filesystem methods
+//! still reply with plain errnos, this is about the errors that the library
+//! itself returns to the caller.
+//!
+
+use std::{error, fmt, io};
+
+/// Error of a mount, session or unmount function
+#[derive(Debug)]
+pub enum Error {
+    /// Mounting the filesystem failed (e.g. the mountpoint doesn't exist, or the
+    /// FUSE device or mount helper isn't available)
+    Mount(io::Error),
+    /// Communicating with the kernel over the channel failed unexpectedly, e.g.
+    /// sending a notification. Other I/O errors convert into this.
+    Channel(io::Error),
+    /// The kernel sent something that doesn't follow the FUSE protocol (e.g. a
+    /// request that is shorter than its header says)
+    Protocol(String),
+    /// Unmounting the filesystem failed (e.g. EBUSY if it's still in use)
+    Unmount(io::Error),
+}
+
+impl Error {
+    /// Returns the errno of the underlying OS error, if there is one
+    pub fn raw_os_error (&self) -> Option<i32> {
+        match *self {
+            Error::Mount(ref err) | Error::Channel(ref err) | Error::Unmount(ref err) => err.raw_os_error(),
+            Error::Protocol(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Mount(ref err) => write!(f, "mount failed: {}", err),
+            Error::Channel(ref err) => write!(f, "channel error: {}", err),
+            Error::Protocol(ref msg) => write!(f, "protocol error: {}", msg),
+            Error::Unmount(ref err) => write!(f, "unmount failed: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description (&self) -> &str {
+        match *self {
+            Error::Mount(_) => "mount failed",
+            Error::Channel(_) => "channel error",
+            Error::Protocol(_) => "protocol error",
+            Error::Unmount(_) => "unmount failed",
+        }
+    }
+
+    fn cause (&self) -> Option<&error::Error> {
+        match *self {
+            Error::Mount(ref err) | Error::Channel(ref err) | Error::Unmount(ref err) => Some(err),
+            Error::Protocol(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from (err: io::Error) -> Error {
+        Error::Channel(err)
+    }
+}
+
+/// Errors convert back into I/O errors (e.g. for callers that still return
+/// io::Result). The underlying I/O error is kept as it is, protocol errors
+/// become errors of kind InvalidData.
+impl From<Error> for io::Error {
+    fn from (err: Error) -> io::Error {
+        match err {
+            Error::Mount(err) | Error::Channel(err) | Error::Unmount(err) => err,
+            Error::Protocol(msg) => io::Error::new(io::ErrorKind::InvalidData, msg),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as StdError;
+    use std::io;
+    use libc::{EBUSY, ENOENT};
+    use super::Error;
+
+    #[test]
+    fn from_io_error () {
+        match Error::from(io::Error::from_raw_os_error(ENOENT)) {
+            Error::Channel(err) => assert_eq!(err.raw_os_error(), Some(ENOENT)),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn into_io_error () {
+        let err: io::Error = Error::Unmount(io::Error::from_raw_os_error(EBUSY)).into();
+        assert_eq!(err.raw_os_error(), Some(EBUSY));
+        let err: io::Error = Error::Protocol("short read".to_string()).into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn raw_os_error () {
+        assert_eq!(Error::Mount(io::Error::from_raw_os_error(ENOENT)).raw_os_error(), Some(ENOENT));
+        assert_eq!(Error::Unmount(io::Error::from_raw_os_error(EBUSY)).raw_os_error(), Some(EBUSY));
+        assert_eq!(Error::Protocol("short read".to_string()).raw_os_error(), None);
+    }
+
+    #[test]
+    fn display () {
+        let err = Error::Protocol("short read".to_string());
+        assert_eq!(err.to_string(), "protocol error: short read");
+        assert!(err.cause().is_none());
+        assert!(Error::Unmount(io::Error::from_raw_os_error(EBUSY)).cause().is_some());
+    }
+}
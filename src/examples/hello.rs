@@ -0,0 +1,87 @@
+//!
+//! The simplest possible filesystem: a read-only root directory that contains
+//! a single file, hello.txt, with the text "Hello World!".
+//!
+
+use std::path::Path;
+use libc::{c_int, EACCES, ENOENT, O_RDWR, O_WRONLY};
+use time::Timespec;
+use {FileType, FileAttr, Filesystem, Request, FUSE_ROOT_ID};
+use {ReplyData, ReplyEntry, ReplyAttr, ReplyOpen, ReplyDirectory};
+
+/// Time the kernel may cache entries and attributes
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+/// Inode number of hello.txt
+const HELLO_TXT_INO: u64 = 2;
+
+/// Contents of hello.txt
+const HELLO_TXT_CONTENT: &'static [u8] = b"Hello World!\n";
+
+/// Hello world filesystem
+pub struct HelloFs {
+    /// Attributes of the root directory
+    root_attr: FileAttr,
+    /// Attributes of hello.txt
+    hello_attr: FileAttr,
+}
+
+impl HelloFs {
+    /// Create a new hello world filesystem
+    pub fn new () -> HelloFs {
+        let mut root_attr = FileAttr::for_dir(FUSE_ROOT_ID);
+        root_attr.perm = 0o555;
+        let mut hello_attr = FileAttr::for_file(HELLO_TXT_INO, HELLO_TXT_CONTENT.len() as u64);
+        hello_attr.perm = 0o444;
+        HelloFs { root_attr: root_attr, hello_attr: hello_attr }
+    }
+}
+
+impl Filesystem for HelloFs {
+    fn lookup (&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        if parent == FUSE_ROOT_ID && name.to_str() == Some("hello.txt") {
+            reply.entry(&TTL, &self.hello_attr, 0);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn getattr (&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match ino {
+            FUSE_ROOT_ID => reply.attr(&TTL, &self.root_attr),
+            HELLO_TXT_INO => reply.attr(&TTL, &self.hello_attr),
+            _ => reply.error(ENOENT),
+        }
+    }
+
+    fn open (&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        if ino != HELLO_TXT_INO {
+            reply.error(ENOENT);
+        } else if flags as c_int & (O_WRONLY | O_RDWR) != 0 {
+            reply.error(EACCES);
+        } else {
+            reply.opened(0, 0);
+        }
+    }
+
+    fn read (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, size: u32, _flags: u32, _lock_owner: Option<u64>, reply: ReplyData) {
+        if ino == HELLO_TXT_INO {
+            reply.data_at(HELLO_TXT_CONTENT, offset, size);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, reply: ReplyDirectory) {
+        if ino != FUSE_ROOT_ID {
+            reply.error(ENOENT);
+            return;
+        }
+        let entries = [
+            (FUSE_ROOT_ID, FileType::Directory, "."),
+            (FUSE_ROOT_ID, FileType::Directory, ".."),
+            (HELLO_TXT_INO, FileType::RegularFile, "hello.txt"),
+        ];
+        reply.entries(offset, entries.iter().cloned());
+    }
+}
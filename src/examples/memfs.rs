@@ -0,0 +1,524 @@
+//!
+//! A writable filesystem that keeps all files and directories in memory.
+//! Everything is lost when the filesystem is unmounted.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libc::{c_int, O_APPEND, O_TRUNC, S_IFMT, S_IFREG};
+use libc::{EBADF, EEXIST, EISDIR, ENOENT, ENOSYS, ENOTDIR, ENOTEMPTY, EPERM};
+use time::{self, Timespec};
+use consts::{FOPEN_DIRECT_IO, FUSE_ATOMIC_O_TRUNC};
+use {apply_umask, ConnectionInfo, DEFAULT_BLKSIZE, FileType, FileAttr, Filesystem, InodeAllocator, Request, FUSE_ROOT_ID};
+use {ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen, ReplyWrite, ReplyCreate, ReplyDirectory};
+
+/// Time the kernel may cache entries and attributes
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+/// A file, directory or symlink
+struct Node {
+    /// Attributes of the node
+    attr: FileAttr,
+    /// Contents of a file or target of a symlink
+    data: Vec<u8>,
+    /// Entries of a directory
+    children: BTreeMap<OsString, u64>,
+    /// Parent of a directory
+    parent: u64,
+    /// Open the file with direct_io, i.e. bypass the page cache
+    direct_io: bool,
+}
+
+impl Node {
+    fn new (ino: u64, kind: FileType, perm: u16, uid: u32, gid: u32) -> Node {
+        let now = time::get_time();
+        Node {
+            attr: FileAttr {
+                ino: ino,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: kind,
+                perm: perm,
+                nlink: if kind == FileType::Directory { 2 } else { 1 },
+                uid: uid,
+                gid: gid,
+                rdev: 0,
+                blksize: DEFAULT_BLKSIZE,
+                flags: 0,
+            },
+            data: Vec::new(),
+            children: BTreeMap::new(),
+            parent: 0,
+            direct_io: false,
+        }
+    }
+
+    /// Update the size attributes after the data changed
+    fn update_size (&mut self) {
+        self.attr.size = self.data.len() as u64;
+        self.attr.blocks = FileAttr::blocks_for(self.attr.size);
+    }
+
+    /// Truncate or extend (with zeros) the data to the given size
+    fn resize (&mut self, size: usize) {
+        let len = self.data.len();
+        if size < len {
+            self.data.truncate(size);
+        } else {
+            self.data.extend(iter::repeat(0).take(size - len));
+        }
+        self.update_size();
+        self.attr.mtime = time::get_time();
+    }
+}
+
+/// Entries of a directory in the order they're listed by readdir
+type DirEntries = Vec<(u64, FileType, OsString)>;
+
+/// In-memory filesystem. Supports regular files, directories, symlinks and hard
+/// links. Nodes are freed as soon as their last link is removed, even if they
+/// are still open.
+///
+/// Directories are listed from a snapshot of their entries that is taken at
+/// opendir (and again when a listing starts over at offset 0, e.g. after
+/// rewinddir), so entries that are added or removed while a directory is read
+/// don't make readdir skip or repeat other entries.
+///
+/// Writes to an open file are kept with its file handle until the handle is
+/// flushed (on close), synced or released. Reads and getattr through the
+/// handle see the written data and the grown size, others see the contents
+/// of the last flush.
+pub struct MemFs {
+    nodes: HashMap<u64, Node>,
+    inodes: InodeAllocator,
+    direct_io_names: Vec<OsString>,
+    reads: Arc<AtomicUsize>,
+    dir_handles: HashMap<u64, DirEntries>,
+    write_buffers: HashMap<u64, Vec<u8>>,
+    next_fh: u64,
+}
+
+impl MemFs {
+    /// Create a new filesystem with an empty root directory
+    pub fn new () -> MemFs {
+        let mut root = Node::new(FUSE_ROOT_ID, FileType::Directory, 0o755, 0, 0);
+        root.parent = FUSE_ROOT_ID;
+        let mut nodes = HashMap::new();
+        nodes.insert(FUSE_ROOT_ID, root);
+        MemFs {
+            nodes: nodes,
+            inodes: InodeAllocator::new(),
+            direct_io_names: Vec::new(),
+            reads: Arc::new(AtomicUsize::new(0)),
+            dir_handles: HashMap::new(),
+            write_buffers: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    /// Open files that are created with the given name with direct_io, so that every
+    /// read and write of them goes to the filesystem instead of the page cache (like
+    /// for device-like or streaming files whose contents change by themselves)
+    pub fn with_direct_io<T: Into<OsString>> (mut self, name: T) -> MemFs {
+        self.direct_io_names.push(name.into());
+        self
+    }
+
+    /// Returns the number of read requests the filesystem got so far. The counter
+    /// is shared, so it can be used to watch the filesystem while it's mounted and
+    /// e.g. tell reads from the page cache from reads that reach the filesystem.
+    pub fn read_counter (&self) -> Arc<AtomicUsize> {
+        self.reads.clone()
+    }
+
+    /// Returns the directory with the given inode number
+    fn dir (&mut self, ino: u64) -> Result<&mut Node, c_int> {
+        match self.nodes.get_mut(&ino) {
+            Some(node) => if node.attr.kind == FileType::Directory { Ok(node) } else { Err(ENOTDIR) },
+            None => Err(ENOENT),
+        }
+    }
+
+    /// Returns a new file or directory handle
+    fn new_fh (&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    /// Store the data written through the given file handle in the given node
+    fn flush_writes (&mut self, ino: u64, fh: u64) {
+        if let Some(data) = self.write_buffers.remove(&fh) {
+            // The node may be gone if the file was removed while open
+            if let Some(node) = self.nodes.get_mut(&ino) {
+                node.data = data;
+                node.update_size();
+            }
+        }
+    }
+
+    /// Returns the inode number of the given entry of a directory
+    fn child (&mut self, parent: u64, name: &Path) -> Result<u64, c_int> {
+        let dir = try!(self.dir(parent));
+        dir.children.get(name.as_os_str()).cloned().ok_or(ENOENT)
+    }
+
+    /// Returns the current entries of the given directory, including . and ..
+    fn dir_entries (&mut self, ino: u64) -> Result<DirEntries, c_int> {
+        let (parent, children): (u64, Vec<(OsString, u64)>) = {
+            let dir = try!(self.dir(ino));
+            (dir.parent, dir.children.iter().map(|(name, &child)| (name.clone(), child)).collect())
+        };
+        let mut entries = vec![(ino, FileType::Directory, OsString::from(".")), (parent, FileType::Directory, OsString::from(".."))];
+        entries.extend(children.into_iter().map(|(name, child)| (child, self.nodes[&child].attr.kind, name)));
+        Ok(entries)
+    }
+
+    /// Create a new node as an entry of the given directory
+    fn insert (&mut self, req: &Request, parent: u64, name: &Path, kind: FileType, perm: u16) -> Result<&mut Node, c_int> {
+        if try!(self.dir(parent)).children.contains_key(name.as_os_str()) {
+            return Err(EEXIST);
+        }
+        // Inode numbers of removed nodes are never freed, since the kernel may still
+        // know them and open files may still use them, so the generation is always 0
+        let (ino, _) = self.inodes.allocate();
+        {
+            let dir = self.nodes.get_mut(&parent).unwrap();
+            dir.children.insert(name.as_os_str().to_os_string(), ino);
+            if kind == FileType::Directory {
+                dir.attr.nlink += 1;
+            }
+        }
+        let mut node = Node::new(ino, kind, perm, req.uid(), req.gid());
+        node.parent = parent;
+        node.direct_io = self.direct_io_names.iter().any(|n| n == name.as_os_str());
+        self.nodes.insert(ino, node);
+        Ok(self.nodes.get_mut(&ino).unwrap())
+    }
+
+    /// Remove an entry from a directory and drop its node when the last link is gone
+    fn remove (&mut self, parent: u64, name: &Path) -> Result<(), c_int> {
+        let ino = try!(self.child(parent, name));
+        let is_dir = {
+            let node = self.nodes.get_mut(&ino).unwrap();
+            if node.attr.kind == FileType::Directory {
+                true
+            } else {
+                node.attr.nlink -= 1;
+                node.attr.ctime = time::get_time();
+                false
+            }
+        };
+        {
+            let dir = self.nodes.get_mut(&parent).unwrap();
+            dir.children.remove(name.as_os_str());
+            if is_dir {
+                dir.attr.nlink -= 1;
+            }
+        }
+        if is_dir || self.nodes[&ino].attr.nlink == 0 {
+            self.nodes.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Move an entry to another name, replacing an existing entry like rename does
+    fn rename_entry (&mut self, parent: u64, name: &Path, newparent: u64, newname: &Path) -> Result<(), c_int> {
+        let ino = try!(self.child(parent, name));
+        let is_dir = self.nodes[&ino].attr.kind == FileType::Directory;
+        // Replace an existing entry (directories only if they're empty)
+        if let Ok(existing) = self.child(newparent, newname) {
+            if existing == ino {
+                return Ok(());
+            }
+            {
+                let node = &self.nodes[&existing];
+                match (is_dir, node.attr.kind == FileType::Directory) {
+                    (true, false) => return Err(ENOTDIR),
+                    (false, true) => return Err(EISDIR),
+                    (true, true) if !node.children.is_empty() => return Err(ENOTEMPTY),
+                    _ => (),
+                }
+            }
+            try!(self.remove(newparent, newname));
+        }
+        try!(self.dir(newparent)).children.insert(newname.as_os_str().to_os_string(), ino);
+        self.nodes.get_mut(&parent).unwrap().children.remove(name.as_os_str());
+        if is_dir {
+            self.nodes.get_mut(&parent).unwrap().attr.nlink -= 1;
+            self.nodes.get_mut(&newparent).unwrap().attr.nlink += 1;
+            self.nodes.get_mut(&ino).unwrap().parent = newparent;
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for MemFs {
+    fn init (&mut self, _req: &Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+        // Truncate in open instead of getting a separate setattr
+        conn.want |= FUSE_ATOMIC_O_TRUNC;
+        Ok(())
+    }
+
+    fn lookup (&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        // The kernel looks up . and .. if the filesystem is exported over NFS. Since
+        // inode numbers are never reused, a generation number of 0 is fine
+        let res = match name.to_str() {
+            Some(".") => self.dir(parent).map(|_| parent),
+            Some("..") => self.dir(parent).map(|dir| dir.parent),
+            _ => self.child(parent, name),
+        };
+        match res {
+            Ok(ino) => reply.entry(&TTL, &self.nodes[&ino].attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getattr (&mut self, _req: &Request, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        let mut attr = match self.nodes.get(&ino) {
+            Some(node) => node.attr,
+            None => return reply.error(ENOENT),
+        };
+        // An open file has the size of what was written through its handle
+        if let Some(data) = fh.and_then(|fh| self.write_buffers.get(&fh)) {
+            attr.size = data.len() as u64;
+            attr.blocks = FileAttr::blocks_for(attr.size);
+        }
+        reply.attr(&TTL, &attr);
+    }
+
+    fn setattr (&mut self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>, fh: Option<u64>, crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, flags: Option<u32>, reply: ReplyAttr) {
+        // Changes through an open file (like ftruncate) apply to what was written through it
+        if let Some(fh) = fh {
+            self.flush_writes(ino, fh);
+        }
+        let node = match self.nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        if let Some(mode) = mode { node.attr.perm = (mode & 0o7777) as u16; }
+        if let Some(uid) = uid { node.attr.uid = uid; }
+        if let Some(gid) = gid { node.attr.gid = gid; }
+        if let Some(size) = size {
+            if node.attr.kind == FileType::Directory {
+                return reply.error(EISDIR);
+            }
+            node.resize(size as usize);
+        }
+        if let Some(atime) = atime { node.attr.atime = atime; }
+        if let Some(mtime) = mtime { node.attr.mtime = mtime; }
+        if let Some(crtime) = crtime { node.attr.crtime = crtime; }
+        if let Some(flags) = flags { node.attr.flags = flags; }
+        node.attr.ctime = time::get_time();
+        reply.attr(&TTL, &node.attr);
+    }
+
+    fn readlink (&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.nodes.get(&ino) {
+            Some(node) if node.attr.kind == FileType::Symlink => reply.data(&node.data),
+            Some(_) => reply.error(EPERM),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn mknod (&mut self, req: &Request, parent: u64, name: &Path, mode: u32, _rdev: u32, reply: ReplyEntry) {
+        // Only regular files are supported
+        if mode & S_IFMT as u32 != S_IFREG as u32 {
+            return reply.error(ENOSYS);
+        }
+        match self.insert(req, parent, name, FileType::RegularFile, (mode & 0o7777) as u16) {
+            Ok(node) => reply.entry(&TTL, &node.attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mkdir (&mut self, req: &Request, parent: u64, name: &Path, mode: u32, reply: ReplyEntry) {
+        match self.insert(req, parent, name, FileType::Directory, (mode & 0o7777) as u16) {
+            Ok(node) => reply.entry(&TTL, &node.attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn unlink (&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        let res = self.child(parent, name).and_then(|ino| {
+            let kind = self.nodes[&ino].attr.kind;
+            match kind {
+                FileType::Directory => Err(EISDIR),
+                _ => self.remove(parent, name),
+            }
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir (&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        let res = self.child(parent, name).and_then(|ino| {
+            let node = &self.nodes[&ino];
+            if node.attr.kind != FileType::Directory {
+                Err(ENOTDIR)
+            } else if !node.children.is_empty() {
+                Err(ENOTEMPTY)
+            } else {
+                Ok(())
+            }
+        }).and_then(|()| {
+            self.remove(parent, name)
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn symlink (&mut self, req: &Request, parent: u64, name: &Path, link: &Path, reply: ReplyEntry) {
+        match self.insert(req, parent, name, FileType::Symlink, 0o777) {
+            Ok(node) => {
+                node.data.push_all(link.as_os_str().as_bytes());
+                node.update_size();
+                reply.entry(&TTL, &node.attr, 0);
+            },
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rename (&mut self, _req: &Request, parent: u64, name: &Path, newparent: u64, newname: &Path, reply: ReplyEmpty) {
+        match self.rename_entry(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn link (&mut self, _req: &Request, ino: u64, newparent: u64, newname: &Path, reply: ReplyEntry) {
+        match self.nodes.get(&ino).map(|node| node.attr.kind) {
+            Some(FileType::Directory) => return reply.error(EPERM),
+            Some(_) => (),
+            None => return reply.error(ENOENT),
+        }
+        let res = self.dir(newparent).and_then(|dir| {
+            if dir.children.contains_key(newname.as_os_str()) {
+                Err(EEXIST)
+            } else {
+                dir.children.insert(newname.as_os_str().to_os_string(), ino);
+                Ok(())
+            }
+        });
+        match res {
+            Ok(()) => {
+                let node = self.nodes.get_mut(&ino).unwrap();
+                node.attr.nlink += 1;
+                node.attr.ctime = time::get_time();
+                reply.entry(&TTL, &node.attr, 0);
+            },
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn open (&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.nodes.get_mut(&ino) {
+            Some(node) => {
+                // With FUSE_ATOMIC_O_TRUNC, truncating is up to us
+                if flags as c_int & O_TRUNC != 0 {
+                    node.resize(0);
+                    node.attr.ctime = node.attr.mtime;
+                }
+            },
+            None => return reply.error(ENOENT),
+        }
+        let fh = self.new_fh();
+        reply.opened(fh, if self.nodes[&ino].direct_io { FOPEN_DIRECT_IO } else { 0 });
+    }
+
+    fn read (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, size: u32, _flags: u32, _lock_owner: Option<u64>, reply: ReplyData) {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        match (self.write_buffers.get(&fh), self.nodes.get(&ino)) {
+            (Some(data), Some(_)) => reply.data_at(data, offset, size),
+            (None, Some(node)) => reply.data_at(&node.data, offset, size),
+            (_, None) => reply.error(ENOENT),
+        }
+    }
+
+    fn write (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], _write_flags: u32, flags: u32, reply: ReplyWrite) {
+        let node = match self.nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        let buffer = self.write_buffers.entry(fh).or_insert_with(|| node.data.clone());
+        // Appending writes go to the end, whatever offset the kernel thought it is
+        let offset = if flags as c_int & O_APPEND != 0 { buffer.len() } else { offset as usize };
+        let end = offset + data.len();
+        let len = buffer.len();
+        if end > len {
+            buffer.extend(iter::repeat(0).take(end - len));
+        }
+        for (dst, src) in buffer[offset..end].iter_mut().zip(data.iter()) {
+            *dst = *src;
+        }
+        node.attr.mtime = time::get_time();
+        reply.written(data.len() as u32);
+    }
+
+    fn flush (&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.flush_writes(ino, fh);
+        reply.ok();
+    }
+
+    fn fsync (&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.flush_writes(ino, fh);
+        reply.ok();
+    }
+
+    fn release (&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        self.flush_writes(ino, fh);
+        reply.ok();
+    }
+
+    fn opendir (&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        match self.dir_entries(ino) {
+            Ok(entries) => {
+                let fh = self.new_fh();
+                self.dir_handles.insert(fh, entries);
+                reply.opened(fh, 0);
+            },
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
+        // A listing that starts over sees the current entries
+        if offset == 0 && self.dir_handles.contains_key(&fh) {
+            match self.dir_entries(ino) {
+                Ok(entries) => { self.dir_handles.insert(fh, entries); },
+                Err(err) => return reply.error(err),
+            }
+        }
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries,
+            None => return reply.error(EBADF),
+        };
+        reply.entries(offset, entries.iter().map(|&(child, kind, ref name)| (child, kind, name)));
+    }
+
+    fn releasedir (&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn create (&mut self, req: &Request, parent: u64, name: &Path, mode: u32, umask: u32, _flags: u32, reply: ReplyCreate) {
+        let fh = self.new_fh();
+        match self.insert(req, parent, name, FileType::RegularFile, apply_umask(mode, umask)) {
+            Ok(node) => reply.created(&TTL, &node.attr, 0, fh, if node.direct_io { FOPEN_DIRECT_IO } else { 0 }),
+            Err(err) => reply.error(err),
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//!
+//! Example filesystem implementations. They show how to implement common
+//! filesystem operations and can be used for testing.
+//!
+
+pub use self::hello::HelloFs;
+pub use self::memfs::MemFs;
+
+mod hello;
+mod memfs;
@@ -0,0 +1,110 @@
+//!
+//! Fallback implementations of filesystem operations that are composed of
+//! other, more primitive operations. A filesystem can opt in to use them by
+//! calling them from its own operation methods.
+//!
+
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use libc::c_int;
+use libc::consts::os::posix88::{EEXIST, EIO};
+use argument::ArgumentIterator;
+use fuse::{fuse_out_header, fuse_entry_out};
+use reply::{Reply, ReplyEntry, ReplyEmpty};
+use {Filesystem, Request};
+
+/// Call a filesystem operation with a reply that captures the result instead of
+/// sending it to the kernel. Returns the reply data or the error code. The
+/// operation must reply before it returns, otherwise it is considered failed.
+fn call<T: Reply, F: FnOnce(T)> (req: &Request, f: F) -> Result<Vec<u8>, c_int> {
+    let (tx, rx) = channel();
+    f(Reply::new(req.unique(), move |data: &[&[u8]]| {
+        let mut bytes = vec![];
+        for d in data { bytes.push_all(d); }
+        let _ = tx.send(bytes);
+    }));
+    match rx.try_recv() {
+        Ok(bytes) => {
+            let header: &fuse_out_header = ArgumentIterator::new(&bytes).fetch();
+            match header.error {
+                0 => Ok(bytes[mem::size_of::<fuse_out_header>()..].to_vec()),
+                err => Err(-err),
+            }
+        },
+        Err(_) => {
+            warn!(target: req.log_target(), "Operation {} didn't reply synchronously, can't use it for a fallback operation", req.unique());
+            Err(EIO)
+        },
+    }
+}
+
+/// Look up an entry and return its inode number. A successful lookup increases the
+/// lookup count of the inode, but since the kernel never sees the reply, the lookup
+/// count is decreased again right away.
+fn lookup_ino<FS: Filesystem> (fs: &mut FS, req: &Request, parent: u64, name: &Path) -> Result<u64, c_int> {
+    let data = try!(call(req, |reply: ReplyEntry| fs.lookup(req, parent, name, reply)));
+    let ino = ArgumentIterator::new(&data).fetch::<fuse_entry_out>().nodeid;
+    fs.forget(req, ino, 1);
+    Ok(ino)
+}
+
+/// Create a hard link to the given inode (see lookup_ino about the lookup count)
+fn link<FS: Filesystem> (fs: &mut FS, req: &Request, ino: u64, newparent: u64, newname: &Path) -> Result<(), c_int> {
+    try!(call(req, |reply: ReplyEntry| fs.link(req, ino, newparent, newname, reply)));
+    fs.forget(req, ino, 1);
+    Ok(())
+}
+
+/// Remove a directory entry
+fn unlink<FS: Filesystem> (fs: &mut FS, req: &Request, parent: u64, name: &Path) -> Result<(), c_int> {
+    try!(call(req, |reply: ReplyEmpty| fs.unlink(req, parent, name, reply)));
+    Ok(())
+}
+
+/// Rename a file by creating a hard link with the new name and removing the old name,
+/// using the filesystem's lookup, link, unlink and forget methods. A filesystem that
+/// implements these can use this to implement rename:
+///
+/// ```ignore
+/// fn rename (&mut self, req: &Request, parent: u64, name: &Path, newparent: u64, newname: &Path, reply: ReplyEmpty) {
+///     fuse::rename_by_link(self, req, parent, name, newparent, newname, reply);
+/// }
+/// ```
+///
+/// Unlike a real rename, this is *not atomic*: other operations may see both names or,
+/// if the new name already existed, none of them for a moment, and a failure can leave
+/// both names in place. Directories can't be renamed this way, since they can't be hard
+/// linked. The used methods must reply before they return (not asynchronously).
+///
+/// Like rename(2), renaming a name to itself or to another hard link of the same
+/// file succeeds without changing anything (unlinking the new name first would
+/// remove the last name of the file).
+///
+/// It can't honour rename flags (like RENAME_NOREPLACE or RENAME_EXCHANGE), which
+/// is why the default Filesystem::rename2 only calls rename for renames without flags.
+pub fn rename_by_link<FS: Filesystem> (fs: &mut FS, req: &Request, parent: u64, name: &Path, newparent: u64, newname: &Path, reply: ReplyEmpty) {
+    if parent == newparent && name == newname {
+        return reply.ok();
+    }
+    let res = lookup_ino(fs, req, parent, name).and_then(|ino| {
+        if lookup_ino(fs, req, newparent, newname) == Ok(ino) {
+            return Ok(false);
+        }
+        try!(match link(fs, req, ino, newparent, newname) {
+            // Replace an existing entry like rename does
+            Err(EEXIST) => {
+                try!(unlink(fs, req, newparent, newname));
+                link(fs, req, ino, newparent, newname)
+            },
+            res => res,
+        });
+        Ok(true)
+    }).and_then(|linked| {
+        if linked { unlink(fs, req, parent, name) } else { Ok(()) }
+    });
+    match res {
+        Ok(()) => reply.ok(),
+        Err(err) => reply.error(err),
+    }
+}
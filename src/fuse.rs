@@ -31,9 +31,55 @@ extern "system" {
 //
 
 pub const FUSE_KERNEL_VERSION: u32 = 7;
+#[cfg(not(feature = "abi-7-9"))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 8;
+#[cfg(all(feature = "abi-7-9", not(feature = "abi-7-10")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 9;
+#[cfg(all(feature = "abi-7-10", not(feature = "abi-7-11")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 10;
+#[cfg(all(feature = "abi-7-11", not(feature = "abi-7-12")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 11;
+#[cfg(all(feature = "abi-7-12", not(feature = "abi-7-13")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 12;
+#[cfg(all(feature = "abi-7-13", not(feature = "abi-7-14")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 13;
+#[cfg(all(feature = "abi-7-14", not(feature = "abi-7-15")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 14;
+#[cfg(all(feature = "abi-7-15", not(feature = "abi-7-16")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 15;
+#[cfg(all(feature = "abi-7-16", not(feature = "abi-7-17")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 16;
+#[cfg(all(feature = "abi-7-17", not(feature = "abi-7-18")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 17;
+#[cfg(all(feature = "abi-7-18", not(feature = "abi-7-19")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 18;
+#[cfg(all(feature = "abi-7-19", not(feature = "abi-7-20")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+#[cfg(all(feature = "abi-7-20", not(feature = "abi-7-21")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 20;
+#[cfg(all(feature = "abi-7-21", not(feature = "abi-7-22")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 21;
+#[cfg(all(feature = "abi-7-22", not(feature = "abi-7-23")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 22;
+#[cfg(all(feature = "abi-7-23", not(feature = "abi-7-36")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 23;
+#[cfg(feature = "abi-7-36")]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 36;
 pub const FUSE_ROOT_ID: u64 = 1;
 
+/// Size of the init reply of kernels before ABI 7.23, which reject larger init replies
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
+/// Opcode of the CUSE (character device in userspace) init request
+pub const CUSE_INIT: u32 = 4096;
+
+/// Ioctls of the fuse device that register and unregister backing files for
+/// passthrough (ABI 7.40)
+#[cfg(target_os = "linux")]
+pub const FUSE_DEV_IOC_BACKING_OPEN: u64 = 0x4010e501;     // _IOW(229, 1, struct fuse_backing_map)
+#[cfg(target_os = "linux")]
+pub const FUSE_DEV_IOC_BACKING_CLOSE: u64 = 0x4004e502;    // _IOW(229, 2, uint32_t)
+
 #[repr(C)]
 pub struct fuse_attr {
     pub ino: u64,
@@ -56,6 +102,12 @@ pub struct fuse_attr {
     pub rdev: u32,
     #[cfg(target_os = "macos")]
     pub flags: u32,             // OS X only, see chflags(2)
+    #[cfg(feature = "abi-7-9")]
+    pub blksize: u32,
+    #[cfg(all(feature = "abi-7-9", target_os = "macos"))]
+    pub padding: u32,
+    #[cfg(all(feature = "abi-7-9", not(target_os = "macos")))]
+    pub flags: u32,             // ABI 7.32, FUSE_ATTR_* flags (padding before)
 }
 
 #[repr(C)]
@@ -89,6 +141,13 @@ pub mod consts {
     pub const FATTR_ATIME: u32              = 1 << 4;
     pub const FATTR_MTIME: u32              = 1 << 5;
     pub const FATTR_FH: u32                 = 1 << 6;
+    #[cfg(feature = "abi-7-9")]
+    pub const FATTR_ATIME_NOW: u32          = 1 << 7;
+    #[cfg(feature = "abi-7-9")]
+    pub const FATTR_MTIME_NOW: u32          = 1 << 8;
+    #[cfg(feature = "abi-7-9")]
+    pub const FATTR_LOCKOWNER: u32          = 1 << 9;
+    pub const FATTR_KILL_SUIDGID: u32       = 1 << 11;  // ABI 7.33, clear suid/sgid (with FUSE_HANDLE_KILLPRIV_V2)
     #[cfg(target_os = "macos")]
     pub const FATTR_CRTIME: u32             = 1 << 28;  // OS X only
     #[cfg(target_os = "macos")]
@@ -101,31 +160,122 @@ pub mod consts {
     // Flags returned by the open request
     pub const FOPEN_DIRECT_IO: u32          = 1 << 0;   // bypass page cache for this open file
     pub const FOPEN_KEEP_CACHE: u32         = 1 << 1;   // don't invalidate the data cache on open
+    #[cfg(not(target_os = "macos"))]
+    pub const FOPEN_PASSTHROUGH: u32        = 1 << 7;   // ABI 7.40, kernel reads and writes the backing file given by backing_id
     #[cfg(target_os = "macos")]
     pub const FOPEN_PURGE_ATTR: u32         = 1 << 30;  // OS X only
     #[cfg(target_os = "macos")]
     pub const FOPEN_PURGE_UBC: u32          = 1 << 31;  // OS X only
 
     // Init request/reply flags
-    pub const FUSE_ASYNC_READ: u32          = 1 << 0;
-    pub const FUSE_POSIX_LOCKS: u32         = 1 << 1;
-    pub const FUSE_FILE_OPS: u32            = 1 << 2;
-    pub const FUSE_ATOMIC_O_TRUNC: u32      = 1 << 3;
-    pub const FUSE_EXPORT_SUPPORT: u32      = 1 << 4;
-    pub const FUSE_BIG_WRITES: u32          = 1 << 5;
-    pub const FUSE_DONT_MASK: u32           = 1 << 6;
+    pub const FUSE_ASYNC_READ: u64          = 1 << 0;
+    pub const FUSE_POSIX_LOCKS: u64         = 1 << 1;
+    pub const FUSE_FILE_OPS: u64            = 1 << 2;
+    pub const FUSE_ATOMIC_O_TRUNC: u64      = 1 << 3;
+    pub const FUSE_EXPORT_SUPPORT: u64      = 1 << 4;
+    pub const FUSE_BIG_WRITES: u64          = 1 << 5;
+    pub const FUSE_DONT_MASK: u64           = 1 << 6;
+    pub const FUSE_AUTO_INVAL_DATA: u64     = 1 << 12;  // ABI 7.20, kernel invalidates cached data when mtime changes
+    pub const FUSE_DO_READDIRPLUS: u64      = 1 << 13;  // ABI 7.21, kernel reads directories with readdirplus
+    pub const FUSE_READDIRPLUS_AUTO: u64    = 1 << 14;  // ABI 7.21, kernel chooses between readdir and readdirplus
+    pub const FUSE_NO_OPEN_SUPPORT: u64     = 1 << 17;  // ABI 7.23, ENOSYS from open means no opens/releases are needed
+    pub const FUSE_PARALLEL_DIROPS: u64     = 1 << 18;  // ABI 7.25, concurrent lookups and readdirs in a directory
+    pub const FUSE_HANDLE_KILLPRIV: u64     = 1 << 19;  // ABI 7.26, filesystem clears suid/sgid/caps on write, chown and truncate
+    pub const FUSE_POSIX_ACL: u64           = 1 << 20;  // ABI 7.26, kernel enforces ACLs stored in xattrs
+    pub const FUSE_ABORT_ERROR: u64         = 1 << 21;  // ABI 7.27, reading the device of an aborted connection fails with ECONNABORTED
+    pub const FUSE_CACHE_SYMLINKS: u64      = 1 << 23;  // ABI 7.28, kernel caches symlink targets
+    pub const FUSE_NO_OPENDIR_SUPPORT: u64  = 1 << 24;  // ABI 7.29, ENOSYS from opendir means no opendirs/releasedirs are needed
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_MAP_ALIGNMENT: u64       = 1 << 26;  // ABI 7.31, map_alignment is valid, DAX mappings are dispatched (see ConnectionInfo::want)
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SUBMOUNTS: u64           = 1 << 27;  // ABI 7.32, kernel mounts directories marked with FUSE_ATTR_SUBMOUNT
+    pub const FUSE_HANDLE_KILLPRIV_V2: u64  = 1 << 28;  // ABI 7.33, like FUSE_HANDLE_KILLPRIV, but the kernel tells when to clear
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SETXATTR_EXT: u64        = 1 << 29;  // ABI 7.33, setxattr requests carry setxattr flags
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_INIT_EXT: u64            = 1 << 30;  // ABI 7.36, init request and reply carry the high 32 bits of the flags (flags2)
     #[cfg(target_os = "macos")]
-    pub const FUSE_CASE_INSENSITIVE: u32    = 1 << 29;  // OS X only
+    pub const FUSE_CASE_INSENSITIVE: u64    = 1 << 29;  // OS X only
     #[cfg(target_os = "macos")]
-    pub const FUSE_VOL_RENAME: u32          = 1 << 30;  // OS X only
+    pub const FUSE_VOL_RENAME: u64          = 1 << 30;  // OS X only
     #[cfg(target_os = "macos")]
-    pub const FUSE_XTIMES: u32              = 1 << 31;  // OS X only
+    pub const FUSE_XTIMES: u64              = 1 << 31;  // OS X only
+    // Flags from here on are in flags2, they're only negotiated with FUSE_INIT_EXT.
+    // FUSE_SECURITY_CTX makes create, mkdir, mknod and symlink carry the security
+    // context of the caller (see Request::security_context).
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SECURITY_CTX: u64        = 1 << 32;  // ABI 7.36, security contexts with requests that create inodes
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_DIRECT_IO_ALLOW_MMAP: u64 = 1 << 36; // ABI 7.39, direct_io files can be mmap'd shared
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_PASSTHROUGH: u64         = 1 << 37;  // ABI 7.40, open files may be backed by a file the kernel reads and writes directly
+
+    // Attr flags (with ABI 7.32)
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_ATTR_SUBMOUNT: u32       = 1 << 0;   // directory is the root of a submount (with FUSE_SUBMOUNTS)
+
+    // Setupmapping flags (with ABI 7.31)
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0;
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SETUPMAPPING_FLAG_READ: u64  = 1 << 1;
 
     // Release flags
     pub const FUSE_RELEASE_FLUSH: u32       = 1 << 0;
+
+    // Rename flags (with FUSE_RENAME2), like the ones of renameat2(2)
+    #[cfg(not(target_os = "macos"))]
+    pub const RENAME_NOREPLACE: u32         = 1 << 0;   // fail with EEXIST if the new name exists
+    #[cfg(not(target_os = "macos"))]
+    pub const RENAME_EXCHANGE: u32          = 1 << 1;   // atomically exchange the old and the new name
+    #[cfg(not(target_os = "macos"))]
+    pub const RENAME_WHITEOUT: u32          = 1 << 2;   // leave a whiteout at the old name (for overlay filesystems)
+
+    // Fsync flags
+    pub const FUSE_FSYNC_FDATASYNC: u32     = 1 << 0;
+
+    // Unique id flag of requests that are resent by the kernel (see FUSE_NOTIFY_RESEND)
+    pub const FUSE_UNIQUE_RESEND: u64       = 1 << 63;
+
+    // Getattr flags
+    #[cfg(feature = "abi-7-9")]
+    pub const FUSE_GETATTR_FH: u32          = 1 << 0;
+
+    // Write flags
+    #[cfg(feature = "abi-7-9")]
+    pub const FUSE_WRITE_CACHE: u32         = 1 << 0;
+    #[cfg(feature = "abi-7-9")]
+    pub const FUSE_WRITE_LOCKOWNER: u32     = 1 << 1;
+    #[cfg(feature = "abi-7-9")]
+    pub const FUSE_WRITE_KILL_SUIDGID: u32  = 1 << 2;   // ABI 7.33, clear suid/sgid (with FUSE_HANDLE_KILLPRIV_V2)
+
+    // Read flags
+    #[cfg(feature = "abi-7-9")]
+    pub const FUSE_READ_LOCKOWNER: u32      = 1 << 1;
+
+    // Ioctl flags
+    #[cfg(feature = "abi-7-11")]
+    pub const FUSE_IOCTL_COMPAT: u32        = 1 << 0;   // 32 bit ioctl on a 64 bit machine
+    #[cfg(feature = "abi-7-11")]
+    pub const FUSE_IOCTL_UNRESTRICTED: u32  = 1 << 1;   // not restricted to well-formed ioctls, retry allowed
+    #[cfg(feature = "abi-7-11")]
+    pub const FUSE_IOCTL_RETRY: u32         = 1 << 2;   // retry with new iovecs
+
+    // Poll flags
+    #[cfg(feature = "abi-7-11")]
+    pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0;  // kernel wants a poll notification when the events change
+
+    // CUSE init flags
+    #[cfg(feature = "abi-7-11")]
+    pub const CUSE_UNRESTRICTED_IOCTL: u32  = 1 << 0;   // use unrestricted ioctls
+
+    // Setxattr flags (with FUSE_SETXATTR_EXT)
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SETXATTR_ACL_KILL_SGID: u32 = 1 << 0; // clear sgid when setting a system.posix_acl_access xattr
 }
 
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum fuse_opcode {
     FUSE_LOOKUP = 1,
     FUSE_FORGET = 2,            // no reply
@@ -163,6 +313,26 @@ pub enum fuse_opcode {
     FUSE_INTERRUPT = 36,
     FUSE_BMAP = 37,
     FUSE_DESTROY = 38,
+    #[cfg(feature = "abi-7-11")]
+    FUSE_IOCTL = 39,
+    #[cfg(feature = "abi-7-11")]
+    FUSE_POLL = 40,
+    #[cfg(feature = "abi-7-15")]
+    FUSE_NOTIFY_REPLY = 41,     // no reply
+    #[cfg(feature = "abi-7-16")]
+    FUSE_BATCH_FORGET = 42,     // no reply
+    #[cfg(feature = "abi-7-21")]
+    FUSE_READDIRPLUS = 44,
+    #[cfg(not(target_os = "macos"))]
+    FUSE_RENAME2 = 45,          // ABI 7.23
+    #[cfg(not(target_os = "macos"))]
+    FUSE_SETUPMAPPING = 48,     // ABI 7.31
+    #[cfg(not(target_os = "macos"))]
+    FUSE_REMOVEMAPPING = 49,    // ABI 7.31
+    #[cfg(not(target_os = "macos"))]
+    FUSE_SYNCFS = 50,           // ABI 7.34
+    #[cfg(not(target_os = "macos"))]
+    FUSE_TMPFILE = 51,          // ABI 7.37
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,       // OS X only
     #[cfg(target_os = "macos")]
@@ -211,6 +381,26 @@ impl fuse_opcode {
             36 => Some(fuse_opcode::FUSE_INTERRUPT),
             37 => Some(fuse_opcode::FUSE_BMAP),
             38 => Some(fuse_opcode::FUSE_DESTROY),
+            #[cfg(feature = "abi-7-11")]
+            39 => Some(fuse_opcode::FUSE_IOCTL),
+            #[cfg(feature = "abi-7-11")]
+            40 => Some(fuse_opcode::FUSE_POLL),
+            #[cfg(feature = "abi-7-15")]
+            41 => Some(fuse_opcode::FUSE_NOTIFY_REPLY),
+            #[cfg(feature = "abi-7-16")]
+            42 => Some(fuse_opcode::FUSE_BATCH_FORGET),
+            #[cfg(feature = "abi-7-21")]
+            44 => Some(fuse_opcode::FUSE_READDIRPLUS),
+            #[cfg(not(target_os = "macos"))]
+            45 => Some(fuse_opcode::FUSE_RENAME2),
+            #[cfg(not(target_os = "macos"))]
+            48 => Some(fuse_opcode::FUSE_SETUPMAPPING),
+            #[cfg(not(target_os = "macos"))]
+            49 => Some(fuse_opcode::FUSE_REMOVEMAPPING),
+            #[cfg(not(target_os = "macos"))]
+            50 => Some(fuse_opcode::FUSE_SYNCFS),
+            #[cfg(not(target_os = "macos"))]
+            51 => Some(fuse_opcode::FUSE_TMPFILE),
             #[cfg(target_os = "macos")]
             61 => Some(fuse_opcode::FUSE_SETVOLNAME),
             #[cfg(target_os = "macos")]
@@ -222,6 +412,31 @@ impl fuse_opcode {
     }
 }
 
+#[repr(C)]
+pub enum fuse_notify_code {
+    FUSE_NOTIFY_POLL = 1,
+    FUSE_NOTIFY_INVAL_INODE = 2,
+    FUSE_NOTIFY_INVAL_ENTRY = 3,
+    FUSE_NOTIFY_STORE = 4,
+    FUSE_NOTIFY_RETRIEVE = 5,
+    FUSE_NOTIFY_DELETE = 6,
+    FUSE_NOTIFY_RESEND = 7,
+}
+
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct fuse_notify_poll_wakeup_out {
+    pub kh: u64,
+}
+
+#[cfg(feature = "abi-7-12")]
+#[repr(C)]
+pub struct fuse_notify_inval_inode_out {
+    pub ino: u64,
+    pub off: i64,
+    pub len: i64,
+}
+
 #[repr(C)]
 pub struct fuse_entry_out {
     pub nodeid: u64,
@@ -238,6 +453,28 @@ pub struct fuse_forget_in {
     pub nlookup: u64,
 }
 
+#[cfg(feature = "abi-7-16")]
+#[repr(C)]
+pub struct fuse_forget_one {
+    pub nodeid: u64,
+    pub nlookup: u64,
+}
+
+#[cfg(feature = "abi-7-16")]
+#[repr(C)]
+pub struct fuse_batch_forget_in {
+    pub count: u32,
+    pub dummy: u32,
+}
+
+#[cfg(feature = "abi-7-9")]
+#[repr(C)]
+pub struct fuse_getattr_in {
+    pub getattr_flags: u32,
+    pub dummy: u32,
+    pub fh: u64,
+}
+
 #[repr(C)]
 pub struct fuse_attr_out {
     pub attr_valid: i64,
@@ -259,6 +496,10 @@ pub struct fuse_getxtimes_out { // OS X only
 pub struct fuse_mknod_in {
     pub mode: u32,
     pub rdev: u32,
+    #[cfg(feature = "abi-7-12")]
+    pub umask: u32,
+    #[cfg(feature = "abi-7-12")]
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -272,6 +513,14 @@ pub struct fuse_rename_in {
     pub newdir: u64,
 }
 
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+pub struct fuse_rename2_in {            // ABI 7.23
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
 #[cfg(target_os = "macos")]
 #[repr(C)]
 pub struct fuse_exchange_in {   // OS X only
@@ -291,7 +540,10 @@ pub struct fuse_setattr_in {
     pub padding: u32,
     pub fh: u64,
     pub size: u64,
+    #[cfg(not(feature = "abi-7-9"))]
     pub unused1: u64,
+    #[cfg(feature = "abi-7-9")]
+    pub lock_owner: u64,
     pub atime: i64,
     pub mtime: i64,
     pub unused2: u64,
@@ -325,11 +577,45 @@ pub struct fuse_open_in {
     pub mode: u32,
 }
 
+#[cfg(feature = "abi-7-12")]
+#[repr(C)]
+pub struct fuse_create_in {
+    pub flags: u32,
+    pub mode: u32,
+    pub umask: u32,
+    pub padding: u32,
+}
+
+// With FUSE_SECURITY_CTX, the names of create, mkdir, mknod and symlink requests
+// are followed by a fuse_secctx_header, then for each context a fuse_secctx, the
+// zero-terminated name of the context and its value, padded to 8 bytes.
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+#[repr(C)]
+pub struct fuse_secctx_header {
+    pub size: u32,              // Size of the header and all contexts
+    pub nr_secctx: u32,
+}
+
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+#[repr(C)]
+pub struct fuse_secctx {
+    pub size: u32,              // Size of the value of the context
+    pub padding: u32,
+}
+
 #[repr(C)]
 pub struct fuse_open_out {
     pub fh: u64,
     pub open_flags: u32,
-    pub padding: u32,
+    pub backing_id: i32,        // ABI 7.40, with FOPEN_PASSTHROUGH (padding before)
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub struct fuse_backing_map {      // ABI 7.40, argument of FUSE_DEV_IOC_BACKING_OPEN
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
 }
 
 #[repr(C)]
@@ -353,6 +639,15 @@ pub struct fuse_read_in {
     pub fh: u64,
     pub offset: u64,
     pub size: u32,
+    #[cfg(not(feature = "abi-7-9"))]
+    pub padding: u32,
+    #[cfg(feature = "abi-7-9")]
+    pub read_flags: u32,
+    #[cfg(feature = "abi-7-9")]
+    pub lock_owner: u64,
+    #[cfg(feature = "abi-7-9")]
+    pub flags: u32,
+    #[cfg(feature = "abi-7-9")]
     pub padding: u32,
 }
 
@@ -362,6 +657,12 @@ pub struct fuse_write_in {
     pub offset: u64,
     pub size: u32,
     pub write_flags: u32,
+    #[cfg(feature = "abi-7-9")]
+    pub lock_owner: u64,
+    #[cfg(feature = "abi-7-9")]
+    pub flags: u32,
+    #[cfg(feature = "abi-7-9")]
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -392,6 +693,44 @@ pub struct fuse_setxattr_in {
     pub padding: u32,           // OS X only
 }
 
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+pub struct fuse_setxattr_ext_in {      // ABI 7.33, used if FUSE_SETXATTR_EXT was granted
+    pub size: u32,
+    pub flags: u32,
+    pub setxattr_flags: u32,
+    pub padding: u32,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+pub struct fuse_setupmapping_in {       // ABI 7.31
+    pub fh: u64,
+    pub foffset: u64,
+    pub len: u64,
+    pub flags: u64,
+    pub moffset: u64,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+pub struct fuse_removemapping_in {      // ABI 7.31, followed by count fuse_removemapping_one
+    pub count: u32,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+pub struct fuse_removemapping_one {
+    pub moffset: u64,
+    pub len: u64,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+pub struct fuse_syncfs_in {             // ABI 7.34
+    pub padding: u64,
+}
+
 #[repr(C)]
 pub struct fuse_getxattr_in {
     pub size: u32,
@@ -413,6 +752,10 @@ pub struct fuse_lk_in {
     pub fh: u64,
     pub owner: u64,
     pub lk: fuse_file_lock,
+    #[cfg(feature = "abi-7-9")]
+    pub lk_flags: u32,
+    #[cfg(feature = "abi-7-9")]
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -434,14 +777,39 @@ pub struct fuse_init_in {
     pub flags: u32,
 }
 
+#[repr(C)]
+pub struct fuse_init_in_ext {       // ABI 7.36, follows fuse_init_in if FUSE_INIT_EXT is set
+    pub flags2: u32,            // high 32 bits of the flags
+    pub unused: [u32; 11],
+}
+
 #[repr(C)]
 pub struct fuse_init_out {
     pub major: u32,
     pub minor: u32,
     pub max_readahead: u32,
     pub flags: u32,
+    #[cfg(not(feature = "abi-7-13"))]
     pub unused: u32,
+    #[cfg(feature = "abi-7-13")]
+    pub max_background: u16,
+    #[cfg(feature = "abi-7-13")]
+    pub congestion_threshold: u16,
     pub max_write: u32,
+    #[cfg(feature = "abi-7-23")]
+    pub time_gran: u32,
+    #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-36")))]
+    pub reserved: [u32; 9],
+    #[cfg(feature = "abi-7-36")]
+    pub max_pages: u16,         // ABI 7.28
+    #[cfg(feature = "abi-7-36")]
+    pub map_alignment: u16,     // ABI 7.31
+    #[cfg(feature = "abi-7-36")]
+    pub flags2: u32,            // ABI 7.36, high 32 bits of the flags (with FUSE_INIT_EXT)
+    #[cfg(feature = "abi-7-36")]
+    pub max_stack_depth: u32,   // ABI 7.40, with FUSE_PASSTHROUGH
+    #[cfg(feature = "abi-7-36")]
+    pub unused: [u32; 6],
 }
 
 #[repr(C)]
@@ -461,6 +829,89 @@ pub struct fuse_bmap_out {
     pub block: u64,
 }
 
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct fuse_ioctl_in {
+    pub fh: u64,
+    pub flags: u32,
+    pub cmd: u32,
+    pub arg: u64,
+    pub in_size: u32,
+    pub out_size: u32,
+}
+
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct fuse_ioctl_out {
+    pub result: i32,
+    pub flags: u32,
+    pub in_iovs: u32,
+    pub out_iovs: u32,
+}
+
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct fuse_poll_in {
+    pub fh: u64,
+    pub kh: u64,
+    pub flags: u32,
+    #[cfg(not(feature = "abi-7-21"))]
+    pub padding: u32,
+    #[cfg(feature = "abi-7-21")]
+    pub events: u32,
+}
+
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct fuse_poll_out {
+    pub revents: u32,
+    pub padding: u32,
+}
+
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct cuse_init_in {
+    pub major: u32,
+    pub minor: u32,
+    pub unused: u32,
+    pub flags: u32,
+}
+
+#[cfg(feature = "abi-7-11")]
+#[repr(C)]
+pub struct cuse_init_out {          // followed by the device info (NUL separated KEY=value strings)
+    pub major: u32,
+    pub minor: u32,
+    pub unused: u32,
+    pub flags: u32,
+    pub max_read: u32,
+    pub max_write: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare: [u32; 10],
+}
+
+#[cfg(feature = "abi-7-15")]
+#[repr(C)]
+pub struct fuse_notify_retrieve_out {
+    pub notify_unique: u64,
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[cfg(feature = "abi-7-15")]
+#[repr(C)]
+pub struct fuse_notify_retrieve_in {      // matches the size of fuse_write_in
+    pub dummy1: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub dummy2: u32,
+    pub dummy3: u64,
+    pub dummy4: u64,
+}
+
 #[repr(C)]
 pub struct fuse_in_header {
     pub len: u32,
@@ -488,3 +939,10 @@ pub struct fuse_dirent {
     pub typ: u32,
     // followed by name of namelen bytes
 }
+
+#[cfg(feature = "abi-7-21")]
+#[repr(C)]
+pub struct fuse_direntplus {
+    pub entry_out: fuse_entry_out,
+    pub dirent: fuse_dirent,
+}
@@ -0,0 +1,150 @@
+//!
+//! Allocation of inode numbers for filesystems that create inodes on the fly.
+//! An inode number may be reused once its inode is gone, but then needs a new
+//! generation number, so that file handles of the old inode (e.g. of NFS
+//! clients) don't end up at the new one.
+//!
+
+use std::collections::{BTreeSet, HashMap};
+use FUSE_ROOT_ID;
+
+/// Allocates inode numbers and their generation numbers. Numbers are handed out
+/// in ascending order, freed numbers are reused (lowest first) with a generation
+/// number that is one higher than the one of the previous inode of the number.
+/// A number is never handed out twice while it's allocated.
+///
+/// Only free the number of an inode when it's gone for good: when it has no
+/// links anymore, isn't open anymore and the kernel forgot it (its lookup count
+/// dropped to zero, see Filesystem::forget). The kernel still uses the number in
+/// requests before that. Since generations are only kept in memory, a filesystem
+/// that is exported over NFS and keeps inodes across restarts must store them
+/// itself (or not reuse numbers at all).
+#[derive(Clone, Debug)]
+pub struct InodeAllocator {
+    /// First inode number the allocator hands out
+    first: u64,
+    /// Next never allocated inode number
+    next: u64,
+    /// Freed inode numbers that can be reused
+    free: BTreeSet<u64>,
+    /// Generation numbers of reused inode numbers (others have generation 0)
+    generations: HashMap<u64, u64>,
+}
+
+impl InodeAllocator {
+    /// Create an allocator that hands out the inode numbers after the root inode
+    /// (which is always allocated)
+    pub fn new () -> InodeAllocator {
+        InodeAllocator::starting_at(FUSE_ROOT_ID + 1)
+    }
+
+    /// Create an allocator that hands out inode numbers starting at the given one,
+    /// e.g. for a filesystem that has some inodes with fixed numbers. Numbers below
+    /// the first one are considered allocated and can't be freed.
+    pub fn starting_at (first: u64) -> InodeAllocator {
+        InodeAllocator { first: first, next: first, free: BTreeSet::new(), generations: HashMap::new() }
+    }
+
+    /// Allocate an inode number. Returns the number and its generation number, which
+    /// must be used in the entry replies of the inode (see ReplyEntry::entry).
+    pub fn allocate (&mut self) -> (u64, u64) {
+        let reused = self.free.iter().next().cloned();
+        match reused {
+            Some(ino) => {
+                self.free.remove(&ino);
+                let generation = self.generations.entry(ino).or_insert(0);
+                *generation += 1;
+                (ino, *generation)
+            },
+            None => {
+                let ino = self.next;
+                self.next += 1;
+                (ino, 0)
+            },
+        }
+    }
+
+    /// Free the given inode number, so that it can be reused. Returns false (and does
+    /// nothing) if the number isn't allocated, e.g. because it's freed twice.
+    pub fn free (&mut self, ino: u64) -> bool {
+        if ino < self.first || ino >= self.next {
+            return false;
+        }
+        self.free.insert(ino)
+    }
+
+    /// Returns true if the given inode number is allocated
+    pub fn is_allocated (&self, ino: u64) -> bool {
+        ino < self.next && !self.free.contains(&ino)
+    }
+
+    /// Returns the generation number of the given inode number (of the inode that
+    /// has it, or had it last if it's free)
+    pub fn generation (&self, ino: u64) -> u64 {
+        self.generations.get(&ino).cloned().unwrap_or(0)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use FUSE_ROOT_ID;
+    use super::InodeAllocator;
+
+    #[test]
+    fn allocate_ascending () {
+        let mut inodes = InodeAllocator::new();
+        assert_eq!(inodes.allocate(), (FUSE_ROOT_ID + 1, 0));
+        assert_eq!(inodes.allocate(), (FUSE_ROOT_ID + 2, 0));
+        assert!(inodes.is_allocated(FUSE_ROOT_ID));
+        assert!(inodes.is_allocated(FUSE_ROOT_ID + 2));
+        assert!(!inodes.is_allocated(FUSE_ROOT_ID + 3));
+        let mut inodes = InodeAllocator::starting_at(100);
+        assert_eq!(inodes.allocate(), (100, 0));
+        // Numbers below the first one are fixed and can't be freed
+        assert!(!inodes.free(99));
+    }
+
+    #[test]
+    fn reuse_bumps_generation () {
+        let mut inodes = InodeAllocator::new();
+        let (a, _) = inodes.allocate();
+        let (b, _) = inodes.allocate();
+        assert!(inodes.free(b));
+        assert!(inodes.free(a));
+        assert!(!inodes.is_allocated(a));
+        // The lowest freed number is reused first, each reuse with a new generation
+        assert_eq!(inodes.allocate(), (a, 1));
+        assert_eq!(inodes.allocate(), (b, 1));
+        assert!(inodes.free(a));
+        assert_eq!(inodes.generation(a), 1);
+        assert_eq!(inodes.allocate(), (a, 2));
+        assert_eq!(inodes.generation(a), 2);
+        assert_eq!(inodes.generation(b), 1);
+    }
+
+    #[test]
+    fn no_double_allocation () {
+        let mut inodes = InodeAllocator::new();
+        let (a, _) = inodes.allocate();
+        assert!(inodes.free(a));
+        // Freeing twice or freeing numbers that were never allocated does nothing
+        assert!(!inodes.free(a));
+        assert!(!inodes.free(a + 1));
+        let mut live = HashSet::new();
+        for _ in 0..10 {
+            let (ino, _) = inodes.allocate();
+            assert!(live.insert(ino), "inode {} allocated twice", ino);
+        }
+        assert!(live.contains(&a));
+        for &ino in [a + 2, a + 5].iter() {
+            assert!(inodes.free(ino));
+            live.remove(&ino);
+        }
+        for _ in 0..3 {
+            let (ino, _) = inodes.allocate();
+            assert!(live.insert(ino), "inode {} allocated twice", ino);
+        }
+    }
+}
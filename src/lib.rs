@@ -8,8 +8,8 @@
 //!
 
 #![feature(convert)]
-#![feature(fnbox)]
 #![feature(libc)]
+#![feature(rt)]
 #![feature(scoped)]
 #![feature(vec_push_all)]
 
@@ -19,9 +19,13 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate time;
+#[cfg(feature = "tracing")]
+#[macro_use(trace_span)]
+extern crate tracing;
+#[cfg(feature = "futures")]
+extern crate futures;
 
 use std::convert::AsRef;
-use std::io;
 use std::ffi::OsStr;
 use std::path::Path;
 use libc::{c_int, ENOSYS};
@@ -29,19 +33,76 @@ use time::Timespec;
 
 pub use fuse::FUSE_ROOT_ID;
 pub use fuse::consts;
-pub use reply::{Reply, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
-pub use reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
+pub use fuse::fuse_opcode;
+pub use access::{check_access, apply_umask};
+pub use reply::{TTL_FOREVER, MAX_REPLY_CHUNKS, errno_of, Reply, ReplySender, ReplyEmpty, ReplyData, ReplyDataWriter, ReplyEntry, ReplyAttr, ReplyOpen};
+pub use reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyXattr, ReplyDirectory};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
+#[cfg(feature = "abi-7-11")]
+pub use reply::{ReplyIoctl, ReplyPoll};
+#[cfg(feature = "abi-7-21")]
+pub use reply::ReplyDirectoryPlus;
+#[cfg(target_os = "linux")]
+pub use cgroup::Cgroup;
+pub use channel::UnmountMode;
+pub use connection::{Capabilities, ConnectionInfo, Negotiation};
+pub use error::Error;
+#[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+pub use cuse::{CuseDevice, CuseSession};
+#[cfg(feature = "abi-7-13")]
+pub use connection::CongestionPolicy;
+#[cfg(target_os = "linux")]
+pub use control::ConnectionControl;
+pub use fallback::rename_by_link;
+pub use inode::InodeAllocator;
+pub use manager::{MountId, MountManager};
+#[cfg(target_os = "linux")]
+pub use mount_ns::unshare_mount_namespace;
+pub use mount_options::{MountOption, mount_args};
+pub use notify::{Notifier, Retrieved, Retrieves};
+#[cfg(feature = "abi-7-12")]
+pub use notify::CoalescingNotifier;
+#[cfg(target_os = "linux")]
+pub use passthrough::BackingFiles;
 pub use request::Request;
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+pub use request::SecurityContext;
 pub use session::{Session, BackgroundSession};
+pub use statfs::{StatFs, statvfs};
+pub use xattr::{namespace, xattr_name, XattrList, ENOATTR};
 
+#[cfg(feature = "futures")]
+pub mod async_fs;
+#[cfg(feature = "examples")]
+pub mod examples;
+
+mod access;
 mod argument;
+#[cfg(target_os = "linux")]
+mod cgroup;
 mod channel;
+mod connection;
+#[cfg(target_os = "linux")]
+mod control;
+#[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+mod cuse;
+mod error;
+mod fallback;
 mod fuse;
+mod inode;
+mod manager;
+#[cfg(target_os = "linux")]
+mod mount_ns;
+mod mount_options;
+mod notify;
+#[cfg(target_os = "linux")]
+mod passthrough;
 mod reply;
 mod request;
 mod session;
+mod statfs;
+mod xattr;
 
 /// File types
 #[derive(Clone, Copy, Debug, Hash, PartialEq)]
@@ -60,6 +121,40 @@ pub enum FileType {
     Symlink,
 }
 
+/// POSIX file lock types
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
+pub enum LockType {
+    /// Shared read lock (F_RDLCK)
+    Read,
+    /// Exclusive write lock (F_WRLCK)
+    Write,
+    /// No lock, releases a lock in setlk (F_UNLCK)
+    Unlock,
+}
+
+/// A single forget of a batch of forgets (see Filesystem::forget_multi)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Forget {
+    /// Inode number
+    pub ino: u64,
+    /// Number of lookups to forget
+    pub nlookup: u64,
+}
+
+/// A single mapping of a removemapping request (see Filesystem::removemapping)
+#[cfg(not(target_os = "macos"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    /// Offset of the mapping in the DAX window
+    pub moffset: u64,
+    /// Length of the mapping
+    pub len: u64,
+}
+
+/// Block size of the attributes created by the FileAttr constructors (the page
+/// size of most systems, which is what the kernel uses by default as well)
+pub const DEFAULT_BLKSIZE: u32 = 4096;
+
 /// File attributes
 #[derive(Clone, Copy, Debug)]
 pub struct FileAttr {
@@ -67,7 +162,10 @@ pub struct FileAttr {
     pub ino: u64,
     /// Size in bytes
     pub size: u64,
-    /// Size in blocks
+    /// Allocated storage in 512-byte blocks (st_blocks), regardless of the block
+    /// size of the filesystem. This is what du reports, and it's independent of
+    /// size: a sparse or compressed file has fewer blocks than its size implies.
+    /// Use FileAttr::blocks_for to compute it for a fully allocated file.
     pub blocks: u64,
     /// Time of last access
     pub atime: Timespec,
@@ -75,7 +173,8 @@ pub struct FileAttr {
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
-    /// Time of creation (OS X only)
+    /// Time of creation (birthtime). Only sent to the kernel on OS X, other systems
+    /// don't have it in their attributes and ignore it.
     pub crtime: Timespec,
     /// Kind of file (directory, file, pipe, etc)
     pub kind: FileType,
@@ -89,20 +188,90 @@ pub struct FileAttr {
     pub gid: u32,
     /// Rdev
     pub rdev: u32,
-    /// Flags (OS X only, see chflags(2))
+    /// Preferred I/O block size (st_blksize), which tools like cp use as their
+    /// buffer size. Must be a power of two, 0 leaves it to the kernel (which uses
+    /// the block size of the mount then). Only sent to the kernel with ABI 7.9 and
+    /// later, older kernels always use the block size of the mount.
+    pub blksize: u32,
+    /// File flags as set by chflags(2), e.g. UF_HIDDEN, UF_IMMUTABLE, UF_APPEND or
+    /// SF_IMMUTABLE. Only sent to the kernel on OS X, which reports them in st_flags
+    /// (and enforces the immutable and append-only flags). Other systems have no such
+    /// field in their attributes and ignore it: on Linux, flags like immutable or
+    /// append-only (lsattr(1)) are queried with the FS_IOC_GETFLAGS ioctl, which the
+    /// kernel doesn't pass to FUSE filesystems.
     pub flags: u32,
 }
 
+impl FileAttr {
+    /// Returns the number of 512-byte blocks needed to store the given number of
+    /// bytes (rounded up), i.e. the blocks of a file of this size without holes
+    pub fn blocks_for (size: u64) -> u64 {
+        (size + 511) / 512
+    }
+
+    /// Attributes of a directory with permissions 0755 and 2 links, owned by the
+    /// user running the filesystem, with all timestamps set to now and a block size
+    /// of DEFAULT_BLKSIZE (like all attributes created by the constructors)
+    pub fn for_dir (ino: u64) -> FileAttr {
+        FileAttr::new(ino, FileType::Directory, 0o755, 2, 0)
+    }
+
+    /// Attributes of a regular file of the given size with permissions 0644, owned
+    /// by the user running the filesystem, with all timestamps set to now
+    pub fn for_file (ino: u64, size: u64) -> FileAttr {
+        FileAttr::new(ino, FileType::RegularFile, 0o644, 1, size)
+    }
+
+    /// Attributes of a symlink with permissions 0777, owned by the user running the
+    /// filesystem, with all timestamps set to now. The size of a symlink is the length
+    /// of its target.
+    pub fn for_symlink (ino: u64, target_len: u64) -> FileAttr {
+        FileAttr::new(ino, FileType::Symlink, 0o777, 1, target_len)
+    }
+
+    fn new (ino: u64, kind: FileType, perm: u16, nlink: u32, size: u64) -> FileAttr {
+        let now = time::get_time();
+        FileAttr {
+            ino: ino,
+            size: size,
+            blocks: FileAttr::blocks_for(size),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: kind,
+            perm: perm,
+            nlink: nlink,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: DEFAULT_BLKSIZE,
+            flags: 0,
+        }
+    }
+}
+
 /// Filesystem trait.
 ///
 /// This trait must be implemented to provide a userspace filesystem via FUSE.
 /// These methods corrospond to fuse_lowlevel_ops in libfuse. Reasonable default
 /// implementations are provided here to get a mountable filesystem that does
 /// nothing.
+///
+/// Optional operations reply ENOSYS by default. For flush, fsync, fsyncdir, the
+/// xattr operations, access, create, interrupt and bmap the kernel remembers this
+/// and doesn't send these requests again on the connection (it falls back to its
+/// own behaviour, e.g. flush and fsync succeed, create is done by mknod and open).
+/// Filesystems should therefore reply ENOSYS, not some other error, for such
+/// operations they don't support.
 pub trait Filesystem {
     /// Initialize filesystem
-    /// Called before any other filesystem method.
-    fn init (&mut self, _req: &Request) -> Result<(), c_int> {
+    /// Called before any other filesystem method. The connection parameters
+    /// tell which capabilities the kernel supports and which mount options
+    /// are active. They can be adjusted to select the capabilities to use. By
+    /// default, the recommended capabilities are used (see ConnectionInfo::want_recommended).
+    fn init (&mut self, _req: &Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+        conn.want_recommended();
         Ok(())
     }
 
@@ -112,6 +281,10 @@ pub trait Filesystem {
     }
 
     /// Look up a directory entry by name and get its attributes.
+    /// If FUSE_EXPORT_SUPPORT was granted at init (see ConnectionInfo::want), the name
+    /// may also be "." (the entry of parent itself) or ".." (the parent of parent).
+    /// If the name doesn't exist, reply with reply.negative() instead of an ENOENT error
+    /// to let the kernel cache the missing name.
     fn lookup (&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
@@ -122,12 +295,32 @@ pub trait Filesystem {
     /// inodes acquire a single reference on each lookup, and lose nlookup references on
     /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
     /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
-    /// inodes will receive a forget message.
+    /// inodes will receive a forget message. Note that a single forget may drop more
+    /// than one reference, so the lookup count of the inode must be decreased by exactly
+    /// nlookup (not by one). Every reply to lookup, mknod, mkdir, symlink, link and
+    /// create increases the lookup count by one. Forget has no reply.
     fn forget (&mut self, _req: &Request, _ino: u64, _nlookup: u64) {
     }
 
+    /// Forget about several inodes at once
+    /// Called with ABI 7.16 or later when the kernel forgets a batch of inodes. The
+    /// default implementation calls forget for each of them. Filesystems that have to
+    /// lock their inode table for a forget can override it to update the lookup counts
+    /// of all inodes under a single lock. Like forget, this has no reply.
+    fn forget_multi (&mut self, req: &Request, forgets: &[Forget]) {
+        for forget in forgets {
+            self.forget(req, forget.ino, forget.nlookup);
+        }
+    }
+
     /// Get file attributes
-    fn getattr (&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
+    /// If the attributes of an open file are requested, fh contains the value set by
+    /// the open method, so the filesystem can answer from the state of the open file
+    /// handle. fh is None otherwise (and always with ABI versions before 7.9). E.g. a
+    /// filesystem that keeps writes with the file handle until they're flushed should
+    /// report the size of the written data if fh is given, and the size of the flushed
+    /// data otherwise.
+    fn getattr (&mut self, _req: &Request, _ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
@@ -137,6 +330,11 @@ pub trait Filesystem {
     }
 
     /// Read symbolic link
+    /// Reply with the target as raw bytes (ReplyData::data). The target may be any
+    /// bytes except NUL (it doesn't need to be valid UTF-8) and must not be NUL
+    /// terminated, the kernel takes the reply size as the length of the target.
+    /// If FUSE_CACHE_SYMLINKS was granted, the kernel caches the target and only calls
+    /// this again after the inode was invalidated.
     fn readlink (&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
         reply.error(ENOSYS);
     }
@@ -168,10 +366,29 @@ pub trait Filesystem {
     }
 
     /// Rename a file
+    /// Filesystems that implement lookup, link and unlink can use rename_by_link
+    /// to implement rename (see there for the atomicity caveats).
     fn rename (&mut self, _req: &Request, _parent: u64, _name: &Path, _newparent: u64, _newname: &Path, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
+    /// Rename a file with rename flags (renameat2(2))
+    /// Called if the negotiated ABI version is 7.23 or later (see
+    /// ConnectionInfo::rename2). The flags are a combination of RENAME_NOREPLACE,
+    /// RENAME_EXCHANGE and RENAME_WHITEOUT (see fuse::consts), unknown flags and
+    /// RENAME_EXCHANGE combined with another flag are already rejected with EINVAL.
+    /// Without flags, the default implementation calls rename. With flags it replies
+    /// ENOSYS, since rename (or rename_by_link) can't honour them; the kernel then
+    /// fails renames with flags with EINVAL and doesn't ask again.
+    #[cfg(not(target_os = "macos"))]
+    fn rename2 (&mut self, req: &Request, parent: u64, name: &Path, newparent: u64, newname: &Path, flags: u32, reply: ReplyEmpty) {
+        if flags == 0 {
+            self.rename(req, parent, name, newparent, newname, reply);
+        } else {
+            reply.error(ENOSYS);
+        }
+    }
+
     /// Create a hard link
     fn link (&mut self, _req: &Request, _ino: u64, _newparent: u64, _newname: &Path, reply: ReplyEntry) {
         reply.error(ENOSYS);
@@ -184,19 +401,31 @@ pub trait Filesystem {
     /// release, fsync). Filesystem may also implement stateless file I/O and not store
     /// anything in fh. There are also some flags (direct_io, keep_cache) which the
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
-    /// structure in <fuse_common.h> for more details.
+    /// structure in <fuse_common.h> for more details. If a file is opened with O_TRUNC,
+    /// the kernel doesn't pass the flag but sends a setattr request with a size of 0
+    /// right after the open, so the filesystem doesn't need to truncate in open. If
+    /// FUSE_ATOMIC_O_TRUNC was granted at init, the kernel passes O_TRUNC instead and
+    /// the filesystem must truncate the file itself. It's not granted by default, the
+    /// filesystem has to want it in init (see ConnectionInfo::want).
+    /// If FUSE_NO_OPEN_SUPPORT was granted at init, a stateless filesystem can reply
+    /// ENOSYS and the kernel won't send any further opens or releases (see
+    /// ConnectionInfo::want). Without the capability, ENOSYS fails the open.
     fn open (&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
         reply.opened(0, 0);
     }
 
     /// Read data
     /// Read should send exactly the number of bytes requested except on EOF or error,
-    /// otherwise the rest of the data will be substituted with zeroes. An exception to
+    /// otherwise the rest of the data will be substituted with zeroes. At the end of the
+    /// file, reply with the remaining bytes only (or none); ReplyData::data_at does this
+    /// for contents that are in memory. An exception to
     /// this is when the file has been opened in 'direct_io' mode, in which case the
     /// return value of the read system call will reflect the return value of this
     /// operation. fh will contain the value set by the open method, or will be undefined
-    /// if the open method didn't set any value.
-    fn read (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _size: u32, reply: ReplyData) {
+    /// if the open method didn't set any value. flags will contain the open flags of the
+    /// file (ABI 7.9+, 0 before). lock_owner is set if the read was issued for a file with
+    /// locks (ABI 7.9+), so the filesystem can check the read against mandatory locks.
+    fn read (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _size: u32, _flags: u32, _lock_owner: Option<u64>, reply: ReplyData) {
         reply.error(ENOSYS);
     }
 
@@ -204,9 +433,28 @@ pub trait Filesystem {
     /// Write should return exactly the number of bytes requested except on error. An
     /// exception to this is when the file has been opened in 'direct_io' mode, in
     /// which case the return value of the write system call will reflect the return
-    /// value of this operation. fh will contain the value set by the open method, or
-    /// will be undefined if the open method didn't set any value.
-    fn write (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
+    /// value of this operation. A filesystem that can only take part of the data may
+    /// reply with a smaller count (see ReplyWrite::written), the caller will see a
+    /// short write and is expected to write the remainder again. fh will contain
+    /// the value set by the open method, or will be undefined if the open method
+    /// didn't set any value. write_flags are FUSE_WRITE_* flags, flags will contain
+    /// the open flags of the file (ABI 7.9+, 0 before).
+    ///
+    /// If the file was opened with O_APPEND, the data must be written at the end of
+    /// the file, whatever the offset is. The kernel passes the end of the file as it
+    /// knows it, which is stale if the file grew without the kernel knowing (e.g.
+    /// on a network filesystem), so the filesystem should append itself. With
+    /// writeback caching, the kernel handles O_APPEND on its own and writes back
+    /// pages at their offsets (with FUSE_WRITE_CACHE in write_flags), which only
+    /// works if all changes of the file go through the kernel.
+    ///
+    /// If FUSE_HANDLE_KILLPRIV_V2 was granted, the kernel doesn't clear the suid and
+    /// sgid bits of a written file itself, but sets FUSE_WRITE_KILL_SUIDGID in
+    /// write_flags when they must be cleared (i.e. when the writer isn't privileged).
+    /// The filesystem must then remove S_ISUID and S_ISGID (the latter only if the
+    /// file is group executable) from the file's mode before or with the write, and
+    /// drop the security.capability xattr, like the kernel does for local filesystems.
+    fn write (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _write_flags: u32, _flags: u32, reply: ReplyWrite) {
         reply.error(ENOSYS);
     }
 
@@ -231,14 +479,16 @@ pub trait Filesystem {
     /// error, but error values are not returned to close() or munmap() which triggered
     /// the release. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same flags as for
-    /// open.
+    /// open. If flush is set, the release implies a flush: cached data should be written
+    /// (like in the flush method) and locks held by lock_owner should be released.
     fn release (&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
         reply.ok();
     }
 
     /// Synchronize file contents
-    /// If the datasync parameter is non-zero, then only the user data should be flushed,
-    /// not the meta data.
+    /// If the datasync parameter is set (fdatasync), then only the user data should be
+    /// flushed, not the meta data (except for metadata needed to read the data back,
+    /// like the file size). Otherwise (fsync), both data and meta data must be flushed.
     fn fsync (&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -249,20 +499,44 @@ pub trait Filesystem {
     /// fsyncdir). Filesystem may also implement stateless directory I/O and not store
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
-    /// between opendir and releasedir.
+    /// between opendir and releasedir. If FUSE_NO_OPENDIR_SUPPORT was granted at init,
+    /// a filesystem without directory stream state can reply ENOSYS and the kernel won't
+    /// send any further opendirs or releasedirs (see ConnectionInfo::want).
     fn opendir (&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
         reply.opened(0, 0);
     }
 
     /// Read directory
-    /// Send a buffer filled using buffer.fill(), with size not exceeding the
-    /// requested size. Send an empty buffer on end of stream. fh will contain the
+    /// Add entries to the reply using reply.add() until it returns true (the reply
+    /// is sized to the size requested by the kernel), then send it with reply.ok().
+    /// The kernel continues with another readdir at the offset of the last entry
+    /// that was sent. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
-    /// didn't set any value.
+    /// didn't set any value. The directory may change between readdir calls of the
+    /// same stream. Offsets must stay valid anyway: if they're indices into the live
+    /// list of entries, removing an entry makes the listing skip another one, and
+    /// adding one makes it repeat one. A simple way to avoid this is to take a
+    /// snapshot of the entries at opendir, keep it with the fh, list it in readdir
+    /// (refreshing it if the offset is 0, e.g. after rewinddir) and drop it in
+    /// releasedir (see examples::MemFs). ReplyDirectory::entries replies with such
+    /// a list, using index plus one as the offset of each entry.
     fn readdir (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, reply: ReplyDirectory) {
         reply.error(ENOSYS);
     }
 
+    /// Read directory with attributes
+    /// Like readdir, but entries carry the attributes of their inodes, which saves
+    /// a lookup per entry for processes that stat every entry (like ls -l). Only
+    /// called if FUSE_DO_READDIRPLUS was granted (see ConnectionInfo::want). Every
+    /// entry added with attributes counts as a lookup of its inode, the filesystem
+    /// must increase the lookup counts of the inodes in reply.lookups() before it
+    /// sends the reply (see ReplyDirectoryPlus::add). This is up to the filesystem,
+    /// the library doesn't track or check lookup counts.
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, reply: ReplyDirectoryPlus) {
+        reply.error(ENOSYS);
+    }
+
     /// Release an open directory
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
@@ -272,9 +546,13 @@ pub trait Filesystem {
     }
 
     /// Synchronize directory contents
-    /// If the datasync parameter is set, then only the directory contents should
-    /// be flushed, not the meta data. fh will contain the value set by the opendir
-    /// method, or will be undefined if the opendir method didn't set any value.
+    /// Called if an application fsyncs a directory (fsync on regular files is passed
+    /// to the fsync method instead). If the datasync parameter is set, then only the
+    /// directory contents should be flushed, not the meta data. fh will contain the
+    /// value set by the opendir method, or will be undefined if the opendir method
+    /// didn't set any value. If this method replies ENOSYS (the default), the kernel
+    /// treats it as success and doesn't send any further fsyncdir requests, so
+    /// filesystems without directory buffering don't need to implement it.
     fn fsyncdir (&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -285,25 +563,39 @@ pub trait Filesystem {
     }
 
     /// Set an extended attribute
-    fn setxattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+    /// flags are the flags of setxattr(2) (XATTR_CREATE, XATTR_REPLACE). position is the
+    /// offset into a resource fork (OS X only, 0 otherwise). setxattr_flags are additional
+    /// flags from the kernel (FUSE_SETXATTR_ACL_KILL_SGID), they're only set if
+    /// FUSE_SETXATTR_EXT was granted at init. With XATTR_CREATE, reply EEXIST if the
+    /// attribute exists, with XATTR_REPLACE, reply ENOATTR if it doesn't.
+    ///
+    /// A filesystem without extended attributes should keep the default ENOSYS replies
+    /// of all xattr operations. The kernel then fails them with EOPNOTSUPP (ENOTSUP),
+    /// which tells tools that the filesystem doesn't support attributes, and stops
+    /// sending them. A filesystem that only supports some namespaces should reply
+    /// EOPNOTSUPP for names in the others.
+    fn setxattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, _setxattr_flags: u32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// Get an extended attribute
-    fn getxattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyData) {
-        // FIXME: If arg.size is zero, the size of the value should be sent with fuse_getxattr_out
-        // FIXME: If arg.size is non-zero, send the value if it fits, or ERANGE otherwise
+    /// If size is zero, the size of the value should be sent with reply.size().
+    /// If size is non-zero, send the value with reply.data() if it fits, or ERANGE otherwise.
+    /// If the attribute doesn't exist, reply ENOATTR (which is ENODATA on Linux).
+    fn getxattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
         reply.error(ENOSYS);
     }
 
     /// List extended attribute names
-    fn listxattr (&mut self, _req: &Request, _ino: u64, reply: ReplyEmpty) {
-        // FIXME: If arg.size is zero, the size of the attribute list should be sent with fuse_getxattr_out
-        // FIXME: If arg.size is non-zero, send the attribute list if it fits, or ERANGE otherwise
+    /// Names must include their namespace prefix, use XattrList to build the list.
+    /// If size is zero, the size of the list should be sent with reply.size().
+    /// If size is non-zero, send the list with reply.data() if it fits, or ERANGE otherwise.
+    fn listxattr (&mut self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
         reply.error(ENOSYS);
     }
 
     /// Remove an extended attribute
+    /// If the attribute doesn't exist, reply ENOATTR (which is ENODATA on Linux).
     fn removexattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -311,7 +603,8 @@ pub trait Filesystem {
     /// Check file access permissions
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
-    /// under Linux kernel versions 2.4.x
+    /// under Linux kernel versions 2.4.x. Use check_access to check the mask against
+    /// the attributes of the file.
     fn access (&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -325,13 +618,43 @@ pub trait Filesystem {
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details. If this method is not
     /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
-    /// and open() methods will be called instead.
-    fn create (&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, _flags: u32, reply: ReplyCreate) {
+    /// and open() methods will be called instead. mode is the file type and the
+    /// permissions of the new file (e.g. S_IFREG | 0o644), with the umask of the
+    /// calling process already applied (unless FUSE_DONT_MASK was granted). umask is
+    /// that umask (ABI 7.12 or later, 0 otherwise), for filesystems that apply it
+    /// themselves (e.g. to combine it with default ACLs). Use apply_umask to get the
+    /// permissions of the new file in either case, the umask must not be applied to
+    /// the permissions otherwise (e.g. by subtracting it). flags are the open flags
+    /// (O_WRONLY, O_RDWR, O_EXCL, O_TRUNC, ...) that apply to the returned handle,
+    /// they don't affect the permissions of the file: a file created with mode 0o444
+    /// can still be written through the handle returned by create.
+    fn create (&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, _umask: u32, _flags: u32, reply: ReplyCreate) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create and open an unnamed temporary file
+    /// Called on open(2) with O_TMPFILE in the directory parent, with kernels of ABI
+    /// 7.37 or later. Like create, but the new file has no name: it isn't an entry of
+    /// parent (or any other directory), so it has no links, until the application
+    /// links it into the namespace with linkat(2) (which is a link request for the
+    /// inode of the file), e.g. to create a file with its complete contents at once.
+    /// If it's never linked, the file is gone once its last handle is released and
+    /// the kernel forgot the inode. Reply with the entry of the new inode and the
+    /// opened handle like in create. mode, umask and flags are like the ones of
+    /// create. If this method is not implemented, the kernel remembers it and fails
+    /// O_TMPFILE opens with EOPNOTSUPP, so applications fall back to named files.
+    #[cfg(not(target_os = "macos"))]
+    fn tmpfile (&mut self, _req: &Request, _parent: u64, _mode: u32, _umask: u32, _flags: u32, reply: ReplyCreate) {
         reply.error(ENOSYS);
     }
 
     /// Test for a POSIX file lock
-    fn getlk (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) {
+    /// The lock range is given as absolute byte offsets, the kernel already resolved
+    /// the whence of struct flock (SEEK_CUR, SEEK_END) before sending the request. The
+    /// end is inclusive, a lock up to the end of the file (l_len of 0) has an end of
+    /// u64::MAX (OFFSET_MAX). Reply with the conflicting lock, or with LockType::Unlock
+    /// if the lock could be placed.
+    fn getlk (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: LockType, _pid: u32, reply: ReplyLock) {
         reply.error(ENOSYS);
     }
 
@@ -341,8 +664,10 @@ pub trait Filesystem {
     /// 'fi->owner' must be used. The l_pid field in 'struct flock' should only be
     /// used to fill in this field in getlk(). Note: if the locking methods are not
     /// implemented, the kernel will still allow file locking to work locally.
-    /// Hence these are only interesting for network filesystems and similar.
-    fn setlk (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
+    /// Hence these are only interesting for network filesystems and similar. The lock
+    /// range is given as absolute byte offsets like in getlk. If sleep is true (F_SETLKW),
+    /// the filesystem should wait until a conflicting lock is released.
+    fn setlk (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: LockType, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -353,6 +678,51 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Poll for events of an open file. events are the requested events (ABI 7.21+,
+    /// 0 before). If FUSE_POLL_SCHEDULE_NOTIFY is set in flags, the kernel wants to be
+    /// told when the events of the file change: keep kh (e.g. by fh, the kernel uses
+    /// the same kh for all polls of an open file) and call Notifier::poll with it once
+    /// the file becomes ready, which wakes up the waiting processes. If this replies
+    /// ENOSYS, the kernel treats the file as always ready and stops polling it.
+    #[cfg(feature = "abi-7-11")]
+    fn poll (&mut self, _req: &Request, _ino: u64, _fh: u64, _kh: u64, _flags: u32, _events: u32, reply: ReplyPoll) {
+        reply.error(ENOSYS);
+    }
+
+    /// Map a range of a file into the DAX window
+    /// Called by virtiofs with DAX to map len bytes of the file at foffset to moffset
+    /// in the DAX window, a shared memory region of the virtio device that guest
+    /// processes access directly. flags tell if the mapping may be read and written
+    /// (FUSE_SETUPMAPPING_FLAG_READ, FUSE_SETUPMAPPING_FLAG_WRITE). Only called if
+    /// FUSE_MAP_ALIGNMENT was granted (see ConnectionInfo::want). Full support needs
+    /// the fd of the DAX window (provided by the virtio device, e.g. the vhost-user
+    /// backend), which the filesystem maps the file range into on its own; this only
+    /// delivers the requests.
+    #[cfg(not(target_os = "macos"))]
+    fn setupmapping (&mut self, _req: &Request, _ino: u64, _fh: u64, _foffset: u64, _len: u64, _flags: u64, _moffset: u64, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove mappings from the DAX window
+    /// Called by virtiofs with DAX to remove the given mappings, set up with
+    /// setupmapping, from the DAX window. Only called if FUSE_MAP_ALIGNMENT was
+    /// granted.
+    #[cfg(not(target_os = "macos"))]
+    fn removemapping (&mut self, _req: &Request, _ino: u64, _mappings: &[Mapping], reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Synchronize the whole filesystem
+    /// Called on syncfs(2) and sync(1) (and when the filesystem is unmounted) if the
+    /// kernel speaks ABI 7.34 or later (see ConnectionInfo::syncfs), to write all data
+    /// that the filesystem buffers (e.g. in a write-back cache) to its storage.
+    /// Replying successfully means it is durable. Filesystems that don't buffer
+    /// anything have nothing to do, so the default implementation replies successfully.
+    #[cfg(not(target_os = "macos"))]
+    fn syncfs (&mut self, _req: &Request, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
     /// OS X only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
@@ -366,8 +736,12 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
-    /// OS X only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
-    /// during init to FUSE_XTIMES to enable
+    /// OS X only: Query extended times (bkuptime and crtime). The kernel asks for them
+    /// when a process queries the backup or creation time with getattrlist(2), like the
+    /// Finder does for the "Created" date of its info window and backup tools like Time
+    /// Machine do for the backup time (0 if the file was never backed up). crtime should
+    /// be the same as the crtime of the file's attributes. Only called if FUSE_XTIMES
+    /// was granted, which is wanted by default (see ConnectionInfo::want).
     #[cfg(target_os = "macos")]
     fn getxtimes (&mut self, _req: &Request, _ino: u64, reply: ReplyXTimes) {
         reply.error(ENOSYS);
@@ -384,7 +758,16 @@ pub fn mount<FS: Filesystem, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, op
 /// a background thread to handle filesystem operations while being mounted
 /// and therefore returns immediately. The returned handle should be stored
 /// to reference the mounted filesystem. If it's dropped, the filesystem will
-/// be unmounted.
-pub fn spawn_mount<'a, FS: Filesystem+Send+'a, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, options: &[&OsStr]) -> io::Result<BackgroundSession<'a>> {
-    Session::new(filesystem, mountpoint.as_ref(), options).spawn()
+/// be unmounted. Returns Error::Mount if mounting fails or the background thread
+/// can't be started (the filesystem is unmounted again then).
+pub fn spawn_mount<'a, FS: Filesystem+Send+'a, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, options: &[&OsStr]) -> Result<BackgroundSession<'a>, Error> {
+    let se = try!(Session::try_new(filesystem, mountpoint.as_ref(), options));
+    se.spawn().map_err(Error::Mount)
+}
+
+/// Unmount the filesystem mounted at the given mountpoint in the given way (see
+/// UnmountMode). The session of the filesystem ends once it's unmounted. Returns
+/// Error::Unmount if unmounting fails.
+pub fn unmount<P: AsRef<Path>> (mountpoint: &P, mode: UnmountMode) -> Result<(), Error> {
+    channel::unmount_with_mode(mountpoint.as_ref(), mode).map_err(Error::Unmount)
 }
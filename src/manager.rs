@@ -0,0 +1,274 @@
+//!
+//! A mount manager runs many sessions in a single thread. Instead of a thread
+//! and a request buffer per mount (like with BackgroundSession), it waits for
+//! requests of all its mounts at once and dispatches them one after another
+//! using a single buffer, so serving many mounts from one process stays cheap.
+//!
+//! Waiting uses poll(2) rather than epoll, since epoll is Linux-only and this
+//! crate also supports OS X and FreeBSD. Polling a few dozen fds costs about
+//! the same each time. There is no worker pool either: filesystem methods take
+//! &mut self, so requests of one mount can't be dispatched at the same time
+//! anyway, and a pool wouldn't help a single busy mount. Filesystems that want
+//! concurrency can reply from their own threads (or use the async dispatcher).
+//!
+
+use std::io;
+use std::path::Path;
+use libc::EINTR;
+use time::Duration;
+use channel;
+use Filesystem;
+use session::{BUFFER_SIZE, Mount, Session};
+
+/// Identifies a mount of a MountManager
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct MountId(usize);
+
+/// Runs the session loops of many mounted filesystems in one thread. Requests are
+/// dispatched one at a time, like in Session::run, so a slow filesystem method
+/// holds up all the mounts of the manager. Filesystems that need to do lengthy
+/// work should reply from other threads. The idle timeout of sessions is ignored,
+/// other session settings (like operation_timeout, catch_panics and
+/// shutdown_timeout) apply like in Session::run.
+///
+/// ```ignore
+/// let mut manager = MountManager::new();
+/// manager.add(Session::new(MyFs::new(), &Path::new("/mnt/a"), &[])).unwrap();
+/// manager.add(Session::new(MyFs::new(), &Path::new("/mnt/b"), &[])).unwrap();
+/// manager.run();
+/// ```
+pub struct MountManager<'a> {
+    /// Mounted sessions
+    mounts: Vec<(MountId, Box<Mount + 'a>)>,
+    /// Id of the next mount that is added
+    next_id: usize,
+    /// Buffer for receiving requests, shared by all mounts
+    buffer: Vec<u8>,
+}
+
+impl<'a> MountManager<'a> {
+    /// Create a new manager without any mounts
+    pub fn new () -> MountManager<'a> {
+        MountManager { mounts: Vec::new(), next_id: 0, buffer: vec![0; BUFFER_SIZE] }
+    }
+
+    /// Add the given session (of a mounted filesystem) to the manager. Returns the
+    /// id to remove it with. The session's channel is switched to non-blocking mode,
+    /// so that waiting for one mount never blocks the others.
+    pub fn add<FS: Filesystem+'a> (&mut self, se: Session<FS>) -> io::Result<MountId> {
+        try!(channel::set_nonblocking(Mount::fd(&se)));
+        let id = MountId(self.next_id);
+        self.next_id += 1;
+        info!(target: Mount::log_target(&se), "Managing mount {}", Mount::mountpoint(&se).display());
+        self.mounts.push((id, Box::new(se)));
+        Ok(id)
+    }
+
+    /// Remove the mount with the given id from the manager. Its session ends like a
+    /// session whose loop ended: the filesystem is destroyed and unmounted. Returns
+    /// false if there's no mount with the given id (e.g. because it already ended).
+    pub fn remove (&mut self, id: MountId) -> bool {
+        match self.mounts.iter().position(|&(mount_id, _)| mount_id == id) {
+            Some(i) => {
+                let (_, mut mount) = self.mounts.remove(i);
+                mount.finish();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the mountpoint of the mount with the given id
+    pub fn mountpoint (&self, id: MountId) -> Option<&Path> {
+        self.mounts.iter().find(|&&(mount_id, _)| mount_id == id).map(|&(_, ref mount)| mount.mountpoint())
+    }
+
+    /// Returns the number of mounts
+    pub fn len (&self) -> usize {
+        self.mounts.len()
+    }
+
+    /// Returns true if the manager has no mounts
+    pub fn is_empty (&self) -> bool {
+        self.mounts.is_empty()
+    }
+
+    /// Wait for requests of all mounts, but at most for the given timeout, and
+    /// dispatch one request of every mount that has one. Mounts whose session ended
+    /// (e.g. because the filesystem was unmounted) are removed. Returns the number
+    /// of mounts that were ready, 0 if the timeout expired. Can be used to run the
+    /// manager from an own loop, e.g. to add mounts in between.
+    pub fn run_once (&mut self, timeout: Duration) -> io::Result<usize> {
+        let fds: Vec<_> = self.mounts.iter().map(|&(_, ref mount)| mount.fd()).collect();
+        let ready = match channel::poll_readable(&fds, timeout) {
+            Ok(ready) => ready,
+            Err(ref err) if err.raw_os_error() == Some(EINTR) => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut count = 0;
+        let mut ended = vec![];
+        for (&mut (id, ref mut mount), &ready) in self.mounts.iter_mut().zip(ready.iter()) {
+            if !ready { continue; }
+            count += 1;
+            match mount.receive_and_dispatch(&mut self.buffer) {
+                Ok(true) => (),
+                Ok(false) => ended.push(id),
+                // Only the failed session ends, the other mounts keep running
+                Err(err) => {
+                    error!(target: mount.log_target(), "Session of {} failed: {}", mount.mountpoint().display(), err);
+                    ended.push(id);
+                },
+            }
+        }
+        for id in ended {
+            self.remove(id);
+        }
+        Ok(count)
+    }
+
+    /// Run the session loops of all mounts until all of them ended
+    pub fn run (&mut self) {
+        while !self.is_empty() {
+            if let Err(err) = self.run_once(Duration::seconds(1)) {
+                // All mounts end, so the error is logged for each of them
+                for &(_, ref mount) in self.mounts.iter() {
+                    error!(target: mount.log_target(), "Failed to wait for requests of {}: {}", mount.mountpoint().display(), err);
+                }
+                break;
+            }
+        }
+    }
+}
+
+impl<'a> Drop for MountManager<'a> {
+    fn drop (&mut self) {
+        for (_, mut mount) in self.mounts.drain(..) {
+            mount.finish();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{mem, slice};
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use libc::{c_int, c_void, size_t, ENOSYS};
+    use time::Duration;
+    use channel::Channel;
+    use fuse::{FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION, fuse_in_header, fuse_out_header};
+    use fuse::fuse_opcode::FUSE_GETATTR;
+    use {Filesystem, Request, Session};
+    use session::Mount;
+    use super::MountManager;
+
+    struct CountingFs {
+        destroyed: Arc<AtomicUsize>,
+    }
+
+    impl Filesystem for CountingFs {
+        fn destroy (&mut self, _req: &Request) {
+            self.destroyed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Create an initialized session that counts destroys with the given counter.
+    /// Returns the session and the fd of the other end of its channel.
+    fn session (destroyed: &Arc<AtomicUsize>) -> (Session<CountingFs>, c_int) {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(CountingFs { destroyed: destroyed.clone() }, ch);
+        se.proto_major = FUSE_KERNEL_VERSION;
+        se.proto_minor = FUSE_KERNEL_MINOR_VERSION;
+        se.initialized = true;
+        (se, fd)
+    }
+
+    /// Send a getattr request (with room for the getattr argument of newer ABIs)
+    /// to the channel at the other end of fd
+    fn send_getattr (fd: c_int) {
+        let len = mem::size_of::<fuse_in_header>() + 16;
+        let header = fuse_in_header { len: len as u32, opcode: FUSE_GETATTR as u32, unique: 0xdeadbeef, nodeid: 0x22, uid: 0, gid: 0, pid: 0, padding: 0 };
+        let mut buffer: Vec<u8> = Vec::with_capacity(len);
+        unsafe {
+            buffer.push_all(slice::from_raw_parts(&header as *const fuse_in_header as *const u8, mem::size_of::<fuse_in_header>()));
+            buffer.push_all(&[0u8; 16]);
+            ::libc::write(fd, buffer.as_ptr() as *const c_void, len as size_t);
+        }
+    }
+
+    /// Receive a reply from the channel at the other end of fd and return its error
+    fn receive_error (fd: c_int) -> i32 {
+        let mut buffer = vec![0u8; 4096];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        assert!(rc >= mem::size_of::<fuse_out_header>() as isize, "failed to receive reply");
+        let header: &fuse_out_header = unsafe { mem::transmute(buffer.as_ptr()) };
+        -header.error
+    }
+
+    #[test]
+    fn manage_mounts () {
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let (se1, fd1) = session(&destroyed);
+        let (se2, fd2) = session(&destroyed);
+        let mut manager = MountManager::new();
+        let id1 = manager.add(se1).unwrap();
+        let id2 = manager.add(se2).unwrap();
+        assert_eq!(manager.len(), 2);
+        // Nothing to do
+        assert_eq!(manager.run_once(Duration::milliseconds(10)).unwrap(), 0);
+        // Only the mount with a request dispatches it
+        send_getattr(fd2);
+        assert_eq!(manager.run_once(Duration::milliseconds(100)).unwrap(), 1);
+        assert_eq!(receive_error(fd2), ENOSYS);
+        // A broken request ends a session like a lost connection
+        unsafe { ::libc::write(fd1, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        send_getattr(fd2);
+        assert_eq!(manager.run_once(Duration::milliseconds(100)).unwrap(), 2);
+        assert_eq!(receive_error(fd2), ENOSYS);
+        assert_eq!(manager.len(), 1);
+        assert!(manager.mountpoint(id1).is_none());
+        assert!(manager.mountpoint(id2).is_some());
+        assert_eq!(destroyed.load(Ordering::SeqCst), 1);
+        assert!(!manager.remove(id1));
+        assert!(manager.remove(id2));
+        assert!(manager.is_empty());
+        assert_eq!(destroyed.load(Ordering::SeqCst), 2);
+        manager.run();
+        unsafe { ::libc::close(fd1); ::libc::close(fd2); }
+    }
+
+    #[test]
+    fn failed_session_ends_alone () {
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let (se1, fd1) = session(&destroyed);
+        let (se2, fd2) = session(&destroyed);
+        // Replace the channel's fd of the first session with a directory, which
+        // is always readable and fails to read with EISDIR
+        let dir = File::open("/").unwrap();
+        unsafe { ::libc::dup2(dir.as_raw_fd(), Mount::fd(&se1)); }
+        let mut manager = MountManager::new();
+        let id1 = manager.add(se1).unwrap();
+        let id2 = manager.add(se2).unwrap();
+        send_getattr(fd2);
+        assert_eq!(manager.run_once(Duration::milliseconds(100)).unwrap(), 2);
+        assert_eq!(receive_error(fd2), ENOSYS);
+        assert!(manager.mountpoint(id1).is_none());
+        assert!(manager.mountpoint(id2).is_some());
+        assert_eq!(destroyed.load(Ordering::SeqCst), 1);
+        unsafe { ::libc::close(fd1); ::libc::close(fd2); }
+    }
+
+    #[test]
+    fn destroy_remaining_mounts () {
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let (se, fd) = session(&destroyed);
+        let mut manager = MountManager::new();
+        manager.add(se).unwrap();
+        drop(manager);
+        assert_eq!(destroyed.load(Ordering::SeqCst), 1);
+        unsafe { ::libc::close(fd); }
+    }
+}
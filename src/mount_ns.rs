@@ -0,0 +1,66 @@
+//!
+//! Private mount namespaces (Linux only). A filesystem that is mounted in a new
+//! mount namespace is only visible to the thread that created the namespace and
+//! to the threads and processes it starts afterwards, e.g. to give a sandboxed
+//! tool a filesystem that nothing else on the system sees.
+//!
+
+use std::{io, ptr};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+// Libc's unshare and mount functions, and their flags
+mod libc {
+    use libc::{c_char, c_int, c_ulong, c_void};
+
+    pub const CLONE_NEWNS: c_int = 0x00020000;
+    pub const CLONE_NEWUSER: c_int = 0x10000000;
+    pub const MS_REC: c_ulong = 0x4000;
+    pub const MS_PRIVATE: c_ulong = 1 << 18;
+
+    extern "system" {
+        pub fn unshare (flags: c_int) -> c_int;
+        pub fn mount (source: *const c_char, target: *const c_char, fstype: *const c_char, flags: c_ulong, data: *const c_void) -> c_int;
+    }
+}
+
+/// Move the calling thread into a new mount namespace and make all mounts in it
+/// private, so that mounts made in it afterwards don't propagate back to the
+/// original namespace (the root mount is shared with systemd, mounts would show
+/// up in the original namespace otherwise). Returns the file of the new namespace
+/// (for setns(2), e.g. to let other threads join it).
+///
+/// Unsharing the mount namespace needs CAP_SYS_ADMIN. With rootless, the thread
+/// is moved into a new user namespace as well (in which the uid and gid of the
+/// caller are mapped to themselves), which gives it that capability in the new
+/// namespaces without being root. That needs unprivileged user namespaces to be
+/// enabled (e.g. kernel.unprivileged_userns_clone on some distributions), a
+/// kernel that allows FUSE mounts in user namespaces (4.18 or later) and a
+/// process that has a single thread (the kernel refuses to unshare the user
+/// namespace of multithreaded processes).
+pub fn unshare_mount_namespace (rootless: bool) -> io::Result<File> {
+    let (uid, gid) = unsafe { (::libc::getuid(), ::libc::getgid()) };
+    let flags = if rootless { libc::CLONE_NEWUSER | libc::CLONE_NEWNS } else { libc::CLONE_NEWNS };
+    if unsafe { libc::unshare(flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if rootless {
+        // Groups can't be mapped unless setgroups is denied
+        try!(write_proc_file("/proc/self/setgroups", "deny"));
+        try!(write_proc_file("/proc/self/uid_map", &format!("{} {} 1", uid, uid)));
+        try!(write_proc_file("/proc/self/gid_map", &format!("{} {} 1", gid, gid)));
+    }
+    let root = CString::new("/").unwrap();
+    if unsafe { libc::mount(ptr::null(), root.as_ptr(), ptr::null(), libc::MS_REC | libc::MS_PRIVATE, ptr::null()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The namespace of the thread, not the one of the process (/proc/self is the
+    // main thread), which stays in the original namespace if it's another thread
+    File::open("/proc/thread-self/ns/mnt")
+}
+
+/// Write the given contents to the given file of /proc in a single write
+fn write_proc_file (path: &str, contents: &str) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path).and_then(|mut file| file.write_all(contents.as_bytes()))
+}
@@ -0,0 +1,117 @@
+//!
+//! Typed mount options. They are rendered into the -o arguments that are
+//! passed to the mount functions.
+//!
+//! A mount shows up in /proc/mounts as "<source> <mountpoint> <type> ...". The
+//! source (Source) is the device name of the mount, which defaults to the program
+//! name. The mount helper takes it from the fsname option, so FUSE has no name
+//! besides the fsname that it could show: Source and FSName set the same thing,
+//! Source just says what it's for. The type is "fuse", or "fuse.<subtype>" if a
+//! subtype (Subtype) is given. To tell several mounts of the same filesystem
+//! apart, give each mount a distinct source (e.g. the archive or the remote host
+//! it serves) and all of them the same subtype.
+//!
+
+use std::ffi::{OsStr, OsString};
+
+/// Mount option
+#[derive(Clone, Debug, PartialEq)]
+pub enum MountOption {
+    /// Name of the filesystem shown as the source (device) of the mount in the
+    /// first column of /proc/mounts and by df (defaults to the program name)
+    FSName(String),
+    /// Source (device name) of the mount in the first column of /proc/mounts.
+    /// It's passed to the mount helper as the fsname, so don't give both.
+    Source(String),
+    /// Subtype of the filesystem. The mount is shown with the type fuse.<subtype>
+    /// in /proc/mounts and by df -T, instead of plain fuse.
+    Subtype(String),
+    /// Allow all users to access the filesystem
+    AllowOther,
+    /// Allow root (in addition to the user that mounted it) to access the filesystem
+    AllowRoot,
+    /// Let the kernel check file permissions based on file modes
+    DefaultPermissions,
+    /// Mount read-only
+    RO,
+    /// Any other option, given as it would appear in the -o list (e.g. "max_read=4096")
+    Custom(String),
+}
+
+impl MountOption {
+    /// Returns the option as it appears in the -o list. Commas and backslashes in
+    /// values are escaped with a backslash, so they don't split the list.
+    fn render (&self) -> String {
+        match *self {
+            MountOption::FSName(ref name) => format!("fsname={}", escape(name)),
+            MountOption::Source(ref source) => format!("fsname={}", escape(source)),
+            MountOption::Subtype(ref subtype) => format!("subtype={}", escape(subtype)),
+            MountOption::AllowOther => "allow_other".to_string(),
+            MountOption::AllowRoot => "allow_root".to_string(),
+            MountOption::DefaultPermissions => "default_permissions".to_string(),
+            MountOption::RO => "ro".to_string(),
+            MountOption::Custom(ref option) => option.clone(),
+        }
+    }
+}
+
+/// Escape commas and backslashes in an option value
+fn escape (value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == '\\' { escaped.push('\\'); }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Render the given mount options into the arguments expected by the mount
+/// functions (-o followed by a comma separated list of options). Returns no
+/// arguments if no options are given.
+///
+/// ```ignore
+/// let args = fuse::mount_args(&[MountOption::FSName("archive.tar".to_string()), MountOption::Subtype("tarfs".to_string())]);
+/// let options: Vec<&OsStr> = args.iter().map(|arg| arg.as_os_str()).collect();
+/// fuse::mount(TarFs::new(), &mountpoint, &options);
+/// ```
+pub fn mount_args (options: &[MountOption]) -> Vec<OsString> {
+    if options.is_empty() {
+        return vec![];
+    }
+    let mut list = String::new();
+    for option in options {
+        if !list.is_empty() { list.push(','); }
+        list.push_str(&option.render());
+    }
+    vec![OsStr::new("-o").to_os_string(), OsString::from(list)]
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use super::{MountOption, mount_args};
+
+    #[test]
+    fn render_subtype_and_fsname () {
+        let args = mount_args(&[MountOption::FSName("archive.tar".to_string()), MountOption::Subtype("tarfs".to_string()), MountOption::RO]);
+        assert_eq!(args, [OsStr::new("-o").to_os_string(), OsStr::new("fsname=archive.tar,subtype=tarfs,ro").to_os_string()]);
+    }
+
+    #[test]
+    fn render_source () {
+        let args = mount_args(&[MountOption::Source("backup@host:/srv".to_string()), MountOption::Subtype("sshfs".to_string())]);
+        assert_eq!(args, [OsStr::new("-o").to_os_string(), OsStr::new("fsname=backup@host:/srv,subtype=sshfs").to_os_string()]);
+    }
+
+    #[test]
+    fn render_escaped () {
+        let args = mount_args(&[MountOption::FSName("a,b\\c".to_string()), MountOption::Custom("max_read=4096".to_string())]);
+        assert_eq!(args, [OsStr::new("-o").to_os_string(), OsStr::new("fsname=a\\,b\\\\c,max_read=4096").to_os_string()]);
+    }
+
+    #[test]
+    fn render_empty () {
+        assert!(mount_args(&[]).is_empty());
+    }
+}
@@ -0,0 +1,579 @@
+//!
+//! A notifier sends messages to the kernel driver that aren't replies to
+//! a request. Notifications are used to tell the kernel about changes that
+//! happened in the filesystem without the kernel asking for them.
+//!
+//! If the kernel can't take a notification right now (the write fails with
+//! EAGAIN), the notification is kept in a bounded queue and written later.
+//! Notifications are always written to the kernel in the order they were
+//! sent: as long as there are queued notifications, new notifications are
+//! queued behind them instead of being written directly. Queued notifications
+//! are retried whenever another notification is sent or if `flush` is called.
+//! If the queue is full, sending fails with an Error::Channel of kind
+//! `WouldBlock` and the notification is dropped, so the caller can decide to
+//! flush and retry later. The notifiers of sessions write without blocking (see
+//! Session::notifier), so they never wait for the kernel to take a notification.
+//!
+//! A notifier can be cloned and sent to other threads. All clones share the
+//! same queue, so the ordering guarantee holds across clones, too.
+//!
+//! Most notifications are one-way. A retrieve notification asks the kernel for
+//! cached data, which the kernel sends back later as a separate request. The
+//! session loop receives it and completes the waiting retrieve.
+//!
+//! A filesystem that changes quickly (e.g. one that mirrors remote data) may
+//! want to invalidate the same inodes over and over. A coalescing notifier
+//! collects inode invalidations and sends them at most once per interval,
+//! merging overlapping ranges of the same inode into one notification. This
+//! trades staleness for throughput: the kernel may serve cached data that is up
+//! to one interval old (or older, until the next invalidation or flush), but it
+//! gets a bounded number of notifications instead of one per change.
+//!
+
+use std::{io, mem, slice};
+#[cfg(feature = "abi-7-12")]
+use std::{cmp, i64};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicUsize;
+#[cfg(feature = "abi-7-15")]
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+#[cfg(feature = "abi-7-15")]
+use std::sync::mpsc::{self, Receiver};
+use channel::OwnedSender;
+use Error;
+use fuse::{fuse_out_header, fuse_notify_code};
+#[cfg(feature = "abi-7-12")]
+use time::{self, Duration, Timespec};
+#[cfg(feature = "abi-7-11")]
+use fuse::fuse_notify_poll_wakeup_out;
+#[cfg(feature = "abi-7-12")]
+use fuse::fuse_notify_inval_inode_out;
+#[cfg(feature = "abi-7-15")]
+use fuse::fuse_notify_retrieve_out;
+
+/// Maximum number of notifications that are queued if the kernel can't take them
+pub const MAX_PENDING_NOTIFICATIONS: usize = 64;
+
+/// Maximum number of separate ranges a coalescing notifier keeps per inode. If an
+/// inode gets more, they're merged into a single range that covers all of them.
+#[cfg(feature = "abi-7-12")]
+pub const MAX_COALESCED_RANGES: usize = 8;
+
+/// Cached data that the kernel returned for a retrieve notification
+#[derive(Debug, PartialEq)]
+pub struct Retrieved {
+    /// Offset of the data in the file
+    pub offset: u64,
+    /// Data from the kernel's page cache. It is shorter than requested (or empty)
+    /// if less of the requested range is cached.
+    pub data: Vec<u8>,
+}
+
+/// Retrieve notifications that wait for the kernel's reply, by the unique id
+/// that was sent with the notification. Clones share the same retrieves.
+#[derive(Clone)]
+pub struct Retrieves {
+    waiting: Arc<Mutex<HashMap<u64, Sender<Retrieved>>>>,
+    next_unique: Arc<AtomicUsize>,
+}
+
+impl Retrieves {
+    /// Create an empty set of retrieves
+    pub fn new () -> Retrieves {
+        Retrieves { waiting: Arc::new(Mutex::new(HashMap::new())), next_unique: Arc::new(AtomicUsize::new(1)) }
+    }
+
+    /// Returns the number of retrieves that wait for the kernel's reply
+    pub fn waiting (&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    /// Complete the retrieve with the given unique id with the data the kernel
+    /// returned. Returns false if no retrieve with this id is waiting.
+    pub fn complete (&self, unique: u64, offset: u64, data: &[u8]) -> bool {
+        match self.waiting.lock().unwrap().remove(&unique) {
+            Some(tx) => {
+                // The receiver may already be gone if nobody waits for the data anymore
+                let _ = tx.send(Retrieved { offset: offset, data: data.to_vec() });
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Cancel all waiting retrieves. Their receivers return an error.
+    pub fn cancel_all (&self) {
+        self.waiting.lock().unwrap().clear();
+    }
+
+    /// Register a new retrieve and return its unique id and the receiver for the data
+    #[cfg(feature = "abi-7-15")]
+    fn register (&self) -> (u64, Receiver<Retrieved>) {
+        let unique = self.next_unique.fetch_add(1, Ordering::SeqCst) as u64;
+        let (tx, rx) = mpsc::channel();
+        self.waiting.lock().unwrap().insert(unique, tx);
+        (unique, rx)
+    }
+
+    /// Forget the retrieve with the given unique id
+    #[cfg(feature = "abi-7-15")]
+    fn cancel (&self, unique: u64) {
+        self.waiting.lock().unwrap().remove(&unique);
+    }
+}
+
+/// State shared by all clones of a notifier
+struct NotifierInner {
+    /// Channel sender for sending notifications
+    ch: OwnedSender,
+    /// Notifications that couldn't be written yet, in the order they were sent
+    pending: Mutex<VecDeque<Vec<u8>>>,
+    /// Retrieve notifications that wait for the kernel's reply
+    retrieves: Retrieves,
+}
+
+/// Notification handle
+#[derive(Clone)]
+pub struct Notifier {
+    inner: Arc<NotifierInner>,
+}
+
+impl Notifier {
+    /// Create a new notifier that sends notifications using the given channel sender
+    pub fn new (ch: OwnedSender) -> Notifier {
+        Notifier::with_retrieves(ch, Retrieves::new())
+    }
+
+    /// Create a new notifier that sends notifications using the given channel sender
+    /// and registers retrieve notifications in the given retrieves (which the session
+    /// completes when the kernel replies)
+    pub fn with_retrieves (ch: OwnedSender, retrieves: Retrieves) -> Notifier {
+        Notifier { inner: Arc::new(NotifierInner { ch: ch, pending: Mutex::new(VecDeque::new()), retrieves: retrieves }) }
+    }
+
+    /// Returns the number of notifications that are queued and not yet written
+    /// to the kernel
+    pub fn pending (&self) -> usize {
+        self.inner.pending.lock().unwrap().len()
+    }
+
+    /// Try to write all queued notifications to the kernel. Stops at the first
+    /// notification the kernel can't take (which stays queued) and returns Ok.
+    /// Other errors are returned (as Error::Channel) and the failed notification is
+    /// dropped.
+    pub fn flush (&self) -> Result<(), Error> {
+        let mut pending = self.inner.pending.lock().unwrap();
+        Notifier::flush_pending(&self.inner.ch, &mut pending).map_err(Error::Channel)
+    }
+
+    /// Ask the kernel to resend all requests that were sent to the filesystem, but
+    /// weren't replied yet. This is useful if the filesystem lost track of requests
+    /// it received (e.g. after restarting its request processing). Resent requests
+    /// carry the FUSE_UNIQUE_RESEND flag in their unique id and are dispatched like
+    /// any other request, except for requests that are still in flight (e.g. being
+    /// replied from another thread): these aren't dispatched again, their pending
+    /// reply goes to the resent request instead. Requires a kernel that supports FUSE_NOTIFY_RESEND (ABI
+    /// 7.40, Linux 6.9), older kernels reject the notification with an error.
+    pub fn resend (&self) -> Result<(), Error> {
+        self.send(fuse_notify_code::FUSE_NOTIFY_RESEND, &[])
+    }
+
+    /// Wake up the processes that poll the file with the given kernel poll handle, the
+    /// kh that Filesystem::poll (or CuseDevice::poll) got with FUSE_POLL_SCHEDULE_NOTIFY.
+    /// The kernel then polls the file again to get its events. Requires ABI 7.11.
+    #[cfg(feature = "abi-7-11")]
+    pub fn poll (&self, kh: u64) -> Result<(), Error> {
+        let arg = fuse_notify_poll_wakeup_out { kh: kh };
+        let argbytes = unsafe { slice::from_raw_parts(&arg as *const fuse_notify_poll_wakeup_out as *const u8, mem::size_of::<fuse_notify_poll_wakeup_out>()) };
+        self.send(fuse_notify_code::FUSE_NOTIFY_POLL, &[argbytes])
+    }
+
+    /// Tell the kernel to invalidate the cached attributes and data of the given inode.
+    /// Cached data from offset to offset + len is dropped, up to the end of the file if
+    /// len is 0 or less. If offset is negative, only the attributes are invalidated.
+    /// Requires ABI 7.12.
+    #[cfg(feature = "abi-7-12")]
+    pub fn inval_inode (&self, ino: u64, offset: i64, len: i64) -> Result<(), Error> {
+        let arg = fuse_notify_inval_inode_out { ino: ino, off: offset, len: len };
+        let argbytes = unsafe { slice::from_raw_parts(&arg as *const fuse_notify_inval_inode_out as *const u8, mem::size_of::<fuse_notify_inval_inode_out>()) };
+        self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_INODE, &[argbytes])
+    }
+
+    /// Ask the kernel for up to size bytes at the given offset of the given inode that
+    /// are in its page cache. Returns a receiver that gets the data when the kernel
+    /// replies. The kernel replies to every retrieve it accepts, with less (or no) data
+    /// if less of the range is cached. If it doesn't accept the notification (e.g.
+    /// ENOENT if the inode isn't known to the kernel), an error is returned.
+    ///
+    /// The reply is received and delivered by the session loop, so don't wait for it
+    /// in a filesystem method: that blocks the loop, and the reply is never received.
+    /// There's no timeout. If the session ends before the kernel replies, the retrieve
+    /// is cancelled and the receiver returns an error. Use try_recv to check for the
+    /// data without blocking. Requires ABI 7.15.
+    #[cfg(feature = "abi-7-15")]
+    pub fn retrieve (&self, ino: u64, offset: u64, size: u32) -> Result<Receiver<Retrieved>, Error> {
+        let (unique, rx) = self.inner.retrieves.register();
+        let arg = fuse_notify_retrieve_out { notify_unique: unique, nodeid: ino, offset: offset, size: size, padding: 0 };
+        let argbytes = unsafe { slice::from_raw_parts(&arg as *const fuse_notify_retrieve_out as *const u8, mem::size_of::<fuse_notify_retrieve_out>()) };
+        match self.send(fuse_notify_code::FUSE_NOTIFY_RETRIEVE, &[argbytes]) {
+            Ok(()) => Ok(rx),
+            Err(err) => {
+                self.inner.retrieves.cancel(unique);
+                Err(err)
+            },
+        }
+    }
+
+    /// Send a notification with the given code and data. Notifications look like
+    /// replies, but have a unique id of 0 and carry the notification code instead
+    /// of an error code. If the kernel can't take the notification, it is queued.
+    /// Errors of writing to the channel are returned as Error::Channel.
+    fn send (&self, code: fuse_notify_code, data: &[&[u8]]) -> Result<(), Error> {
+        let len = data.iter().fold(0, |l, d| { l + d.len() });
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + len) as u32,
+            error: code as i32,
+            unique: 0,
+        };
+        let headerbytes = unsafe { slice::from_raw_parts(&header as *const fuse_out_header as *const u8, mem::size_of::<fuse_out_header>()) };
+        let mut sendbytes = headerbytes.to_vec();
+        for d in data { sendbytes.push_all(d); }
+        let mut pending = self.inner.pending.lock().unwrap();
+        try!(Notifier::flush_pending(&self.inner.ch, &mut pending));
+        if pending.is_empty() {
+            match self.inner.ch.send(&[&sendbytes]) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res.map_err(Error::Channel),
+            }
+        }
+        if pending.len() >= MAX_PENDING_NOTIFICATIONS {
+            return Err(Error::Channel(io::Error::new(io::ErrorKind::WouldBlock, "notification queue full")));
+        }
+        pending.push_back(sendbytes);
+        Ok(())
+    }
+
+    /// Write queued notifications in order until the queue is empty or the kernel
+    /// can't take more
+    fn flush_pending (ch: &OwnedSender, pending: &mut VecDeque<Vec<u8>>) -> io::Result<()> {
+        while let Some(bytes) = pending.pop_front() {
+            match ch.send(&[&bytes]) {
+                Ok(()) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    pending.push_front(bytes);
+                    break;
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Range of cached data from start to end (exclusive). An end of i64::MAX stands
+/// for the end of the file.
+#[cfg(feature = "abi-7-12")]
+type Range = (i64, i64);
+
+/// Inode invalidations that were coalesced, but not sent yet
+#[cfg(feature = "abi-7-12")]
+struct Coalesced {
+    /// Data ranges to invalidate by inode, sorted and not overlapping. An inode
+    /// without ranges only gets its attributes invalidated.
+    inodes: HashMap<u64, Vec<Range>>,
+    /// Time of the last flush, None if there was none yet
+    last_flush: Option<Timespec>,
+}
+
+/// Notifier that coalesces inode invalidations (see the module documentation).
+/// Invalidations are sent right away if the interval passed since the last flush,
+/// otherwise they're kept until then. Since there's no timer, kept invalidations
+/// are only sent with a later invalidation or by calling flush, so a filesystem
+/// that stops changing should flush (e.g. from a thread that flushes regularly).
+/// Clones share the same coalesced invalidations. Requires ABI 7.12.
+#[cfg(feature = "abi-7-12")]
+#[derive(Clone)]
+pub struct CoalescingNotifier {
+    notifier: Notifier,
+    interval: Duration,
+    coalesced: Arc<Mutex<Coalesced>>,
+}
+
+#[cfg(feature = "abi-7-12")]
+impl CoalescingNotifier {
+    /// Create a coalescing notifier that sends invalidations with the given notifier
+    /// at most once per interval
+    pub fn new (notifier: Notifier, interval: Duration) -> CoalescingNotifier {
+        let coalesced = Coalesced { inodes: HashMap::new(), last_flush: None };
+        CoalescingNotifier { notifier: notifier, interval: interval, coalesced: Arc::new(Mutex::new(coalesced)) }
+    }
+
+    /// Returns the number of inodes with invalidations that weren't sent yet
+    pub fn pending (&self) -> usize {
+        self.coalesced.lock().unwrap().inodes.len()
+    }
+
+    /// Invalidate the cached attributes and data of the given inode, like
+    /// Notifier::inval_inode. The range is merged with the unsent invalidations of
+    /// the inode. If the interval passed since the last flush, all coalesced
+    /// invalidations are sent.
+    pub fn inval_inode (&self, ino: u64, offset: i64, len: i64) -> Result<(), Error> {
+        let mut coalesced = self.coalesced.lock().unwrap();
+        {
+            let ranges = coalesced.inodes.entry(ino).or_insert(vec![]);
+            if offset >= 0 {
+                let end = if len <= 0 { i64::MAX } else { offset.saturating_add(len) };
+                add_range(ranges, (offset, end));
+            }
+        }
+        let now = time::get_time();
+        let due = match coalesced.last_flush {
+            Some(last_flush) => now - last_flush >= self.interval,
+            None => true,
+        };
+        if due { self.flush_coalesced(&mut coalesced, now) } else { Ok(()) }
+    }
+
+    /// Send all coalesced invalidations now. If sending fails, the invalidations that
+    /// weren't sent yet are kept and the error is returned.
+    pub fn flush (&self) -> Result<(), Error> {
+        let mut coalesced = self.coalesced.lock().unwrap();
+        self.flush_coalesced(&mut coalesced, time::get_time())
+    }
+
+    fn flush_coalesced (&self, coalesced: &mut Coalesced, now: Timespec) -> Result<(), Error> {
+        coalesced.last_flush = Some(now);
+        let inodes: Vec<u64> = coalesced.inodes.keys().cloned().collect();
+        for ino in inodes {
+            let ranges = coalesced.inodes.remove(&ino).unwrap();
+            if ranges.is_empty() {
+                if let Err(err) = self.notifier.inval_inode(ino, -1, 0) {
+                    coalesced.inodes.insert(ino, ranges);
+                    return Err(err);
+                }
+            }
+            for (i, &(start, end)) in ranges.iter().enumerate() {
+                let len = if end == i64::MAX { 0 } else { end - start };
+                if let Err(err) = self.notifier.inval_inode(ino, start, len) {
+                    coalesced.inodes.insert(ino, ranges[i..].to_vec());
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Add the given range to the sorted, non-overlapping ranges, merging it with the
+/// ranges it overlaps or touches. If there are more than MAX_COALESCED_RANGES ranges
+/// afterwards, they're merged into one.
+#[cfg(feature = "abi-7-12")]
+fn add_range (ranges: &mut Vec<Range>, range: Range) {
+    let (mut start, mut end) = range;
+    let mut merged = Vec::with_capacity(ranges.len() + 1);
+    for &(s, e) in ranges.iter() {
+        if e < start || s > end {
+            merged.push((s, e));
+        } else {
+            start = cmp::min(start, s);
+            end = cmp::max(end, e);
+        }
+    }
+    merged.push((start, end));
+    merged.sort();
+    if merged.len() > MAX_COALESCED_RANGES {
+        let (first, last) = (merged[0].0, merged[merged.len() - 1].1);
+        merged = vec![(first, last)];
+    }
+    *ranges = merged;
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use libc::c_void;
+    use channel::Channel;
+    use Error;
+    use super::{Notifier, MAX_PENDING_NOTIFICATIONS};
+    #[cfg(feature = "abi-7-12")]
+    use std::{i64, mem};
+    #[cfg(feature = "abi-7-12")]
+    use time::Duration;
+    #[cfg(feature = "abi-7-12")]
+    use fuse::fuse_notify_inval_inode_out;
+    #[cfg(feature = "abi-7-12")]
+    use super::{CoalescingNotifier, MAX_COALESCED_RANGES, add_range};
+    #[cfg(feature = "abi-7-15")]
+    use super::{Retrieved, Retrieves};
+
+    fn receive (fd: ::libc::c_int) -> Vec<u8> {
+        let mut buffer = [0u8; 64];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as ::libc::size_t) };
+        assert!(rc >= 0);
+        buffer[..rc as usize].to_vec()
+    }
+
+    #[test]
+    fn notify_resend () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let notifier = Notifier::new(ch.owned_sender().unwrap());
+        notifier.resend().unwrap();
+        assert_eq!(receive(fd), [0x10, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn notify_queue_on_eagain () {
+        let (ch, fd) = Channel::pair().unwrap();
+        ch.set_nonblocking().unwrap();
+        let notifier = Notifier::new(ch.owned_sender().unwrap());
+        // Fill the channel until the first notification gets queued
+        let mut written = 0;
+        while notifier.pending() == 0 {
+            notifier.resend().unwrap();
+            written += 1;
+        }
+        written -= 1;
+        // Fill the queue until it overflows
+        while notifier.pending() < MAX_PENDING_NOTIFICATIONS {
+            notifier.resend().unwrap();
+        }
+        match notifier.resend() {
+            Err(Error::Channel(err)) => assert_eq!(err.kind(), ::std::io::ErrorKind::WouldBlock),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // Drain the channel, flushing queued notifications as room becomes available
+        let mut received = 0;
+        while received < written + MAX_PENDING_NOTIFICATIONS {
+            assert_eq!(receive(fd).len(), 16);
+            received += 1;
+            notifier.flush().unwrap();
+        }
+        assert_eq!(notifier.pending(), 0);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn notify_from_clone_after_channel_dropped () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let notifier = Notifier::new(ch.owned_sender().unwrap());
+        drop(ch);
+        let clone = notifier.clone();
+        thread::spawn(move || {
+            clone.resend().unwrap();
+        }).join().unwrap();
+        assert_eq!(receive(fd).len(), 16);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-15")]
+    fn notify_retrieve () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let retrieves = Retrieves::new();
+        let notifier = Notifier::with_retrieves(ch.owned_sender().unwrap(), retrieves.clone());
+        let rx = notifier.retrieve(0x22, 0x1000, 16).unwrap();
+        assert_eq!(&receive(fd)[..], &[
+            0x30, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ][..]);
+        assert_eq!(retrieves.waiting(), 1);
+        assert!(rx.try_recv().is_err());
+        assert!(!retrieves.complete(2, 0x1000, b"hello"));
+        assert!(retrieves.complete(1, 0x1000, b"hello"));
+        assert_eq!(rx.recv().unwrap(), Retrieved { offset: 0x1000, data: b"hello".to_vec() });
+        assert_eq!(retrieves.waiting(), 0);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-15")]
+    fn notify_retrieve_cancelled () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let retrieves = Retrieves::new();
+        let notifier = Notifier::with_retrieves(ch.owned_sender().unwrap(), retrieves.clone());
+        let rx = notifier.retrieve(0x22, 0, 16).unwrap();
+        retrieves.cancel_all();
+        assert!(rx.recv().is_err());
+        unsafe { ::libc::close(fd); }
+    }
+
+    /// Returns the inode, offset and length of a received inode invalidation
+    #[cfg(feature = "abi-7-12")]
+    fn inval_of (bytes: &[u8]) -> (u64, i64, i64) {
+        assert_eq!(bytes.len(), 16 + mem::size_of::<fuse_notify_inval_inode_out>());
+        assert_eq!(bytes[4], 2);
+        let arg: &fuse_notify_inval_inode_out = unsafe { mem::transmute(bytes[16..].as_ptr()) };
+        (arg.ino, arg.off, arg.len)
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn notify_poll () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let notifier = Notifier::new(ch.owned_sender().unwrap());
+        notifier.poll(0x1122334455667788).unwrap();
+        assert_eq!(&receive(fd)[..], &[
+            0x18, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+        ][..]);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-12")]
+    fn notify_inval_inode () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let notifier = Notifier::new(ch.owned_sender().unwrap());
+        notifier.inval_inode(0x22, 0x1000, 0x2000).unwrap();
+        assert_eq!(&receive(fd)[..], &[
+            0x28, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ][..]);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-12")]
+    fn coalesce_ranges () {
+        let mut ranges = vec![];
+        add_range(&mut ranges, (0x1000, 0x2000));
+        add_range(&mut ranges, (0x4000, 0x5000));
+        add_range(&mut ranges, (0x2000, 0x3000));
+        assert_eq!(ranges, [(0x1000, 0x3000), (0x4000, 0x5000)]);
+        add_range(&mut ranges, (0x2800, i64::MAX));
+        assert_eq!(ranges, [(0x1000, i64::MAX)]);
+        // Too many separate ranges are merged into one
+        let mut ranges = vec![];
+        for i in 0..MAX_COALESCED_RANGES as i64 + 1 {
+            add_range(&mut ranges, (i * 0x2000, i * 0x2000 + 0x1000));
+        }
+        assert_eq!(ranges, [(0, MAX_COALESCED_RANGES as i64 * 0x2000 + 0x1000)]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-12")]
+    fn coalescing_notifier () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let notifier = CoalescingNotifier::new(Notifier::new(ch.owned_sender().unwrap()), Duration::hours(1));
+        // The first invalidation is sent right away, later ones wait for the interval
+        notifier.inval_inode(0x22, 0, 0x1000).unwrap();
+        assert_eq!(inval_of(&receive(fd)), (0x22, 0, 0x1000));
+        notifier.inval_inode(0x22, 0x1000, 0x1000).unwrap();
+        notifier.inval_inode(0x22, 0x800, 0x1000).unwrap();
+        notifier.inval_inode(0x33, -1, 0).unwrap();
+        notifier.inval_inode(0x44, 0x1000, 0).unwrap();
+        assert_eq!(notifier.pending(), 3);
+        notifier.flush().unwrap();
+        assert_eq!(notifier.pending(), 0);
+        let mut received: Vec<(u64, i64, i64)> = (0..3).map(|_| inval_of(&receive(fd))).collect();
+        received.sort();
+        assert_eq!(received, [(0x22, 0x800, 0x1800), (0x33, -1, 0), (0x44, 0x1000, 0)]);
+        unsafe { ::libc::close(fd); }
+    }
+}
@@ -0,0 +1,47 @@
+//!
+//! Passthrough (Linux 6.9 or later, ABI 7.40) lets the kernel read and write an
+//! open file of another filesystem (a backing file) directly, instead of sending
+//! the reads and writes of an open file to the filesystem. This gives loopback
+//! style filesystems close to native throughput, while they still decide about
+//! lookups, attributes and opens.
+//!
+//! The filesystem registers the backing file with the connection, which returns
+//! a backing id, and replies to the open with ReplyOpen::passthrough and that id.
+//! It needs FUSE_PASSTHROUGH to be granted during init (see ConnectionInfo::want)
+//! and registering backing files needs CAP_SYS_ADMIN.
+//!
+
+use std::io;
+use std::sync::Arc;
+use std::os::unix::io::AsRawFd;
+use channel::OwnedSender;
+
+/// Handle to register backing files of a session (see Session::backing_files).
+/// Like a notifier, it has its own duplicate of the channel's file descriptor,
+/// so it can be cloned, stored in the filesystem and used from other threads.
+#[derive(Clone)]
+pub struct BackingFiles {
+    ch: Arc<OwnedSender>,
+}
+
+impl BackingFiles {
+    /// Create a new handle that registers backing files using the given channel sender
+    pub fn new (ch: OwnedSender) -> BackingFiles {
+        BackingFiles { ch: Arc::new(ch) }
+    }
+
+    /// Register the given open file as a backing file and return its backing id.
+    /// The kernel keeps its own reference to the file, so it may be closed after
+    /// registering. Fails if FUSE_PASSTHROUGH wasn't granted or the process lacks
+    /// CAP_SYS_ADMIN.
+    pub fn open<F: AsRawFd> (&self, file: &F) -> io::Result<u32> {
+        self.ch.backing_open(file.as_raw_fd())
+    }
+
+    /// Unregister the backing file with the given backing id. Files that were
+    /// opened with it stay backed by it until they're released, so this can be
+    /// called right after the open reply if the id isn't used for other opens.
+    pub fn close (&self, backing_id: u32) -> io::Result<()> {
+        self.ch.backing_close(backing_id)
+    }
+}
@@ -6,28 +6,97 @@
 //! replying with a block of data without cloning the data.
 //! A reply *must always* be used (by calling either ok() or error()
 //! exactly once).
+//! Replies are sent using a reply sender, which is the channel to the kernel
+//! driver for regular requests, but can be any other transport as well.
 //!
 
-use std::{mem, ptr, slice};
+use std::{cmp, i64, io, mem, ptr, slice};
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::marker::PhantomData;
-use std::boxed::FnBox;
 use std::os::unix::ffi::OsStrExt;
 use libc::{c_int, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK};
-use libc::consts::os::posix88::EIO;
+#[cfg(any(test, feature = "test-util"))]
+use libc::S_IFMT;
+use libc::consts::os::posix88::{EIO, ENOENT};
 use time::Timespec;
+use statfs::StatFs;
 use fuse::{fuse_attr, fuse_kstatfs, fuse_file_lock, fuse_entry_out, fuse_attr_out};
-use fuse::{fuse_open_out, fuse_write_out, fuse_statfs_out, fuse_lk_out, fuse_bmap_out};
+use fuse::{fuse_open_out, fuse_write_out, fuse_statfs_out, fuse_lk_out, fuse_bmap_out, fuse_getxattr_out};
 #[cfg(target_os = "macos")]
 use fuse::fuse_getxtimes_out;
 use fuse::{fuse_out_header, fuse_dirent};
-use {FileType, FileAttr};
+#[cfg(feature = "abi-7-21")]
+use fuse::fuse_direntplus;
+#[cfg(target_os = "linux")]
+use fuse::consts::FOPEN_PASSTHROUGH;
+#[cfg(all(feature = "abi-7-9", not(target_os = "macos")))]
+use fuse::consts::FUSE_ATTR_SUBMOUNT;
+#[cfg(feature = "abi-7-11")]
+use fuse::{fuse_ioctl_out, fuse_poll_out};
+use {FileType, FileAttr, LockType};
+
+/// Entry and attribute timeout that makes the kernel cache entries and attributes
+/// indefinitely (the max representable number of seconds). Only use this if the
+/// filesystem guarantees that the entries and attributes never change (e.g. read-only
+/// archives or immutable snapshots), since the kernel won't ask for them again unless
+/// they're explicitly invalidated.
+pub const TTL_FOREVER: Timespec = Timespec { sec: i64::MAX, nsec: 0 };
+
+/// Max number of data chunks of a reply that are sent without copying (the max number
+/// of buffers a vectored write takes is 1024 on most systems, one is for the header)
+pub const MAX_REPLY_CHUNKS: usize = 1023;
+
+/// Generic reply sender trait. A reply sender takes care of sending the
+/// serialized bytes of a reply to where the request came from
+pub trait ReplySender: Send + 'static {
+    /// Send all data in the slice of slice of bytes in a single write. An ENOENT
+    /// error means that the kernel doesn't wait for the reply anymore (e.g. the
+    /// request was interrupted), which is not a failure of the filesystem.
+    fn send (&self, data: &[&[u8]]) -> io::Result<()>;
+
+    /// Returns the target of log messages about replies sent with this sender (e.g.
+    /// if sending failed). Replies to the requests of a session log to the target
+    /// of the session (see Session::log_target).
+    fn log_target (&self) -> &str {
+        "fuse::reply"
+    }
+}
+
+impl<F: Fn(&[&[u8]])+Send+'static> ReplySender for F {
+    fn send (&self, data: &[&[u8]]) -> io::Result<()> {
+        self(data);
+        Ok(())
+    }
+}
 
 /// Generic reply trait
 pub trait Reply {
     /// Create a new reply for the given request
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> Self;
+    fn new<S: ReplySender> (unique: u64, sender: S) -> Self;
+
+    /// Reply to a request with the given error code
+    fn error (self, err: c_int);
+
+    /// Reply to a request with the given result. If it is Ok, the given function is
+    /// called with the reply and the value to send the successful reply, otherwise
+    /// the reply is an error with the given error code.
+    ///
+    /// ```ignore
+    /// reply.respond(fs::read(&path).map_err(|e| errno_of(&e)), |reply, data| reply.data(&data));
+    /// ```
+    fn respond<T, F: FnOnce(Self, T)> (self, result: Result<T, c_int>, f: F) where Self: Sized {
+        match result {
+            Ok(value) => f(self, value),
+            Err(err) => self.error(err),
+        }
+    }
+}
+
+/// Returns the error code of the given I/O error to reply with. Errors that don't
+/// come from the operating system (e.g. invalid data) get EIO.
+pub fn errno_of (err: &io::Error) -> c_int {
+    err.raw_os_error().unwrap_or(EIO)
 }
 
 /// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
@@ -58,6 +127,99 @@ fn mode_from_kind_and_perm (kind: FileType, perm: u16) -> u32 {
     }) as u32 | perm as u32
 }
 
+// Lock types of struct flock (see fcntl.h). Linux has its own values, OS X and
+// the BSDs share theirs.
+#[cfg(target_os = "linux")]
+const F_RDLCK: u32 = 0;
+#[cfg(target_os = "linux")]
+const F_WRLCK: u32 = 1;
+#[cfg(target_os = "linux")]
+const F_UNLCK: u32 = 2;
+#[cfg(not(target_os = "linux"))]
+const F_RDLCK: u32 = 1;
+#[cfg(not(target_os = "linux"))]
+const F_UNLCK: u32 = 2;
+#[cfg(not(target_os = "linux"))]
+const F_WRLCK: u32 = 3;
+
+/// Returns the lock type for a raw lock type sent by the kernel
+pub fn lock_type_from_raw (typ: u32) -> Option<LockType> {
+    match typ {
+        F_RDLCK => Some(LockType::Read),
+        F_WRLCK => Some(LockType::Write),
+        F_UNLCK => Some(LockType::Unlock),
+        _ => None,
+    }
+}
+
+/// Returns the raw lock type expected by the kernel for a lock type
+fn raw_from_lock_type (typ: LockType) -> u32 {
+    match typ {
+        LockType::Read => F_RDLCK,
+        LockType::Write => F_WRLCK,
+        LockType::Unlock => F_UNLCK,
+    }
+}
+
+// See mode_from_kind_and_perm about the typecast
+#[allow(trivial_numeric_casts)]
+/// Returns the file kind for a given mode
+#[cfg(any(test, feature = "test-util"))]
+fn kind_from_mode (mode: u32) -> FileType {
+    match mode & S_IFMT as u32 {
+        m if m == S_IFIFO as u32 => FileType::NamedPipe,
+        m if m == S_IFCHR as u32 => FileType::CharDevice,
+        m if m == S_IFBLK as u32 => FileType::BlockDevice,
+        m if m == S_IFDIR as u32 => FileType::Directory,
+        m if m == S_IFREG as u32 => FileType::RegularFile,
+        m if m == S_IFLNK as u32 => FileType::Symlink,
+        _ => panic!("Unknown file type in mode {:#o}", mode),
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl FileAttr {
+    /// Decode file attributes from a serialized fuse_attr, the reverse of how replies
+    /// serialize them. Meant for tests that check the contents of replies: the attributes
+    /// start at byte 16 of the data of getattr and setattr replies, and at byte 40 of the
+    /// data of lookup, mknod, mkdir, symlink, link and create replies. Panics if there are
+    /// not enough bytes or the file type is unknown.
+    pub fn from_bytes (bytes: &[u8]) -> FileAttr {
+        assert!(bytes.len() >= mem::size_of::<fuse_attr>());
+        let attr: fuse_attr = unsafe {
+            let mut attr: fuse_attr = mem::zeroed();
+            ptr::copy_nonoverlapping(bytes.as_ptr(), &mut attr as *mut fuse_attr as *mut u8, mem::size_of::<fuse_attr>());
+            attr
+        };
+        #[cfg(target_os = "macos")] #[inline]
+        fn get_crtime_and_flags (attr: &fuse_attr) -> (Timespec, u32) { (Timespec::new(attr.crtime, attr.crtimensec), attr.flags) }
+        #[cfg(not(target_os = "macos"))] #[inline]
+        fn get_crtime_and_flags (_attr: &fuse_attr) -> (Timespec, u32) { (Timespec::new(0, 0), 0) }
+        let (crtime, flags) = get_crtime_and_flags(&attr);
+        #[cfg(feature = "abi-7-9")] #[inline]
+        fn get_blksize (attr: &fuse_attr) -> u32 { attr.blksize }
+        #[cfg(not(feature = "abi-7-9"))] #[inline]
+        fn get_blksize (_attr: &fuse_attr) -> u32 { 0 }
+        FileAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: Timespec::new(attr.atime, attr.atimensec),
+            mtime: Timespec::new(attr.mtime, attr.mtimensec),
+            ctime: Timespec::new(attr.ctime, attr.ctimensec),
+            crtime: crtime,
+            kind: kind_from_mode(attr.mode),
+            perm: (attr.mode & 0o7777) as u16,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            blksize: get_blksize(&attr),
+            flags: flags,
+        }
+    }
+}
+
 /// Returns a fuse_attr from FileAttr
 #[cfg(target_os = "macos")]
 fn fuse_attr_from_attr (attr: &FileAttr) -> fuse_attr {
@@ -79,6 +241,10 @@ fn fuse_attr_from_attr (attr: &FileAttr) -> fuse_attr {
         gid: attr.gid,
         rdev: attr.rdev,
         flags: attr.flags,
+        #[cfg(feature = "abi-7-9")]
+        blksize: attr.blksize,
+        #[cfg(feature = "abi-7-9")]
+        padding: 0,
     }
 }
 
@@ -100,6 +266,23 @@ fn fuse_attr_from_attr (attr: &FileAttr) -> fuse_attr {
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        #[cfg(feature = "abi-7-9")]
+        blksize: attr.blksize,
+        #[cfg(feature = "abi-7-9")]
+        flags: 0,
+    }
+}
+
+/// Returns a fuse_entry_out for the given entry
+fn fuse_entry_out_from_attr (entry_ttl: &Timespec, attr_ttl: &Timespec, attr: &FileAttr, generation: u64) -> fuse_entry_out {
+    fuse_entry_out {
+        nodeid: attr.ino,
+        generation: generation,
+        entry_valid: entry_ttl.sec,
+        attr_valid: attr_ttl.sec,
+        entry_valid_nsec: entry_ttl.nsec,
+        attr_valid_nsec: attr_ttl.nsec,
+        attr: fuse_attr_from_attr(attr),
     }
 }
 
@@ -109,21 +292,30 @@ fn fuse_attr_from_attr (attr: &FileAttr) -> fuse_attr {
 pub struct ReplyRaw<T> {
     /// Unique id of the request to reply to
     unique: u64,
-    /// Closure to call for sending the reply
-    sender: Option<Box<for<'a> FnBox(&'a [&'a [u8]]) + Send>>,
+    /// Sender to use for sending the reply
+    sender: Option<Box<ReplySender>>,
     /// Marker for being able to have T on this struct (which enforces
     /// reply types to send the correct type of data)
     marker: PhantomData<T>,
 }
 
 impl<T> Reply for ReplyRaw<T> {
-    fn new<F: FnBox(&[&[u8]])+Send+'static> (unique: u64, sender: F) -> ReplyRaw<T> {
-        let sender: Box<for<'a> FnBox(&'a [&'a [u8]]) + Send> = Box::new(sender);
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyRaw<T> {
+        let sender: Box<ReplySender> = Box::new(sender);
         ReplyRaw { unique: unique, sender: Some(sender), marker: PhantomData }
     }
+
+    fn error (mut self, err: c_int) {
+        self.send(err, &[]);
+    }
 }
 
 impl<T> ReplyRaw<T> {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
     /// Reply to a request with the given error code and data. Must be called
     /// only once (the `ok` and `error` methods ensure this by consuming `self`)
     fn send (&mut self, err: c_int, bytes: &[&[u8]]) {
@@ -134,11 +326,18 @@ impl<T> ReplyRaw<T> {
             error: -err,
             unique: self.unique,
         };
+        let unique = self.unique;
         as_bytes(&header, |headerbytes| {
             let sender = self.sender.take().unwrap();
             let mut sendbytes = headerbytes.to_vec();
             sendbytes.push_all(bytes);
-            sender.call_box((&sendbytes,));
+            if let Err(err) = sender.send(&sendbytes) {
+                match err.raw_os_error() {
+                    // The request was already cancelled by the kernel
+                    Some(ENOENT) => debug!(target: sender.log_target(), "Reply to request {} not needed anymore", unique),
+                    _ => error!(target: sender.log_target(), "Failed to send reply to request {}: {}", unique, err),
+                }
+            }
         });
     }
 
@@ -149,16 +348,34 @@ impl<T> ReplyRaw<T> {
         })
     }
 
-    /// Reply to a request with the given error code
-    pub fn error (mut self, err: c_int) {
-        self.send(err, &[]);
+    /// Reply to a request with only the first size bytes of the given type, for
+    /// replies whose size depends on the ABI version of the kernel (older kernels
+    /// reject replies that are larger than their version of the type)
+    pub fn ok_truncated (mut self, data: &T, size: usize) {
+        as_bytes(data, |bytes| {
+            if bytes.is_empty() {
+                self.send(0, bytes);
+            } else {
+                let size = cmp::min(size, bytes[0].len());
+                self.send(0, &[&bytes[0][..size]]);
+            }
+        })
+    }
+
+    /// Reply to a request with the given type of data, followed by the given bytes
+    pub fn ok_with (mut self, data: &T, extra: &[u8]) {
+        as_bytes(data, |bytes| {
+            let mut bytes = bytes.to_vec();
+            bytes.push(extra);
+            self.send(0, &bytes);
+        })
     }
 }
 
 impl<T> Drop for ReplyRaw<T> {
     fn drop (&mut self) {
         if self.sender.is_some() {
-            warn!("Reply not sent for operation {}, replying with I/O error", self.unique);
+            warn!(target: self.sender.as_ref().unwrap().log_target(), "Reply not sent for operation {}, replying with I/O error", self.unique);
             self.send(EIO, &[]);
         }
     }
@@ -167,25 +384,36 @@ impl<T> Drop for ReplyRaw<T> {
 ///
 /// Empty reply
 ///
+/// The reply of all operations that either succeed without returning anything or
+/// fail with an error: unlink, rmdir, rename, flush, release, fsync, releasedir,
+/// fsyncdir, setxattr, removexattr, access and setlk (and setvolname and exchange
+/// on OS X).
 pub struct ReplyEmpty {
     reply: ReplyRaw<()>,
 }
 
 impl Reply for ReplyEmpty {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyEmpty {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyEmpty {
         ReplyEmpty { reply: Reply::new(unique, sender) }
     }
-}
 
-impl ReplyEmpty {
-    /// Reply to a request with nothing
-    pub fn ok (mut self) {
-        self.reply.send(0, &[]);
+    fn error (self, err: c_int) {
+        self.reply.error(err);
     }
+}
 
+impl ReplyEmpty {
     /// Reply to a request with the given error code
     pub fn error (self, err: c_int) {
-        self.reply.error(err);
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with nothing: just a reply header without data and
+    /// without an error. The length in the header is exactly the size of the
+    /// header, the kernel fails the operation with EIO (and logs a wrong reply
+    /// size) if it differs from what it expects for the operation.
+    pub fn ok (mut self) {
+        self.reply.send(0, &[]);
     }
 }
 
@@ -197,20 +425,117 @@ pub struct ReplyData {
 }
 
 impl Reply for ReplyData {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyData {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyData {
         ReplyData { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyData {
-    /// Reply to a request with the given data
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the given data. Also used to reply to readlink with
+    /// the raw bytes of the target (without a NUL terminator). Exactly the given bytes
+    /// are sent: as a reply to read, fewer bytes than requested is a short read that
+    /// tells the kernel that the file ends there (it's never padded).
     pub fn data (mut self, data: &[u8]) {
         self.reply.send(0, &[data]);
     }
 
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a read with up to size bytes of the given contents of a file, starting
+    /// at the given offset. If the file ends before offset + size, only the bytes up
+    /// to its end are sent (no bytes at all if offset is at or beyond the end).
+    pub fn data_at (self, contents: &[u8], offset: u64, size: u32) {
+        let start = cmp::min(offset, contents.len() as u64) as usize;
+        let end = cmp::min(start as u64 + size as u64, contents.len() as u64) as usize;
+        self.data(&contents[start..end]);
+    }
+
+    /// Reply to a request with data that is made up of the given chunks (e.g. blocks
+    /// of a cache, mmap'd pages or buffers from a network stream), without copying
+    /// them into one buffer first. The kernel expects a reply in a single write with
+    /// the total size declared in its header, so a reply can't be sent in parts as
+    /// data arrives; the header and all chunks are written at once with a vectored
+    /// write. If there are more than MAX_REPLY_CHUNKS chunks, they are copied into
+    /// one buffer.
+    pub fn data_vectored (mut self, chunks: &[&[u8]]) {
+        if chunks.len() <= MAX_REPLY_CHUNKS {
+            self.reply.send(0, chunks);
+        } else {
+            let mut data = Vec::with_capacity(chunks.iter().fold(0, |l, c| l + c.len()));
+            for chunk in chunks { data.push_all(chunk); }
+            self.reply.send(0, &[&data]);
+        }
+    }
+
+    /// Returns a writer that replies with data that arrives in parts (e.g. copied
+    /// from a network stream with io::copy), see ReplyDataWriter. size is the exact
+    /// number of bytes that will be written, e.g. the size of the read or less if
+    /// the file ends before.
+    pub fn writer (self, size: u32) -> ReplyDataWriter {
+        let mut writer = ReplyDataWriter { reply: Some(self), size: size as usize, data: Vec::with_capacity(size as usize) };
+        if size == 0 {
+            writer.send();
+        }
+        writer
+    }
+}
+
+/// Writer for a data reply that is fed in parts. The kernel takes a reply in a
+/// single write with exactly the size declared in its header, so the data is
+/// collected until the size that was given to ReplyData::writer is complete and
+/// sent at once then. Writing more than that fails with InvalidInput. If fewer
+/// bytes are written, finish (or dropping the writer) replies with EIO, since
+/// a shorter reply to a read would tell the kernel that the file ends there.
+pub struct ReplyDataWriter {
+    reply: Option<ReplyData>,
+    size: usize,
+    data: Vec<u8>,
+}
+
+impl ReplyDataWriter {
+    /// Send the collected data
+    fn send (&mut self) {
+        if let Some(reply) = self.reply.take() {
+            reply.data(&self.data);
+            self.data = Vec::new();
+        }
+    }
+
+    /// Finish the reply. Fails (and replies with EIO) if fewer bytes than the
+    /// declared size were written.
+    pub fn finish (mut self) -> io::Result<()> {
+        match self.reply.take() {
+            Some(reply) => {
+                reply.error(EIO);
+                Err(io::Error::new(io::ErrorKind::Other, "reply data is shorter than its declared size"))
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl io::Write for ReplyDataWriter {
+    fn write (&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.reply.is_none() && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "reply data is longer than its declared size"));
+        }
+        let len = cmp::min(buf.len(), self.size - self.data.len());
+        self.data.push_all(&buf[..len]);
+        if self.data.len() == self.size {
+            self.send();
+        }
+        Ok(len)
+    }
+
+    fn flush (&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
@@ -222,29 +547,74 @@ pub struct ReplyEntry {
 }
 
 impl Reply for ReplyEntry {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyEntry {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyEntry {
         ReplyEntry { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyEntry {
-    /// Reply to a request with the given entry
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the given entry. The kernel caches both the name of
+    /// the entry and its attributes for the given time. The inode number of the
+    /// attributes (attr.ino) becomes the node id of the entry, which the kernel uses
+    /// in all further requests of the inode (and counts a lookup for), so it must be
+    /// the real inode number, not 0.
+    ///
+    /// ```ignore
+    /// reply.entry(&TTL, &self.attr(ino), 0);
+    /// ```
     pub fn entry (self, ttl: &Timespec, attr: &FileAttr, generation: u64) {
+        self.entry_with_ttls(ttl, ttl, attr, generation);
+    }
+
+    /// Reply to a request with the given entry. The kernel caches the name of the
+    /// entry (the mapping of the name to the inode) for entry_ttl and its attributes
+    /// for attr_ttl, e.g. a long entry_ttl for a name that rarely changes and a short
+    /// attr_ttl for a file whose size changes often.
+    pub fn entry_with_ttls (self, entry_ttl: &Timespec, attr_ttl: &Timespec, attr: &FileAttr, generation: u64) {
+        self.reply.ok(&fuse_entry_out_from_attr(entry_ttl, attr_ttl, attr, generation));
+    }
+
+    /// Reply to a lookup with the given directory entry and mark it as the root of
+    /// a submount. If FUSE_SUBMOUNTS was granted (see ConnectionInfo::submounts),
+    /// the kernel mounts the directory as a separate mount of the same connection
+    /// when it is crossed, so it gets its own device number (st_dev) and is a mount
+    /// boundary for tools like find -xdev and du -x. The submount goes away with the
+    /// filesystem or when it's unused for a while. Only directories can be submounts,
+    /// the flag is ignored for other entries, for entries of inodes the kernel already
+    /// knows and without FUSE_SUBMOUNTS.
+    #[cfg(all(feature = "abi-7-9", not(target_os = "macos")))]
+    pub fn submount (self, ttl: &Timespec, attr: &FileAttr, generation: u64) {
+        let mut entry = fuse_entry_out_from_attr(ttl, ttl, attr, generation);
+        entry.attr.flags |= FUSE_ATTR_SUBMOUNT;
+        self.reply.ok(&entry);
+    }
+
+    /// Reply to a lookup with a negative entry: the name doesn't exist and the kernel
+    /// caches this for the given time, so further lookups of the name fail with ENOENT
+    /// without asking the filesystem. This is only valid as a reply to lookup. If the
+    /// name is created while the negative entry is cached by other means than through
+    /// the kernel (e.g. on a network filesystem), the filesystem must invalidate it
+    /// with an entry invalidation notification.
+    pub fn negative (self, ttl: &Timespec) {
         self.reply.ok(&fuse_entry_out {
-            nodeid: attr.ino,
-            generation: generation,
+            nodeid: 0,
+            generation: 0,
             entry_valid: ttl.sec,
-            attr_valid: ttl.sec,
+            attr_valid: 0,
             entry_valid_nsec: ttl.nsec,
-            attr_valid_nsec: ttl.nsec,
-            attr: fuse_attr_from_attr(attr),
+            attr_valid_nsec: 0,
+            attr: unsafe { mem::zeroed() },
         });
     }
-
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
-    }
 }
 
 ///
@@ -255,12 +625,21 @@ pub struct ReplyAttr {
 }
 
 impl Reply for ReplyAttr {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyAttr {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyAttr {
         ReplyAttr { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyAttr {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
     /// Reply to a request with the given attribute
     pub fn attr (self, ttl: &Timespec, attr: &FileAttr) {
         self.reply.ok(&fuse_attr_out {
@@ -270,11 +649,6 @@ impl ReplyAttr {
             attr: fuse_attr_from_attr(attr),
         });
     }
-
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
-    }
 }
 
 ///
@@ -287,14 +661,24 @@ pub struct ReplyXTimes {
 
 #[cfg(target_os = "macos")]
 impl Reply for ReplyXTimes {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyXTimes {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyXTimes {
         ReplyXTimes { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 #[cfg(target_os = "macos")]
 impl ReplyXTimes {
-    /// Reply to a request with the given xtimes
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the given backup time and creation time. Times are
+    /// sent like those of the attributes, seconds of both first, then nanoseconds.
     pub fn xtimes (self, bkuptime: Timespec, crtime: Timespec) {
         self.reply.ok(&fuse_getxtimes_out {
             bkuptime: bkuptime.sec,
@@ -303,11 +687,6 @@ impl ReplyXTimes {
             crtimensec: crtime.nsec,
         });
     }
-
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
-    }
 }
 
 ///
@@ -318,24 +697,56 @@ pub struct ReplyOpen {
 }
 
 impl Reply for ReplyOpen {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyOpen {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyOpen {
         ReplyOpen { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyOpen {
-    /// Reply to a request with the given open result
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the given open result. flags are FOPEN_* flags that
+    /// decide how the kernel caches the file for this open file handle:
+    ///
+    /// * FOPEN_DIRECT_IO: reads and writes bypass the page cache and go straight
+    ///   to the filesystem, with the sizes the caller used. Good for files whose
+    ///   contents change without the kernel knowing (device-like or streaming files).
+    ///   The handle can't be used for shared mmap, unless FUSE_DIRECT_IO_ALLOW_MMAP
+    ///   was granted (see ConnectionInfo::direct_io_mmap).
+    /// * FOPEN_KEEP_CACHE: don't invalidate the cached data of the file when it's
+    ///   opened. Without it, the page cache of the file is dropped on every open.
+    ///
+    /// Both are decided per open, so a file can be opened cached once and with
+    /// direct_io another time. FOPEN_KEEP_CACHE has no effect on reads through a
+    /// direct_io handle, but keeps the cache for the other handles of the file.
     pub fn opened (self, fh: u64, flags: u32) {
         self.reply.ok(&fuse_open_out {
             fh: fh,
             open_flags: flags,
-            padding: 0,
+            backing_id: 0,
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with an open file that is backed by the backing file
+    /// with the given id (see BackingFiles). The kernel then reads and writes the
+    /// backing file directly, without sending reads and writes to the filesystem.
+    /// Other operations of the open file (like flush and release) still get the
+    /// given fh. Only possible if FUSE_PASSTHROUGH was granted (see
+    /// ConnectionInfo::passthrough), the kernel fails the open otherwise.
+    #[cfg(target_os = "linux")]
+    pub fn passthrough (self, fh: u64, flags: u32, backing_id: u32) {
+        self.reply.ok(&fuse_open_out {
+            fh: fh,
+            open_flags: flags | FOPEN_PASSTHROUGH,
+            backing_id: backing_id as i32,
+        });
     }
 }
 
@@ -347,24 +758,32 @@ pub struct ReplyWrite {
 }
 
 impl Reply for ReplyWrite {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyWrite {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyWrite {
         ReplyWrite { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyWrite {
-    /// Reply to a request with the given open result
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the number of bytes written. This may be less than
+    /// the size of the data (a short write), e.g. if the filesystem is running out of
+    /// space. The write system call then returns the short count and the caller can
+    /// retry the remaining bytes. Only for files opened with direct_io, a count of 0
+    /// is also passed on to the caller.
     pub fn written (self, size: u32) {
         self.reply.ok(&fuse_write_out {
             size: size,
             padding: 0,
         });
     }
-
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
-    }
 }
 
 ///
@@ -375,12 +794,21 @@ pub struct ReplyStatfs {
 }
 
 impl Reply for ReplyStatfs {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyStatfs {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyStatfs {
         ReplyStatfs { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyStatfs {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
     /// Reply to a request with the given open result
     pub fn statfs (self, blocks: u64, bfree: u64, bavail: u64, files: u64, ffree: u64, bsize: u32, namelen: u32, frsize: u32) {
         self.reply.ok(&fuse_statfs_out {
@@ -399,9 +827,10 @@ impl ReplyStatfs {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given statistics (e.g. of a backing filesystem,
+    /// see StatFs::for_path)
+    pub fn stat (self, st: &StatFs) {
+        self.statfs(st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize);
     }
 }
 
@@ -413,12 +842,21 @@ pub struct ReplyCreate {
 }
 
 impl Reply for ReplyCreate {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyCreate {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyCreate {
         ReplyCreate { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyCreate {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
     /// Reply to a request with the given entry
     pub fn created (self, ttl: &Timespec, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
         self.reply.ok(&(fuse_entry_out {
@@ -432,14 +870,9 @@ impl ReplyCreate {
         }, fuse_open_out {
             fh: fh,
             open_flags: flags,
-            padding: 0,
+            backing_id: 0,
         }));
     }
-
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
-    }
 }
 
 ///
@@ -450,28 +883,33 @@ pub struct ReplyLock {
 }
 
 impl Reply for ReplyLock {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyLock {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyLock {
         ReplyLock { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyLock {
-    /// Reply to a request with the given open result
-    pub fn locked (self, start: u64, end: u64, typ: u32, pid: u32) {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the given lock (the conflicting lock, or a lock of
+    /// type LockType::Unlock if there's no conflict)
+    pub fn locked (self, start: u64, end: u64, typ: LockType, pid: u32) {
         self.reply.ok(&fuse_lk_out {
             lk: fuse_file_lock {
                 start: start,
                 end: end,
-                typ: typ,
+                typ: raw_from_lock_type(typ),
                 pid: pid,
             },
         });
     }
-
-    /// Reply to a request with the given error code
-    pub fn error (self, err: c_int) {
-        self.reply.error(err);
-    }
 }
 
 ///
@@ -482,25 +920,140 @@ pub struct ReplyBmap {
 }
 
 impl Reply for ReplyBmap {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyBmap {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyBmap {
         ReplyBmap { reply: Reply::new(unique, sender) }
     }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
 }
 
 impl ReplyBmap {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
     /// Reply to a request with the given open result
     pub fn bmap (self, block: u64) {
         self.reply.ok(&fuse_bmap_out {
             block: block,
         });
     }
+}
+
+///
+/// Ioctl reply
+///
+#[cfg(feature = "abi-7-11")]
+pub struct ReplyIoctl {
+    reply: ReplyRaw<fuse_ioctl_out>,
+}
+
+#[cfg(feature = "abi-7-11")]
+impl Reply for ReplyIoctl {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyIoctl {
+        ReplyIoctl { reply: Reply::new(unique, sender) }
+    }
 
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+#[cfg(feature = "abi-7-11")]
+impl ReplyIoctl {
     /// Reply to a request with the given error code
     pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the given result (returned by the ioctl call) and the
+    /// data that is copied out to the caller. The data may not be larger than the
+    /// out size of the request.
+    pub fn ioctl (self, result: i32, data: &[u8]) {
+        self.reply.ok_with(&fuse_ioctl_out {
+            result: result,
+            flags: 0,
+            in_iovs: 0,
+            out_iovs: 0,
+        }, data);
+    }
+}
+
+///
+/// Poll reply
+///
+#[cfg(feature = "abi-7-11")]
+pub struct ReplyPoll {
+    reply: ReplyRaw<fuse_poll_out>,
+}
+
+#[cfg(feature = "abi-7-11")]
+impl Reply for ReplyPoll {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyPoll {
+        ReplyPoll { reply: Reply::new(unique, sender) }
+    }
+
+    fn error (self, err: c_int) {
         self.reply.error(err);
     }
 }
 
+#[cfg(feature = "abi-7-11")]
+impl ReplyPoll {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the poll events (POLLIN, POLLOUT, ...) that are ready
+    pub fn poll (self, revents: u32) {
+        self.reply.ok(&fuse_poll_out {
+            revents: revents,
+            padding: 0,
+        });
+    }
+}
+
+///
+/// Xattr reply
+///
+pub struct ReplyXattr {
+    reply: ReplyRaw<fuse_getxattr_out>,
+}
+
+impl Reply for ReplyXattr {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyXattr {
+        ReplyXattr { reply: Reply::new(unique, sender) }
+    }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+impl ReplyXattr {
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Reply to a request with the size of the xattr value or name list
+    pub fn size (self, size: u32) {
+        self.reply.ok(&fuse_getxattr_out {
+            size: size,
+            padding: 0,
+        });
+    }
+
+    /// Reply to a request with the xattr value or name list
+    pub fn data (mut self, data: &[u8]) {
+        self.reply.send(0, &[data]);
+    }
+}
+
 ///
 /// Directory reply
 ///
@@ -511,13 +1064,24 @@ pub struct ReplyDirectory {
 }
 
 impl Reply for ReplyDirectory {
-    fn new<F: FnOnce(&[&[u8]])+Send> (unique: u64, sender: F) -> ReplyDirectory {
-        ReplyDirectory { reply: Reply::new(unique, sender), size: 0, data: Vec::with_capacity(4096) }
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyDirectory {
+        ReplyDirectory { reply: Reply::new(unique, sender), size: 4096, data: Vec::with_capacity(4096) }
+    }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
     }
 }
 
 impl ReplyDirectory {
-    /// Changes the max size of the directory buffer
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        Reply::error(self, err);
+    }
+
+    /// Changes the max size of the directory buffer. The kernel tells the max size
+    /// it accepts with each readdir request, the reply passed to readdir already is
+    /// sized accordingly.
     pub fn sized (mut self, size: usize) -> ReplyDirectory {
         self.size = size;
         self.data.reserve(size);
@@ -532,7 +1096,7 @@ impl ReplyDirectory {
         let entlen = mem::size_of::<fuse_dirent>() + name.len();
         let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1);  // 64bit align
         let padlen = entsize - entlen;
-        if self.data.len() + entsize > self.data.capacity() { return true; }
+        if self.data.len() + entsize > self.size { return true; }
         unsafe {
             let p = self.data.as_mut_ptr().offset(self.data.len() as isize);
             let pdirent: *mut fuse_dirent = mem::transmute(p);
@@ -550,33 +1114,206 @@ impl ReplyDirectory {
         false
     }
 
+    /// Reply to a request with the given directory entries (inode number, file type
+    /// and name), starting at the given readdir offset. Entries are added until the
+    /// buffer is full, the offset of each entry is its index in the list plus one
+    /// (the offset the next readdir starts at). For this to work across readdir
+    /// calls, the list must stay the same for the whole directory stream, e.g. a
+    /// snapshot taken at opendir (see Filesystem::readdir).
+    ///
+    /// ```ignore
+    /// let snapshot = &self.dirs[&fh];
+    /// reply.entries(offset, snapshot.iter().map(|&(ino, kind, ref name)| (ino, kind, name)));
+    /// ```
+    pub fn entries<T: AsRef<OsStr>, I: IntoIterator<Item=(u64, FileType, T)>> (mut self, offset: u64, entries: I) {
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if self.add(ino, i as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        self.ok();
+    }
+
     /// Reply to a request with the filled directory buffer
     pub fn ok (mut self) {
         self.reply.send(0, &[&self.data]);
     }
+}
 
+///
+/// Directory plus reply (readdirplus)
+///
+#[cfg(feature = "abi-7-21")]
+pub struct ReplyDirectoryPlus {
+    reply: ReplyRaw<()>,
+    size: usize,
+    data: Vec<u8>,
+    lookups: Vec<u64>,
+}
+
+#[cfg(feature = "abi-7-21")]
+impl Reply for ReplyDirectoryPlus {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyDirectoryPlus {
+        ReplyDirectoryPlus { reply: Reply::new(unique, sender), size: 4096, data: Vec::with_capacity(4096), lookups: vec![] }
+    }
+
+    fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+#[cfg(feature = "abi-7-21")]
+impl ReplyDirectoryPlus {
     /// Reply to a request with the given error code
     pub fn error (self, err: c_int) {
-        self.reply.error(err);
+        Reply::error(self, err);
+    }
+
+    /// Changes the max size of the directory buffer (see ReplyDirectory::sized)
+    pub fn sized (mut self, size: usize) -> ReplyDirectoryPlus {
+        self.size = size;
+        self.data.reserve(size);
+        self
+    }
+
+    /// Add an entry with its attributes to the directory reply buffer. Returns true if
+    /// the buffer is full (and the entry wasn't added). Offsets work like with
+    /// ReplyDirectory::add, the TTLs like with ReplyEntry::entry_with_ttls.
+    ///
+    /// **The kernel counts every entry it gets with attributes as a lookup of the
+    /// inode**, exactly like a reply to lookup, and sends a matching forget later. The
+    /// filesystem must therefore increase the lookup count of the inode of each entry
+    /// that was added (see lookups), otherwise it forgets inodes the kernel still
+    /// uses. The library doesn't keep lookup counts, so it can't check this. The "."
+    /// and ".." entries are the exception: the kernel ignores their attributes and
+    /// doesn't count them, so they're added without attributes.
+    pub fn add<T: AsRef<OsStr>> (&mut self, ino: u64, offset: u64, name: T, attr: &FileAttr, generation: u64, entry_ttl: &Timespec, attr_ttl: &Timespec) -> bool {
+        let name = name.as_ref();
+        if name.as_bytes() == b"." || name.as_bytes() == b".." {
+            return self.add_without_attr(ino, offset, attr.kind, name);
+        }
+        let entry = fuse_entry_out_from_attr(entry_ttl, attr_ttl, attr, generation);
+        if self.push(entry, ino, offset, attr.kind, name.as_bytes()) {
+            return true;
+        }
+        self.lookups.push(ino);
+        false
+    }
+
+    /// Add an entry without attributes to the directory reply buffer, like with
+    /// ReplyDirectory::add. Returns true if the buffer is full. The kernel doesn't
+    /// count a lookup for such an entry, it looks the name up on its own when it's
+    /// used.
+    pub fn add_without_attr<T: AsRef<OsStr>> (&mut self, ino: u64, offset: u64, kind: FileType, name: T) -> bool {
+        // An entry with a node id of 0 tells the kernel that there are no attributes
+        let entry: fuse_entry_out = unsafe { mem::zeroed() };
+        self.push(entry, ino, offset, kind, name.as_ref().as_bytes())
+    }
+
+    /// Returns the inode numbers of the added entries the kernel counts a lookup for
+    /// once the reply is sent (one per entry, so an inode may appear more than once).
+    /// This is only a record for the filesystem to update its lookup counts from,
+    /// sending the reply doesn't change any counts.
+    pub fn lookups (&self) -> &[u64] {
+        &self.lookups
+    }
+
+    /// Add an entry to the buffer. Returns true if the buffer is full.
+    fn push (&mut self, entry: fuse_entry_out, ino: u64, offset: u64, kind: FileType, name: &[u8]) -> bool {
+        let entlen = mem::size_of::<fuse_direntplus>() + name.len();
+        let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1);  // 64bit align
+        let padlen = entsize - entlen;
+        if self.data.len() + entsize > self.size { return true; }
+        unsafe {
+            let p = self.data.as_mut_ptr().offset(self.data.len() as isize);
+            let pdirent: *mut fuse_direntplus = mem::transmute(p);
+            ptr::write(&mut (*pdirent).entry_out, entry);
+            (*pdirent).dirent.ino = ino;
+            (*pdirent).dirent.off = offset;
+            (*pdirent).dirent.namelen = name.len() as u32;
+            (*pdirent).dirent.typ = mode_from_kind_and_perm(kind, 0) >> 12;
+            let p = p.offset(mem::size_of_val(&*pdirent) as isize);
+            ptr::copy_nonoverlapping(name.as_ptr(), p, name.len());
+            let p = p.offset(name.len() as isize);
+            ptr::write_bytes(p, 0u8, padlen);
+            let newlen = self.data.len() + entsize;
+            self.data.set_len(newlen);
+        }
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer
+    pub fn ok (mut self) {
+        self.reply.send(0, &[&self.data]);
     }
 }
 
 
 #[cfg(test)]
 mod test {
-    use std::thread;
+    use std::{io, mem, thread};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
     use std::sync::mpsc::channel;
     use time::Timespec;
-    use super::as_bytes;
-    use super::{Reply, ReplyRaw, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
-    use super::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
+    use super::{as_bytes, errno_of};
+    use super::{TTL_FOREVER, MAX_REPLY_CHUNKS, Reply, ReplySender, ReplyRaw, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
+    use super::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyXattr, ReplyDirectory};
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
-    use {FileType, FileAttr};
+    #[cfg(feature = "abi-7-21")]
+    use super::ReplyDirectoryPlus;
+    use libc::{c_int, c_void, size_t};
+    use libc::consts::os::posix88::{EIO, ENOENT};
+    use channel::Channel;
+    use fuse::fuse_entry_out;
+    use {FileType, FileAttr, LockType, StatFs};
 
     #[allow(dead_code)]
     struct Data { a: u8, b: u8, c: u16 }
 
+    /// Reply sender that keeps all sent bytes for later inspection
+    #[derive(Clone)]
+    struct CapturingSender {
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl CapturingSender {
+        fn new () -> CapturingSender {
+            CapturingSender { sent: Arc::new(Mutex::new(vec![])) }
+        }
+
+        fn sent (&self) -> Vec<Vec<u8>> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl ReplySender for CapturingSender {
+        fn send (&self, data: &[&[u8]]) -> io::Result<()> {
+            let mut bytes = vec![];
+            for d in data { bytes.push_all(d); }
+            self.sent.lock().unwrap().push(bytes);
+            Ok(())
+        }
+    }
+
+    /// Sender that fails to send with the given error
+    struct FailingSender {
+        err: c_int,
+        calls: Arc<Mutex<usize>>,
+    }
+
+    impl ReplySender for FailingSender {
+        fn send (&self, _data: &[&[u8]]) -> io::Result<()> {
+            *self.calls.lock().unwrap() += 1;
+            Err(io::Error::from_raw_os_error(self.err))
+        }
+    }
+
+    fn fail<T: Reply> (reply: T, err: c_int) {
+        reply.error(err);
+    }
+
     #[test]
     fn serialize_empty () {
         let data = ();
@@ -612,7 +1349,7 @@ mod test {
     #[test]
     fn reply_raw () {
         let data = Data { a: 0x12, b: 0x34, c: 0x5678 };
-        let reply: ReplyRaw<Data> = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyRaw<Data> = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x12, 0x34, 0x78, 0x56],
@@ -623,7 +1360,7 @@ mod test {
 
     #[test]
     fn reply_error () {
-        let reply: ReplyRaw<Data> = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyRaw<Data> = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x10, 0x00, 0x00, 0x00, 0xbe, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
             ], bytes);
@@ -633,7 +1370,7 @@ mod test {
 
     #[test]
     fn reply_empty () {
-        let reply: ReplyEmpty = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyEmpty = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
             ], bytes);
@@ -641,9 +1378,44 @@ mod test {
         reply.ok();
     }
 
+    #[test]
+    fn reply_empty_length () {
+        let sender = CapturingSender::new();
+        let reply: ReplyEmpty = Reply::new(0xdeadbeef, sender.clone());
+        reply.ok();
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender.clone());
+        reply.data(&[]);
+        let reply: ReplyEmpty = Reply::new(0xdeadbeef, sender.clone());
+        reply.error(EIO);
+        let sent = sender.sent();
+        // Successful replies without data are just the header, with a length of exactly 16 bytes
+        let header = [0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(&sent[0][..], &header[..]);
+        assert_eq!(&sent[1][..], &header[..]);
+        assert_eq!(&sent[2][..], &[0x10, 0x00, 0x00, 0x00, 0xfb, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00][..]);
+    }
+
+    #[test]
+    fn reply_data_at () {
+        let contents = b"hello world";
+        let sender = CapturingSender::new();
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender.clone());
+        reply.data_at(contents, 6, 3);
+        // Reading past the end gets a short reply with the remaining bytes only
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender.clone());
+        reply.data_at(contents, 6, 4096);
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender.clone());
+        reply.data_at(contents, 4096, 4096);
+        let sent = sender.sent();
+        assert_eq!(&sent[0][16..], &b"wor"[..]);
+        assert_eq!(&sent[1][..4], &[0x15, 0x00, 0x00, 0x00][..]);
+        assert_eq!(&sent[1][16..], &b"world"[..]);
+        assert_eq!(sent[2].len(), 16);
+    }
+
     #[test]
     fn reply_data () {
-        let reply: ReplyData = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyData = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0xde, 0xad, 0xbe, 0xef],
@@ -652,9 +1424,93 @@ mod test {
         reply.data(&[0xde, 0xad, 0xbe, 0xef]);
     }
 
+    #[test]
+    fn reply_respond () {
+        let sender = CapturingSender::new();
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender.clone());
+        reply.respond(Ok(vec![0xde, 0xad, 0xbe, 0xef]), |reply, data| reply.data(&data));
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender.clone());
+        let err = io::Error::from_raw_os_error(ENOENT);
+        reply.respond(Err(errno_of(&err)), |reply, data: Vec<u8>| reply.data(&data));
+        assert_eq!(sender.sent(), [
+            vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00,  0xde, 0xad, 0xbe, 0xef],
+            vec![0x10, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+        ]);
+        assert_eq!(errno_of(&io::Error::new(io::ErrorKind::Other, "no errno")), EIO);
+    }
+
+    #[test]
+    fn reply_data_vectored () {
+        let reply: ReplyData = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x15, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xde, 0xad],
+                vec![0xbe, 0xef, 0x00],
+            ], bytes);
+        });
+        let chunks: [&[u8]; 2] = [&[0xde, 0xad], &[0xbe, 0xef, 0x00]];
+        reply.data_vectored(&chunks);
+    }
+
+    #[test]
+    fn reply_data_vectored_received () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let reply: ReplyData = Reply::new(0xdeadbeef, ch.sender());
+        let chunks: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let slices: Vec<&[u8]> = chunks.iter().map(|chunk| &chunk[..]).collect();
+        reply.data_vectored(&slices);
+        // The kernel (here the other end of the channel) gets a single reply with all chunks
+        let mut buffer = vec![0u8; 4096];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        let expected: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+        assert_eq!(rc as usize, 16 + expected.len());
+        assert_eq!(&buffer[..4], &[(16 + expected.len()) as u8, (16 + expected.len() >> 8) as u8, 0x00, 0x00][..]);
+        assert_eq!(&buffer[16..rc as usize], &expected[..]);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn reply_data_vectored_many_chunks () {
+        let chunk = [0x42u8];
+        let chunks: Vec<&[u8]> = (0..MAX_REPLY_CHUNKS + 1).map(|_| &chunk[..]).collect();
+        let reply: ReplyData = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!(bytes.len(), 2);
+            assert_eq!(bytes[1].len(), MAX_REPLY_CHUNKS + 1);
+        });
+        reply.data_vectored(&chunks);
+    }
+
+    #[test]
+    fn reply_data_writer () {
+        let reply: ReplyData = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x15, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xde, 0xad, 0xbe, 0xef, 0x00],
+            ], bytes);
+        });
+        let mut writer = reply.writer(5);
+        io::copy(&mut &[0xdeu8, 0xad, 0xbe][..], &mut writer).unwrap();
+        writer.write_all(&[0xef, 0x00]).unwrap();
+        // The reply was sent with the declared size, more data doesn't fit
+        assert_eq!(writer.write_all(&[0x42]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn reply_data_writer_short () {
+        let reply: ReplyData = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x10, 0x00, 0x00, 0x00, 0xfb, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ], bytes);
+        });
+        let mut writer = reply.writer(5);
+        writer.write_all(&[0xde, 0xad]).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
     #[test]
     fn reply_entry () {
-        let reply: ReplyEntry = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             if cfg!(target_os = "macos") {
                 assert_eq!([
                     vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
@@ -684,13 +1540,36 @@ mod test {
         });
         let time = Timespec::new(0x1234, 0x5678);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
-            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, blksize: 0, flags: 0x99 };
         reply.entry(&time, &attr, 0xaa);
     }
 
+    #[test]
+    fn reply_entry_from_attr () {
+        let sender = CapturingSender::new();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender.clone());
+        let mut attr = FileAttr::for_file(0x4242, 1000);
+        attr.uid = 1000;
+        attr.gid = 100;
+        reply.entry(&Timespec::new(5, 6), &attr, 7);
+        let sent = sender.sent();
+        assert_eq!(sent[0].len(), 16 + mem::size_of::<fuse_entry_out>());
+        let entry: &fuse_entry_out = unsafe { mem::transmute(sent[0][16..].as_ptr()) };
+        // The inode number of the attributes is the node id of the entry
+        assert_eq!(entry.nodeid, 0x4242);
+        assert_eq!(entry.generation, 7);
+        assert_eq!((entry.entry_valid, entry.entry_valid_nsec), (5, 6));
+        assert_eq!((entry.attr_valid, entry.attr_valid_nsec), (5, 6));
+        assert_eq!(entry.attr.ino, 0x4242);
+        assert_eq!(entry.attr.size, 1000);
+        assert_eq!(entry.attr.mode, 0o100644);
+        assert_eq!((entry.attr.uid, entry.attr.gid), (1000, 100));
+        assert_eq!(entry.attr.nlink, attr.nlink);
+    }
+
     #[test]
     fn reply_attr () {
-        let reply: ReplyAttr = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             if cfg!(target_os = "macos") {
                 assert_eq!([
                     vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
@@ -716,14 +1595,14 @@ mod test {
         });
         let time = Timespec::new(0x1234, 0x5678);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
-            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, blksize: 0, flags: 0x99 };
         reply.attr(&time, &attr);
     }
 
     #[test]
     #[cfg(target_os = "macos")]
     fn reply_xtimes () {
-        let reply: ReplyXTimes = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyXTimes = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -734,9 +1613,23 @@ mod test {
         reply.xtimes(time, time);
     }
 
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn reply_xtimes_layout () {
+        // osxfuse expects bkuptime, crtime, bkuptimensec, crtimensec
+        let reply: ReplyXTimes = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x33, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00],
+            ], bytes);
+        });
+        reply.xtimes(Timespec::new(0x11, 0x33), Timespec::new(0x22, 0x44));
+    }
+
     #[test]
     fn reply_open () {
-        let reply: ReplyOpen = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
@@ -745,9 +1638,21 @@ mod test {
         reply.opened(0x1122, 0x33);
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reply_open_passthrough () {
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x82, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00],
+            ], bytes);
+        });
+        reply.passthrough(0x1122, 0x02, 5);
+    }
+
     #[test]
     fn reply_write () {
-        let reply: ReplyWrite = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyWrite = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
@@ -758,7 +1663,7 @@ mod test {
 
     #[test]
     fn reply_statfs () {
-        let reply: ReplyStatfs = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyStatfs = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -771,9 +1676,20 @@ mod test {
         reply.statfs(0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88);
     }
 
+    #[test]
+    fn reply_statfs_stat () {
+        let sender = CapturingSender::new();
+        let reply: ReplyStatfs = Reply::new(0xdeadbeef, sender.clone());
+        reply.statfs(0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88);
+        let reply: ReplyStatfs = Reply::new(0xdeadbeef, sender.clone());
+        reply.stat(&StatFs { blocks: 0x11, bfree: 0x22, bavail: 0x33, files: 0x44, ffree: 0x55, bsize: 0x66, namelen: 0x77, frsize: 0x88 });
+        let sent = sender.sent();
+        assert_eq!(sent[0], sent[1]);
+    }
+
     #[test]
     fn reply_create () {
-        let reply: ReplyCreate = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyCreate = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             if cfg!(target_os = "macos") {
                 assert_eq!([
                     vec![0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
@@ -805,25 +1721,25 @@ mod test {
         });
         let time = Timespec::new(0x1234, 0x5678);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
-            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, blksize: 0, flags: 0x99 };
         reply.created(&time, &attr, 0xaa, 0xbb, 0xcc);
     }
 
     #[test]
     fn reply_lock () {
-        let reply: ReplyLock = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyLock = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                     0x33, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00],
+                     0x02, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00],
             ], bytes);
         });
-        reply.locked(0x11, 0x22, 0x33, 0x44);
+        reply.locked(0x11, 0x22, LockType::Unlock, 0x44);
     }
 
     #[test]
     fn reply_bmap () {
-        let reply: ReplyBmap = Reply::new(0xdeadbeef, |bytes| {
+        let reply: ReplyBmap = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
@@ -834,7 +1750,7 @@ mod test {
 
     #[test]
     fn reply_directory () {
-        let mut reply: ReplyDirectory = Reply::new(0xdeadbeef, |bytes| {
+        let mut reply: ReplyDirectory = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
             assert_eq!([
                 vec![0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                 vec![0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -848,10 +1764,93 @@ mod test {
         reply.ok();
     }
 
+    #[test]
+    fn reply_directory_sized () {
+        let mut reply: ReplyDirectory = ReplyDirectory::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!(bytes[0][0], 0x30);
+            assert_eq!(bytes[1].len(), 32);
+        }).sized(40);
+        assert!(!reply.add(0xaabb, 1, FileType::Directory, "hello"));
+        // The second entry doesn't fit into the remaining 8 bytes
+        assert!(reply.add(0xccdd, 2, FileType::RegularFile, "world.rs"));
+        reply.ok();
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-21")]
+    fn reply_directory_plus () {
+        let sender = CapturingSender::new();
+        let mut reply: ReplyDirectoryPlus = ReplyDirectoryPlus::new(0xdeadbeef, sender.clone()).sized(480);
+        let ttl = Timespec::new(1, 0);
+        assert!(!reply.add(0xaabb, 1, "hello", &FileAttr::for_file(0xaabb, 5), 0xcc, &ttl, &ttl));
+        assert!(!reply.add_without_attr(0xccdd, 2, FileType::RegularFile, "skipped"));
+        assert!(!reply.add(0x01, 3, "..", &FileAttr::for_dir(0x01), 0, &ttl, &ttl));
+        // The buffer is full, the entry isn't added and not counted
+        assert!(reply.add(0xeeff, 4, "world", &FileAttr::for_file(0xeeff, 5), 0, &ttl, &ttl));
+        // Only the entry with attributes counts as a lookup, not the skipped one or ..
+        assert_eq!(reply.lookups(), &[0xaabb][..]);
+        reply.ok();
+        let sent = sender.sent();
+        // Each entry is a fuse_entry_out (128 bytes) and a fuse_dirent (24 bytes) with the name, 64bit aligned
+        assert_eq!(sent[0].len(), 16 + 3 * 160);
+        // Entry of hello: node id, generation, ..., dirent with ino, offset, name length, type and name
+        assert_eq!(&sent[0][16..32], &[0xbb, 0xaa, 0, 0, 0, 0, 0, 0, 0xcc, 0, 0, 0, 0, 0, 0, 0][..]);
+        assert_eq!(&sent[0][16+128..16+160], &[0xbb, 0xaa, 0, 0, 0, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0,
+            0x05, 0, 0, 0, 0x08, 0, 0, 0, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0, 0, 0][..]);
+        // The skipped entry and .. have a node id of 0, but still list their inode number
+        assert_eq!(&sent[0][176..184], &[0u8; 8][..]);
+        assert_eq!(&sent[0][176+128..176+144], &[0xdd, 0xcc, 0, 0, 0, 0, 0, 0, 0x02, 0, 0, 0, 0, 0, 0, 0][..]);
+        assert_eq!(&sent[0][336..344], &[0u8; 8][..]);
+        assert_eq!(&sent[0][336+128..336+136], &[0x01, 0, 0, 0, 0, 0, 0, 0][..]);
+    }
+
+    /// Returns the offsets of the entries in the given directory reply
+    fn dirent_offsets (bytes: &[u8]) -> Vec<u64> {
+        let mut offsets = vec![];
+        let mut pos = 16;
+        while pos < bytes.len() {
+            let off = bytes[pos+8..pos+16].iter().rev().fold(0, |off, &b| off << 8 | b as u64);
+            let namelen = bytes[pos+16] as usize;
+            offsets.push(off);
+            pos += (24 + namelen + 7) & !7;
+        }
+        offsets
+    }
+
+    #[test]
+    fn reply_directory_entries () {
+        let mut listing = vec![(1, FileType::Directory, "."), (1, FileType::Directory, ".."),
+            (2, FileType::RegularFile, "a"), (3, FileType::RegularFile, "b"), (4, FileType::RegularFile, "c")];
+        // Each entry takes 32 bytes, so only 2 of them fit
+        let sender = CapturingSender::new();
+        let reply: ReplyDirectory = ReplyDirectory::new(0xdeadbeef, sender.clone()).sized(64);
+        reply.entries(0, listing.clone());
+        let sent = sender.sent();
+        assert_eq!(dirent_offsets(&sent[0]), [1, 2]);
+        // Resume at the offset of the last entry, from a snapshot of the listing
+        // taken before it changed
+        let snapshot = listing.clone();
+        listing.remove(2);
+        listing.push((5, FileType::RegularFile, "d"));
+        let sender = CapturingSender::new();
+        let reply: ReplyDirectory = ReplyDirectory::new(0xdeadbeef, sender.clone()).sized(64);
+        reply.entries(2, snapshot.clone());
+        assert_eq!(dirent_offsets(&sender.sent()[0]), [3, 4]);
+        let sender = CapturingSender::new();
+        let reply: ReplyDirectory = ReplyDirectory::new(0xdeadbeef, sender.clone()).sized(64);
+        reply.entries(4, snapshot);
+        assert_eq!(dirent_offsets(&sender.sent()[0]), [5]);
+        // Past the end, the reply is empty
+        let sender = CapturingSender::new();
+        let reply: ReplyDirectory = ReplyDirectory::new(0xdeadbeef, sender.clone());
+        reply.entries(5, listing.iter().cloned().take(4));
+        assert_eq!(sender.sent()[0].len(), 16);
+    }
+
     #[test]
     fn async_reply () {
         let (tx, rx) = channel::<()>();
-        let reply: ReplyEmpty = Reply::new(0xdeadbeef, move |_| {
+        let reply: ReplyEmpty = Reply::new(0xdeadbeef, move |_: &[&[u8]]| {
             tx.send(()).unwrap();
         });
         thread::spawn(move || {
@@ -859,4 +1858,205 @@ mod test {
         });
         rx.recv().unwrap();
     }
+
+    #[test]
+    fn reply_custom_sender () {
+        let sender = CapturingSender::new();
+        let reply: ReplyWrite = Reply::new(0xdeadbeef, sender.clone());
+        reply.written(0x1122);
+        assert_eq!(sender.sent(), vec![
+            vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00,
+                 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ]);
+    }
+
+    #[test]
+    fn reply_send_failure () {
+        // Neither an interrupted request nor a broken channel lets the reply panic
+        for &err in [ENOENT, EIO].iter() {
+            let calls = Arc::new(Mutex::new(0));
+            let reply: ReplyEmpty = Reply::new(0xdeadbeef, FailingSender { err: err, calls: calls.clone() });
+            reply.ok();
+            assert_eq!(*calls.lock().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn reply_trait_error () {
+        let sender = CapturingSender::new();
+        fail(ReplyData::new(0xdeadbeef, sender.clone()), 2);
+        fail(ReplyDirectory::new(0xcafebabe, sender.clone()), 66);
+        assert_eq!(sender.sent(), vec![
+            vec![0x10, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            vec![0x10, 0x00, 0x00, 0x00, 0xbe, 0xff, 0xff, 0xff,  0xbe, 0xba, 0xfe, 0xca, 0x00, 0x00, 0x00, 0x00],
+        ]);
+    }
+
+    #[test]
+    fn reply_entry_negative () {
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            // A successful reply with an inode number of 0 and only the entry ttl set
+            assert_eq!(&bytes[0][4..8], &[0x00, 0x00, 0x00, 0x00][..]);
+            assert_eq!(&bytes[1][..40], &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                         0x3c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                         0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..]);
+            assert!(bytes[1][40..].iter().all(|&b| b == 0));
+        });
+        reply.negative(&Timespec::new(60, 0x4321));
+    }
+
+    #[test]
+    fn reply_attr_forever () {
+        let sender = CapturingSender::new();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, sender.clone());
+        let time = Timespec::new(0x1234, 0x5678);
+        let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, blksize: 0, flags: 0x99 };
+        reply.attr(&TTL_FOREVER, &attr);
+        let sent = sender.sent();
+        assert_eq!(sent[0][16..28], [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,  0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn attr_from_bytes () {
+        let sender = CapturingSender::new();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender.clone());
+        let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: Timespec::new(0x1234, 0x5678), mtime: Timespec::new(0x2345, 0x6789),
+            ctime: Timespec::new(0x3456, 0x789a), crtime: Timespec::new(0x4567, 0x89ab), kind: FileType::Symlink, perm: 0o4755,
+            nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, blksize: 0x1000, flags: 0x99 };
+        reply.entry(&Timespec::new(1, 0), &attr, 0xaa);
+        let decoded = FileAttr::from_bytes(&sender.sent()[0][16 + 40..]);
+        assert_eq!(decoded.ino, 0x11);
+        assert_eq!(decoded.size, 0x22);
+        assert_eq!(decoded.blocks, 0x33);
+        assert_eq!(decoded.atime, Timespec::new(0x1234, 0x5678));
+        assert_eq!(decoded.mtime, Timespec::new(0x2345, 0x6789));
+        assert_eq!(decoded.ctime, Timespec::new(0x3456, 0x789a));
+        assert_eq!(decoded.kind, FileType::Symlink);
+        assert_eq!(decoded.perm, 0o4755);
+        assert_eq!((decoded.nlink, decoded.uid, decoded.gid, decoded.rdev), (0x55, 0x66, 0x77, 0x88));
+        if cfg!(feature = "abi-7-9") {
+            assert_eq!(decoded.blksize, 0x1000);
+        }
+        if cfg!(target_os = "macos") {
+            assert_eq!(decoded.crtime, Timespec::new(0x4567, 0x89ab));
+            assert_eq!(decoded.flags, 0x99);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-9", not(target_os = "macos")))]
+    fn reply_entry_submount () {
+        let sender = CapturingSender::new();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender.clone());
+        reply.submount(&Timespec::new(1, 0), &FileAttr::for_dir(0x11), 0xaa);
+        let sent = sender.sent();
+        // The attr flags are the last field of the attributes, after blksize
+        assert_eq!(sent[0].len(), 16 + 40 + 88);
+        assert_eq!(&sent[0][16 + 40 + 84..], &[0x01u8, 0x00, 0x00, 0x00][..]);
+        // Plain entries have no attr flags
+        let sender = CapturingSender::new();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender.clone());
+        reply.entry(&Timespec::new(1, 0), &FileAttr::for_dir(0x11), 0xaa);
+        assert_eq!(&sender.sent()[0][16 + 40 + 84..], &[0x00u8, 0x00, 0x00, 0x00][..]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-9")]
+    fn reply_attr_blksize () {
+        let sender = CapturingSender::new();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, sender.clone());
+        let mut attr = FileAttr::for_file(0x11, 0x22);
+        attr.blksize = 0x10000;
+        reply.attr(&Timespec::new(1, 0), &attr);
+        let sent = sender.sent();
+        // The padding after the valid time and after blksize (the last field but the
+        // attr flags, which plain attributes don't have) is zeroed
+        let len = sent[0].len();
+        assert_eq!(&sent[0][16 + 12..16 + 16], &[0x00u8, 0x00, 0x00, 0x00][..]);
+        assert_eq!(&sent[0][len - 8..], &[0x00u8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00][..]);
+        // The constructors use the default block size
+        let sender = CapturingSender::new();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, sender.clone());
+        reply.attr(&Timespec::new(1, 0), &FileAttr::for_dir(0x11));
+        let sent = sender.sent();
+        let len = sent[0].len();
+        assert_eq!(&sent[0][len - 8..len - 4], &[0x00u8, 0x10, 0x00, 0x00][..]);
+    }
+
+    #[test]
+    fn reply_entry_with_ttls () {
+        let sender = CapturingSender::new();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender.clone());
+        let attr = FileAttr::for_file(0x11, 0x22);
+        reply.entry_with_ttls(&Timespec::new(0x1234, 0x5678), &Timespec::new(0x12, 0x34), &attr, 0xaa);
+        let sent = sender.sent();
+        // entry_valid, attr_valid, entry_valid_nsec and attr_valid_nsec follow nodeid and generation
+        assert_eq!(sent[0][32..56], [0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                     0x78, 0x56, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn reply_attr_flags () {
+        // SF_IMMUTABLE and UF_HIDDEN of chflags(2)
+        const FLAGS: u32 = 0x00020000 | 0x00008000;
+        let sender = CapturingSender::new();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, sender.clone());
+        let mut attr = FileAttr::for_file(0x11, 0x22);
+        attr.flags = FLAGS;
+        reply.attr(&TTL_FOREVER, &attr);
+        let decoded = FileAttr::from_bytes(&sender.sent()[0][16 + 16..]);
+        if cfg!(target_os = "macos") {
+            assert_eq!(decoded.flags, FLAGS);
+        } else {
+            // Not part of the attributes on other systems
+            assert_eq!(decoded.flags, 0);
+        }
+    }
+
+    #[test]
+    fn reply_attr_sparse_blocks () {
+        let sender = CapturingSender::new();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, sender.clone());
+        // A 1 MiB file with only its first 4 KiB allocated
+        let mut attr = FileAttr::for_file(0x11, 1 << 20);
+        assert_eq!(attr.blocks, 2048);
+        attr.blocks = FileAttr::blocks_for(4096);
+        reply.attr(&TTL_FOREVER, &attr);
+        let sent = sender.sent();
+        assert_eq!(sent[0][40..56], [0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00,  0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn attr_constructors () {
+        let dir = FileAttr::for_dir(1);
+        assert_eq!((dir.ino, dir.kind, dir.perm, dir.nlink, dir.size), (1, FileType::Directory, 0o755, 2, 0));
+        let file = FileAttr::for_file(2, 1000);
+        assert_eq!((file.ino, file.kind, file.perm, file.nlink, file.size, file.blocks), (2, FileType::RegularFile, 0o644, 1, 1000, 2));
+        assert_eq!(file.mtime, file.ctime);
+        let link = FileAttr::for_symlink(3, 7);
+        assert_eq!((link.ino, link.kind, link.perm, link.nlink, link.size), (3, FileType::Symlink, 0o777, 1, 7));
+    }
+
+    #[test]
+    fn reply_xattr_size () {
+        let reply: ReplyXattr = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00],
+            ], bytes);
+        });
+        reply.size(0x12345678);
+    }
+
+    #[test]
+    fn reply_xattr_data () {
+        let reply: ReplyXattr = Reply::new(0xdeadbeef, |bytes: &[&[u8]]| {
+            assert_eq!([
+                vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x61, 0x2e, 0x62, 0x00],
+            ], bytes);
+        });
+        reply.data(b"a.b\0");
+    }
 }
@@ -3,30 +3,60 @@
 //! kernel driver wants us to perform.
 //!
 
-use std::mem;
-use libc::consts::os::posix88::{EIO, ENOSYS, EPROTO};
-use time::Timespec;
+use std::{cmp, fmt, io, mem};
+use std::cell::Cell;
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+use std::ffi::OsStr;
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+use std::os::unix::ffi::OsStrExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libc::{c_int, O_RDWR, O_TRUNC, O_WRONLY, S_IFMT, S_IFCHR, S_IFBLK};
+use libc::consts::os::posix88::{EINVAL, EIO, ENOSYS, EPROTO, EROFS};
+use time::{self, Duration, Timespec};
 use argument::ArgumentIterator;
 use channel::ChannelSender;
-use Filesystem;
+use {Filesystem, ConnectionInfo};
+use connection::{set_negotiation, Capabilities, Negotiation, UNSUPPORTED_FLAGS};
+#[cfg(feature = "abi-7-16")]
+use Forget;
+#[cfg(not(target_os = "macos"))]
+use Mapping;
+#[cfg(target_os = "linux")]
+use Cgroup;
 use fuse::*;
 use fuse::consts::*;
 use fuse::fuse_opcode::*;
-use reply::{Reply, ReplyRaw, ReplyEmpty, ReplyDirectory};
+use reply::{Reply, ReplySender, ReplyRaw, ReplyEmpty, ReplyWrite, ReplyLock, ReplyDirectory, lock_type_from_raw};
+#[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+use reply::ReplyIoctl;
+#[cfg(feature = "abi-7-21")]
+use reply::ReplyDirectoryPlus;
 use session::{MAX_WRITE_SIZE, Session};
+#[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+use cuse::{CuseDevice, CuseSession};
 
 /// We generally support async reads, lookups of . and .. and writes larger than 4k
-#[cfg(not(target_os = "macos"))]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES;
+#[cfg(all(not(target_os = "macos"), not(feature = "abi-7-36")))]
+const INIT_FLAGS: u64 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES;
+
+/// With ABI 7.36, the init reply carries the high 32 bits of the flags, so we
+/// additionally support the extended init of newer kernels
+#[cfg(all(not(target_os = "macos"), feature = "abi-7-36"))]
+const INIT_FLAGS: u64 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_INIT_EXT;
 
 /// On OS X, we additionally support case insensitiveness, volume renames and xtimes
 /// TODO: we should eventually let the filesystem implementation decide which flags to set
 #[cfg(target_os = "macos")]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
+const INIT_FLAGS: u64 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
 
-/// Create a new request from the given buffer
-pub fn request<'a> (ch: ChannelSender, buffer: &'a [u8]) -> Option<Request<'a>> {
-    Request::new(ch, buffer)
+/// Create a new request from the given buffer. If a timeout is given, the
+/// request gets a deadline that lies the given duration in the future. The
+/// request counts as in flight in the given requests until it is replied. Log
+/// messages about the request and its reply go to the given target.
+pub fn request<'a> (ch: ChannelSender, buffer: &'a [u8], timeout: Option<Duration>, inflight: &Arc<Inflight>, log_target: &str) -> Option<Request<'a>> {
+    Request::new(ch, buffer, timeout, inflight.clone(), log_target)
 }
 
 /// Dispatch request to the given filesystem
@@ -34,6 +64,282 @@ pub fn dispatch<FS: Filesystem> (req: &Request, se: &mut Session<FS>) {
     req.dispatch(se);
 }
 
+/// Dispatch request to the given CUSE device
+#[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+pub fn dispatch_cuse<D: CuseDevice> (req: &Request, se: &mut CuseSession<D>) {
+    req.dispatch_cuse(se);
+}
+
+/// Destroy the filesystem of the given session if it was initialized but not destroyed
+/// yet (e.g. because the connection to the kernel was lost or aborted). Since there's no
+/// DESTROY request from the kernel, the filesystem gets a request with an empty header.
+pub fn destroy<FS: Filesystem> (ch: ChannelSender, se: &mut Session<FS>) {
+    if !se.initialized || se.destroyed { return; }
+    debug!(target: &se.log_target, "DESTROY after the connection ended");
+    let log_target = se.log_target.clone();
+    with_destroy_request(ch, &log_target, |req| se.filesystem.destroy(req));
+    se.destroyed = true;
+}
+
+/// Destroy the device of the given CUSE session if it was initialized but not destroyed
+/// yet. The kernel doesn't send DESTROY requests to CUSE devices, so this is the usual
+/// way a device is destroyed.
+#[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+pub fn destroy_cuse<D: CuseDevice> (ch: ChannelSender, se: &mut CuseSession<D>) {
+    if !se.initialized || se.destroyed { return; }
+    debug!(target: &se.log_target, "DESTROY after the connection ended");
+    let log_target = se.log_target.clone();
+    with_destroy_request(ch, &log_target, |req| se.device.destroy(req));
+    se.destroyed = true;
+}
+
+/// Call the given function with a destroy request that has an empty header
+fn with_destroy_request<F: FnOnce(&Request)> (ch: ChannelSender, log_target: &str, f: F) {
+    let header = fuse_in_header {
+        len: mem::size_of::<fuse_in_header>() as u32,
+        opcode: FUSE_DESTROY as u32,
+        unique: 0,
+        nodeid: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        padding: 0,
+    };
+    let req = Request {
+        ch: ch,
+        header: &header,
+        data: &[],
+        deadline: None,
+        inflight: Arc::new(Inflight::new()),
+        log_target: log_target.to_string(),
+        #[cfg(feature = "tracing")]
+        span: trace_span!("fuse_request", opcode = header.opcode, unique = header.unique, nodeid = header.nodeid, error = tracing::field::Empty),
+    };
+    f(&req);
+}
+
+/// Record the outcome of a request (the error code of the reply) in the
+/// request's tracing span
+#[cfg(feature = "tracing")]
+fn trace_reply (span: &tracing::Span, buffer: &[&[u8]]) {
+    if let Some(bytes) = buffer.first() {
+        let header: &fuse_out_header = ArgumentIterator::new(bytes).fetch();
+        span.record("error", &header.error);
+    }
+}
+
+/// Reply sender that records the outcome of a request in the request's
+/// tracing span before sending the reply
+#[cfg(feature = "tracing")]
+struct TracingSender {
+    ch: ChannelSender,
+    span: tracing::Span,
+}
+
+#[cfg(feature = "tracing")]
+impl ReplySender for TracingSender {
+    fn send (&self, data: &[&[u8]]) -> io::Result<()> {
+        trace_reply(&self.span, data);
+        ReplySender::send(&self.ch, data)
+    }
+}
+
+/// Requests of a session that were dispatched, but not replied yet (see
+/// Session::inflight), by unique id without the resend flag. If the kernel resends
+/// a request (see Notifier::resend) while it's still in flight, the filesystem is
+/// still working on it: the resent request isn't dispatched again, instead the
+/// reply to the original is sent with the unique id of the resent request, which
+/// is the one the kernel waits for.
+pub struct Inflight {
+    /// Number of requests in flight with the unique id and whether it was resent
+    requests: Mutex<HashMap<u64, (usize, bool)>>,
+}
+
+impl Inflight {
+    /// Create a new set of in-flight requests
+    pub fn new () -> Inflight {
+        Inflight { requests: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the number of requests in flight
+    pub fn len (&self) -> usize {
+        self.requests.lock().unwrap().values().fold(0, |n, &(count, _)| n + count)
+    }
+
+    /// Count the request with the given unique id as in flight
+    fn insert (&self, unique: u64) {
+        self.requests.lock().unwrap().entry(unique & !FUSE_UNIQUE_RESEND).or_insert((0, false)).0 += 1;
+    }
+
+    /// Mark the request with the given unique id as resent. Returns false if it
+    /// isn't in flight (anymore), the resent request has to be dispatched then.
+    fn resend (&self, unique: u64) -> bool {
+        match self.requests.lock().unwrap().get_mut(&(unique & !FUSE_UNIQUE_RESEND)) {
+            Some(request) => { request.1 = true; true },
+            None => false,
+        }
+    }
+
+    /// Count one request with the given unique id as replied
+    fn remove (requests: &mut HashMap<u64, (usize, bool)>, unique: u64) {
+        let unique = unique & !FUSE_UNIQUE_RESEND;
+        let replied = match requests.get_mut(&unique) {
+            Some(request) => { request.0 -= 1; request.0 == 0 },
+            None => false,
+        };
+        if replied {
+            requests.remove(&unique);
+        }
+    }
+}
+
+/// Reply sender that counts its request as in flight until the reply was sent
+/// (or the reply was dropped without sending, see Session::inflight). It logs to
+/// the target of the request's session.
+struct InflightSender<S: ReplySender> {
+    sender: S,
+    unique: u64,
+    inflight: Arc<Inflight>,
+    replied: Cell<bool>,
+    log_target: String,
+}
+
+impl<S: ReplySender> InflightSender<S> {
+    fn new (sender: S, unique: u64, inflight: Arc<Inflight>, log_target: String) -> InflightSender<S> {
+        inflight.insert(unique);
+        InflightSender { sender: sender, unique: unique, inflight: inflight, replied: Cell::new(false), log_target: log_target }
+    }
+}
+
+impl<S: ReplySender> ReplySender for InflightSender<S> {
+    fn send (&self, data: &[&[u8]]) -> io::Result<()> {
+        // The request is removed before the reply is written, so the requests aren't
+        // locked while writing (which may block). If the kernel resends the request
+        // in between, it isn't found and is dispatched again; the kernel doesn't
+        // wait for the original unique id anymore and fails this reply with ENOENT.
+        let resent = {
+            let mut requests = self.inflight.requests.lock().unwrap();
+            let resent = requests.get(&(self.unique & !FUSE_UNIQUE_RESEND)).map_or(false, |&(_, resent)| resent);
+            Inflight::remove(&mut requests, self.unique);
+            resent
+        };
+        self.replied.set(true);
+        match data.first() {
+            Some(bytes) if resent && self.unique & FUSE_UNIQUE_RESEND == 0 => {
+                let mut header = bytes.to_vec();
+                {
+                    let out: &mut fuse_out_header = unsafe { mem::transmute(header.as_mut_ptr()) };
+                    out.unique |= FUSE_UNIQUE_RESEND;
+                }
+                let mut sendbytes: Vec<&[u8]> = vec![&header[..]];
+                sendbytes.push_all(&data[1..]);
+                self.sender.send(&sendbytes)
+            },
+            _ => self.sender.send(data),
+        }
+    }
+
+    fn log_target (&self) -> &str {
+        &self.log_target
+    }
+}
+
+impl<S: ReplySender> Drop for InflightSender<S> {
+    fn drop (&mut self) {
+        if !self.replied.get() {
+            Inflight::remove(&mut self.inflight.requests.lock().unwrap(), self.unique);
+        }
+    }
+}
+
+/// Reply sender that counts the handles opened by successful replies to open,
+/// opendir and create (see Session::open_handles)
+struct OpenSender<S: ReplySender> {
+    sender: S,
+    handles: Arc<AtomicUsize>,
+}
+
+impl<S: ReplySender> ReplySender for OpenSender<S> {
+    fn send (&self, data: &[&[u8]]) -> io::Result<()> {
+        try!(self.sender.send(data));
+        if let Some(bytes) = data.first() {
+            let header: &fuse_out_header = ArgumentIterator::new(bytes).fetch();
+            if header.error == 0 {
+                self.handles.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    fn log_target (&self) -> &str {
+        self.sender.log_target()
+    }
+}
+
+/// Count a released handle (see Session::open_handles)
+fn release_handle (handles: &AtomicUsize) {
+    // Releases only come after the open was replied, so this can't underflow
+    // unless the handle was never counted. The count is only decremented if it
+    // didn't change since it was checked, so concurrent releases can't both see
+    // the last handle and underflow.
+    let mut count = handles.load(Ordering::SeqCst);
+    while count > 0 {
+        let previous = handles.compare_and_swap(count, count - 1, Ordering::SeqCst);
+        if previous == count { break; }
+        count = previous;
+    }
+}
+
+/// Returns the umask of the argument of a create or tmpfile request. Before ABI
+/// 7.12, the kernel applies the umask itself and doesn't send it.
+#[cfg(feature = "abi-7-12")]
+fn create_umask (arg: &fuse_create_in) -> u32 { arg.umask }
+#[cfg(not(feature = "abi-7-12"))]
+fn create_umask (_arg: &fuse_open_in) -> u32 { 0 }
+
+/// Security context of the process that makes a request to create an inode (see
+/// Request::security_context)
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SecurityContext<'a> {
+    /// Name of the extended attribute the context is stored in by filesystems that
+    /// support it (e.g. "security.selinux")
+    pub name: &'a OsStr,
+    /// Value of the context (e.g. the SELinux label the new inode should get),
+    /// as it should be stored in the extended attribute
+    pub value: &'a [u8],
+}
+
+/// Parses the security contexts that follow the names of a create, mkdir, mknod or
+/// symlink request: a fuse_secctx_header with the size of all contexts and their
+/// number, then for each context a fuse_secctx with the size of its value, the
+/// zero-terminated name of the context and the value (padded to 8 bytes). The kernel
+/// sends at most one context, of the security module that is active. Returns None
+/// if there's no context, or if the data is too short for the sizes it declares.
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+fn parse_security_context (data: &[u8]) -> Option<SecurityContext> {
+    if data.len() < mem::size_of::<fuse_secctx_header>() + mem::size_of::<fuse_secctx>() {
+        return None;
+    }
+    let header: &fuse_secctx_header = ArgumentIterator::new(data).fetch();
+    if header.nr_secctx == 0 || (header.size as usize) > data.len() {
+        return None;
+    }
+    let mut data = ArgumentIterator::new(&data[..header.size as usize]);
+    data.fetch::<fuse_secctx_header>();
+    let secctx: &fuse_secctx = data.fetch();
+    let rest = data.fetch_data();
+    let namelen = match rest.iter().position(|&b| b == 0) {
+        Some(namelen) => namelen,
+        None => return None,
+    };
+    let value = &rest[namelen + 1..];
+    if value.len() < secctx.size as usize {
+        return None;
+    }
+    Some(SecurityContext { name: OsStr::from_bytes(&rest[..namelen]), value: &value[..secctx.size as usize] })
+}
+
 /// Request data structure
 pub struct Request<'a> {
     /// Channel sender for sending the reply
@@ -42,27 +348,56 @@ pub struct Request<'a> {
     header: &'a fuse_in_header,
     /// Operation-specific data payload
     data: &'a [u8],
+    /// Time by which the filesystem should have replied, if any
+    deadline: Option<Timespec>,
+    /// Requests of the session that are not replied yet
+    inflight: Arc<Inflight>,
+    /// Target of log messages about the request and its reply
+    log_target: String,
+    /// Tracing span of this request, which is closed when the reply is sent
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl<'a> fmt::Debug for Request<'a> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request {{ unique: {}, opcode: {}, nodeid: {:#018x}, uid: {}, gid: {}, pid: {} }}",
+            self.header.unique, self.header.opcode, self.header.nodeid, self.header.uid, self.header.gid, self.header.pid)
+    }
 }
 
 impl<'a> Request<'a> {
     /// Create a new request from the given buffer
-    fn new (ch: ChannelSender, buffer: &'a [u8]) -> Option<Request<'a>> {
+    fn new (ch: ChannelSender, buffer: &'a [u8], timeout: Option<Duration>, inflight: Arc<Inflight>, log_target: &str) -> Option<Request<'a>> {
         // Every request always begins with a fuse_in_header struct
         // followed by arbitrary data depending on which opcode it contains
         if buffer.len() < mem::size_of::<fuse_in_header>() {
-            error!("Short read of FUSE request ({} < {})", buffer.len(), mem::size_of::<fuse_in_header>());
+            error!(target: log_target, "Short read of FUSE request ({} < {})", buffer.len(), mem::size_of::<fuse_in_header>());
             return None;
         }
         let mut data = ArgumentIterator::new(buffer);
+        let header: &fuse_in_header = data.fetch();
+        // The kernel driver makes sure that a read gets exactly one request, so a
+        // length that doesn't match means a broken request (or a buffer that is too
+        // small). Parsing it anyway would read arguments from stale bytes.
+        if buffer.len() < header.len as usize {
+            error!(target: log_target, "Short read of FUSE request ({} < {})", buffer.len(), header.len);
+            return None;
+        }
+        if buffer.len() > header.len as usize {
+            error!(target: log_target, "Invalid length of FUSE request ({} bytes, header says {})", buffer.len(), header.len);
+            return None;
+        }
         let req = Request {
             ch: ch,
-            header: data.fetch(),
+            header: header,
             data: data.fetch_data(),
+            deadline: timeout.map(|timeout| time::get_time() + timeout),
+            inflight: inflight,
+            log_target: log_target.to_string(),
+            #[cfg(feature = "tracing")]
+            span: trace_span!("fuse_request", opcode = header.opcode, unique = header.unique, nodeid = header.nodeid, error = tracing::field::Empty),
         };
-        if buffer.len() < req.header.len as usize {
-            error!("Short read of FUSE request ({} < {})", buffer.len(), req.header.len);
-            return None;
-        }
         Some(req)
     }
 
@@ -70,93 +405,264 @@ impl<'a> Request<'a> {
     /// This calls the appropriate filesystem operation method for the
     /// request and sends back the returned reply to the kernel
     fn dispatch<FS: Filesystem> (&self, se: &mut Session<FS>) {
+        #[cfg(feature = "tracing")]
+        let _entered = self.span.enter();
         let opcode = match fuse_opcode::from_u32(self.header.opcode) {
             Some(op) => op,
+            // CUSE init means the channel is a character device, which needs to be
+            // served by a CuseSession. A filesystem session can't answer it, so
+            // reject it and end the session
+            None if self.header.opcode == CUSE_INIT => {
+                error!(target: &se.log_target, "Rejecting CUSE init in a filesystem session, CUSE devices need a CuseSession");
+                se.destroyed = true;
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+                return;
+            },
             None => {
-                warn!("Ignoring unknown FUSE operation {}", self.header.opcode);
+                warn!(target: &se.log_target, "Ignoring unknown FUSE operation {}", self.header.opcode);
                 self.reply::<ReplyEmpty>().error(ENOSYS);
                 return;
             },
         };
+        if self.is_resent_inflight(&se.log_target) {
+            return;
+        }
         let mut data = ArgumentIterator::new(self.data);
         match opcode {
             // Filesystem initialization
             FUSE_INIT => {
                 let reply: ReplyRaw<fuse_init_out> = self.reply();
                 let arg: &fuse_init_in = data.fetch();
-                debug!("INIT({})   kernel: ABI {}.{}, flags {:#x}, max readahead {}", self.header.unique, arg.major, arg.minor, arg.flags, arg.max_readahead);
-                // We don't support ABI versions before 7.6
+                debug!(target: &se.log_target, "INIT({})   kernel: ABI {}.{}, flags {:#x}, max readahead {}", self.header.unique, arg.major, arg.minor, arg.flags, arg.max_readahead);
+                // We don't support ABI versions before 7.6. Reject the init without
+                // calling into the filesystem and treat the session as ended, so that
+                // no other requests are dispatched over an incompatible connection.
                 if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
-                    error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
+                    error!(target: &se.log_target, "Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
+                    se.destroyed = true;
                     reply.error(EPROTO);
                     return;
                 }
                 // Remember ABI version supported by kernel
                 se.proto_major = arg.major;
                 se.proto_minor = arg.minor;
+                // Kernels with ABI 7.36 or later may send the high 32 bits of the flags
+                // in an extended init request (FUSE_INIT_EXT)
+                #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                #[inline]
+                fn get_flags2 (arg: &fuse_init_in, data: &mut ArgumentIterator) -> u32 {
+                    if arg.minor >= 36 && arg.flags as u64 & FUSE_INIT_EXT != 0 {
+                        let ext: &fuse_init_in_ext = data.fetch();
+                        ext.flags2
+                    } else {
+                        0
+                    }
+                }
+                #[cfg(not(all(feature = "abi-7-36", not(target_os = "macos"))))]
+                #[inline]
+                fn get_flags2 (_arg: &fuse_init_in, _data: &mut ArgumentIterator) -> u32 { 0 }
+                let flags = arg.flags as u64 | (get_flags2(arg, &mut data) as u64) << 32;
+                // Fill in what the kernel is capable of and what we'd like to use
+                se.conn.proto_major = arg.major;
+                se.conn.proto_minor = arg.minor;
+                se.conn.capable = flags;
+                se.conn.want = flags & INIT_FLAGS;
+                se.conn.max_readahead = arg.max_readahead;
+                se.conn.max_write = MAX_WRITE_SIZE as u32;
                 // Call filesystem init method and give it a chance to return an error
-                let res = se.filesystem.init(self);
+                // or to adjust the connection parameters
+                let res = se.filesystem.init(self, &mut se.conn);
                 if let Err(err) = res {
                     reply.error(err);
                     return;
                 }
+                let requested = se.conn.want;
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
                 // supports only lower major versions, we replied with an error above.
+                // Only capabilities the kernel supports (and this library can use) are granted
+                se.conn.want &= flags & !UNSUPPORTED_FLAGS;
+                #[cfg(feature = "abi-7-23")]
+                fn check_time_gran (conn: &mut ConnectionInfo, log_target: &str) {
+                    if !conn.valid_time_gran() {
+                        warn!(target: log_target, "Invalid time granularity {}, using 1ns", conn.time_gran);
+                        conn.time_gran = 1;
+                    }
+                }
+                #[cfg(not(feature = "abi-7-23"))]
+                fn check_time_gran (_conn: &mut ConnectionInfo, _log_target: &str) { }
+                check_time_gran(&mut se.conn, &se.log_target);
+                #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                fn check_flags2 (conn: &mut ConnectionInfo) {
+                    // The kernel ignores the high 32 bits of the flags without FUSE_INIT_EXT
+                    if !conn.granted(FUSE_INIT_EXT) {
+                        conn.want &= 0xffff_ffff;
+                    }
+                }
+                #[cfg(not(all(feature = "abi-7-36", not(target_os = "macos"))))]
+                fn check_flags2 (_conn: &mut ConnectionInfo) { }
+                check_flags2(&mut se.conn);
+                #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                fn check_max_stack_depth (conn: &mut ConnectionInfo, log_target: &str) {
+                    // The kernel silently doesn't grant passthrough with an invalid depth
+                    if conn.granted(FUSE_PASSTHROUGH) && !conn.valid_max_stack_depth() {
+                        warn!(target: log_target, "Invalid max stack depth {}, not using passthrough", conn.max_stack_depth);
+                        conn.want &= !FUSE_PASSTHROUGH;
+                    }
+                }
+                #[cfg(not(all(feature = "abi-7-36", not(target_os = "macos"))))]
+                fn check_max_stack_depth (_conn: &mut ConnectionInfo, _log_target: &str) { }
+                check_max_stack_depth(&mut se.conn, &se.log_target);
+                // The kernel turns on default permission checking if it enforces ACLs
+                if se.conn.posix_acl() {
+                    se.conn.default_permissions = true;
+                }
                 let init = fuse_init_out {
                     major: FUSE_KERNEL_VERSION,
                     minor: FUSE_KERNEL_MINOR_VERSION,
-                    max_readahead: arg.max_readahead,       // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS,          // use features given in INIT_FLAGS and reported as capable
+                    max_readahead: cmp::min(se.conn.max_readahead, arg.max_readahead),  // readahead size as requested by the filesystem
+                    flags: se.conn.want as u32,                                         // use features wanted by the filesystem and reported as capable
+                    #[cfg(not(feature = "abi-7-13"))]
                     unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32,       // use a max write size that fits into the session's buffer
+                    #[cfg(feature = "abi-7-13")]
+                    max_background: se.conn.max_background,                             // background requests as requested by the filesystem
+                    #[cfg(feature = "abi-7-13")]
+                    congestion_threshold: se.conn.congestion_threshold,                 // congestion threshold as requested by the filesystem
+                    max_write: cmp::min(se.conn.max_write, MAX_WRITE_SIZE as u32),      // use a max write size that fits into the session's buffer
+                    #[cfg(feature = "abi-7-23")]
+                    time_gran: se.conn.time_gran,                                       // timestamp granularity as requested by the filesystem
+                    #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-36")))]
+                    reserved: [0; 9],
+                    #[cfg(feature = "abi-7-36")]
+                    max_pages: 0,
+                    #[cfg(feature = "abi-7-36")]
+                    map_alignment: 0,
+                    #[cfg(feature = "abi-7-36")]
+                    flags2: (se.conn.want >> 32) as u32,                                // only read by the kernel if FUSE_INIT_EXT is granted
+                    #[cfg(feature = "abi-7-36")]
+                    max_stack_depth: se.conn.max_stack_depth,                           // only read by the kernel if FUSE_PASSTHROUGH is granted
+                    #[cfg(feature = "abi-7-36")]
+                    unused: [0; 6],
+                };
+                debug!(target: &se.log_target, "INIT({}) response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}", self.header.unique, init.major, init.minor, init.flags, init.max_readahead, init.max_write);
+                #[cfg(feature = "abi-7-13")]
+                #[inline]
+                fn get_background (init: &fuse_init_out) -> (u16, u16) { (init.max_background, init.congestion_threshold) }
+                #[cfg(not(feature = "abi-7-13"))]
+                #[inline]
+                fn get_background (_init: &fuse_init_out) -> (u16, u16) { (0, 0) }
+                let (max_background, congestion_threshold) = get_background(&init);
+                let negotiation = Negotiation {
+                    kernel_version: (arg.major, arg.minor),
+                    library_version: (FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION),
+                    offered: Capabilities::new(flags),
+                    requested: Capabilities::new(requested),
+                    effective: se.conn.capabilities(),
+                    max_readahead: init.max_readahead,
+                    max_write: init.max_write,
+                    max_background: max_background,
+                    congestion_threshold: congestion_threshold,
                 };
-                debug!("INIT({}) response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}", self.header.unique, init.major, init.minor, init.flags, init.max_readahead, init.max_write);
+                debug!(target: &se.log_target, "INIT({}) negotiated:\n{}", self.header.unique, negotiation);
+                set_negotiation(&mut se.conn, negotiation);
                 se.initialized = true;
-                reply.ok(&init);
+                // Kernels before ABI 7.23 only accept an init reply of their size
+                let size = if se.conn.negotiated_minor() < 23 { FUSE_COMPAT_22_INIT_OUT_SIZE } else { mem::size_of::<fuse_init_out>() };
+                reply.ok_truncated(&init, size);
             },
             // Any operation is invalid before initialization
             _ if !se.initialized => {
-                warn!("Ignoring FUSE operation {} before init", self.header.opcode);
+                warn!(target: &se.log_target, "Ignoring FUSE operation {} before init", self.header.opcode);
                 self.reply::<ReplyEmpty>().error(EIO);
             },
             // Filesystem destroyed
             FUSE_DESTROY => {
-                debug!("DESTROY({})", self.header.unique);
+                debug!(target: &se.log_target, "DESTROY({})", self.header.unique);
                 se.filesystem.destroy(self);
                 se.destroyed = true;
                 self.reply::<ReplyEmpty>().ok();
             }
             // Any operation is invalid after destroy
             _ if se.destroyed => {
-                warn!("Ignoring FUSE operation {} after destroy", self.header.opcode);
+                warn!(target: &se.log_target, "Ignoring FUSE operation {} after destroy", self.header.opcode);
                 self.reply::<ReplyEmpty>().error(EIO);
             }
+            // Operations the filesystem disabled aren't passed to it
+            _ if !se.conn.enabled(opcode) => {
+                debug!(target: &se.log_target, "Rejecting disabled FUSE operation {}", self.header.opcode);
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            // Modifying operations are invalid on a read-only mount
+            FUSE_SETATTR | FUSE_MKNOD | FUSE_MKDIR | FUSE_UNLINK | FUSE_RMDIR | FUSE_SYMLINK | FUSE_RENAME |
+            FUSE_LINK | FUSE_WRITE | FUSE_SETXATTR | FUSE_REMOVEXATTR | FUSE_CREATE if se.conn.read_only => {
+                self.reject_read_only(&se.log_target);
+            }
+            #[cfg(not(target_os = "macos"))]
+            FUSE_RENAME2 | FUSE_TMPFILE if se.conn.read_only => {
+                self.reject_read_only(&se.log_target);
+            }
+            #[cfg(target_os = "macos")]
+            FUSE_SETVOLNAME | FUSE_EXCHANGE if se.conn.read_only => {
+                self.reject_read_only(&se.log_target);
+            }
 
             FUSE_INTERRUPT => {
                 let arg: &fuse_interrupt_in = data.fetch();
-                debug!("INTERRUPT({}) unique {}", self.header.unique, arg.unique);
+                debug!(target: &se.log_target, "INTERRUPT({}) unique {}", self.header.unique, arg.unique);
                 // TODO: handle FUSE_INTERRUPT
                 self.reply::<ReplyEmpty>().error(ENOSYS);
             },
 
             FUSE_LOOKUP => {
                 let name = data.fetch_path();
-                debug!("LOOKUP({}) parent {:#018x}, name {}", self.header.unique, self.header.nodeid, name.display());
+                debug!(target: &se.log_target, "LOOKUP({}) parent {:#018x}, name {}", self.header.unique, self.header.nodeid, name.display());
                 se.filesystem.lookup(self, self.header.nodeid, &name, self.reply());
             },
             FUSE_FORGET => {
                 let arg: &fuse_forget_in = data.fetch();
-                debug!("FORGET({}) ino {:#018x}, nlookup {}", self.header.unique, self.header.nodeid, arg.nlookup);
+                debug!(target: &se.log_target, "FORGET({}) ino {:#018x}, nlookup {}", self.header.unique, self.header.nodeid, arg.nlookup);
                 se.filesystem.forget(self, self.header.nodeid, arg.nlookup);    // no reply
             },
+            #[cfg(feature = "abi-7-15")]
+            FUSE_NOTIFY_REPLY => {
+                let arg: &fuse_notify_retrieve_in = data.fetch();
+                let bytes = data.fetch_data();
+                let len = cmp::min(arg.size as usize, bytes.len());
+                debug!(target: &se.log_target, "NOTIFY_REPLY({}) ino {:#018x}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.offset, len);
+                if !se.retrieves.complete(self.header.unique, arg.offset, &bytes[..len]) {      // no reply
+                    warn!(target: &se.log_target, "Ignoring reply to unknown retrieve notification {}", self.header.unique);
+                }
+            },
+            #[cfg(feature = "abi-7-16")]
+            FUSE_BATCH_FORGET => {
+                let arg: &fuse_batch_forget_in = data.fetch();
+                debug!(target: &se.log_target, "BATCH_FORGET({}) count {}", self.header.unique, arg.count);
+                let forgets: Vec<Forget> = (0..arg.count).map(|_| {
+                    let node: &fuse_forget_one = data.fetch();
+                    Forget { ino: node.nodeid, nlookup: node.nlookup }
+                }).collect();
+                se.filesystem.forget_multi(self, &forgets);     // no reply
+            },
+            #[cfg(not(feature = "abi-7-9"))]
+            FUSE_GETATTR => {
+                debug!(target: &se.log_target, "GETATTR({}) ino {:#018x}", self.header.unique, self.header.nodeid);
+                se.filesystem.getattr(self, self.header.nodeid, None, self.reply());
+            },
+            #[cfg(feature = "abi-7-9")]
             FUSE_GETATTR => {
-                debug!("GETATTR({}) ino {:#018x}", self.header.unique, self.header.nodeid);
-                se.filesystem.getattr(self, self.header.nodeid, self.reply());
+                let arg: &fuse_getattr_in = data.fetch();
+                debug!(target: &se.log_target, "GETATTR({}) ino {:#018x}, flags {:#x}, fh {}", self.header.unique, self.header.nodeid, arg.getattr_flags, arg.fh);
+                // fh is only valid with FUSE_GETATTR_FH, the kernel doesn't set it otherwise.
+                // Other flags are unknown (from newer kernels) and ignored.
+                if arg.getattr_flags & !FUSE_GETATTR_FH != 0 {
+                    debug!(target: &se.log_target, "GETATTR({}) ignoring unknown flags {:#x}", self.header.unique, arg.getattr_flags & !FUSE_GETATTR_FH);
+                }
+                let fh = match arg.getattr_flags & FUSE_GETATTR_FH { 0 => None, _ => Some(arg.fh) };
+                se.filesystem.getattr(self, self.header.nodeid, fh, self.reply());
             },
             FUSE_SETATTR => {
                 let arg: &fuse_setattr_in = data.fetch();
-                debug!("SETATTR({}) ino {:#018x}, valid {:#x}", self.header.unique, self.header.nodeid, arg.valid);
+                debug!(target: &se.log_target, "SETATTR({}) ino {:#018x}, valid {:#x}", self.header.unique, self.header.nodeid, arg.valid);
                 let mode = match arg.valid & FATTR_MODE { 0 => None, _ => Some(arg.mode) };
                 let uid = match arg.valid & FATTR_UID { 0 => None, _ => Some(arg.uid) };
                 let gid = match arg.valid & FATTR_GID { 0 => None, _ => Some(arg.gid) };
@@ -180,168 +686,307 @@ impl<'a> Request<'a> {
                 se.filesystem.setattr(self, self.header.nodeid, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, self.reply());
             },
             FUSE_READLINK => {
-                debug!("READLINK({}) ino {:#018x}", self.header.unique, self.header.nodeid);
+                debug!(target: &se.log_target, "READLINK({}) ino {:#018x}", self.header.unique, self.header.nodeid);
                 se.filesystem.readlink(self, self.header.nodeid, self.reply());
             },
             FUSE_MKNOD => {
                 let arg: &fuse_mknod_in = data.fetch();
                 let name = data.fetch_path();
-                debug!("MKNOD({}) parent {:#018x}, name {}, mode {:#05o}, rdev {}", self.header.unique, self.header.nodeid, name.display(), arg.mode, arg.rdev);
+                debug!(target: &se.log_target, "MKNOD({}) parent {:#018x}, name {}, mode {:#05o}, rdev {}", self.header.unique, self.header.nodeid, name.display(), arg.mode, arg.rdev);
+                let typ = arg.mode & S_IFMT as u32;
+                if se.conn.nodev && (typ == S_IFCHR as u32 || typ == S_IFBLK as u32) {
+                    warn!(target: &se.log_target, "Creating device node {} on a nodev mount, it can't be opened", name.display());
+                }
                 se.filesystem.mknod(self, self.header.nodeid, &name, arg.mode, arg.rdev, self.reply());
             },
             FUSE_MKDIR => {
                 let arg: &fuse_mkdir_in = data.fetch();
                 let name = data.fetch_path();
-                debug!("MKDIR({}) parent {:#018x}, name {}, mode {:#05o}", self.header.unique, self.header.nodeid, name.display(), arg.mode);
+                debug!(target: &se.log_target, "MKDIR({}) parent {:#018x}, name {}, mode {:#05o}", self.header.unique, self.header.nodeid, name.display(), arg.mode);
                 se.filesystem.mkdir(self, self.header.nodeid, &name, arg.mode, self.reply());
             },
             FUSE_UNLINK => {
                 let name = data.fetch_path();
-                debug!("UNLINK({}) parent {:#018x}, name {}", self.header.unique, self.header.nodeid, name.display());
+                debug!(target: &se.log_target, "UNLINK({}) parent {:#018x}, name {}", self.header.unique, self.header.nodeid, name.display());
                 se.filesystem.unlink(self, self.header.nodeid, &name, self.reply());
             },
             FUSE_RMDIR => {
                 let name = data.fetch_path();
-                debug!("RMDIR({}) parent {:#018x}, name {}", self.header.unique, self.header.nodeid, name.display());
+                debug!(target: &se.log_target, "RMDIR({}) parent {:#018x}, name {}", self.header.unique, self.header.nodeid, name.display());
                 se.filesystem.rmdir(self, self.header.nodeid, &name, self.reply());
             },
             FUSE_SYMLINK => {
                 let name = data.fetch_path();
                 let link = data.fetch_path();
-                debug!("SYMLINK({}) parent {:#018x}, name {}, link {}", self.header.unique, self.header.nodeid, name.display(), link.display());
+                debug!(target: &se.log_target, "SYMLINK({}) parent {:#018x}, name {}, link {}", self.header.unique, self.header.nodeid, name.display(), link.display());
                 se.filesystem.symlink(self, self.header.nodeid, &name, &link, self.reply());
             },
             FUSE_RENAME => {
                 let arg: &fuse_rename_in = data.fetch();
                 let name = data.fetch_path();
                 let newname = data.fetch_path();
-                debug!("RENAME({}) parent {:#018x}, name {}, newparent {:#018x}, newname {}", self.header.unique, self.header.nodeid, name.display(), arg.newdir, newname.display());
+                debug!(target: &se.log_target, "RENAME({}) parent {:#018x}, name {}, newparent {:#018x}, newname {}", self.header.unique, self.header.nodeid, name.display(), arg.newdir, newname.display());
                 se.filesystem.rename(self, self.header.nodeid, &name, arg.newdir, &newname, self.reply());
             },
+            #[cfg(not(target_os = "macos"))]
+            FUSE_RENAME2 => {
+                let arg: &fuse_rename2_in = data.fetch();
+                let name = data.fetch_path();
+                let newname = data.fetch_path();
+                debug!(target: &se.log_target, "RENAME2({}) parent {:#018x}, name {}, newparent {:#018x}, newname {}, flags {:#x}", self.header.unique, self.header.nodeid, name.display(), arg.newdir, newname.display(), arg.flags);
+                // Like renameat2(2), reject unknown flags and exchanging combined with others
+                let known = RENAME_NOREPLACE | RENAME_EXCHANGE | RENAME_WHITEOUT;
+                if arg.flags & !known != 0 || arg.flags & RENAME_EXCHANGE != 0 && arg.flags != RENAME_EXCHANGE {
+                    self.reply::<ReplyEmpty>().error(EINVAL);
+                    return;
+                }
+                se.filesystem.rename2(self, self.header.nodeid, &name, arg.newdir, &newname, arg.flags, self.reply());
+            },
             FUSE_LINK => {
                 let arg: &fuse_link_in = data.fetch();
                 let newname = data.fetch_path();
-                debug!("LINK({}) ino {:#018x}, newparent {:#018x}, newname {}", self.header.unique, arg.oldnodeid, self.header.nodeid, newname.display());
+                debug!(target: &se.log_target, "LINK({}) ino {:#018x}, newparent {:#018x}, newname {}", self.header.unique, arg.oldnodeid, self.header.nodeid, newname.display());
                 se.filesystem.link(self, arg.oldnodeid, self.header.nodeid, &newname, self.reply());
             },
             FUSE_OPEN => {
                 let arg: &fuse_open_in = data.fetch();
-                debug!("OPEN({}) ino {:#018x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.flags);
-                se.filesystem.open(self, self.header.nodeid, arg.flags, self.reply());
+                debug!(target: &se.log_target, "OPEN({}) ino {:#018x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.flags);
+                if se.conn.read_only && arg.flags as c_int & (O_WRONLY | O_RDWR | O_TRUNC) != 0 {
+                    self.reject_read_only(&se.log_target);
+                    return;
+                }
+                se.filesystem.open(self, self.header.nodeid, arg.flags, self.open_reply(&se.open_handles));
             },
             FUSE_READ => {
                 let arg: &fuse_read_in = data.fetch();
-                debug!("READ({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
-                se.filesystem.read(self, self.header.nodeid, arg.fh, arg.offset, arg.size, self.reply());
+                debug!(target: &se.log_target, "READ({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
+                #[cfg(feature = "abi-7-9")] #[inline]
+                fn get_flags (arg: &fuse_read_in) -> (u32, Option<u64>) {
+                    let lock_owner = match arg.read_flags & FUSE_READ_LOCKOWNER { 0 => None, _ => Some(arg.lock_owner) };
+                    (arg.flags, lock_owner)
+                }
+                #[cfg(not(feature = "abi-7-9"))] #[inline]
+                fn get_flags (_arg: &fuse_read_in) -> (u32, Option<u64>) { (0, None) }
+                let (flags, lock_owner) = get_flags(arg);
+                se.filesystem.read(self, self.header.nodeid, arg.fh, arg.offset, arg.size, flags, lock_owner, self.reply());
             },
             FUSE_WRITE => {
                 let arg: &fuse_write_in = data.fetch();
                 let data = data.fetch_data();
-                assert!(data.len() == arg.size as usize);
-                debug!("WRITE({}) ino {:#018x}, fh {}, offset {}, size {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size, arg.write_flags);
-                se.filesystem.write(self, self.header.nodeid, arg.fh, arg.offset, data, arg.write_flags, self.reply());
+                debug!(target: &se.log_target, "WRITE({}) ino {:#018x}, fh {}, offset {}, size {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size, arg.write_flags);
+                // Never pass on more data than was received
+                if data.len() < arg.size as usize {
+                    error!(target: &se.log_target, "Short write request {}, got {} of {} bytes", self.header.unique, data.len(), arg.size);
+                    self.reply::<ReplyWrite>().error(EIO);
+                    return;
+                }
+                #[cfg(feature = "abi-7-9")] #[inline]
+                fn get_flags (arg: &fuse_write_in) -> u32 { arg.flags }
+                #[cfg(not(feature = "abi-7-9"))] #[inline]
+                fn get_flags (_arg: &fuse_write_in) -> u32 { 0 }
+                se.filesystem.write(self, self.header.nodeid, arg.fh, arg.offset, &data[..arg.size as usize], arg.write_flags, get_flags(arg), self.reply());
             },
             FUSE_FLUSH => {
                 let arg: &fuse_flush_in = data.fetch();
-                debug!("FLUSH({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.lock_owner);
+                debug!(target: &se.log_target, "FLUSH({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.lock_owner);
                 se.filesystem.flush(self, self.header.nodeid, arg.fh, arg.lock_owner, self.reply());
             },
             FUSE_RELEASE => {
                 let arg: &fuse_release_in = data.fetch();
                 let flush = match arg.release_flags & FUSE_RELEASE_FLUSH { 0 => false, _ => true };
-                debug!("RELEASE({}) ino {:#018x}, fh {}, flags {:#x}, release flags {:#x}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
+                debug!(target: &se.log_target, "RELEASE({}) ino {:#018x}, fh {}, flags {:#x}, release flags {:#x}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
+                release_handle(&se.open_handles);
                 se.filesystem.release(self, self.header.nodeid, arg.fh, arg.flags, arg.lock_owner, flush, self.reply());
             },
             FUSE_FSYNC => {
                 let arg: &fuse_fsync_in = data.fetch();
-                let datasync = match arg.fsync_flags & 1 { 0 => false, _ => true };
-                debug!("FSYNC({}) ino {:#018x}, fh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.fsync_flags);
+                let datasync = match arg.fsync_flags & FUSE_FSYNC_FDATASYNC { 0 => false, _ => true };
+                debug!(target: &se.log_target, "FSYNC({}) ino {:#018x}, fh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.fsync_flags);
                 se.filesystem.fsync(self, self.header.nodeid, arg.fh, datasync, self.reply());
             },
             FUSE_OPENDIR => {
                 let arg: &fuse_open_in = data.fetch();
-                debug!("OPENDIR({}) ino {:#018x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.flags);
-                se.filesystem.opendir(self, self.header.nodeid, arg.flags, self.reply());
+                debug!(target: &se.log_target, "OPENDIR({}) ino {:#018x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.flags);
+                se.filesystem.opendir(self, self.header.nodeid, arg.flags, self.open_reply(&se.open_handles));
             },
             FUSE_READDIR => {
                 let arg: &fuse_read_in = data.fetch();
-                debug!("READDIR({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
+                debug!(target: &se.log_target, "READDIR({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
                 se.filesystem.readdir(self, self.header.nodeid, arg.fh, arg.offset, self.reply::<ReplyDirectory>().sized(arg.size as usize));
             },
+            #[cfg(feature = "abi-7-21")]
+            FUSE_READDIRPLUS => {
+                let arg: &fuse_read_in = data.fetch();
+                debug!(target: &se.log_target, "READDIRPLUS({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
+                se.filesystem.readdirplus(self, self.header.nodeid, arg.fh, arg.offset, self.reply::<ReplyDirectoryPlus>().sized(arg.size as usize));
+            },
             FUSE_RELEASEDIR => {
                 let arg: &fuse_release_in = data.fetch();
-                debug!("RELEASEDIR({}) ino {:#018x}, fh {}, flags {:#x}, release flags {:#x}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
+                debug!(target: &se.log_target, "RELEASEDIR({}) ino {:#018x}, fh {}, flags {:#x}, release flags {:#x}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
+                release_handle(&se.open_handles);
                 se.filesystem.releasedir(self, self.header.nodeid, arg.fh, arg.flags, self.reply());
             },
             FUSE_FSYNCDIR => {
                 let arg: &fuse_fsync_in = data.fetch();
-                let datasync = match arg.fsync_flags & 1 { 0 => false, _ => true };
-                debug!("FSYNCDIR({}) ino {:#018x}, fh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.fsync_flags);
+                let datasync = match arg.fsync_flags & FUSE_FSYNC_FDATASYNC { 0 => false, _ => true };
+                debug!(target: &se.log_target, "FSYNCDIR({}) ino {:#018x}, fh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.fsync_flags);
                 se.filesystem.fsyncdir(self, self.header.nodeid, arg.fh, datasync, self.reply());
             },
             FUSE_STATFS => {
-                debug!("STATFS({}) ino {:#018x}", self.header.unique, self.header.nodeid);
+                debug!(target: &se.log_target, "STATFS({}) ino {:#018x}", self.header.unique, self.header.nodeid);
                 se.filesystem.statfs(self, self.header.nodeid, self.reply());
             },
             FUSE_SETXATTR => {
-                let arg: &fuse_setxattr_in = data.fetch();
-                let name = data.fetch_str();
-                let value = data.fetch_data();
-                assert!(value.len() == arg.size as usize);
-                debug!("SETXATTR({}) ino {:#018x}, name {:?}, size {}, flags {:#x}", self.header.unique, self.header.nodeid, name, arg.size, arg.flags);
                 #[cfg(target_os = "macos")] #[inline]
                 fn get_position (arg: &fuse_setxattr_in) -> u32 { arg.position }
                 #[cfg(not(target_os = "macos"))] #[inline]
                 fn get_position (_arg: &fuse_setxattr_in) -> u32 { 0 }
-                se.filesystem.setxattr(self, self.header.nodeid, name, value, arg.flags, get_position(arg), self.reply());
+                // Returns size, flags, position and setxattr flags of the request. The
+                // argument is larger and carries setxattr flags with FUSE_SETXATTR_EXT.
+                #[cfg(not(target_os = "macos"))] #[inline]
+                fn get_arg (data: &mut ArgumentIterator, ext: bool) -> (u32, u32, u32, u32) {
+                    if ext {
+                        let arg: &fuse_setxattr_ext_in = data.fetch();
+                        (arg.size, arg.flags, 0, arg.setxattr_flags)
+                    } else {
+                        let arg: &fuse_setxattr_in = data.fetch();
+                        (arg.size, arg.flags, get_position(arg), 0)
+                    }
+                }
+                #[cfg(target_os = "macos")] #[inline]
+                fn get_arg (data: &mut ArgumentIterator, _ext: bool) -> (u32, u32, u32, u32) {
+                    let arg: &fuse_setxattr_in = data.fetch();
+                    (arg.size, arg.flags, get_position(arg), 0)
+                }
+                let (size, flags, position, setxattr_flags) = get_arg(&mut data, se.conn.setxattr_ext());
+                let name = data.fetch_str();
+                let value = data.fetch_data();
+                debug!(target: &se.log_target, "SETXATTR({}) ino {:#018x}, name {:?}, size {}, flags {:#x}, setxattr flags {:#x}", self.header.unique, self.header.nodeid, name, size, flags, setxattr_flags);
+                // Never pass on a value of another size than the kernel said
+                if value.len() != size as usize {
+                    error!(target: &se.log_target, "Malformed setxattr request {}, got {} of {} bytes", self.header.unique, value.len(), size);
+                    self.reply::<ReplyEmpty>().error(EIO);
+                    return;
+                }
+                se.filesystem.setxattr(self, self.header.nodeid, name, value, flags, position, setxattr_flags, self.reply());
             },
             FUSE_GETXATTR => {
                 let arg: &fuse_getxattr_in = data.fetch();
                 let name = data.fetch_str();
-                debug!("GETXATTR({}) ino {:#018x}, name {:?}, size {}", self.header.unique, self.header.nodeid, name, arg.size);
-                se.filesystem.getxattr(self, self.header.nodeid, name, self.reply());
+                debug!(target: &se.log_target, "GETXATTR({}) ino {:#018x}, name {:?}, size {}", self.header.unique, self.header.nodeid, name, arg.size);
+                se.filesystem.getxattr(self, self.header.nodeid, name, arg.size, self.reply());
             },
             FUSE_LISTXATTR => {
                 let arg: &fuse_getxattr_in = data.fetch();
-                debug!("LISTXATTR({}) ino {:#018x}, size {}", self.header.unique, self.header.nodeid, arg.size);
-                se.filesystem.listxattr(self, self.header.nodeid, self.reply());
+                debug!(target: &se.log_target, "LISTXATTR({}) ino {:#018x}, size {}", self.header.unique, self.header.nodeid, arg.size);
+                se.filesystem.listxattr(self, self.header.nodeid, arg.size, self.reply());
             },
             FUSE_REMOVEXATTR => {
                 let name = data.fetch_str();
-                debug!("REMOVEXATTR({}) ino {:#018x}, name {:?}", self.header.unique, self.header.nodeid, name);
+                debug!(target: &se.log_target, "REMOVEXATTR({}) ino {:#018x}, name {:?}", self.header.unique, self.header.nodeid, name);
                 se.filesystem.removexattr(self, self.header.nodeid, name, self.reply());
             },
             FUSE_ACCESS => {
                 let arg: &fuse_access_in = data.fetch();
-                debug!("ACCESS({}) ino {:#018x}, mask {:#05o}", self.header.unique, self.header.nodeid, arg.mask);
+                debug!(target: &se.log_target, "ACCESS({}) ino {:#018x}, mask {:#05o}", self.header.unique, self.header.nodeid, arg.mask);
                 se.filesystem.access(self, self.header.nodeid, arg.mask, self.reply());
             },
             FUSE_CREATE => {
+                #[cfg(not(feature = "abi-7-12"))]
                 let arg: &fuse_open_in = data.fetch();
+                #[cfg(feature = "abi-7-12")]
+                let arg: &fuse_create_in = data.fetch();
                 let name = data.fetch_path();
-                debug!("CREATE({}) parent {:#018x}, name {}, mode {:#05o}, flags {:#x}", self.header.unique, self.header.nodeid, name.display(), arg.mode, arg.flags);
-                se.filesystem.create(self, self.header.nodeid, &name, arg.mode, arg.flags, self.reply());
+                debug!(target: &se.log_target, "CREATE({}) parent {:#018x}, name {}, mode {:#05o}, flags {:#x}", self.header.unique, self.header.nodeid, name.display(), arg.mode, arg.flags);
+                se.filesystem.create(self, self.header.nodeid, &name, arg.mode, create_umask(arg), arg.flags, self.open_reply(&se.open_handles));
+            },
+            #[cfg(not(target_os = "macos"))]
+            FUSE_TMPFILE => {
+                // Same argument as create, but without a name
+                #[cfg(not(feature = "abi-7-12"))]
+                let arg: &fuse_open_in = data.fetch();
+                #[cfg(feature = "abi-7-12")]
+                let arg: &fuse_create_in = data.fetch();
+                debug!(target: &se.log_target, "TMPFILE({}) parent {:#018x}, mode {:#05o}, flags {:#x}", self.header.unique, self.header.nodeid, arg.mode, arg.flags);
+                se.filesystem.tmpfile(self, self.header.nodeid, arg.mode, create_umask(arg), arg.flags, self.open_reply(&se.open_handles));
             },
             FUSE_GETLK => {
                 let arg: &fuse_lk_in = data.fetch();
-                debug!("GETLK({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.owner);
-                se.filesystem.getlk(self, self.header.nodeid, arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, self.reply());
+                debug!(target: &se.log_target, "GETLK({}) ino {:#018x}, fh {}, lock owner {}, type {}", self.header.unique, self.header.nodeid, arg.fh, arg.owner, arg.lk.typ);
+                match lock_type_from_raw(arg.lk.typ) {
+                    Some(typ) => se.filesystem.getlk(self, self.header.nodeid, arg.fh, arg.owner, arg.lk.start, arg.lk.end, typ, arg.lk.pid, self.reply()),
+                    None => self.reply::<ReplyLock>().error(EINVAL),
+                }
             },
             FUSE_SETLK | FUSE_SETLKW => {
                 let arg: &fuse_lk_in = data.fetch();
                 let sleep = match opcode { FUSE_SETLKW => true, _ => false };
-                debug!("SETLK({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.owner);
-                se.filesystem.setlk(self, self.header.nodeid, arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, sleep, self.reply());
+                debug!(target: &se.log_target, "SETLK({}) ino {:#018x}, fh {}, lock owner {}, type {}", self.header.unique, self.header.nodeid, arg.fh, arg.owner, arg.lk.typ);
+                match lock_type_from_raw(arg.lk.typ) {
+                    Some(typ) => se.filesystem.setlk(self, self.header.nodeid, arg.fh, arg.owner, arg.lk.start, arg.lk.end, typ, arg.lk.pid, sleep, self.reply()),
+                    None => self.reply::<ReplyEmpty>().error(EINVAL),
+                }
             },
             FUSE_BMAP => {
                 let arg: &fuse_bmap_in = data.fetch();
-                debug!("BMAP({}) ino {:#018x}, blocksize {}, ids {}", self.header.unique, self.header.nodeid, arg.blocksize, arg.block);
+                debug!(target: &se.log_target, "BMAP({}) ino {:#018x}, blocksize {}, ids {}", self.header.unique, self.header.nodeid, arg.blocksize, arg.block);
                 se.filesystem.bmap(self, self.header.nodeid, arg.blocksize, arg.block, self.reply());
             },
+            #[cfg(feature = "abi-7-11")]
+            FUSE_POLL => {
+                let arg: &fuse_poll_in = data.fetch();
+                #[cfg(feature = "abi-7-21")] #[inline]
+                fn get_events (arg: &fuse_poll_in) -> u32 { arg.events }
+                #[cfg(not(feature = "abi-7-21"))] #[inline]
+                fn get_events (_arg: &fuse_poll_in) -> u32 { 0 }
+                debug!(target: &se.log_target, "POLL({}) ino {:#018x}, fh {}, kh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.kh, arg.flags);
+                se.filesystem.poll(self, self.header.nodeid, arg.fh, arg.kh, arg.flags, get_events(arg), self.reply());
+            },
+            // Only CUSE devices support ioctl so far
+            #[cfg(feature = "abi-7-11")]
+            FUSE_IOCTL => {
+                debug!(target: &se.log_target, "Ignoring FUSE operation {} on a filesystem", self.header.opcode);
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            },
+            #[cfg(not(target_os = "macos"))]
+            FUSE_SETUPMAPPING => {
+                let arg: &fuse_setupmapping_in = data.fetch();
+                debug!(target: &se.log_target, "SETUPMAPPING({}) ino {:#018x}, fh {}, foffset {}, len {}, flags {:#x}, moffset {}", self.header.unique, self.header.nodeid, arg.fh, arg.foffset, arg.len, arg.flags, arg.moffset);
+                if !se.conn.dax_mappings() {
+                    self.reply::<ReplyEmpty>().error(ENOSYS);
+                    return;
+                }
+                se.filesystem.setupmapping(self, self.header.nodeid, arg.fh, arg.foffset, arg.len, arg.flags, arg.moffset, self.reply());
+            },
+            #[cfg(not(target_os = "macos"))]
+            FUSE_REMOVEMAPPING => {
+                let arg: &fuse_removemapping_in = data.fetch();
+                debug!(target: &se.log_target, "REMOVEMAPPING({}) ino {:#018x}, count {}", self.header.unique, self.header.nodeid, arg.count);
+                if !se.conn.dax_mappings() {
+                    self.reply::<ReplyEmpty>().error(ENOSYS);
+                    return;
+                }
+                let mappings: Vec<Mapping> = (0..arg.count).map(|_| {
+                    let mapping: &fuse_removemapping_one = data.fetch();
+                    Mapping { moffset: mapping.moffset, len: mapping.len }
+                }).collect();
+                se.filesystem.removemapping(self, self.header.nodeid, &mappings, self.reply());
+            },
+            #[cfg(not(target_os = "macos"))]
+            FUSE_SYNCFS => {
+                let _arg: &fuse_syncfs_in = data.fetch();
+                debug!(target: &se.log_target, "SYNCFS({})", self.header.unique);
+                if !se.conn.syncfs() {
+                    // Older kernels don't have the operation
+                    self.reply::<ReplyEmpty>().error(ENOSYS);
+                    return;
+                }
+                se.filesystem.syncfs(self, self.reply());
+            },
             #[cfg(target_os = "macos")]
             FUSE_SETVOLNAME => {                        // OS X only
                 let name = data.fetch_str();
-                debug!("SETVOLNAME({}) name {:?}", self.header.unique, name);
+                debug!(target: &se.log_target, "SETVOLNAME({}) name {:?}", self.header.unique, name);
                 se.filesystem.setvolname(self, name, self.reply());
             },
             #[cfg(target_os = "macos")]
@@ -349,27 +994,193 @@ impl<'a> Request<'a> {
                 let arg: &fuse_exchange_in = data.fetch();
                 let oldname = data.fetch_path();
                 let newname = data.fetch_path();
-                debug!("EXCHANGE({}) parent {:#018x}, name {}, newparent {:#018x}, newname {}, options {:#x}", self.header.unique, arg.olddir, oldname.display(), arg.newdir, newname.display(), arg.options);
+                debug!(target: &se.log_target, "EXCHANGE({}) parent {:#018x}, name {}, newparent {:#018x}, newname {}, options {:#x}", self.header.unique, arg.olddir, oldname.display(), arg.newdir, newname.display(), arg.options);
                 se.filesystem.exchange(self, arg.olddir, &oldname, arg.newdir, &newname, arg.options, self.reply());
             },
             #[cfg(target_os = "macos")]
             FUSE_GETXTIMES => {                         // OS X only
-                debug!("GETXTIMES({}) ino {:#018x}", self.header.unique, self.header.nodeid);
+                debug!(target: &se.log_target, "GETXTIMES({}) ino {:#018x}", self.header.unique, self.header.nodeid);
                 se.filesystem.getxtimes(self, self.header.nodeid, self.reply());
             },
         }
     }
 
+    /// Dispatch request to the given CUSE device. CUSE devices have their own
+    /// init handshake and only get the operations of an open character device.
+    #[cfg(all(feature = "abi-7-11", not(target_os = "macos")))]
+    fn dispatch_cuse<D: CuseDevice> (&self, se: &mut CuseSession<D>) {
+        #[cfg(feature = "tracing")]
+        let _entered = self.span.enter();
+        if self.is_resent_inflight(&se.log_target) {
+            return;
+        }
+        let mut data = ArgumentIterator::new(self.data);
+        // Device initialization
+        if self.header.opcode == CUSE_INIT {
+            let reply: ReplyRaw<cuse_init_out> = self.reply();
+            let arg: &cuse_init_in = data.fetch();
+            debug!(target: &se.log_target, "CUSE_INIT({})   kernel: ABI {}.{}, flags {:#x}", self.header.unique, arg.major, arg.minor, arg.flags);
+            // CUSE needs ABI 7.11 or later. Like with filesystems, reject the init
+            // and treat the session as ended otherwise.
+            if arg.major != FUSE_KERNEL_VERSION || arg.minor < 11 {
+                error!(target: &se.log_target, "Unsupported CUSE ABI version {}.{}", arg.major, arg.minor);
+                se.destroyed = true;
+                reply.error(EPROTO);
+                return;
+            }
+            if let Err(err) = se.device.init(self) {
+                reply.error(err);
+                return;
+            }
+            // Only restricted ioctls are supported, so no CUSE_UNRESTRICTED_IOCTL
+            let init = cuse_init_out {
+                major: FUSE_KERNEL_VERSION,
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                unused: 0,
+                flags: 0,
+                max_read: MAX_WRITE_SIZE as u32,
+                max_write: MAX_WRITE_SIZE as u32,
+                dev_major: se.dev_major,
+                dev_minor: se.dev_minor,
+                spare: [0; 10],
+            };
+            // The device info is a list of KEY=value strings, each terminated by a NUL
+            let info = format!("DEVNAME={}\0", se.name);
+            debug!(target: &se.log_target, "CUSE_INIT({}) response: ABI {}.{}, device {} ({}:{})", self.header.unique, init.major, init.minor, se.name, init.dev_major, init.dev_minor);
+            se.initialized = true;
+            reply.ok_with(&init, info.as_bytes());
+            return;
+        }
+        // Any operation is invalid before initialization
+        if !se.initialized {
+            warn!(target: &se.log_target, "Ignoring FUSE operation {} before CUSE init", self.header.opcode);
+            self.reply::<ReplyEmpty>().error(EIO);
+            return;
+        }
+        // Any operation is invalid after destroy
+        if se.destroyed {
+            warn!(target: &se.log_target, "Ignoring FUSE operation {} after destroy", self.header.opcode);
+            self.reply::<ReplyEmpty>().error(EIO);
+            return;
+        }
+        match fuse_opcode::from_u32(self.header.opcode) {
+            Some(FUSE_OPEN) => {
+                let arg: &fuse_open_in = data.fetch();
+                debug!(target: &se.log_target, "OPEN({}) flags {:#x}", self.header.unique, arg.flags);
+                se.device.open(self, arg.flags, self.reply());
+            },
+            Some(FUSE_READ) => {
+                let arg: &fuse_read_in = data.fetch();
+                debug!(target: &se.log_target, "READ({}) fh {}, offset {}, size {}", self.header.unique, arg.fh, arg.offset, arg.size);
+                se.device.read(self, arg.fh, arg.offset, arg.size, self.reply());
+            },
+            Some(FUSE_WRITE) => {
+                let arg: &fuse_write_in = data.fetch();
+                let data = data.fetch_data();
+                debug!(target: &se.log_target, "WRITE({}) fh {}, offset {}, size {}, flags {:#x}", self.header.unique, arg.fh, arg.offset, arg.size, arg.write_flags);
+                // Never pass on more data than was received
+                if data.len() < arg.size as usize {
+                    error!(target: &se.log_target, "Short write request {}, got {} of {} bytes", self.header.unique, data.len(), arg.size);
+                    self.reply::<ReplyWrite>().error(EIO);
+                    return;
+                }
+                se.device.write(self, arg.fh, arg.offset, &data[..arg.size as usize], arg.write_flags, self.reply());
+            },
+            Some(FUSE_FLUSH) => {
+                let arg: &fuse_flush_in = data.fetch();
+                debug!(target: &se.log_target, "FLUSH({}) fh {}, lock owner {}", self.header.unique, arg.fh, arg.lock_owner);
+                se.device.flush(self, arg.fh, arg.lock_owner, self.reply());
+            },
+            Some(FUSE_RELEASE) => {
+                let arg: &fuse_release_in = data.fetch();
+                debug!(target: &se.log_target, "RELEASE({}) fh {}, flags {:#x}", self.header.unique, arg.fh, arg.flags);
+                se.device.release(self, arg.fh, arg.flags, self.reply());
+            },
+            Some(FUSE_FSYNC) => {
+                let arg: &fuse_fsync_in = data.fetch();
+                let datasync = match arg.fsync_flags & FUSE_FSYNC_FDATASYNC { 0 => false, _ => true };
+                debug!(target: &se.log_target, "FSYNC({}) fh {}, flags {:#x}", self.header.unique, arg.fh, arg.fsync_flags);
+                se.device.fsync(self, arg.fh, datasync, self.reply());
+            },
+            Some(FUSE_IOCTL) => {
+                let arg: &fuse_ioctl_in = data.fetch();
+                let data = data.fetch_data();
+                debug!(target: &se.log_target, "IOCTL({}) fh {}, flags {:#x}, cmd {:#x}, arg {:#x}, in size {}, out size {}", self.header.unique, arg.fh, arg.flags, arg.cmd, arg.arg, arg.in_size, arg.out_size);
+                if data.len() < arg.in_size as usize {
+                    error!(target: &se.log_target, "Short ioctl request {}, got {} of {} bytes", self.header.unique, data.len(), arg.in_size);
+                    self.reply::<ReplyIoctl>().error(EIO);
+                    return;
+                }
+                se.device.ioctl(self, arg.fh, arg.flags, arg.cmd, arg.arg, &data[..arg.in_size as usize], arg.out_size, self.reply());
+            },
+            Some(FUSE_POLL) => {
+                let arg: &fuse_poll_in = data.fetch();
+                #[cfg(feature = "abi-7-21")] #[inline]
+                fn get_events (arg: &fuse_poll_in) -> u32 { arg.events }
+                #[cfg(not(feature = "abi-7-21"))] #[inline]
+                fn get_events (_arg: &fuse_poll_in) -> u32 { 0 }
+                debug!(target: &se.log_target, "POLL({}) fh {}, kh {}, flags {:#x}", self.header.unique, arg.fh, arg.kh, arg.flags);
+                se.device.poll(self, arg.fh, arg.kh, arg.flags, get_events(arg), self.reply());
+            },
+            Some(FUSE_INTERRUPT) => {
+                debug!(target: &se.log_target, "INTERRUPT({})", self.header.unique);
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            },
+            Some(FUSE_DESTROY) => {
+                debug!(target: &se.log_target, "DESTROY({})", self.header.unique);
+                se.device.destroy(self);
+                se.destroyed = true;
+                self.reply::<ReplyEmpty>().ok();
+            },
+            _ => {
+                warn!(target: &se.log_target, "Ignoring FUSE operation {} on a CUSE device", self.header.opcode);
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            },
+        }
+    }
+
+    /// Requests resent by the kernel (see Notifier::resend) are dispatched like any
+    /// other request, unless the original request is still in flight. The reply to
+    /// the original is sent for the resent request then (see Inflight), and this
+    /// returns true. The reply uses the same unique id (including the resend flag)
+    /// so the kernel can match it to the original request.
+    fn is_resent_inflight (&self, log_target: &str) -> bool {
+        if self.header.unique & FUSE_UNIQUE_RESEND == 0 {
+            return false;
+        }
+        let inflight = self.inflight.resend(self.header.unique);
+        debug!(target: log_target, "Request {} was resent by the kernel{}", self.header.unique & !FUSE_UNIQUE_RESEND, if inflight { ", still in flight" } else { "" });
+        inflight
+    }
+
+    /// Returns the sender for the reply to this request
+    #[cfg(not(feature = "tracing"))]
+    fn sender (&self) -> InflightSender<ChannelSender> {
+        InflightSender::new(self.ch, self.header.unique, self.inflight.clone(), self.log_target.clone())
+    }
+
+    /// Returns the sender for the reply to this request
+    #[cfg(feature = "tracing")]
+    fn sender (&self) -> InflightSender<TracingSender> {
+        InflightSender::new(TracingSender { ch: self.ch, span: self.span.clone() }, self.header.unique, self.inflight.clone(), self.log_target.clone())
+    }
+
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply> (&self) -> T {
-        let ch = self.ch;
-        Reply::new(self.header.unique, move |buffer| {
-            match ch.send(buffer) {
-                Ok(()) => (),
-                Err(err) => error!("Failed to send FUSE reply: {}", err),
-            }
-        })
+        Reply::new(self.header.unique, self.sender())
+    }
+
+    /// Create a reply object like reply, that counts the opened handle in the
+    /// given counter if the reply is successful
+    fn open_reply<T: Reply> (&self, handles: &Arc<AtomicUsize>) -> T {
+        Reply::new(self.header.unique, OpenSender { sender: self.sender(), handles: handles.clone() })
+    }
+
+    /// Reply to this request of a modifying operation on a read-only mount
+    fn reject_read_only (&self, log_target: &str) {
+        debug!(target: log_target, "Rejecting FUSE operation {} on read-only mount", self.header.opcode);
+        self.reply::<ReplyEmpty>().error(EROFS);
     }
 
     /// Returns the unique identifier of this request
@@ -378,7 +1189,16 @@ impl<'a> Request<'a> {
         self.header.unique
     }
 
-    /// Returns the uid of this request
+    /// Returns the node id (inode number) this request operates on. For operations
+    /// on a directory entry (e.g. lookup, mkdir, unlink), it's the parent directory.
+    /// It's 0 for requests that don't operate on a node (e.g. init, destroy).
+    #[inline] #[allow(dead_code)]
+    pub fn nodeid (&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Returns the uid of this request. On Linux, it's the uid of the calling process
+    /// in the user namespace of the process that mounted the filesystem.
     #[inline] #[allow(dead_code)]
     pub fn uid (&self) -> u32 {
         self.header.uid
@@ -390,9 +1210,1926 @@ impl<'a> Request<'a> {
         self.header.gid
     }
 
-    /// Returns the pid of this request
+    /// Returns the pid of this request. On Linux, it's the pid of the calling process
+    /// (the thread group id) in the pid namespace of the process that mounted the
+    /// filesystem, not in the namespace of the caller: a process in a container has
+    /// a different pid here than inside the container. It's 0 if the caller isn't
+    /// visible in the namespace of the mount (e.g. it runs in a sibling namespace)
+    /// and for requests that the kernel makes on its own (e.g. forget, or release
+    /// when the last reference is dropped). The pid may also be of a process that
+    /// exited (or even got reused) by the time the filesystem looks at it, so it
+    /// identifies the caller only approximately.
     #[inline] #[allow(dead_code)]
     pub fn pid (&self) -> u32 {
         self.header.pid
     }
+
+    /// Returns the cgroups of the calling process (see Cgroup::of_process), e.g. to
+    /// attribute the request to the container it came from. Only works if the
+    /// filesystem runs in the pid namespace that mounted it, since that's the
+    /// namespace of the pid of the request.
+    #[cfg(target_os = "linux")] #[allow(dead_code)]
+    pub fn cgroups (&self) -> io::Result<Vec<Cgroup>> {
+        Cgroup::of_process(self.header.pid)
+    }
+
+    /// Returns the deadline of this request, if the session has an operation
+    /// timeout set. The deadline is cooperative: the filesystem should check it
+    /// during long running operations (e.g. network requests) and reply with
+    /// an error like ETIMEDOUT or EIO once it passed. A callback that blocks
+    /// can't be cancelled by the session.
+    #[inline] #[allow(dead_code)]
+    pub fn deadline (&self) -> Option<Timespec> {
+        self.deadline
+    }
+
+    /// Returns the target of log messages about this request, which is the log target
+    /// of its session (see Session::log_target), e.g. to log messages of the filesystem
+    /// about the request along with the messages of the session
+    #[inline] #[allow(dead_code)]
+    pub fn log_target (&self) -> &str {
+        &self.log_target
+    }
+
+    /// Returns true if the deadline of this request has passed
+    #[inline] #[allow(dead_code)]
+    pub fn timed_out (&self) -> bool {
+        match self.deadline {
+            Some(deadline) => time::get_time() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Returns the security context of the calling process for requests that create
+    /// an inode (create, mkdir, mknod and symlink), if the filesystem wants and was
+    /// granted FUSE_SECURITY_CTX (ABI 7.36). The filesystem should label the new
+    /// inode with it before replying (e.g. by setting the extended attribute of the
+    /// context), since it would be denied access to an unlabeled inode on systems
+    /// that enforce SELinux. It's None for other requests, without the capability
+    /// and if the caller has no security context.
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))] #[allow(dead_code)]
+    pub fn security_context (&self) -> Option<SecurityContext<'a>> {
+        // The contexts follow the argument and the name(s) of the request
+        let mut data = ArgumentIterator::new(self.data);
+        match fuse_opcode::from_u32(self.header.opcode) {
+            Some(FUSE_CREATE) => { data.fetch::<fuse_create_in>(); data.fetch_str(); },
+            Some(FUSE_MKDIR) => { data.fetch::<fuse_mkdir_in>(); data.fetch_str(); },
+            Some(FUSE_MKNOD) => { data.fetch::<fuse_mknod_in>(); data.fetch_str(); },
+            Some(FUSE_SYMLINK) => { data.fetch_str(); data.fetch_str(); },
+            _ => return None,
+        }
+        parse_security_context(data.fetch_data())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{cmp, mem, ptr, slice, thread};
+    use std::collections::HashMap;
+    use std::ffi::{OsStr, OsString};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use libc::{c_int, c_void, size_t, O_CREAT, O_WRONLY, O_TRUNC, S_IFREG};
+    use libc::consts::os::posix88::{EEXIST, EINVAL, EIO, ENOENT, ENOSYS, EPROTO, EROFS};
+    use time::{Duration, Timespec};
+    use channel::{Channel, ChannelSender};
+    use session::{MAX_WRITE_SIZE, Session};
+    use fuse::*;
+    use fuse::consts::*;
+    use fuse::fuse_opcode::*;
+    use reply::{ReplySender, ReplyEmpty, ReplyEntry, ReplyAttr, ReplyData, ReplyOpen, ReplyCreate, ReplyWrite, ReplyLock, ReplyXattr, ReplyDirectory};
+    #[cfg(feature = "abi-7-11")]
+    use reply::ReplyPoll;
+    #[cfg(feature = "abi-7-21")]
+    use reply::ReplyDirectoryPlus;
+    use {Filesystem, ConnectionInfo, FileAttr, FileType, LockType, rename_by_link, ENOATTR};
+    #[cfg(feature = "abi-7-16")]
+    use Forget;
+    #[cfg(not(target_os = "macos"))]
+    use Mapping;
+    #[cfg(feature = "abi-7-13")]
+    use CongestionPolicy;
+    use super::{request, dispatch, release_handle, Inflight, InflightSender};
+
+    /// Returns the memory representation of an arbitrary type as bytes
+    fn bytes_of<T> (data: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(data as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    /// Build a request buffer for the given operation, node id, argument and
+    /// additional data (like names) that follows the argument
+    fn request_bytes<T> (opcode: fuse_opcode, nodeid: u64, arg: &T, extra: &[u8]) -> Vec<u8> {
+        let len = mem::size_of::<fuse_in_header>() + mem::size_of::<T>() + extra.len();
+        let header = fuse_in_header {
+            len: len as u32,
+            opcode: opcode as u32,
+            unique: 0xdeadbeef,
+            nodeid: nodeid,
+            uid: 0x1000,
+            gid: 0x1000,
+            pid: 0x1234,
+            padding: 0,
+        };
+        let mut buffer: Vec<u8> = Vec::with_capacity(len);
+        buffer.push_all(bytes_of(&header));
+        buffer.push_all(bytes_of(arg));
+        buffer.push_all(extra);
+        buffer
+    }
+
+    /// Returns true if an init request with the given flags is extended (FUSE_INIT_EXT)
+    #[cfg(not(target_os = "macos"))]
+    fn init_ext (flags: u64) -> bool {
+        flags & FUSE_INIT_EXT != 0
+    }
+
+    /// Returns true if an init request with the given flags is extended (never on OS X)
+    #[cfg(target_os = "macos")]
+    fn init_ext (_flags: u64) -> bool {
+        false
+    }
+
+    /// An initialized session connected to a test channel. Requests are
+    /// dispatched directly, replies can be received from the other end
+    /// of the channel.
+    pub struct TestSession<FS: Filesystem> {
+        pub se: Session<FS>,
+        sender: ChannelSender,
+        fd: c_int,
+        inflight: Arc<Inflight>,
+    }
+
+    impl<FS: Filesystem> TestSession<FS> {
+        /// Create a new initialized test session for the given filesystem
+        pub fn new (filesystem: FS) -> TestSession<FS> {
+            let mut ts = TestSession::uninitialized(filesystem);
+            ts.se.proto_major = FUSE_KERNEL_VERSION;
+            ts.se.proto_minor = FUSE_KERNEL_MINOR_VERSION;
+            ts.se.initialized = true;
+            ts
+        }
+
+        /// Create a new test session for the given filesystem that still
+        /// expects an init request
+        pub fn uninitialized (filesystem: FS) -> TestSession<FS> {
+            let (ch, fd) = Channel::pair().unwrap();
+            let sender = ch.sender();
+            let se = Session::with_channel(filesystem, ch);
+            TestSession { se: se, sender: sender, fd: fd, inflight: Arc::new(Inflight::new()) }
+        }
+
+        /// Dispatch the given request buffer to the filesystem
+        pub fn dispatch (&mut self, buffer: &[u8]) {
+            let req = request(self.sender, buffer, self.se.operation_timeout, &self.inflight, &self.se.log_target).unwrap();
+            dispatch(&req, &mut self.se);
+        }
+
+        /// Dispatch an init request of a kernel of the given minor version that is capable
+        /// of the given flags and return the init reply. The high 32 bits of the flags
+        /// are sent in an extended init request if the flags include FUSE_INIT_EXT.
+        pub fn init (&mut self, minor: u32, flags: u64) -> fuse_init_out {
+            let arg = fuse_init_in { major: 7, minor: minor, max_readahead: 0, flags: flags as u32 };
+            let ext = fuse_init_in_ext { flags2: (flags >> 32) as u32, unused: [0; 11] };
+            let extra: &[u8] = if init_ext(flags) { bytes_of(&ext) } else { &[] };
+            self.dispatch(&request_bytes(FUSE_INIT, 0, &arg, extra));
+            let buffer = self.receive();
+            unsafe { ptr::read(buffer[mem::size_of::<fuse_out_header>()..].as_ptr() as *const fuse_init_out) }
+        }
+
+        /// Receive the next reply (can block if no reply was sent)
+        pub fn receive (&self) -> Vec<u8> {
+            let mut buffer: Vec<u8> = Vec::with_capacity(4096);
+            let rc = unsafe { ::libc::read(self.fd, buffer.as_mut_ptr() as *mut c_void, buffer.capacity() as size_t) };
+            assert!(rc >= mem::size_of::<fuse_out_header>() as isize, "failed to receive reply");
+            unsafe { buffer.set_len(rc as usize); }
+            buffer
+        }
+
+        /// Receive the next reply and return its error code
+        pub fn receive_error (&self) -> i32 {
+            let buffer = self.receive();
+            let header: &fuse_out_header = unsafe { mem::transmute(buffer.as_ptr()) };
+            -header.error
+        }
+    }
+
+    impl<FS: Filesystem> Drop for TestSession<FS> {
+        fn drop (&mut self) {
+            unsafe { ::libc::close(self.fd); }
+        }
+    }
+
+    #[derive(Default)]
+    struct TruncateFs {
+        atomic_o_trunc: bool,
+        open_flags: Option<u32>,
+        setattr_size: Option<u64>,
+        setattr_fh: Option<u64>,
+    }
+
+    impl Filesystem for TruncateFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            if self.atomic_o_trunc {
+                conn.want |= FUSE_ATOMIC_O_TRUNC;
+            }
+            Ok(())
+        }
+
+        fn open (&mut self, _req: &super::Request, _ino: u64, flags: u32, reply: ReplyOpen) {
+            self.open_flags = Some(flags);
+            reply.opened(0x11, 0);
+        }
+
+        fn setattr (&mut self, _req: &super::Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+            self.setattr_size = size;
+            self.setattr_fh = fh;
+            reply.error(ENOSYS);
+        }
+    }
+
+    #[derive(Default)]
+    struct GetattrFs {
+        fh: Option<Option<u64>>,
+    }
+
+    impl Filesystem for GetattrFs {
+        fn getattr (&mut self, _req: &super::Request, _ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+            self.fh = Some(fh);
+            reply.error(ENOSYS);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-9")]
+    fn getattr_fh () {
+        let mut ts = TestSession::new(GetattrFs::default());
+        let arg = fuse_getattr_in { getattr_flags: FUSE_GETATTR_FH, dummy: 0, fh: 0x1234 };
+        ts.dispatch(&request_bytes(FUSE_GETATTR, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.fh, Some(Some(0x1234)));
+        // Without the flag, fh is garbage and not passed, even if other flags are set
+        for &flags in [0, 0x6].iter() {
+            let arg = fuse_getattr_in { getattr_flags: flags, dummy: 0, fh: 0xdeadbeef };
+            ts.dispatch(&request_bytes(FUSE_GETATTR, 0x22, &arg, &[]));
+            assert_eq!(ts.receive_error(), ENOSYS);
+            assert_eq!(ts.se.filesystem.fh, Some(None));
+        }
+        // Unknown flags don't affect a valid fh
+        let arg = fuse_getattr_in { getattr_flags: FUSE_GETATTR_FH | 0x6, dummy: 0, fh: 0x5678 };
+        ts.dispatch(&request_bytes(FUSE_GETATTR, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.fh, Some(Some(0x5678)));
+    }
+
+    #[test]
+    #[cfg(not(feature = "abi-7-9"))]
+    fn getattr_without_fh () {
+        let mut ts = TestSession::new(GetattrFs::default());
+        ts.dispatch(&request_bytes(FUSE_GETATTR, 0x22, &(), &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.fh, Some(None));
+    }
+
+    #[derive(Default)]
+    struct FsyncFs {
+        fsync: Option<(u64, bool)>,
+        fsyncdir: Option<(u64, bool)>,
+    }
+
+    impl Filesystem for FsyncFs {
+        fn fsync (&mut self, _req: &super::Request, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+            self.fsync = Some((fh, datasync));
+            reply.ok();
+        }
+
+        fn fsyncdir (&mut self, _req: &super::Request, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+            self.fsyncdir = Some((fh, datasync));
+            reply.ok();
+        }
+    }
+
+    #[test]
+    fn fsyncdir () {
+        let mut ts = TestSession::new(FsyncFs::default());
+        ts.dispatch(&request_bytes(FUSE_FSYNCDIR, 0x22, &fuse_fsync_in { fh: 0x11, fsync_flags: 0, padding: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.fsyncdir, Some((0x11, false)));
+        ts.dispatch(&request_bytes(FUSE_FSYNCDIR, 0x22, &fuse_fsync_in { fh: 0x11, fsync_flags: FUSE_FSYNC_FDATASYNC, padding: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.fsyncdir, Some((0x11, true)));
+        assert_eq!(ts.se.filesystem.fsync, None);
+    }
+
+    #[test]
+    fn fsync_datasync () {
+        let mut ts = TestSession::new(FsyncFs::default());
+        ts.dispatch(&request_bytes(FUSE_FSYNC, 0x22, &fuse_fsync_in { fh: 0x11, fsync_flags: FUSE_FSYNC_FDATASYNC, padding: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.fsync, Some((0x11, true)));
+        ts.dispatch(&request_bytes(FUSE_FSYNC, 0x22, &fuse_fsync_in { fh: 0x11, fsync_flags: 0, padding: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.fsync, Some((0x11, false)));
+        assert_eq!(ts.se.filesystem.fsyncdir, None);
+    }
+
+    #[derive(Default)]
+    struct ForgetFs {
+        forgotten: Vec<(u64, u64)>,
+    }
+
+    impl Filesystem for ForgetFs {
+        fn forget (&mut self, _req: &super::Request, ino: u64, nlookup: u64) {
+            self.forgotten.push((ino, nlookup));
+        }
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    #[derive(Default)]
+    struct ForgetMultiFs {
+        batches: Vec<Vec<Forget>>,
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    impl Filesystem for ForgetMultiFs {
+        fn forget (&mut self, _req: &super::Request, _ino: u64, _nlookup: u64) {
+            panic!("forget called instead of forget_multi");
+        }
+
+        fn forget_multi (&mut self, _req: &super::Request, forgets: &[Forget]) {
+            self.batches.push(forgets.to_vec());
+        }
+    }
+
+    #[test]
+    fn forget_nlookup () {
+        let mut ts = TestSession::new(ForgetFs::default());
+        ts.dispatch(&request_bytes(FUSE_FORGET, 0x22, &fuse_forget_in { nlookup: 3 }, &[]));
+        assert_eq!(ts.se.filesystem.forgotten, [(0x22, 3)]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget () {
+        let mut ts = TestSession::new(ForgetFs::default());
+        let mut nodes = Vec::new();
+        nodes.push_all(bytes_of(&fuse_forget_one { nodeid: 0x22, nlookup: 3 }));
+        nodes.push_all(bytes_of(&fuse_forget_one { nodeid: 0x33, nlookup: 1 }));
+        ts.dispatch(&request_bytes(FUSE_BATCH_FORGET, 0, &fuse_batch_forget_in { count: 2, dummy: 0 }, &nodes));
+        assert_eq!(ts.se.filesystem.forgotten, [(0x22, 3), (0x33, 1)]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget_multi () {
+        let mut ts = TestSession::new(ForgetMultiFs::default());
+        let mut nodes = Vec::new();
+        nodes.push_all(bytes_of(&fuse_forget_one { nodeid: 0x22, nlookup: 3 }));
+        nodes.push_all(bytes_of(&fuse_forget_one { nodeid: 0x33, nlookup: 1 }));
+        ts.dispatch(&request_bytes(FUSE_BATCH_FORGET, 0, &fuse_batch_forget_in { count: 2, dummy: 0 }, &nodes));
+        assert_eq!(ts.se.filesystem.batches, [vec![Forget { ino: 0x22, nlookup: 3 }, Forget { ino: 0x33, nlookup: 1 }]]);
+    }
+
+    #[derive(Default)]
+    struct DeadlineFs {
+        deadline: Option<(bool, bool)>,
+    }
+
+    impl Filesystem for DeadlineFs {
+        fn access (&mut self, req: &super::Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+            self.deadline = Some((req.deadline().is_some(), req.timed_out()));
+            reply.ok();
+        }
+    }
+
+    #[derive(Default)]
+    struct PendingFs {
+        readlinks: usize,
+        pending: Option<ReplyData>,
+    }
+
+    impl Filesystem for PendingFs {
+        fn readlink (&mut self, _req: &super::Request, _ino: u64, reply: ReplyData) {
+            // Keep the reply to send it later
+            self.readlinks += 1;
+            self.pending = Some(reply);
+        }
+    }
+
+    /// Returns the given request as resent by the kernel
+    fn resent (mut bytes: Vec<u8>) -> Vec<u8> {
+        {
+            let header: &mut fuse_in_header = unsafe { mem::transmute(bytes.as_mut_ptr()) };
+            header.unique |= FUSE_UNIQUE_RESEND;
+        }
+        bytes
+    }
+
+    #[test]
+    fn resend_inflight () {
+        let mut ts = TestSession::new(PendingFs::default());
+        let bytes = request_bytes(FUSE_READLINK, 0x22, &(), &[]);
+        ts.dispatch(&bytes);
+        // The kernel resends the request while the filesystem still works on it,
+        // the resent request isn't dispatched again
+        ts.dispatch(&resent(bytes.clone()));
+        assert_eq!(ts.se.filesystem.readlinks, 1);
+        assert_eq!(ts.inflight.len(), 1);
+        // The reply to the original goes to the resent request
+        ts.se.filesystem.pending.take().unwrap().data(b"target");
+        let reply = ts.receive();
+        let header: &fuse_out_header = unsafe { mem::transmute(reply.as_ptr()) };
+        assert_eq!(header.unique, 0xdeadbeef | FUSE_UNIQUE_RESEND);
+        assert_eq!(header.error, 0);
+        assert_eq!(&reply[mem::size_of::<fuse_out_header>()..], b"target");
+        assert_eq!(ts.inflight.len(), 0);
+        // Once the original was replied, a resent request is dispatched like any other
+        ts.dispatch(&resent(bytes));
+        assert_eq!(ts.se.filesystem.readlinks, 2);
+        ts.se.filesystem.pending.take().unwrap().error(ENOENT);
+        let reply = ts.receive();
+        let header: &fuse_out_header = unsafe { mem::transmute(reply.as_ptr()) };
+        assert_eq!(header.unique, 0xdeadbeef | FUSE_UNIQUE_RESEND);
+        assert_eq!(header.error, -ENOENT);
+        assert_eq!(ts.inflight.len(), 0);
+    }
+
+    #[test]
+    fn inflight_unlocked_while_sending () {
+        let inflight = Arc::new(Inflight::new());
+        let sending = inflight.clone();
+        // Other requests can be inserted and replied while a reply is written
+        let sender = InflightSender::new(move |_: &[&[u8]]| {
+            assert!(sending.requests.try_lock().is_ok());
+            assert_eq!(sending.len(), 0);
+        }, 0xdeadbeef, inflight.clone(), "fuse::test".to_string());
+        assert_eq!(inflight.len(), 1);
+        let header = [0u8; 16];
+        sender.send(&[&header[..]]).unwrap();
+        assert_eq!(inflight.len(), 0);
+    }
+
+    #[derive(Default)]
+    struct LinkFs {
+        entries: HashMap<(u64, PathBuf), u64>,
+        lookups: HashMap<u64, i64>,
+    }
+
+    impl LinkFs {
+        fn reply_entry (&mut self, ino: u64, reply: ReplyEntry) {
+            *self.lookups.entry(ino).or_insert(0) += 1;
+            let time = Timespec::new(0, 0);
+            let attr = FileAttr { ino: ino, size: 0, blocks: 0, atime: time, mtime: time, ctime: time, crtime: time,
+                kind: FileType::RegularFile, perm: 0o644, nlink: 1, uid: 0, gid: 0, rdev: 0, blksize: 0, flags: 0 };
+            reply.entry(&time, &attr, 0);
+        }
+    }
+
+    impl Filesystem for LinkFs {
+        fn lookup (&mut self, _req: &super::Request, parent: u64, name: &Path, reply: ReplyEntry) {
+            match self.entries.get(&(parent, name.to_path_buf())).cloned() {
+                Some(ino) => self.reply_entry(ino, reply),
+                None => reply.error(ENOENT),
+            }
+        }
+
+        fn forget (&mut self, _req: &super::Request, ino: u64, nlookup: u64) {
+            *self.lookups.entry(ino).or_insert(0) -= nlookup as i64;
+        }
+
+        fn link (&mut self, _req: &super::Request, ino: u64, newparent: u64, newname: &Path, reply: ReplyEntry) {
+            let key = (newparent, newname.to_path_buf());
+            if self.entries.contains_key(&key) {
+                return reply.error(EEXIST);
+            }
+            self.entries.insert(key, ino);
+            self.reply_entry(ino, reply);
+        }
+
+        fn unlink (&mut self, _req: &super::Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+            match self.entries.remove(&(parent, name.to_path_buf())) {
+                Some(_) => reply.ok(),
+                None => reply.error(ENOENT),
+            }
+        }
+
+        fn rename (&mut self, req: &super::Request, parent: u64, name: &Path, newparent: u64, newname: &Path, reply: ReplyEmpty) {
+            rename_by_link(self, req, parent, name, newparent, newname, reply);
+        }
+    }
+
+    #[test]
+    fn rename_by_link_fallback () {
+        let mut fs = LinkFs::default();
+        fs.entries.insert((1, PathBuf::from("a")), 10);
+        fs.entries.insert((1, PathBuf::from("b")), 11);
+        let mut ts = TestSession::new(fs);
+        // Rename to a new name
+        ts.dispatch(&request_bytes(FUSE_RENAME, 1, &fuse_rename_in { newdir: 2 }, b"a\0c\0"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("a"))), None);
+        assert_eq!(ts.se.filesystem.entries.get(&(2, PathBuf::from("c"))), Some(&10));
+        // Rename replacing an existing name
+        ts.dispatch(&request_bytes(FUSE_RENAME, 2, &fuse_rename_in { newdir: 1 }, b"c\0b\0"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.entries.len(), 1);
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("b"))), Some(&10));
+        // Rename of a missing name
+        ts.dispatch(&request_bytes(FUSE_RENAME, 1, &fuse_rename_in { newdir: 1 }, b"x\0y\0"));
+        assert_eq!(ts.receive_error(), ENOENT);
+        // Lookups done internally didn't leak into the lookup count
+        assert_eq!(ts.se.filesystem.lookups.get(&10), Some(&0));
+    }
+
+    #[test]
+    fn rename_by_link_to_itself () {
+        let mut fs = LinkFs::default();
+        fs.entries.insert((1, PathBuf::from("a")), 10);
+        let mut ts = TestSession::new(fs);
+        ts.dispatch(&request_bytes(FUSE_RENAME, 1, &fuse_rename_in { newdir: 1 }, b"a\0a\0"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("a"))), Some(&10));
+        assert_eq!(ts.se.filesystem.entries.len(), 1);
+    }
+
+    #[test]
+    fn rename_by_link_to_same_inode () {
+        let mut fs = LinkFs::default();
+        fs.entries.insert((1, PathBuf::from("a")), 10);
+        fs.entries.insert((2, PathBuf::from("b")), 10);
+        let mut ts = TestSession::new(fs);
+        // Both names are hard links of the same file, which keeps them both
+        ts.dispatch(&request_bytes(FUSE_RENAME, 1, &fuse_rename_in { newdir: 2 }, b"a\0b\0"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("a"))), Some(&10));
+        assert_eq!(ts.se.filesystem.entries.get(&(2, PathBuf::from("b"))), Some(&10));
+        assert_eq!(ts.se.filesystem.lookups.get(&10), Some(&0));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[derive(Default)]
+    struct Rename2Fs {
+        renamed: Vec<(u64, PathBuf, u64, PathBuf, u32)>,
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    impl Filesystem for Rename2Fs {
+        fn rename2 (&mut self, _req: &super::Request, parent: u64, name: &Path, newparent: u64, newname: &Path, flags: u32, reply: ReplyEmpty) {
+            self.renamed.push((parent, name.to_path_buf(), newparent, newname.to_path_buf(), flags));
+            reply.ok();
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn rename2 () {
+        let arg = |flags| fuse_rename2_in { newdir: 2, flags: flags, padding: 0 };
+        let mut ts = TestSession::new(Rename2Fs::default());
+        // Supported flags are passed to the filesystem
+        ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &arg(RENAME_NOREPLACE), b"a\0b\0"));
+        assert_eq!(ts.receive_error(), 0);
+        ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &arg(RENAME_EXCHANGE), b"a\0b\0"));
+        assert_eq!(ts.receive_error(), 0);
+        ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &arg(RENAME_NOREPLACE | RENAME_WHITEOUT), b"a\0b\0"));
+        assert_eq!(ts.receive_error(), 0);
+        // Unknown flags and exchanging with other flags are invalid
+        ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &arg(1 << 3), b"a\0b\0"));
+        assert_eq!(ts.receive_error(), EINVAL);
+        ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &arg(RENAME_EXCHANGE | RENAME_NOREPLACE), b"a\0b\0"));
+        assert_eq!(ts.receive_error(), EINVAL);
+        assert_eq!(&ts.se.filesystem.renamed[..], &[
+            (1, PathBuf::from("a"), 2, PathBuf::from("b"), RENAME_NOREPLACE),
+            (1, PathBuf::from("a"), 2, PathBuf::from("b"), RENAME_EXCHANGE),
+            (1, PathBuf::from("a"), 2, PathBuf::from("b"), RENAME_NOREPLACE | RENAME_WHITEOUT),
+        ][..]);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn rename2_default () {
+        let mut fs = LinkFs::default();
+        fs.entries.insert((1, PathBuf::from("a")), 10);
+        fs.entries.insert((1, PathBuf::from("b")), 11);
+        let mut ts = TestSession::new(fs);
+        // With flags, rename (here rename_by_link) isn't used, it would replace or not exchange
+        for &flags in [RENAME_NOREPLACE, RENAME_EXCHANGE].iter() {
+            ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &fuse_rename2_in { newdir: 1, flags: flags, padding: 0 }, b"a\0b\0"));
+            assert_eq!(ts.receive_error(), ENOSYS);
+        }
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("a"))), Some(&10));
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("b"))), Some(&11));
+        // Without flags, it's a plain rename
+        ts.dispatch(&request_bytes(FUSE_RENAME2, 1, &fuse_rename2_in { newdir: 1, flags: 0, padding: 0 }, b"a\0b\0"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.entries.len(), 1);
+        assert_eq!(ts.se.filesystem.entries.get(&(1, PathBuf::from("b"))), Some(&10));
+    }
+
+    #[derive(Default)]
+    struct DirFs {
+        next_fh: u64,
+        handles: HashMap<u64, Vec<(u64, &'static str)>>,
+    }
+
+    impl Filesystem for DirFs {
+        fn opendir (&mut self, _req: &super::Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+            self.next_fh += 1;
+            self.handles.insert(self.next_fh, vec![(0x11, "a"), (0x12, "b"), (0x13, "c")]);
+            reply.opened(self.next_fh, 0);
+        }
+
+        fn readdir (&mut self, _req: &super::Request, _ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+            let entries = match self.handles.get(&fh) {
+                Some(entries) => entries,
+                None => return reply.error(EIO),
+            };
+            for (i, &(ino, name)) in entries.iter().enumerate().skip(offset as usize) {
+                if reply.add(ino, i as u64 + 1, FileType::RegularFile, name) { break; }
+            }
+            reply.ok();
+        }
+
+        fn releasedir (&mut self, _req: &super::Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+            self.handles.remove(&fh);
+            reply.ok();
+        }
+    }
+
+    #[test]
+    fn opendir_readdir_releasedir () {
+        let mut ts = TestSession::new(DirFs::default());
+        ts.dispatch(&request_bytes(FUSE_OPENDIR, 0x22, &fuse_open_in { flags: 0, mode: 0 }, &[]));
+        let buffer = ts.receive();
+        let open: &fuse_open_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        let fh = open.fh;
+        assert_eq!(fh, 1);
+        // The first readdir only has room for two entries (32 bytes each)...
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.offset = 0;
+        arg.size = 64;
+        ts.dispatch(&request_bytes(FUSE_READDIR, 0x22, &arg, &[]));
+        let buffer = ts.receive();
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + 64);
+        let dirent: &fuse_dirent = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(dirent.ino, 0x11);
+        // ...the second one continues at the offset of the last entry using the same fh
+        arg.offset = 2;
+        ts.dispatch(&request_bytes(FUSE_READDIR, 0x22, &arg, &[]));
+        let buffer = ts.receive();
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + 32);
+        let dirent: &fuse_dirent = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(dirent.ino, 0x13);
+        assert_eq!(dirent.off, 3);
+        // Releasing the directory frees the handle
+        let mut arg: fuse_release_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        ts.dispatch(&request_bytes(FUSE_RELEASEDIR, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert!(ts.se.filesystem.handles.is_empty());
+    }
+
+    /// Filesystem that accepts at most one page per write
+    struct ShortWriteFs;
+
+    impl Filesystem for ShortWriteFs {
+        fn write (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _offset: u64, data: &[u8], _write_flags: u32, _flags: u32, reply: ReplyWrite) {
+            reply.written(cmp::min(data.len(), 4096) as u32);
+        }
+    }
+
+    #[cfg(feature = "abi-7-9")]
+    #[derive(Default)]
+    struct WriteFlagsFs {
+        write_flags: Option<u32>,
+    }
+
+    #[cfg(feature = "abi-7-9")]
+    impl Filesystem for WriteFlagsFs {
+        fn write (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _offset: u64, data: &[u8], write_flags: u32, _flags: u32, reply: ReplyWrite) {
+            self.write_flags = Some(write_flags);
+            reply.written(data.len() as u32);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-9")]
+    fn write_kill_suidgid () {
+        let mut ts = TestSession::new(WriteFlagsFs::default());
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.size = 5;
+        arg.write_flags = FUSE_WRITE_KILL_SUIDGID | FUSE_WRITE_LOCKOWNER;
+        ts.dispatch(&request_bytes(FUSE_WRITE, 0x22, &arg, b"hello"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.write_flags, Some(FUSE_WRITE_KILL_SUIDGID | FUSE_WRITE_LOCKOWNER));
+        arg.write_flags = 0;
+        ts.dispatch(&request_bytes(FUSE_WRITE, 0x22, &arg, b"hello"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.write_flags, Some(0));
+    }
+
+    #[test]
+    fn write_size_exceeds_request () {
+        let mut ts = TestSession::new(ShortWriteFs);
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.size = 8192;
+        ts.dispatch(&request_bytes(FUSE_WRITE, 0x22, &arg, &[0x42; 4096]));
+        assert_eq!(ts.receive_error(), EIO);
+    }
+
+    #[test]
+    fn short_write () {
+        let mut ts = TestSession::new(ShortWriteFs);
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.size = 8192;
+        ts.dispatch(&request_bytes(FUSE_WRITE, 0x22, &arg, &[0x42; 8192]));
+        let buffer = ts.receive();
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_write_out>());
+        let write: &fuse_write_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(write.size, 4096);
+    }
+
+    #[test]
+    #[cfg(all(feature = "examples", feature = "abi-7-9"))]
+    fn write_append () {
+        use libc::O_APPEND;
+        use examples::MemFs;
+        let mut ts = TestSession::new(MemFs::new());
+        let flags = (O_WRONLY | O_CREAT | O_APPEND) as u32;
+        #[cfg(not(feature = "abi-7-12"))]
+        let arg = fuse_open_in { flags: flags, mode: S_IFREG as u32 | 0o644 };
+        #[cfg(feature = "abi-7-12")]
+        let arg = fuse_create_in { flags: flags, mode: S_IFREG as u32 | 0o644, umask: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_CREATE, FUSE_ROOT_ID, &arg, b"log\0"));
+        let buffer = ts.receive();
+        let entry: &fuse_entry_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        let open: &fuse_open_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_entry_out>()..].as_ptr()) };
+        let (ino, fh) = (entry.nodeid, open.fh);
+        // Both writes claim offset 0, the second one must still land at the end
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.flags = flags;
+        arg.size = 5;
+        ts.dispatch(&request_bytes(FUSE_WRITE, ino, &arg, b"hello"));
+        assert_eq!(ts.receive_error(), 0);
+        arg.size = 6;
+        ts.dispatch(&request_bytes(FUSE_WRITE, ino, &arg, b" world"));
+        assert_eq!(ts.receive_error(), 0);
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.size = 64;
+        ts.dispatch(&request_bytes(FUSE_READ, ino, &arg, &[]));
+        let buffer = ts.receive();
+        assert_eq!(&buffer[mem::size_of::<fuse_out_header>()..], &b"hello world"[..]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "examples", feature = "abi-7-9"))]
+    fn getattr_fh_after_write () {
+        use examples::MemFs;
+        let mut ts = TestSession::new(MemFs::new());
+        let flags = (O_WRONLY | O_CREAT) as u32;
+        #[cfg(not(feature = "abi-7-12"))]
+        let arg = fuse_open_in { flags: flags, mode: S_IFREG as u32 | 0o644 };
+        #[cfg(feature = "abi-7-12")]
+        let arg = fuse_create_in { flags: flags, mode: S_IFREG as u32 | 0o644, umask: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_CREATE, FUSE_ROOT_ID, &arg, b"log\0"));
+        let buffer = ts.receive();
+        let entry: &fuse_entry_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        let open: &fuse_open_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_entry_out>()..].as_ptr()) };
+        let (ino, fh) = (entry.nodeid, open.fh);
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.flags = flags;
+        arg.size = 11;
+        ts.dispatch(&request_bytes(FUSE_WRITE, ino, &arg, b"hello world"));
+        assert_eq!(ts.receive_error(), 0);
+        // An fstat through the handle sees the size of the unflushed write, a stat
+        // by path doesn't yet
+        let expected = [(FUSE_GETATTR_FH, 11), (0, 0)];
+        for &(getattr_flags, size) in expected.iter() {
+            let arg = fuse_getattr_in { getattr_flags: getattr_flags, dummy: 0, fh: fh };
+            ts.dispatch(&request_bytes(FUSE_GETATTR, ino, &arg, &[]));
+            let buffer = ts.receive();
+            let attr: &fuse_attr_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+            assert_eq!(attr.attr.size, size);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "examples", feature = "abi-7-12"))]
+    fn create_umask () {
+        use examples::MemFs;
+        let mut ts = TestSession::new(MemFs::new());
+        // Without FUSE_DONT_MASK, the kernel applied the umask of 022 already, with
+        // it the filesystem applies it. Both must end up with 0644.
+        let modes = [("masked\0", 0o644), ("unmasked\0", 0o666)];
+        for &(name, perm) in modes.iter() {
+            let arg = fuse_create_in { flags: (O_WRONLY | O_CREAT) as u32, mode: S_IFREG as u32 | perm, umask: 0o022, padding: 0 };
+            ts.dispatch(&request_bytes(FUSE_CREATE, FUSE_ROOT_ID, &arg, name.as_bytes()));
+            let buffer = ts.receive();
+            let entry: &fuse_entry_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+            assert_eq!(entry.attr.mode, S_IFREG as u32 | 0o644);
+        }
+    }
+
+    #[cfg(feature = "abi-7-9")]
+    #[derive(Default)]
+    struct ReadFs {
+        read: Option<(u64, u32, u32, Option<u64>)>,
+    }
+
+    #[cfg(feature = "abi-7-9")]
+    impl Filesystem for ReadFs {
+        fn read (&mut self, _req: &super::Request, _ino: u64, _fh: u64, offset: u64, size: u32, flags: u32, lock_owner: Option<u64>, reply: ReplyData) {
+            self.read = Some((offset, size, flags, lock_owner));
+            reply.data(&[]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-9")]
+    fn read_flags () {
+        let mut ts = TestSession::new(ReadFs::default());
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.offset = 0x1000;
+        arg.size = 0x200;
+        arg.flags = O_WRONLY as u32;
+        ts.dispatch(&request_bytes(FUSE_READ, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.read, Some((0x1000, 0x200, O_WRONLY as u32, None)));
+        // The lock owner is only valid if the kernel says so
+        arg.lock_owner = 0xc0ffee;
+        ts.dispatch(&request_bytes(FUSE_READ, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.read, Some((0x1000, 0x200, O_WRONLY as u32, None)));
+        arg.read_flags = FUSE_READ_LOCKOWNER;
+        ts.dispatch(&request_bytes(FUSE_READ, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.read, Some((0x1000, 0x200, O_WRONLY as u32, Some(0xc0ffee))));
+    }
+
+    #[derive(Default)]
+    struct LockFs {
+        locks: Vec<(u64, u64, LockType, bool)>,
+    }
+
+    impl Filesystem for LockFs {
+        fn getlk (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _lock_owner: u64, start: u64, end: u64, _typ: LockType, _pid: u32, reply: ReplyLock) {
+            reply.locked(start, end, LockType::Unlock, 0);
+        }
+
+        fn setlk (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _lock_owner: u64, start: u64, end: u64, typ: LockType, _pid: u32, sleep: bool, reply: ReplyEmpty) {
+            self.locks.push((start, end, typ, sleep));
+            reply.ok();
+        }
+    }
+
+    #[test]
+    fn lock_types () {
+        let mut ts = TestSession::new(LockFs::default());
+        let mut arg: fuse_lk_in = unsafe { mem::zeroed() };
+        arg.lk.start = 0x100;
+        arg.lk.end = 0xffffffffffffffff;
+        // F_UNLCK has the same value on all platforms
+        arg.lk.typ = 2;
+        ts.dispatch(&request_bytes(FUSE_SETLKW, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.locks, [(0x100, 0xffffffffffffffff, LockType::Unlock, true)]);
+        ts.dispatch(&request_bytes(FUSE_GETLK, 0x22, &arg, &[]));
+        let buffer = ts.receive();
+        let lk: &fuse_lk_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(lk.lk.typ, 2);
+        assert_eq!(lk.lk.start, 0x100);
+        // Unknown lock types are rejected
+        arg.lk.typ = 0x42;
+        ts.dispatch(&request_bytes(FUSE_SETLK, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), EINVAL);
+        ts.dispatch(&request_bytes(FUSE_GETLK, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), EINVAL);
+        assert_eq!(ts.se.filesystem.locks.len(), 1);
+    }
+
+    #[derive(Default)]
+    struct ReleaseFs {
+        release: Option<(u64, u32, u64, bool)>,
+    }
+
+    impl Filesystem for ReleaseFs {
+        fn release (&mut self, _req: &super::Request, _ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+            self.release = Some((fh, flags, lock_owner, flush));
+            reply.ok();
+        }
+    }
+
+    #[test]
+    fn release_arguments () {
+        let mut ts = TestSession::new(ReleaseFs::default());
+        let arg = fuse_release_in { fh: 0x11, flags: O_WRONLY as u32, release_flags: FUSE_RELEASE_FLUSH, lock_owner: 0xc0ffee };
+        ts.dispatch(&request_bytes(FUSE_RELEASE, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.release, Some((0x11, O_WRONLY as u32, 0xc0ffee, true)));
+        let arg = fuse_release_in { fh: 0x12, flags: 0, release_flags: 0, lock_owner: 0 };
+        ts.dispatch(&request_bytes(FUSE_RELEASE, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.release, Some((0x12, 0, 0, false)));
+    }
+
+    #[derive(Default)]
+    struct InitFs {
+        initialized: bool,
+        default_permissions: bool,
+        allow_other: bool,
+    }
+
+    impl Filesystem for InitFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            self.initialized = true;
+            self.default_permissions = conn.default_permissions;
+            self.allow_other = conn.allow_other;
+            conn.max_write = 4096;
+            conn.want |= FUSE_PARALLEL_DIROPS;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn init_connection_info () {
+        let mut ts = TestSession::uninitialized(InitFs::default());
+        ts.se.conn.default_permissions = true;
+        let arg = fuse_init_in { major: 7, minor: FUSE_KERNEL_MINOR_VERSION, max_readahead: 0x20000, flags: (FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_PARALLEL_DIROPS) as u32 };
+        ts.dispatch(&request_bytes(FUSE_INIT, 0, &arg, &[]));
+        let buffer = ts.receive();
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>());
+        let init: &fuse_init_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS);
+        assert_eq!(init.max_readahead, 0x20000);
+        assert_eq!(init.max_write, 4096);
+        assert!(ts.se.initialized);
+        assert!(ts.se.filesystem.initialized);
+        assert!(ts.se.filesystem.default_permissions);
+        assert!(!ts.se.filesystem.allow_other);
+        assert_eq!(ts.se.conn.capable, FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_PARALLEL_DIROPS);
+        assert!(ts.se.conn.parallel_dirops());
+    }
+
+    #[test]
+    fn init_negotiation () {
+        let mut ts = TestSession::uninitialized(InitFs::default());
+        assert!(ts.se.conn.negotiation().is_none());
+        // The filesystem wants FUSE_PARALLEL_DIROPS, which the kernel doesn't offer
+        ts.init(40, FUSE_ASYNC_READ | FUSE_POSIX_LOCKS);
+        let negotiation = ts.se.conn.negotiation().unwrap().clone();
+        assert_eq!(negotiation.kernel_version, (7, 40));
+        assert_eq!(negotiation.library_version, (7, FUSE_KERNEL_MINOR_VERSION));
+        assert_eq!(negotiation.offered.bits(), FUSE_ASYNC_READ | FUSE_POSIX_LOCKS);
+        assert_eq!(negotiation.requested.bits(), FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS);
+        assert_eq!(negotiation.effective.bits(), FUSE_ASYNC_READ);
+        assert_eq!((negotiation.max_readahead, negotiation.max_write), (0, 4096));
+        let report = negotiation.to_string();
+        assert!(report.contains("FUSE_POSIX_LOCKS           yes     no        no"));
+        assert!(report.contains("FUSE_PARALLEL_DIROPS       no      yes       no"));
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    struct TimeGranFs {
+        time_gran: u32,
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    impl Filesystem for TimeGranFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.time_gran = self.time_gran;
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-23")]
+    fn init_time_gran () {
+        for &(time_gran, expected) in [(1_000_000_000, 1_000_000_000), (1000, 1000), (3, 1)].iter() {
+            let mut ts = TestSession::uninitialized(TimeGranFs { time_gran: time_gran });
+            let init = ts.init(FUSE_KERNEL_MINOR_VERSION, 0);
+            assert_eq!(init.time_gran, expected);
+        }
+    }
+
+    /// Filesystem whose init wants the given capabilities and drops the given unwanted
+    /// ones from the capabilities that are wanted by default
+    #[derive(Default)]
+    struct WantFs {
+        want: u64,
+        unwant: u64,
+    }
+
+    impl Filesystem for WantFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.want |= self.want;
+            conn.want &= !self.unwant;
+            Ok(())
+        }
+    }
+
+    /// Returns all flags of the given init reply, including the high 32 bits (flags2)
+    #[cfg(feature = "abi-7-36")]
+    fn reply_flags (init: &fuse_init_out) -> u64 {
+        init.flags as u64 | (init.flags2 as u64) << 32
+    }
+
+    /// Returns all flags of the given init reply
+    #[cfg(not(feature = "abi-7-36"))]
+    fn reply_flags (init: &fuse_init_out) -> u64 {
+        init.flags as u64
+    }
+
+    /// Check that the given capability is granted (as told by the given accessor) if the
+    /// filesystem wants it and a kernel of the given minor version, that is capable of
+    /// the given base flags, is capable of it as well. Otherwise, it isn't granted.
+    fn check_wanted (minor: u32, base: u64, flag: u64, granted: fn(&ConnectionInfo) -> bool) {
+        let mut ts = TestSession::uninitialized(WantFs { want: flag, unwant: 0 });
+        let init = ts.init(minor, base | flag);
+        assert_eq!(reply_flags(&init), base | flag);
+        assert!(granted(&ts.se.conn));
+        // Not granted if the kernel isn't capable of it
+        let mut ts = TestSession::uninitialized(WantFs { want: flag, unwant: 0 });
+        let init = ts.init(minor, base);
+        assert_eq!(reply_flags(&init), base);
+        assert!(!granted(&ts.se.conn));
+        // Not granted if the filesystem doesn't want it
+        let mut ts = TestSession::uninitialized(WantFs::default());
+        let init = ts.init(minor, base | flag);
+        assert_eq!(reply_flags(&init), base);
+        assert!(!granted(&ts.se.conn));
+    }
+
+    #[test]
+    fn init_wanted () {
+        check_wanted(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ, FUSE_POSIX_ACL, ConnectionInfo::posix_acl);
+        check_wanted(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ, FUSE_CACHE_SYMLINKS, ConnectionInfo::cache_symlinks);
+        check_wanted(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ, FUSE_AUTO_INVAL_DATA, ConnectionInfo::auto_inval_data);
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    fn init_wanted_ext () {
+        // Capabilities in flags2 are negotiated with an extended init
+        check_wanted(39, FUSE_INIT_EXT, FUSE_DIRECT_IO_ALLOW_MMAP, ConnectionInfo::direct_io_mmap);
+        check_wanted(40, FUSE_INIT_EXT, FUSE_PASSTHROUGH, ConnectionInfo::passthrough);
+    }
+
+    #[test]
+    fn init_posix_acl () {
+        // Granting POSIX ACLs implies default permissions
+        let mut ts = TestSession::uninitialized(WantFs { want: FUSE_POSIX_ACL, unwant: 0 });
+        ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ);
+        assert!(!ts.se.conn.default_permissions);
+        let mut ts = TestSession::uninitialized(WantFs { want: FUSE_POSIX_ACL, unwant: 0 });
+        ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_POSIX_ACL);
+        assert!(ts.se.conn.default_permissions);
+    }
+
+    struct NoOpenFs;
+
+    impl Filesystem for NoOpenFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.want |= FUSE_NO_OPEN_SUPPORT | FUSE_NO_OPENDIR_SUPPORT;
+            Ok(())
+        }
+
+        fn open (&mut self, _req: &super::Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+            reply.error(ENOSYS);
+        }
+
+        fn opendir (&mut self, _req: &super::Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+            reply.error(ENOSYS);
+        }
+    }
+
+    #[test]
+    fn init_no_open_support () {
+        let mut ts = TestSession::uninitialized(NoOpenFs);
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_NO_OPEN_SUPPORT | FUSE_NO_OPENDIR_SUPPORT);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ | FUSE_NO_OPEN_SUPPORT | FUSE_NO_OPENDIR_SUPPORT);
+        assert!(ts.se.conn.no_open_support());
+        assert!(ts.se.conn.no_opendir_support());
+        // ENOSYS from open and opendir is passed to the kernel, which stops sending them
+        ts.dispatch(&request_bytes(FUSE_OPEN, 0x22, &fuse_open_in { flags: 0, mode: 0 }, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        ts.dispatch(&request_bytes(FUSE_OPENDIR, 0x22, &fuse_open_in { flags: 0, mode: 0 }, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        // Only granted if the kernel supports it
+        let mut ts = TestSession::uninitialized(NoOpenFs);
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ);
+        assert!(!ts.se.conn.no_open_support());
+        assert!(!ts.se.conn.no_opendir_support());
+    }
+
+    #[test]
+    fn init_export_support () {
+        // Wanted by default
+        let mut ts = TestSession::uninitialized(WantFs::default());
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT);
+        assert!(ts.se.conn.export_support());
+        // Filesystems that don't have stable inode numbers can opt out
+        let mut ts = TestSession::uninitialized(WantFs { want: 0, unwant: FUSE_EXPORT_SUPPORT });
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ);
+        assert!(!ts.se.conn.export_support());
+    }
+
+    struct SymlinkFs;
+
+    impl Filesystem for SymlinkFs {
+        fn readlink (&mut self, _req: &super::Request, _ino: u64, reply: ReplyData) {
+            reply.data(b"target-\xff\xfe");
+        }
+    }
+
+    #[test]
+    fn readlink_raw_target () {
+        let mut ts = TestSession::new(SymlinkFs);
+        ts.dispatch(&request_bytes(FUSE_READLINK, 0x22, &(), &[]));
+        let buffer = ts.receive();
+        // The target is passed on as is, not as UTF-8 and without a NUL
+        assert_eq!(&buffer[mem::size_of::<fuse_out_header>()..], &b"target-\xff\xfe"[..]);
+    }
+
+    #[test]
+    fn init_recommended () {
+        // EmptyFs doesn't implement init, so the default init asks for the recommended capabilities
+        let mut ts = TestSession::uninitialized(EmptyFs);
+        let flags = FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_BIG_WRITES | FUSE_DONT_MASK | FUSE_AUTO_INVAL_DATA | FUSE_PARALLEL_DIROPS;
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, flags);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ | FUSE_BIG_WRITES | FUSE_AUTO_INVAL_DATA);
+        assert_eq!(init.max_write, MAX_WRITE_SIZE as u32);
+        assert!(ts.se.conn.auto_inval_data());
+        // Only what the kernel is capable of is asked for
+        let mut ts = TestSession::uninitialized(EmptyFs);
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ);
+        assert_eq!(ts.se.conn.want, FUSE_ASYNC_READ);
+    }
+
+    #[cfg(feature = "abi-7-13")]
+    struct LowLatencyFs;
+
+    #[cfg(feature = "abi-7-13")]
+    impl Filesystem for LowLatencyFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.set_congestion_policy(CongestionPolicy::LowLatency);
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-13")]
+    fn init_congestion_policy () {
+        let mut ts = TestSession::uninitialized(LowLatencyFs);
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, 0);
+        assert_eq!(init.max_background, 4);
+        assert_eq!(init.congestion_threshold, 3);
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    fn init_ext () {
+        let want = FUSE_DIRECT_IO_ALLOW_MMAP | FUSE_SECURITY_CTX;
+        let mut ts = TestSession::uninitialized(WantFs { want: want, unwant: 0 });
+        let init = ts.init(39, FUSE_ASYNC_READ | FUSE_INIT_EXT | want);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ | FUSE_INIT_EXT);
+        assert_eq!(init.flags2 as u64, want >> 32);
+        assert_eq!(ts.se.conn.capable, FUSE_ASYNC_READ | FUSE_INIT_EXT | want);
+        assert!(ts.se.conn.granted(FUSE_DIRECT_IO_ALLOW_MMAP));
+        assert!(ts.se.conn.granted(FUSE_SECURITY_CTX));
+        // The high bits aren't granted without FUSE_INIT_EXT
+        let mut ts = TestSession::uninitialized(WantFs { want: want, unwant: FUSE_INIT_EXT });
+        let init = ts.init(39, FUSE_ASYNC_READ | FUSE_INIT_EXT | want);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ);
+        assert_eq!(init.flags2, 0);
+        assert!(!ts.se.conn.granted(FUSE_DIRECT_IO_ALLOW_MMAP));
+        // Older kernels don't send the extended init
+        let mut ts = TestSession::uninitialized(WantFs { want: want, unwant: 0 });
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ);
+        assert_eq!(init.flags2, 0);
+        assert_eq!(ts.se.conn.capable, FUSE_ASYNC_READ);
+    }
+
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    #[derive(Default)]
+    struct LabelFs {
+        labels: Vec<Option<(OsString, Vec<u8>)>>,
+    }
+
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    impl LabelFs {
+        fn label (&mut self, req: &super::Request, reply: ReplyEntry) {
+            self.labels.push(req.security_context().map(|ctx| (ctx.name.to_os_string(), ctx.value.to_vec())));
+            reply.error(ENOSYS);
+        }
+    }
+
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    impl Filesystem for LabelFs {
+        fn mkdir (&mut self, req: &super::Request, _parent: u64, _name: &Path, _mode: u32, reply: ReplyEntry) {
+            self.label(req, reply);
+        }
+
+        fn symlink (&mut self, req: &super::Request, _parent: u64, _name: &Path, _link: &Path, reply: ReplyEntry) {
+            self.label(req, reply);
+        }
+    }
+
+    /// Returns security contexts as the kernel sends them after the names of a
+    /// request, with a single context of the given name and value
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    fn secctx_bytes (name: &str, value: &[u8]) -> Vec<u8> {
+        let len = mem::size_of::<fuse_secctx>() + name.len() + 1 + value.len();
+        let padded = (len + 7) & !7;
+        let header = fuse_secctx_header { size: (mem::size_of::<fuse_secctx_header>() + padded) as u32, nr_secctx: 1 };
+        let secctx = fuse_secctx { size: value.len() as u32, padding: 0 };
+        let mut buffer = Vec::new();
+        buffer.push_all(bytes_of(&header));
+        buffer.push_all(bytes_of(&secctx));
+        buffer.push_all(name.as_bytes());
+        buffer.push(0);
+        buffer.push_all(value);
+        buffer.push_all(&[0; 8][..padded - len]);
+        buffer
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    fn security_context () {
+        let mut ts = TestSession::new(LabelFs::default());
+        let label = b"unconfined_u:object_r:user_home_t:s0\0";
+        let expected = Some((OsStr::new("security.selinux").to_os_string(), label.to_vec()));
+        let arg = fuse_mkdir_in { mode: 0o755, padding: 0 };
+        let mut extra = b"dir\0".to_vec();
+        extra.push_all(&secctx_bytes("security.selinux", label));
+        ts.dispatch(&request_bytes(FUSE_MKDIR, FUSE_ROOT_ID, &arg, &extra));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.labels.pop().unwrap(), expected);
+        // The contexts of a symlink follow the name and the target
+        let mut extra = b"link\0target\0".to_vec();
+        extra.push_all(&secctx_bytes("security.selinux", label));
+        ts.dispatch(&request_bytes(FUSE_SYMLINK, FUSE_ROOT_ID, &(), &extra));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.labels.pop().unwrap(), expected);
+        // Callers without a context get a header without contexts
+        let header = fuse_secctx_header { size: mem::size_of::<fuse_secctx_header>() as u32, nr_secctx: 0 };
+        let mut extra = b"dir\0".to_vec();
+        extra.push_all(bytes_of(&header));
+        ts.dispatch(&request_bytes(FUSE_MKDIR, FUSE_ROOT_ID, &arg, &extra));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.labels.pop().unwrap(), None);
+        // Without FUSE_SECURITY_CTX, nothing follows the name
+        ts.dispatch(&request_bytes(FUSE_MKDIR, FUSE_ROOT_ID, &arg, b"dir\0"));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.labels.pop().unwrap(), None);
+        // A context that is shorter than it says is ignored
+        let mut extra = b"dir\0".to_vec();
+        let secctx = secctx_bytes("security.selinux", label);
+        extra.push_all(&secctx[..secctx.len() - 16]);
+        ts.dispatch(&request_bytes(FUSE_MKDIR, FUSE_ROOT_ID, &arg, &extra));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.labels.pop().unwrap(), None);
+    }
+
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    struct StackDepthFs {
+        max_stack_depth: u32,
+    }
+
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    impl Filesystem for StackDepthFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.want |= FUSE_PASSTHROUGH;
+            conn.max_stack_depth = self.max_stack_depth;
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    fn init_max_stack_depth () {
+        for &(max_stack_depth, granted) in [(1, true), (2, true), (0, false), (3, false)].iter() {
+            let mut ts = TestSession::uninitialized(StackDepthFs { max_stack_depth: max_stack_depth });
+            let init = ts.init(40, FUSE_INIT_EXT | FUSE_PASSTHROUGH);
+            // The depth is sent as set, but passthrough isn't granted with a depth the kernel rejects
+            assert_eq!(init.max_stack_depth, max_stack_depth);
+            assert_eq!(init.flags2 as u64 == FUSE_PASSTHROUGH >> 32, granted);
+            assert_eq!(ts.se.conn.passthrough(), granted);
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[derive(Default)]
+    struct MappingFs {
+        setup: Option<(u64, u64, u64, u64, u64, u64)>,
+        removed: Vec<Mapping>,
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    impl Filesystem for MappingFs {
+        fn setupmapping (&mut self, _req: &super::Request, ino: u64, fh: u64, foffset: u64, len: u64, flags: u64, moffset: u64, reply: ReplyEmpty) {
+            self.setup = Some((ino, fh, foffset, len, flags, moffset));
+            reply.ok();
+        }
+
+        fn removemapping (&mut self, _req: &super::Request, _ino: u64, mappings: &[Mapping], reply: ReplyEmpty) {
+            self.removed.push_all(mappings);
+            reply.ok();
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn dax_mappings () {
+        let setup = fuse_setupmapping_in { fh: 0x11, foffset: 0x1000, len: 0x200000, flags: FUSE_SETUPMAPPING_FLAG_READ | FUSE_SETUPMAPPING_FLAG_WRITE, moffset: 0x400000 };
+        let remove = fuse_removemapping_in { count: 2 };
+        let mut mappings = vec![];
+        mappings.push_all(bytes_of(&fuse_removemapping_one { moffset: 0x400000, len: 0x200000 }));
+        mappings.push_all(bytes_of(&fuse_removemapping_one { moffset: 0x800000, len: 0x1000 }));
+        // Not dispatched without FUSE_MAP_ALIGNMENT
+        let mut ts = TestSession::new(MappingFs::default());
+        ts.dispatch(&request_bytes(FUSE_SETUPMAPPING, 0x22, &setup, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        ts.dispatch(&request_bytes(FUSE_REMOVEMAPPING, 0x22, &remove, &mappings));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.setup, None);
+        assert!(ts.se.filesystem.removed.is_empty());
+        ts.se.conn.capable |= FUSE_MAP_ALIGNMENT;
+        ts.se.conn.want |= FUSE_MAP_ALIGNMENT;
+        ts.dispatch(&request_bytes(FUSE_SETUPMAPPING, 0x22, &setup, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.setup, Some((0x22, 0x11, 0x1000, 0x200000, FUSE_SETUPMAPPING_FLAG_READ | FUSE_SETUPMAPPING_FLAG_WRITE, 0x400000)));
+        ts.dispatch(&request_bytes(FUSE_REMOVEMAPPING, 0x22, &remove, &mappings));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(&ts.se.filesystem.removed[..], &[
+            Mapping { moffset: 0x400000, len: 0x200000 },
+            Mapping { moffset: 0x800000, len: 0x1000 },
+        ][..]);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[derive(Default)]
+    struct SyncFs {
+        synced: usize,
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    impl Filesystem for SyncFs {
+        fn syncfs (&mut self, _req: &super::Request, reply: ReplyEmpty) {
+            self.synced += 1;
+            reply.ok();
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn syncfs () {
+        let arg = fuse_syncfs_in { padding: 0 };
+        // Kernels before ABI 7.34 don't have the operation
+        let mut ts = TestSession::new(SyncFs::default());
+        ts.se.conn.proto_minor = 33;
+        ts.dispatch(&request_bytes(FUSE_SYNCFS, FUSE_ROOT_ID, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.synced, 0);
+        // Newer kernels have it, whatever ABI version this library is built for
+        let mut ts = TestSession::uninitialized(SyncFs::default());
+        ts.init(34, FUSE_ASYNC_READ);
+        assert!(ts.se.conn.syncfs());
+        ts.dispatch(&request_bytes(FUSE_SYNCFS, FUSE_ROOT_ID, &arg, &[]));
+        // The reply is empty and successful
+        let reply = ts.receive();
+        assert_eq!(reply.len(), mem::size_of::<fuse_out_header>());
+        assert_eq!(&reply[4..8], &[0u8, 0, 0, 0][..]);
+        assert_eq!(ts.se.filesystem.synced, 1);
+    }
+
+    #[test]
+    fn init_old_kernel () {
+        let mut ts = TestSession::uninitialized(WantFs { want: FUSE_DO_READDIRPLUS, unwant: 0 });
+        let arg = fuse_init_in { major: 7, minor: 8, max_readahead: 0, flags: FUSE_ASYNC_READ as u32 };
+        ts.dispatch(&request_bytes(FUSE_INIT, 0, &arg, &[]));
+        // The init reply has the size the old kernel expects
+        let buffer = ts.receive();
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + FUSE_COMPAT_22_INIT_OUT_SIZE);
+        let out: &fuse_out_header = unsafe { mem::transmute(buffer.as_ptr()) };
+        assert_eq!(out.len as usize, buffer.len());
+        assert_eq!(ts.se.conn.negotiated_minor(), 8);
+        // Readdirplus and rename2 are newer than the kernel, they're unsupported
+        assert!(ts.se.conn.supports(FUSE_ASYNC_READ));
+        assert!(!ts.se.conn.supports(FUSE_DO_READDIRPLUS));
+        assert!(!ts.se.conn.readdirplus());
+        assert!(!ts.se.conn.rename2());
+        // A kernel of the same version gets the full init reply
+        let mut ts = TestSession::uninitialized(WantFs { want: FUSE_DO_READDIRPLUS, unwant: 0 });
+        let arg = fuse_init_in { major: 7, minor: FUSE_KERNEL_MINOR_VERSION, max_readahead: 0, flags: (FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS) as u32 };
+        ts.dispatch(&request_bytes(FUSE_INIT, 0, &arg, &[]));
+        let buffer = ts.receive();
+        assert_eq!(ts.se.conn.negotiated_minor(), FUSE_KERNEL_MINOR_VERSION);
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>());
+        // Readdirplus can only be granted if this library dispatches it
+        assert_eq!(ts.se.conn.supports(FUSE_DO_READDIRPLUS), cfg!(feature = "abi-7-21"));
+        assert_eq!(ts.se.conn.readdirplus(), cfg!(feature = "abi-7-21"));
+    }
+
+    #[test]
+    fn init_unsupported_major () {
+        let mut ts = TestSession::uninitialized(InitFs::default());
+        let arg = fuse_init_in { major: 6, minor: 0, max_readahead: 0, flags: 0 };
+        ts.dispatch(&request_bytes(FUSE_INIT, 0, &arg, &[]));
+        assert_eq!(ts.receive_error(), EPROTO);
+        assert!(!ts.se.filesystem.initialized);
+        assert!(!ts.se.initialized);
+        assert!(ts.se.destroyed);
+        // Nothing is dispatched after the rejected init
+        ts.dispatch(&request_bytes(FUSE_GETATTR, 0x22, &(), &[]));
+        assert_eq!(ts.receive_error(), EIO);
+    }
+
+    #[test]
+    fn request_deadline () {
+        let mut ts = TestSession::new(DeadlineFs::default());
+        let arg = fuse_access_in { mask: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_ACCESS, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.deadline, Some((false, false)));
+        ts.se.operation_timeout = Some(Duration::seconds(60));
+        ts.dispatch(&request_bytes(FUSE_ACCESS, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.deadline, Some((true, false)));
+        ts.se.operation_timeout = Some(Duration::zero());
+        ts.dispatch(&request_bytes(FUSE_ACCESS, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.deadline, Some((true, true)));
+    }
+
+    #[test]
+    fn open_truncate () {
+        let mut ts = TestSession::new(TruncateFs::default());
+        // FUSE_ATOMIC_O_TRUNC isn't granted by default, so the kernel opens the file first...
+        assert!(!ts.se.conn.atomic_o_trunc());
+        let flags = (O_WRONLY | O_TRUNC) as u32;
+        ts.dispatch(&request_bytes(FUSE_OPEN, 0x22, &fuse_open_in { flags: flags, mode: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.open_flags, Some(flags));
+        // ...and then truncates it with a setattr request that sets the size to 0
+        let mut arg: fuse_setattr_in = unsafe { mem::zeroed() };
+        arg.valid = FATTR_SIZE | FATTR_FH;
+        arg.size = 0;
+        arg.fh = 0x11;
+        ts.dispatch(&request_bytes(FUSE_SETATTR, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert_eq!(ts.se.filesystem.setattr_size, Some(0));
+        assert_eq!(ts.se.filesystem.setattr_fh, Some(0x11));
+    }
+
+    #[test]
+    fn init_atomic_o_trunc () {
+        let mut ts = TestSession::uninitialized(TruncateFs { atomic_o_trunc: true, ..TruncateFs::default() });
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC);
+        assert!(ts.se.conn.atomic_o_trunc());
+        // The kernel passes O_TRUNC to open and doesn't send a setattr afterwards
+        let flags = (O_WRONLY | O_TRUNC) as u32;
+        ts.dispatch(&request_bytes(FUSE_OPEN, 0x22, &fuse_open_in { flags: flags, mode: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.open_flags, Some(flags));
+        assert_eq!(ts.se.filesystem.setattr_size, None);
+        // Not granted if the filesystem doesn't want it
+        let mut ts = TestSession::uninitialized(TruncateFs::default());
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ);
+        assert!(!ts.se.conn.atomic_o_trunc());
+        // Not granted if the kernel doesn't offer it, even if the filesystem wants it
+        let mut ts = TestSession::uninitialized(TruncateFs { atomic_o_trunc: true, ..TruncateFs::default() });
+        let init = ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ);
+        assert_eq!(init.flags as u64, FUSE_ASYNC_READ);
+        assert!(!ts.se.conn.atomic_o_trunc());
+    }
+
+    #[test]
+    #[cfg(feature = "examples")]
+    fn open_truncate_memfs () {
+        use examples::MemFs;
+        // MemFs wants FUSE_ATOMIC_O_TRUNC, so an open with O_TRUNC truncates the file
+        let mut ts = TestSession::uninitialized(MemFs::new());
+        ts.init(FUSE_KERNEL_MINOR_VERSION, FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC);
+        assert!(ts.se.conn.atomic_o_trunc());
+        let flags = (O_WRONLY | O_CREAT) as u32;
+        #[cfg(not(feature = "abi-7-12"))]
+        let arg = fuse_open_in { flags: flags, mode: S_IFREG as u32 | 0o644 };
+        #[cfg(feature = "abi-7-12")]
+        let arg = fuse_create_in { flags: flags, mode: S_IFREG as u32 | 0o644, umask: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_CREATE, FUSE_ROOT_ID, &arg, b"log\0"));
+        let buffer = ts.receive();
+        let entry: &fuse_entry_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        let open: &fuse_open_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_entry_out>()..].as_ptr()) };
+        let (ino, fh) = (entry.nodeid, open.fh);
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.size = 11;
+        ts.dispatch(&request_bytes(FUSE_WRITE, ino, &arg, b"hello world"));
+        assert_eq!(ts.receive_error(), 0);
+        let mut arg: fuse_release_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        ts.dispatch(&request_bytes(FUSE_RELEASE, ino, &arg, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        // Only the open with O_TRUNC empties it, there's no setattr to do that
+        let sizes = [(O_WRONLY, 11), (O_WRONLY | O_TRUNC, 0)];
+        for &(flags, size) in sizes.iter() {
+            ts.dispatch(&request_bytes(FUSE_OPEN, ino, &fuse_open_in { flags: flags as u32, mode: 0 }, &[]));
+            assert_eq!(ts.receive_error(), 0);
+            ts.dispatch(&request_bytes(FUSE_LOOKUP, FUSE_ROOT_ID, &(), b"log\0"));
+            let buffer = ts.receive();
+            let entry: &fuse_entry_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+            assert_eq!(entry.attr.size, size);
+        }
+    }
+
+    #[derive(Default)]
+    struct XattrFs {
+        set: Option<(OsString, Vec<u8>, u32, u32)>,
+    }
+
+    impl Filesystem for XattrFs {
+        fn setxattr (&mut self, _req: &super::Request, _ino: u64, name: &OsStr, value: &[u8], flags: u32, _position: u32, setxattr_flags: u32, reply: ReplyEmpty) {
+            self.set = Some((name.to_os_string(), value.to_vec(), flags, setxattr_flags));
+            reply.ok();
+        }
+
+        fn getxattr (&mut self, _req: &super::Request, _ino: u64, name: &OsStr, _size: u32, reply: ReplyXattr) {
+            match self.set {
+                Some((ref set_name, ref value, _, _)) if &**set_name == name => reply.data(value),
+                _ => reply.error(ENOATTR),
+            }
+        }
+
+        fn removexattr (&mut self, _req: &super::Request, _ino: u64, name: &OsStr, reply: ReplyEmpty) {
+            match self.set {
+                Some((ref set_name, _, _, _)) if &**set_name == name => reply.ok(),
+                _ => reply.error(ENOATTR),
+            }
+        }
+    }
+
+    #[test]
+    fn xattr_missing () {
+        let mut ts = TestSession::new(XattrFs::default());
+        let mut arg: fuse_getxattr_in = unsafe { mem::zeroed() };
+        arg.size = 64;
+        ts.dispatch(&request_bytes(FUSE_GETXATTR, 0x22, &arg, b"user.missing\0"));
+        #[cfg(target_os = "linux")]
+        assert_eq!(ts.receive_error(), ::libc::ENODATA);
+        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        assert_eq!(ts.receive_error(), ::libc::ENOATTR);
+        ts.dispatch(&request_bytes(FUSE_REMOVEXATTR, 0x22, &(), b"user.missing\0"));
+        assert_eq!(ts.receive_error(), ENOATTR);
+    }
+
+    #[test]
+    fn setxattr_legacy () {
+        let mut ts = TestSession::new(XattrFs::default());
+        let mut arg: fuse_setxattr_in = unsafe { mem::zeroed() };
+        arg.size = 5;
+        arg.flags = 1;
+        ts.dispatch(&request_bytes(FUSE_SETXATTR, 0x22, &arg, b"user.a\0hello"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.set, Some((OsStr::new("user.a").to_os_string(), b"hello".to_vec(), 1, 0)));
+    }
+
+    #[test]
+    fn setxattr_truncated () {
+        let mut ts = TestSession::new(XattrFs::default());
+        let mut arg: fuse_setxattr_in = unsafe { mem::zeroed() };
+        arg.size = 5;
+        ts.dispatch(&request_bytes(FUSE_SETXATTR, 0x22, &arg, b"user.a\0hel"));
+        assert_eq!(ts.receive_error(), EIO);
+        assert_eq!(ts.se.filesystem.set, None);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn setxattr_ext () {
+        let mut ts = TestSession::new(XattrFs::default());
+        ts.se.conn.capable |= FUSE_SETXATTR_EXT;
+        ts.se.conn.want |= FUSE_SETXATTR_EXT;
+        let arg = fuse_setxattr_ext_in { size: 5, flags: 2, setxattr_flags: FUSE_SETXATTR_ACL_KILL_SGID, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_SETXATTR, 0x22, &arg, b"user.a\0hello"));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.set, Some((OsStr::new("user.a").to_os_string(), b"hello".to_vec(), 2, FUSE_SETXATTR_ACL_KILL_SGID)));
+    }
+
+    #[derive(Default)]
+    struct NoGetxattrFs {
+        getxattr_called: bool,
+    }
+
+    impl Filesystem for NoGetxattrFs {
+        fn init (&mut self, _req: &super::Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.disable(FUSE_GETXATTR);
+            conn.disable(FUSE_FORGET);
+            Ok(())
+        }
+
+        fn getxattr (&mut self, _req: &super::Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+            self.getxattr_called = true;
+            reply.size(5);
+        }
+    }
+
+    #[test]
+    fn disabled_op () {
+        let mut ts = TestSession::uninitialized(NoGetxattrFs::default());
+        ts.init(FUSE_KERNEL_MINOR_VERSION, 0);
+        assert!(!ts.se.conn.enabled(FUSE_GETXATTR));
+        assert!(ts.se.conn.enabled(FUSE_LISTXATTR));
+        // Operations without a reply can't be disabled
+        assert!(ts.se.conn.enabled(FUSE_FORGET));
+        let arg: fuse_getxattr_in = unsafe { mem::zeroed() };
+        ts.dispatch(&request_bytes(FUSE_GETXATTR, 0x22, &arg, b"user.a\0"));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        assert!(!ts.se.filesystem.getxattr_called);
+    }
+
+    #[cfg(feature = "abi-7-21")]
+    #[derive(Default)]
+    struct DirPlusFs {
+        readdirplus: Option<(u64, u64, u64)>,
+    }
+
+    #[cfg(feature = "abi-7-21")]
+    impl Filesystem for DirPlusFs {
+        fn readdirplus (&mut self, _req: &super::Request, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectoryPlus) {
+            self.readdirplus = Some((ino, fh, offset));
+            let ttl = Timespec::new(1, 0);
+            reply.add(0x33, 1, "a", &FileAttr::for_file(0x33, 0), 0, &ttl, &ttl);
+            // Doesn't fit into the requested size
+            reply.add(0x44, 2, "b", &FileAttr::for_file(0x44, 0), 0, &ttl, &ttl);
+            reply.ok();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus () {
+        let mut ts = TestSession::new(DirPlusFs::default());
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.fh = 0x12;
+        arg.offset = 0;
+        arg.size = 200;
+        ts.dispatch(&request_bytes(FUSE_READDIRPLUS, 0x22, &arg, &[]));
+        let buffer = ts.receive();
+        assert_eq!(buffer.len(), mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_direntplus>() + 8);
+        assert_eq!(ts.se.filesystem.readdirplus, Some((0x22, 0x12, 0)));
+    }
+
+    #[derive(Default)]
+    struct CreateFs {
+        created: Option<(u32, u32, u32)>,
+    }
+
+    impl Filesystem for CreateFs {
+        fn create (&mut self, _req: &super::Request, _parent: u64, _name: &Path, mode: u32, umask: u32, flags: u32, reply: ReplyCreate) {
+            self.created = Some((mode, umask, flags));
+            reply.error(EEXIST);
+        }
+    }
+
+    #[test]
+    fn create_mode_and_flags () {
+        let mut ts = TestSession::new(CreateFs::default());
+        let mode = S_IFREG as u32 | 0o644;
+        let flags = (O_WRONLY | O_CREAT) as u32;
+        #[cfg(not(feature = "abi-7-12"))]
+        let (arg, umask) = (fuse_open_in { flags: flags, mode: mode }, 0);
+        #[cfg(feature = "abi-7-12")]
+        let (arg, umask) = (fuse_create_in { flags: flags, mode: mode, umask: 0o022, padding: 0 }, 0o022);
+        ts.dispatch(&request_bytes(FUSE_CREATE, 0x01, &arg, b"new\0"));
+        assert_eq!(ts.receive_error(), EEXIST);
+        assert_eq!(ts.se.filesystem.created, Some((mode, umask, flags)));
+    }
+
+    #[test]
+    fn read_only () {
+        let mut ts = TestSession::new(TruncateFs::default());
+        ts.se.conn.read_only = true;
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.size = 5;
+        ts.dispatch(&request_bytes(FUSE_WRITE, 0x22, &arg, b"hello"));
+        assert_eq!(ts.receive_error(), EROFS);
+        #[cfg(not(feature = "abi-7-12"))]
+        let arg = fuse_open_in { flags: O_WRONLY as u32, mode: 0o644 };
+        #[cfg(feature = "abi-7-12")]
+        let arg = fuse_create_in { flags: O_WRONLY as u32, mode: 0o644, umask: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_CREATE, 0x01, &arg, b"new\0"));
+        assert_eq!(ts.receive_error(), EROFS);
+        // Opening for writing is rejected, opening for reading still works
+        ts.dispatch(&request_bytes(FUSE_OPEN, 0x22, &fuse_open_in { flags: O_WRONLY as u32, mode: 0 }, &[]));
+        assert_eq!(ts.receive_error(), EROFS);
+        assert_eq!(ts.se.filesystem.open_flags, None);
+        ts.dispatch(&request_bytes(FUSE_OPEN, 0x22, &fuse_open_in { flags: 0, mode: 0 }, &[]));
+        assert_eq!(ts.receive_error(), 0);
+        assert_eq!(ts.se.filesystem.open_flags, Some(0));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn read_only_tmpfile () {
+        let mut ts = TestSession::new(TmpFs::default());
+        ts.se.conn.read_only = true;
+        #[cfg(not(feature = "abi-7-12"))]
+        let arg = fuse_open_in { flags: O_WRONLY as u32, mode: 0o644 };
+        #[cfg(feature = "abi-7-12")]
+        let arg = fuse_create_in { flags: O_WRONLY as u32, mode: 0o644, umask: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_TMPFILE, 0x01, &arg, &[]));
+        assert_eq!(ts.receive_error(), EROFS);
+        assert_eq!(ts.se.filesystem.created, None);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-15")]
+    fn notify_reply_completes_retrieve () {
+        let mut ts = TestSession::new(TruncateFs::default());
+        let rx = ts.se.notifier().unwrap().retrieve(0x22, 0x1000, 16).unwrap();
+        let buffer = ts.receive();
+        let out: &fuse_notify_retrieve_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        assert_eq!(out.nodeid, 0x22);
+        // The kernel replies with the cached data, using the unique id of the notification
+        let arg = fuse_notify_retrieve_in { dummy1: 0, offset: 0x1000, size: 5, dummy2: 0, dummy3: 0, dummy4: 0 };
+        let mut bytes = request_bytes(FUSE_NOTIFY_REPLY, 0x22, &arg, b"hello");
+        for (b, u) in bytes[8..16].iter_mut().zip(bytes_of(&out.notify_unique).iter()) { *b = *u; }
+        ts.dispatch(&bytes);
+        let retrieved = rx.recv().unwrap();
+        assert_eq!(retrieved.offset, 0x1000);
+        assert_eq!(retrieved.data, b"hello");
+        assert_eq!(ts.se.retrieves.waiting(), 0);
+    }
+
+    #[test]
+    fn unimplemented_fsync () {
+        // The kernel only disables fsync if it gets ENOSYS back
+        let mut ts = TestSession::new(TruncateFs::default());
+        let arg = fuse_fsync_in { fh: 0x11, fsync_flags: 0, padding: 0 };
+        ts.dispatch(&request_bytes(FUSE_FSYNC, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        let arg = fuse_flush_in { fh: 0x11, unused: 0, padding: 0, lock_owner: 0 };
+        ts.dispatch(&request_bytes(FUSE_FLUSH, 0x22, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+    }
+
+    #[test]
+    fn request_nodeid_and_debug () {
+        let ts = TestSession::new(TruncateFs::default());
+        let bytes = request_bytes(FUSE_GETATTR, 0x22, &(), &[]);
+        let req = request(ts.sender, &bytes, None, &Arc::new(Inflight::new()), "fuse::mount::test").unwrap();
+        assert_eq!(req.nodeid(), 0x22);
+        assert_eq!(req.log_target(), "fuse::mount::test");
+        assert_eq!(format!("{:?}", req), "Request { unique: 3735928559, opcode: 3, nodeid: 0x0000000000000022, uid: 4096, gid: 4096, pid: 4660 }");
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[derive(Default)]
+    struct TmpFs {
+        created: Option<(u64, u32, u32, u32)>,
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    impl Filesystem for TmpFs {
+        fn tmpfile (&mut self, _req: &super::Request, parent: u64, mode: u32, umask: u32, flags: u32, reply: ReplyCreate) {
+            self.created = Some((parent, mode, umask, flags));
+            let mut attr = FileAttr::for_file(0x33, 0);
+            attr.nlink = 0;
+            reply.created(&Timespec::new(1, 0), &attr, 0, 0x44, 0);
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn tmpfile () {
+        let flags = O_WRONLY as u32;
+        #[cfg(not(feature = "abi-7-12"))]
+        let arg = fuse_open_in { flags: flags, mode: S_IFREG as u32 | 0o600 };
+        #[cfg(feature = "abi-7-12")]
+        let arg = fuse_create_in { flags: flags, mode: S_IFREG as u32 | 0o600, umask: 0o022, padding: 0 };
+        // Without an implementation, the kernel falls back
+        let mut ts = TestSession::new(EmptyFs);
+        ts.dispatch(&request_bytes(FUSE_TMPFILE, FUSE_ROOT_ID, &arg, &[]));
+        assert_eq!(ts.receive_error(), ENOSYS);
+        let mut ts = TestSession::new(TmpFs::default());
+        ts.dispatch(&request_bytes(FUSE_TMPFILE, FUSE_ROOT_ID, &arg, &[]));
+        let buffer = ts.receive();
+        let entry: &fuse_entry_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>()..].as_ptr()) };
+        let open: &fuse_open_out = unsafe { mem::transmute(buffer[mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_entry_out>()..].as_ptr()) };
+        assert_eq!((entry.nodeid, entry.attr.nlink, open.fh), (0x33, 0, 0x44));
+        let umask = if cfg!(feature = "abi-7-12") { 0o022 } else { 0 };
+        assert_eq!(ts.se.filesystem.created, Some((FUSE_ROOT_ID, S_IFREG as u32 | 0o600, umask, flags)));
+        // The opened handle counts like the ones of create
+        assert_eq!(ts.se.open_handles.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn release_handle_concurrently () {
+        let handles = Arc::new(AtomicUsize::new(1));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let handles = handles.clone();
+            thread::spawn(move || release_handle(&handles))
+        }).collect();
+        for releaser in threads {
+            releaser.join().unwrap();
+        }
+        // Releases of handles that were never counted don't underflow
+        assert_eq!(handles.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn request_length_mismatch () {
+        let ts = TestSession::new(TruncateFs::default());
+        let inflight = Arc::new(Inflight::new());
+        let bytes = request_bytes(FUSE_GETATTR, 0x22, &0u64, &[]);
+        assert!(request(ts.sender, &bytes, None, &inflight, "fuse::test").is_some());
+        // The header claims more bytes than were read
+        assert!(request(ts.sender, &bytes[..bytes.len() - 4], None, &inflight, "fuse::test").is_none());
+        // The header claims fewer bytes than were read
+        let mut longer = bytes.clone();
+        longer.push_all(&[0; 8]);
+        assert!(request(ts.sender, &longer, None, &inflight, "fuse::test").is_none());
+        // The header claims less than its own size
+        let mut bogus = request_bytes(FUSE_GETATTR, 0x22, &(), &[]);
+        bogus[0] = 8;
+        assert!(request(ts.sender, &bogus, None, &inflight, "fuse::test").is_none());
+    }
+
+    /// Filesystem that succeeds with all operations that reply with nothing
+    struct EmptyFs;
+
+    impl Filesystem for EmptyFs {
+        fn unlink (&mut self, _req: &super::Request, _parent: u64, _name: &Path, reply: ReplyEmpty) { reply.ok(); }
+        fn rmdir (&mut self, _req: &super::Request, _parent: u64, _name: &Path, reply: ReplyEmpty) { reply.ok(); }
+        fn rename (&mut self, _req: &super::Request, _parent: u64, _name: &Path, _newparent: u64, _newname: &Path, reply: ReplyEmpty) { reply.ok(); }
+        fn flush (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) { reply.ok(); }
+        fn release (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) { reply.ok(); }
+        fn fsync (&mut self, _req: &super::Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) { reply.ok(); }
+        fn setxattr (&mut self, _req: &super::Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, _setxattr_flags: u32, reply: ReplyEmpty) { reply.ok(); }
+        fn removexattr (&mut self, _req: &super::Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) { reply.ok(); }
+        fn access (&mut self, _req: &super::Request, _ino: u64, _mask: u32, reply: ReplyEmpty) { reply.ok(); }
+    }
+
+    #[test]
+    fn empty_replies () {
+        let mut ts = TestSession::new(EmptyFs);
+        let mut setxattr: fuse_setxattr_in = unsafe { mem::zeroed() };
+        setxattr.size = 5;
+        let requests = vec![
+            request_bytes(FUSE_UNLINK, 1, &(), b"foo\0"),
+            request_bytes(FUSE_RMDIR, 1, &(), b"foo\0"),
+            request_bytes(FUSE_RENAME, 1, &fuse_rename_in { newdir: 1 }, b"foo\0bar\0"),
+            request_bytes(FUSE_FLUSH, 2, &unsafe { mem::zeroed::<fuse_flush_in>() }, &[]),
+            request_bytes(FUSE_RELEASE, 2, &unsafe { mem::zeroed::<fuse_release_in>() }, &[]),
+            request_bytes(FUSE_FSYNC, 2, &unsafe { mem::zeroed::<fuse_fsync_in>() }, &[]),
+            request_bytes(FUSE_SETXATTR, 2, &setxattr, b"user.foo\0hello"),
+            request_bytes(FUSE_REMOVEXATTR, 2, &(), b"user.foo\0"),
+            request_bytes(FUSE_ACCESS, 2, &unsafe { mem::zeroed::<fuse_access_in>() }, &[]),
+        ];
+        for bytes in requests {
+            ts.dispatch(&bytes);
+            // A header of its own length, without data and without an error
+            assert_eq!(&ts.receive()[..], &[0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00][..]);
+        }
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    #[derive(Default)]
+    struct PollFs {
+        handles: HashMap<u64, u64>,
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    impl Filesystem for PollFs {
+        fn poll (&mut self, _req: &super::Request, _ino: u64, fh: u64, kh: u64, flags: u32, _events: u32, reply: ReplyPoll) {
+            if flags & FUSE_POLL_SCHEDULE_NOTIFY != 0 {
+                self.handles.insert(fh, kh);
+            }
+            reply.poll(0);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn poll () {
+        let mut ts = TestSession::new(PollFs::default());
+        let mut arg: fuse_poll_in = unsafe { mem::zeroed() };
+        arg.fh = 0x11;
+        arg.kh = 0x22;
+        arg.flags = FUSE_POLL_SCHEDULE_NOTIFY;
+        ts.dispatch(&request_bytes(FUSE_POLL, 2, &arg, &[]));
+        let reply = ts.receive();
+        assert_eq!(reply.len(), mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_poll_out>());
+        assert_eq!(ts.se.filesystem.handles.get(&0x11), Some(&0x22));
+    }
 }
@@ -7,14 +7,30 @@
 //!
 
 use std::io;
+use std::io::Read;
 use std::ffi::OsStr;
+use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::path::{PathBuf, Path};
+use std::rt::unwind;
+use std::thread;
 use std::thread::{Builder, JoinGuard};
-use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
+use libc::{c_int, c_void, size_t, EAGAIN, ECONNABORTED, EINTR, ENODEV, ENOENT};
+use time::{self, Duration};
 use channel;
-use channel::Channel;
+use channel::{Channel, UnmountMode};
+use connection::ConnectionInfo;
+#[cfg(target_os = "linux")]
+use control::ConnectionControl;
+#[cfg(target_os = "linux")]
+use mount_ns::unshare_mount_namespace;
+use Error;
 use Filesystem;
-use request::{request, dispatch};
+use notify::{Notifier, Retrieves};
+#[cfg(target_os = "linux")]
+use passthrough::BackingFiles;
+use request::{Inflight, Request, request, dispatch, destroy};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on OS X
@@ -23,7 +39,7 @@ pub const MAX_WRITE_SIZE: usize = 16*1024*1024;
 
 /// Size of the buffer for reading a request from the kernel. Since the kernel may send
 /// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+pub const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
 
 /// The session data structure
 pub struct Session<FS: Filesystem> {
@@ -35,68 +51,498 @@ pub struct Session<FS: Filesystem> {
     pub proto_major: u32,
     /// FUSE protocol minor version
     pub proto_minor: u32,
+    /// Connection parameters, negotiated with the kernel during init
+    pub conn: ConnectionInfo,
     /// True if the filesystem is initialized (init operation done)
     pub initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// Default timeout for operations. If set, every request gets a deadline
+    /// that the filesystem can consult (see Request::deadline). The session loop
+    /// dispatches one request at a time and can't interrupt a filesystem method that
+    /// blocks, so for ordinary filesystems the deadline is cooperative. Operations of
+    /// an AsyncFilesystem (run by an AsyncDispatcher) that didn't finish by their
+    /// deadline are replied with ETIMEDOUT automatically.
+    pub operation_timeout: Option<Duration>,
+    /// If set, the session ends (and the filesystem is unmounted) when no request
+    /// arrived for this long and no files or directories are open. See run.
+    pub idle_timeout: Option<Duration>,
+    /// Number of file and directory handles that were opened successfully (by
+    /// open, opendir or create) and are not released yet
+    pub open_handles: Arc<AtomicUsize>,
+    /// Retrieve notifications sent by notifiers of this session that wait for
+    /// the kernel's reply
+    pub retrieves: Retrieves,
+    /// If set, a panic in a filesystem method doesn't end the session. The request
+    /// gets an EIO reply (if it wasn't replied yet), the panic is logged and the
+    /// session continues with the next request. See run.
+    pub catch_panics: bool,
+    /// If set, the session waits up to this long for requests that were dispatched,
+    /// but not replied yet (e.g. by other threads), when the session loop ends. See
+    /// drain.
+    pub shutdown_timeout: Option<Duration>,
+    /// Target of the log messages of this session (see set_label). Defaults to
+    /// fuse::mount::<name>, where name is the last component of the mountpoint,
+    /// so that e.g. RUST_LOG=fuse::mount::myfs=debug only logs the mount at .../myfs
+    pub log_target: String,
+    /// Requests that were dispatched, but not replied yet
+    inflight: Arc<Inflight>,
+    /// Private mount namespace the filesystem is mounted in (see new_in_namespace)
+    mount_namespace: Option<File>,
 }
 
 impl<FS: Filesystem> Session<FS> {
-    /// Create a new session by mounting the given filesystem to the given mountpoint
+    /// Create a new session by mounting the given filesystem to the given mountpoint.
+    /// Panics if mounting fails (see try_new).
     pub fn new (filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> Session<FS> {
-        info!("Mounting {}", mountpoint.display());
-        let ch = match Channel::new(mountpoint, options) {
-            Ok(ch) => ch,
+        match Session::try_new(filesystem, mountpoint, options) {
+            Ok(se) => se,
             Err(err) => panic!("Unable to mount filesystem. Error {}", err),
-        };
+        }
+    }
+
+    /// Create a new session by mounting the given filesystem to the given mountpoint.
+    /// Returns Error::Mount if mounting fails.
+    pub fn try_new (filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> Result<Session<FS>, Error> {
+        let log_target = mount_log_target(mountpoint);
+        info!(target: &log_target, "Mounting {}", mountpoint.display());
+        let ch = try!(Channel::new(mountpoint, options).map_err(Error::Mount));
+        Ok(Session {
+            filesystem: filesystem,
+            ch: ch,
+            proto_major: 0,
+            proto_minor: 0,
+            conn: ConnectionInfo::new(options),
+            initialized: false,
+            destroyed: false,
+            operation_timeout: None,
+            idle_timeout: None,
+            open_handles: Arc::new(AtomicUsize::new(0)),
+            retrieves: Retrieves::new(),
+            catch_panics: false,
+            shutdown_timeout: None,
+            log_target: log_target,
+            inflight: Arc::new(Inflight::new()),
+            mount_namespace: None,
+        })
+    }
+
+    /// Create a new session for the given filesystem that uses the given channel
+    /// instead of mounting (used for replaying and testing with an unmounted channel)
+    pub fn with_channel (filesystem: FS, ch: Channel) -> Session<FS> {
         Session {
             filesystem: filesystem,
             ch: ch,
             proto_major: 0,
             proto_minor: 0,
+            conn: ConnectionInfo::new(&[]),
             initialized: false,
             destroyed: false,
+            operation_timeout: None,
+            idle_timeout: None,
+            open_handles: Arc::new(AtomicUsize::new(0)),
+            retrieves: Retrieves::new(),
+            catch_panics: false,
+            shutdown_timeout: None,
+            log_target: "fuse::mount".to_string(),
+            inflight: Arc::new(Inflight::new()),
+            mount_namespace: None,
         }
     }
 
+    /// Create a new session by mounting the given filesystem to the given mountpoint
+    /// in a new mount namespace (Linux only). The calling thread is moved into the
+    /// namespace before mounting, so the mount (and the mount helper, which runs as
+    /// a child process) ends up in it. Only the calling thread and the threads and
+    /// processes it starts afterwards (like the thread of spawn) see the mount, the
+    /// rest of the system (including other threads of this process) doesn't. See
+    /// unshare_mount_namespace about the privileges this needs and rootless. Like
+    /// try_new, this returns Error::Mount if creating the namespace or mounting fails.
+    #[cfg(target_os = "linux")]
+    pub fn new_in_namespace (filesystem: FS, mountpoint: &Path, options: &[&OsStr], rootless: bool) -> Result<Session<FS>, Error> {
+        let log_target = mount_log_target(mountpoint);
+        let namespace = try!(unshare_mount_namespace(rootless).map_err(Error::Mount));
+        info!(target: &log_target, "Mounting {} in a new mount namespace", mountpoint.display());
+        let ch = try!(Channel::new(mountpoint, options).map_err(Error::Mount));
+        let mut se = Session::with_channel(filesystem, ch);
+        se.conn = ConnectionInfo::new(options);
+        se.log_target = log_target;
+        se.mount_namespace = Some(namespace);
+        Ok(se)
+    }
+
+    /// Returns the private mount namespace the filesystem is mounted in, if it was
+    /// mounted with new_in_namespace. Other threads can join it with setns(2) on the
+    /// file to access the mount.
+    pub fn mount_namespace (&self) -> Option<&File> {
+        self.mount_namespace.as_ref()
+    }
+
+    /// Label the log messages of this session with the given label instead of the
+    /// mountpoint's name. Messages then use the target fuse::mount::<label>, which
+    /// tells apart several mounts with the same mountpoint name.
+    pub fn set_label (&mut self, label: &str) {
+        self.log_target = format!("fuse::mount::{}", label);
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint (&self) -> &Path {
         &self.ch.mountpoint()
     }
 
+    /// Returns the control files of the connection to the kernel (Linux only), to
+    /// read and change max_background and congestion_threshold while mounted
+    #[cfg(target_os = "linux")]
+    pub fn connection_control (&self) -> io::Result<ConnectionControl> {
+        ConnectionControl::for_mountpoint(self.mountpoint())
+    }
+
+    /// Returns the number of requests that were dispatched to the filesystem, but
+    /// not replied yet (e.g. because the filesystem replies asynchronously from
+    /// another thread). A number that keeps growing hints at operations that never
+    /// finish. Requests without a reply (like forget) aren't counted.
+    pub fn inflight (&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// Wait up to the given time until all dispatched requests are replied. Returns
+    /// true if none are left. Used when the session loop ends (see shutdown_timeout),
+    /// so that replies that other threads are still working on reach the kernel
+    /// before the filesystem is destroyed and unmounted. Requests that are still
+    /// unreplied after the timeout fail: a reply that is dropped sends EIO, and once
+    /// the session is dropped, the kernel fails the remaining requests of the
+    /// connection (their replies are lost). Draining doesn't help if the connection
+    /// already ended (the filesystem was unmounted or aborted), since the kernel
+    /// then failed all outstanding requests itself.
+    pub fn drain (&self, timeout: Duration) -> bool {
+        let deadline = time::get_time() + timeout;
+        while self.inflight() > 0 {
+            if time::get_time() >= deadline {
+                return false;
+            }
+            thread::sleep_ms(1);
+        }
+        true
+    }
+
+    /// Returns a notifier that can be used to send notifications to the kernel
+    /// driver. To make the kernel resend requests that the filesystem lost track
+    /// of, use Notifier::resend. The notifier has its own duplicate of the channel's
+    /// file descriptor, so it can be cloned, sent to other threads (or stored in
+    /// the filesystem) and used independently of the session. The session loop
+    /// completes retrieves (see Notifier::retrieve) when the kernel replies.
+    ///
+    /// Notifications are written without blocking: the ones the kernel can't take
+    /// right away are queued by the notifier. Since the duplicate shares the file
+    /// status of the channel, this switches the channel to non-blocking mode, too.
+    /// The session loop waits for requests before receiving them, so it doesn't spin.
+    /// Returns Error::Channel if the file descriptor can't be duplicated.
+    pub fn notifier (&self) -> Result<Notifier, Error> {
+        let retrieves = self.retrieves.clone();
+        let ch = try!(self.ch.owned_sender());
+        try!(self.ch.set_nonblocking());
+        Ok(Notifier::with_retrieves(ch, retrieves))
+    }
+
+    /// Returns a handle to register backing files for passthrough (Linux only,
+    /// see BackingFiles and ReplyOpen::passthrough)
+    #[cfg(target_os = "linux")]
+    pub fn backing_files (&self) -> io::Result<BackingFiles> {
+        self.ch.owned_sender().map(BackingFiles::new)
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
     /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
     /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
+    /// may run concurrent by spawning threads. The loop ends when the filesystem is
+    /// unmounted or the connection is aborted, and the filesystem is destroyed.
+    ///
+    /// An administrator can abort a stuck filesystem (e.g. one that hangs in an
+    /// operation, so that unmounting blocks) by writing to the abort file of its
+    /// connection, /sys/fs/fuse/connections/<n>/abort, where n is the device number
+    /// of the mountpoint (stat -c %d). All operations then fail with ENOTCONN and the
+    /// loop ends. The mountpoint still needs to be unmounted afterwards.
+    ///
+    /// If idle_timeout is set, the loop also ends when no request arrived for that
+    /// long, unless files or directories are still open (a process might still use
+    /// them without needing a request, e.g. for reading from the page cache). The
+    /// filesystem is then unmounted when the session is dropped. If the mountpoint is
+    /// busy for other reasons (e.g. it's the current directory of a process), the
+    /// unmount fails and the mountpoint is left disconnected.
+    ///
+    /// If a filesystem method panics, the unreplied request gets an EIO reply. By
+    /// default the panic then ends the loop (and the thread running it), so that
+    /// the filesystem is unmounted. If catch_panics is set, the loop continues with
+    /// the next request instead. This keeps the filesystem mounted, but it can't undo
+    /// what the filesystem did before it panicked: its state may be inconsistent (e.g.
+    /// half updated inode tables) and mutexes it holds are poisoned.
+    ///
+    /// If shutdown_timeout is set, the session waits for unreplied requests before
+    /// it destroys the filesystem (see drain). Without it, the filesystem is destroyed
+    /// right away.
+    ///
+    /// An invalid request from the kernel ends the loop as well. An unexpected error
+    /// of the channel panics after the filesystem was destroyed (see try_run).
     pub fn run (&mut self) {
+        if let Err(Error::Channel(err)) = self.try_run() {
+            panic!("Lost connection to FUSE device. Error: {}", err);
+        }
+    }
+
+    /// Run the session loop like run, but return how it ended: Ok if the filesystem
+    /// was unmounted, the connection was aborted or the session was idle for too long,
+    /// Error::Protocol if the kernel sent an invalid request and Error::Channel if
+    /// receiving from the channel failed unexpectedly. The filesystem is destroyed in
+    /// any case.
+    pub fn try_run (&mut self) -> Result<(), Error> {
         // Buffer for receiving requests from the kernel. Only one is allocated and
         // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
+        let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+        let res = self.run_loop(&mut buffer);
+        self.finish();
+        res
+    }
+
+    /// Receive and dispatch requests until the session should end
+    fn run_loop (&mut self, buffer: &mut [u8]) -> Result<(), Error> {
         loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match request(self.ch.sender(), &buffer) {
-                    // Dispatch request
-                    Some(req) => dispatch(&req, self),
-                    // Quit loop on illegal request
-                    None => break,
+            // Wait for the next request. The channel is non-blocking once a notifier
+            // was handed out, so receiving right away could fail with EAGAIN over and
+            // over. Without an idle timeout, the wait only ends with a request.
+            let timeout = self.idle_timeout.unwrap_or(Duration::max_value());
+            match self.ch.wait(timeout) {
+                Ok(true) => (),
+                Ok(false) => if self.idle_timeout.is_some() && self.open_handles.load(Ordering::SeqCst) == 0 {
+                    info!(target: &self.log_target, "No requests for {} ms, ending session", timeout.num_milliseconds());
+                    return Ok(());
+                } else {
+                    continue;
                 },
-                Err(ref err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => panic!("Lost connection to FUSE device. Error: {}", err),
+                Err(err) => if try!(self.receive_failed(err)) {
+                    continue;
+                } else {
+                    return Ok(());
                 },
             }
+            if !try!(self.receive_and_dispatch(buffer)) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receive the next request into the given buffer and dispatch it. Returns false
+    /// if the connection ended, an error if receiving failed unexpectedly or the
+    /// request is invalid.
+    fn receive_and_dispatch (&mut self, buffer: &mut [u8]) -> Result<bool, Error> {
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read
+        match self.ch.receive_into(buffer) {
+            Ok(data) => match request(self.ch.sender(), data, self.operation_timeout, &self.inflight, &self.log_target) {
+                // Dispatch request
+                Some(req) => {
+                    if self.catch_panics {
+                        dispatch_catching_panics(&req, self);
+                    } else {
+                        dispatch(&req, self);
+                    }
+                    Ok(true)
+                },
+                // Quit loop on illegal request (the reason is logged by Request::new)
+                None => Err(Error::Protocol(format!("invalid request of {} bytes", data.len()))),
+            },
+            Err(err) => self.receive_failed(err),
+        }
+    }
+
+    /// Handle the given error of receiving from the channel. Returns true if
+    /// receiving can be retried, false if the connection ended and Error::Channel
+    /// if the error is unexpected.
+    fn receive_failed (&self, err: io::Error) -> Result<bool, Error> {
+        match retry_receive(&err) {
+            Some(true) => Ok(true),
+            Some(false) => {
+                info!(target: &self.log_target, "Connection to FUSE device ended: {}", err);
+                Ok(false)
+            },
+            None => Err(Error::Channel(err)),
         }
     }
+
+    /// End the session after the session loop ended
+    fn finish (&mut self) {
+        // Give replies that are still in the works a chance to reach the kernel
+        if let Some(timeout) = self.shutdown_timeout {
+            if !self.drain(timeout) {
+                warn!(target: &self.log_target, "{} requests still unreplied after {} ms", self.inflight(), timeout.num_milliseconds());
+            }
+        }
+        // The kernel won't reply to retrieves anymore, wake up everyone still waiting
+        self.retrieves.cancel_all();
+        // The kernel only sends a destroy request on some systems, make sure the
+        // filesystem is always destroyed when the session ends
+        let ch = self.ch.sender();
+        destroy(ch, self);
+    }
+
+    /// Replay recorded requests to the given filesystem without mounting it, e.g. to
+    /// check protocol compatibility against captured traffic or as an entry point for
+    /// fuzzing. Returns the replies the filesystem sent, in the order they were sent
+    /// (each one starting with its fuse_out_header).
+    ///
+    /// The reader provides a sequence of records. Each record is the length of a
+    /// request in bytes as a 32 bit little endian integer, followed by the request as
+    /// the kernel sends it (a fuse_in_header and the arguments, in native byte order).
+    /// Requests are dispatched like in a mounted session, so records usually start
+    /// with an init request. At the end of the reader, the filesystem is destroyed
+    /// like at the end of a session. A truncated record or a malformed request (e.g.
+    /// shorter than the length in its header) ends the replay with an InvalidInput
+    /// error. Replies sent after replay returned (e.g. from other threads) are lost.
+    pub fn replay<R: Read> (reader: R, filesystem: FS) -> io::Result<Vec<Vec<u8>>> {
+        let mut reader = reader;
+        let (ch, fd) = try!(Channel::pair());
+        let replies = channel::set_nonblocking(fd).and_then(|()| {
+            let mut se = Session::with_channel(filesystem, ch);
+            se.log_target = "fuse::replay".to_string();
+            let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+            let mut replies = vec![];
+            while let Some(record) = try!(read_record(&mut reader)) {
+                match request(se.ch.sender(), &record, se.operation_timeout, &se.inflight, &se.log_target) {
+                    Some(req) => dispatch(&req, &mut se),
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed request record")),
+                }
+                try!(receive_replies(fd, &mut buffer, &mut replies));
+            }
+            let ch = se.ch.sender();
+            destroy(ch, &mut se);
+            try!(receive_replies(fd, &mut buffer, &mut replies));
+            Ok(replies)
+        });
+        unsafe { ::libc::close(fd); }
+        replies
+    }
+}
+
+/// Read the next request record (see Session::replay) from the given reader.
+/// Returns None at the end of the reader.
+fn read_record<R: Read> (reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match try!(read_full(reader, &mut len)) {
+        0 => return Ok(None),
+        4 => (),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "truncated request record")),
+    }
+    let len = len[0] as usize | (len[1] as usize) << 8 | (len[2] as usize) << 16 | (len[3] as usize) << 24;
+    if len > BUFFER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "request record too large"));
+    }
+    let mut record = vec![0; len];
+    if try!(read_full(reader, &mut record)) < len {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "truncated request record"));
+    }
+    Ok(Some(record))
+}
+
+/// Read from the given reader until the buffer is full or the reader ends. Returns
+/// the number of bytes read.
+fn read_full<R: Read> (reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Receive all replies that wait at the given non-blocking fd
+fn receive_replies (fd: c_int, buffer: &mut [u8], replies: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    loop {
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::WouldBlock { Ok(()) } else { Err(err) };
+        }
+        replies.push(buffer[..rc as usize].to_vec());
+    }
+}
+
+/// Dispatch the given request, but catch a panic of the filesystem instead of
+/// unwinding out of the session loop. The reply of the request is dropped while
+/// unwinding, which sends an EIO reply if the request wasn't replied yet.
+fn dispatch_catching_panics<FS: Filesystem> (req: &Request, se: &mut Session<FS>) {
+    let res = unsafe { unwind::try(|| dispatch(req, se)) };
+    if res.is_err() {
+        error!(target: &se.log_target, "Filesystem panicked in {:?}, continuing with the next request", req);
+    }
+}
+
+/// Returns the default log target of a session mounted to the given mountpoint
+fn mount_log_target (mountpoint: &Path) -> String {
+    match mountpoint.file_name() {
+        Some(name) => format!("fuse::mount::{}", name.to_string_lossy()),
+        None => "fuse::mount".to_string(),
+    }
+}
+
+/// Returns Some(true) if receiving failed with the given error only temporarily
+/// and can be retried, Some(false) if the connection to the kernel ended and None
+/// if the error is unexpected (the connection is unusable then).
+pub fn retry_receive (err: &io::Error) -> Option<bool> {
+    match err.raw_os_error() {
+        // Operation interrupted. Accordingly to FUSE, this is safe to retry
+        Some(ENOENT) => Some(true),
+        // Interrupted system call, retry
+        Some(EINTR) => Some(true),
+        // Explicitly try again
+        Some(EAGAIN) => Some(true),
+        // Filesystem was unmounted or the connection was aborted, quit the loop
+        Some(ENODEV) => Some(false),
+        // Connection was aborted (newer kernels), quit the loop
+        Some(ECONNABORTED) => Some(false),
+        // Unhandled error
+        _ => None,
+    }
+}
+
+/// A session that runs under the control of a MountManager. The manager waits
+/// for requests of all its sessions and lets each session handle its requests.
+pub trait Mount {
+    /// Returns the file descriptor of the channel to wait for
+    fn fd (&self) -> c_int;
+    /// Returns the path of the mounted filesystem
+    fn mountpoint (&self) -> &Path;
+    /// Returns the target of the session's log messages (see Session::log_target)
+    fn log_target (&self) -> &str;
+    /// Receive and dispatch the next request using the given buffer. Returns false
+    /// if the session ended and an error if it failed (see Session::try_run).
+    fn receive_and_dispatch (&mut self, buffer: &mut [u8]) -> Result<bool, Error>;
+    /// End the session (wait for unreplied requests and destroy the filesystem)
+    fn finish (&mut self);
+}
+
+impl<FS: Filesystem> Mount for Session<FS> {
+    fn fd (&self) -> c_int {
+        self.ch.fd()
+    }
+
+    fn mountpoint (&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    fn log_target (&self) -> &str {
+        &self.log_target
+    }
+
+    fn receive_and_dispatch (&mut self, buffer: &mut [u8]) -> Result<bool, Error> {
+        Session::receive_and_dispatch(self, buffer)
+    }
+
+    fn finish (&mut self) {
+        Session::finish(self)
+    }
 }
 
 impl<'a, FS: Filesystem+Send+'a> Session<FS> {
@@ -108,7 +554,7 @@ impl<'a, FS: Filesystem+Send+'a> Session<FS> {
 
 impl<FS: Filesystem> Drop for Session<FS> {
     fn drop (&mut self) {
-        info!("Unmounted {}", self.mountpoint().display());
+        info!(target: &self.log_target, "Unmounted {}", self.mountpoint().display());
     }
 }
 
@@ -118,6 +564,13 @@ pub struct BackgroundSession<'a> {
     pub mountpoint: PathBuf,
     /// Thread guard of the background session
     pub guard: JoinGuard<'a, ()>,
+    /// How to unmount if the filesystem is busy when the handle is dropped, Lazy
+    /// by default. Dropping the handle waits for the session to end, so with Lazy,
+    /// it waits until the filesystem isn't busy anymore. With None, the mount stays
+    /// in place and dropping the handle blocks until it's unmounted otherwise.
+    pub busy_unmount: Option<UnmountMode>,
+    /// Target of the log messages (the log target of the session)
+    log_target: String,
 }
 
 impl<'a> BackgroundSession<'a> {
@@ -126,23 +579,474 @@ impl<'a> BackgroundSession<'a> {
     /// the filesystem is unmounted and the given session ends.
     pub fn new<FS: Filesystem+Send+'a> (se: Session<FS>) -> io::Result<BackgroundSession<'a>> {
         let mountpoint = se.mountpoint().to_path_buf();
+        let log_target = se.log_target.clone();
         let builder = Builder::new().name(format!("FUSE {}", mountpoint.display()));
         let guard = try!(builder.scoped(move || {
             let mut se = se;
-            se.run();
+            if let Err(err) = se.try_run() {
+                error!(target: &se.log_target, "Session of {} failed: {}", se.mountpoint().display(), err);
+            }
         }));
-        Ok(BackgroundSession { mountpoint: mountpoint, guard: guard })
+        Ok(BackgroundSession { mountpoint: mountpoint, guard: guard, busy_unmount: Some(UnmountMode::Lazy), log_target: log_target })
+    }
+}
+
+impl<'a> BackgroundSession<'a> {
+    /// Returns the control files of the connection to the kernel (Linux only),
+    /// see Session::connection_control
+    #[cfg(target_os = "linux")]
+    pub fn connection_control (&self) -> io::Result<ConnectionControl> {
+        ConnectionControl::for_mountpoint(&self.mountpoint)
     }
 }
 
 impl<'a> Drop for BackgroundSession<'a> {
     fn drop (&mut self) {
-        info!("Unmounting {}", self.mountpoint.display());
+        info!(target: &self.log_target, "Unmounting {}", self.mountpoint.display());
         // Unmounting the filesystem will eventually end the session loop,
         // drop the session and hence end the background thread.
-        match channel::unmount(&self.mountpoint) {
+        let (mountpoint, log_target) = (&self.mountpoint, &self.log_target);
+        let res = channel::unmount_busy(|mode| {
+            if mode != UnmountMode::Normal {
+                warn!(target: log_target, "{} is busy, unmounting it with {:?}", mountpoint.display(), mode);
+            }
+            channel::unmount_with_mode(mountpoint, mode)
+        }, self.busy_unmount);
+        match res {
             Ok(()) => (),
-            Err(err) => error!("Failed to unmount {}: {}", self.mountpoint.display(), err),
+            Err(err) => error!(target: &self.log_target, "Failed to unmount {}: {}", self.mountpoint.display(), err),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{io, mem, slice, thread};
+    use std::sync::atomic::Ordering;
+    use libc::{c_int, c_void, size_t, EAGAIN, ECONNABORTED, EINTR, EIO, EISDIR, ENODEV};
+    use time::{self, Duration};
+    use channel::Channel;
+    use libc::ENOSYS;
+    use fuse::{FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION, fuse_in_header, fuse_out_header, fuse_init_in, fuse_open_in, fuse_release_in};
+    use fuse::fuse_opcode::{FUSE_INIT, FUSE_GETATTR, FUSE_OPEN, FUSE_RELEASE};
+    use {Error, Filesystem, Request, ReplyAttr, UnmountMode};
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    #[cfg(target_os = "linux")]
+    use std::{env, fs};
+    #[cfg(target_os = "linux")]
+    use std::ffi::CString;
+    #[cfg(target_os = "linux")]
+    use std::fs::OpenOptions;
+    #[cfg(target_os = "linux")]
+    use std::io::{Read, Write};
+    #[cfg(target_os = "linux")]
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(target_os = "linux")]
+    use std::path::PathBuf;
+    #[cfg(target_os = "linux")]
+    use libc::{c_char, c_ulong};
+    #[cfg(target_os = "linux")]
+    use fuse::consts::FUSE_ABORT_ERROR;
+    #[cfg(target_os = "linux")]
+    use {ConnectionControl, ConnectionInfo};
+    use super::{Session, mount_log_target, retry_receive};
+
+    #[cfg(target_os = "linux")]
+    extern "system" {
+        fn mount (source: *const c_char, target: *const c_char, fstype: *const c_char, flags: c_ulong, data: *const c_void) -> c_int;
+    }
+
+    /// Mount a FUSE device at a new directory with the mount syscall (like the mount
+    /// helper does), without a session reading it yet. Returns None if that isn't
+    /// possible, e.g. if not running as root or without /dev/fuse.
+    #[cfg(target_os = "linux")]
+    fn mount_device (name: &str) -> Option<(File, PathBuf)> {
+        if unsafe { ::libc::getuid() } != 0 {
+            return None;
+        }
+        let dev = match OpenOptions::new().read(true).write(true).open("/dev/fuse") {
+            Ok(dev) => dev,
+            Err(_) => return None,
+        };
+        let pid = fs::read_link("/proc/self").unwrap();
+        let mountpoint = env::temp_dir().join(format!("fuse-{}-test-{}", name, pid.display()));
+        fs::create_dir_all(&mountpoint).unwrap();
+        let target = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+        let data = CString::new(format!("fd={},rootmode=40000,user_id=0,group_id=0", dev.as_raw_fd())).unwrap();
+        let fstype = b"fuse\0".as_ptr() as *const c_char;
+        match unsafe { mount(fstype, target.as_ptr(), fstype, 0, data.as_ptr() as *const c_void) } {
+            0 => Some((dev, mountpoint)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn receive_errors () {
+        assert_eq!(retry_receive(&io::Error::from_raw_os_error(EINTR)), Some(true));
+        assert_eq!(retry_receive(&io::Error::from_raw_os_error(EAGAIN)), Some(true));
+        assert_eq!(retry_receive(&io::Error::from_raw_os_error(ENODEV)), Some(false));
+        assert_eq!(retry_receive(&io::Error::from_raw_os_error(ECONNABORTED)), Some(false));
+        assert_eq!(retry_receive(&io::Error::from_raw_os_error(EISDIR)), None);
+    }
+
+    #[test]
+    fn log_targets () {
+        assert_eq!(mount_log_target(Path::new("/mnt/myfs")), "fuse::mount::myfs");
+        assert_eq!(mount_log_target(Path::new("/")), "fuse::mount");
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        assert_eq!(se.log_target, "fuse::mount");
+        se.set_label("archive");
+        assert_eq!(se.log_target, "fuse::mount::archive");
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[derive(Default)]
+    struct DestroyFs {
+        destroyed: bool,
+    }
+
+    impl Filesystem for DestroyFs {
+        fn destroy (&mut self, _req: &Request) {
+            self.destroyed = true;
+        }
+    }
+
+    #[test]
+    fn destroy_when_connection_ends () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        se.initialized = true;
+        // A broken request ends the loop like a lost connection
+        unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        se.run();
+        assert!(se.destroyed);
+        assert!(se.filesystem.destroyed);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn destroy_when_unmounted () {
+        let (dev, mountpoint) = match mount_device("unmounted") {
+            Some(mount) => mount,
+            None => return,
+        };
+        ::unmount(&mountpoint, UnmountMode::Lazy).unwrap();
+        let (ch, fd) = Channel::pair().unwrap();
+        unsafe { ::libc::dup2(dev.as_raw_fd(), ch.fd()); }
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        se.initialized = true;
+        // Reading the device of an unmounted filesystem fails with ENODEV
+        se.try_run().unwrap();
+        assert!(se.destroyed);
+        assert!(se.filesystem.destroyed);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[derive(Default)]
+    struct AbortErrorFs {
+        destroyed: bool,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Filesystem for AbortErrorFs {
+        fn init (&mut self, _req: &Request, conn: &mut ConnectionInfo) -> Result<(), c_int> {
+            conn.want |= FUSE_ABORT_ERROR;
+            Ok(())
+        }
+
+        fn destroy (&mut self, _req: &Request) {
+            self.destroyed = true;
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn destroy_when_aborted () {
+        // Connections are aborted through the fuse control filesystem
+        let mut mounts = String::new();
+        File::open("/proc/mounts").unwrap().read_to_string(&mut mounts).unwrap();
+        if !mounts.contains("fusectl") {
+            return;
+        }
+        let (dev, mountpoint) = match mount_device("aborted") {
+            Some(mount) => mount,
+            None => return,
+        };
+        let (ch, fd) = Channel::pair().unwrap();
+        unsafe { ::libc::dup2(dev.as_raw_fd(), ch.fd()); }
+        let mut se = Session::with_channel(AbortErrorFs::default(), ch);
+        let path = mountpoint.clone();
+        let aborter = thread::spawn(move || {
+            // A stat returns once the session replied to init
+            let _ = fs::metadata(&path);
+            let control = ConnectionControl::for_mountpoint(&path).unwrap();
+            File::create(control.dir().join("abort")).and_then(|mut file| file.write_all(b"1")).unwrap();
+        });
+        // With FUSE_ABORT_ERROR, reading the device of an aborted connection fails
+        // with ECONNABORTED
+        se.try_run().unwrap();
+        aborter.join().unwrap();
+        assert!(se.conn.granted(FUSE_ABORT_ERROR));
+        assert!(se.destroyed);
+        assert!(se.filesystem.destroyed);
+        ::unmount(&mountpoint, UnmountMode::Lazy).unwrap();
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn mount_error () {
+        match Session::try_new(DestroyFs::default(), Path::new("/nonexistent/mountpoint"), &[]) {
+            Err(Error::Mount(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("mounted to a nonexistent mountpoint"),
+        }
+    }
+
+    #[test]
+    fn protocol_error () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        se.initialized = true;
+        unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        match se.try_run() {
+            Err(Error::Protocol(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // The filesystem is destroyed even though the session failed
+        assert!(se.filesystem.destroyed);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn run_with_notifier () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        se.initialized = true;
+        // The notifier makes the channel non-blocking, the loop still waits for requests
+        let notifier = se.notifier().unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep_ms(50);
+            unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        });
+        match se.try_run() {
+            Err(Error::Protocol(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        writer.join().unwrap();
+        notifier.resend().unwrap();
+        let mut buffer = [0u8; 64];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        assert_eq!(rc, 16);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn channel_error () {
+        let (ch, fd) = Channel::pair().unwrap();
+        // Replace the channel's fd with a directory, which fails to read with EISDIR
+        let dir = File::open("/").unwrap();
+        unsafe { ::libc::dup2(dir.as_raw_fd(), ch.fd()); }
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        match se.try_run() {
+            Err(Error::Channel(err)) => assert_eq!(err.raw_os_error(), Some(EISDIR)),
+            res => panic!("unexpected result {:?}", res),
+        }
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn unmount_error () {
+        match ::unmount(&"/nonexistent/mountpoint", UnmountMode::Normal) {
+            Err(Error::Unmount(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    /// Send a request with the given argument to the channel at the other end of fd
+    fn send_request<T> (fd: c_int, opcode: u32, arg: &T) {
+        let len = mem::size_of::<fuse_in_header>() + mem::size_of::<T>();
+        let header = fuse_in_header { len: len as u32, opcode: opcode, unique: 0xdeadbeef, nodeid: 0x22, uid: 0, gid: 0, pid: 0, padding: 0 };
+        let mut buffer: Vec<u8> = Vec::with_capacity(len);
+        unsafe {
+            buffer.push_all(slice::from_raw_parts(&header as *const fuse_in_header as *const u8, mem::size_of::<fuse_in_header>()));
+            buffer.push_all(slice::from_raw_parts(arg as *const T as *const u8, mem::size_of::<T>()));
+            ::libc::write(fd, buffer.as_ptr() as *const c_void, len as size_t);
+        }
+    }
+
+    #[test]
+    fn idle_timeout () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        se.initialized = true;
+        se.idle_timeout = Some(Duration::milliseconds(10));
+        se.run();
+        assert!(se.filesystem.destroyed);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[test]
+    fn idle_timeout_with_open_handles () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(DestroyFs::default(), ch);
+        se.initialized = true;
+        se.idle_timeout = Some(Duration::milliseconds(10));
+        // Open a file and keep it open for a while before releasing it
+        send_request(fd, FUSE_OPEN as u32, &fuse_open_in { flags: 0, mode: 0 });
+        let started = time::get_time();
+        let client = thread::spawn(move || {
+            thread::sleep_ms(100);
+            let arg: fuse_release_in = unsafe { mem::zeroed() };
+            send_request(fd, FUSE_RELEASE as u32, &arg);
+        });
+        se.run();
+        assert!(time::get_time() - started >= Duration::milliseconds(100));
+        assert_eq!(se.open_handles.load(Ordering::SeqCst), 0);
+        client.join().unwrap();
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[derive(Default)]
+    struct PanicFs {
+        destroyed: bool,
+    }
+
+    impl Filesystem for PanicFs {
+        fn getattr (&mut self, _req: &Request, _ino: u64, _fh: Option<u64>, _reply: ReplyAttr) {
+            panic!("deliberate panic in getattr");
         }
+
+        fn destroy (&mut self, _req: &Request) {
+            self.destroyed = true;
+        }
+    }
+
+    #[test]
+    fn catch_panics () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(PanicFs::default(), ch);
+        se.initialized = true;
+        se.catch_panics = true;
+        send_request(fd, FUSE_GETATTR as u32, &[0u8; 16]);
+        // The loop survives the panic and ends at the broken request
+        unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        se.run();
+        assert!(se.filesystem.destroyed);
+        let mut buffer = [0u8; 64];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        assert_eq!(rc as usize, mem::size_of::<fuse_out_header>());
+        let header: &fuse_out_header = unsafe { mem::transmute(buffer.as_ptr()) };
+        assert_eq!(header.error, -EIO);
+        assert_eq!(header.unique, 0xdeadbeef);
+        unsafe { ::libc::close(fd); }
+    }
+
+    #[derive(Default)]
+    struct PendingFs {
+        pending: Option<ReplyAttr>,
+    }
+
+    impl Filesystem for PendingFs {
+        fn getattr (&mut self, _req: &Request, _ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+            // Keep the reply to send it later
+            self.pending = Some(reply);
+        }
+    }
+
+    #[test]
+    fn inflight_requests () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(PendingFs::default(), ch);
+        se.initialized = true;
+        send_request(fd, FUSE_OPEN as u32, &fuse_open_in { flags: 0, mode: 0 });
+        send_request(fd, FUSE_GETATTR as u32, &[0u8; 16]);
+        unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        se.run();
+        // The open was replied, the getattr is still pending
+        assert_eq!(se.inflight(), 1);
+        se.filesystem.pending.take().unwrap().error(EIO);
+        assert_eq!(se.inflight(), 0);
+        unsafe { ::libc::close(fd); }
+    }
+
+    struct SlowFs;
+
+    impl Filesystem for SlowFs {
+        fn getattr (&mut self, _req: &Request, _ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+            // Reply from another thread after a while
+            thread::spawn(move || {
+                thread::sleep_ms(50);
+                reply.error(ENOSYS);
+            });
+        }
+    }
+
+    #[test]
+    fn drain_on_shutdown () {
+        let (ch, fd) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(SlowFs, ch);
+        se.initialized = true;
+        se.shutdown_timeout = Some(Duration::seconds(5));
+        send_request(fd, FUSE_GETATTR as u32, &[0u8; 16]);
+        unsafe { ::libc::write(fd, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        let started = time::get_time();
+        se.run();
+        // The loop waited for the slow reply
+        assert!(time::get_time() - started >= Duration::milliseconds(50));
+        assert_eq!(se.inflight(), 0);
+        let mut buffer = [0u8; 64];
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        assert_eq!(rc as usize, mem::size_of::<fuse_out_header>());
+        let header: &fuse_out_header = unsafe { mem::transmute(buffer.as_ptr()) };
+        assert_eq!(header.error, -ENOSYS);
+        // Draining gives up after the timeout
+        let (ch, fd2) = Channel::pair().unwrap();
+        let mut se = Session::with_channel(PendingFs::default(), ch);
+        se.initialized = true;
+        se.shutdown_timeout = Some(Duration::milliseconds(10));
+        send_request(fd2, FUSE_GETATTR as u32, &[0u8; 16]);
+        unsafe { ::libc::write(fd2, [0u8; 4].as_ptr() as *const c_void, 4 as size_t); }
+        se.run();
+        assert_eq!(se.inflight(), 1);
+        assert!(!se.drain(Duration::milliseconds(1)));
+        unsafe { ::libc::close(fd); ::libc::close(fd2); }
+    }
+
+    /// Returns a replay record of a request with the given opcode, unique id and argument
+    fn record<T> (opcode: u32, unique: u64, arg: &T) -> Vec<u8> {
+        let len = mem::size_of::<fuse_in_header>() + mem::size_of::<T>();
+        let header = fuse_in_header { len: len as u32, opcode: opcode, unique: unique, nodeid: 1, uid: 0, gid: 0, pid: 0, padding: 0 };
+        let mut record = vec![len as u8, (len >> 8) as u8, 0, 0];
+        unsafe {
+            record.push_all(slice::from_raw_parts(&header as *const fuse_in_header as *const u8, mem::size_of::<fuse_in_header>()));
+            record.push_all(slice::from_raw_parts(arg as *const T as *const u8, mem::size_of::<T>()));
+        }
+        record
+    }
+
+    #[test]
+    fn replay () {
+        let mut init: fuse_init_in = unsafe { mem::zeroed() };
+        init.major = FUSE_KERNEL_VERSION;
+        init.minor = FUSE_KERNEL_MINOR_VERSION;
+        let mut records = record(FUSE_INIT as u32, 1, &init);
+        records.push_all(&record(FUSE_GETATTR as u32, 2, &[0u8; 16]));
+        let replies = Session::replay(&records[..], DestroyFs::default()).unwrap();
+        assert_eq!(replies.len(), 2);
+        let header: &fuse_out_header = unsafe { mem::transmute(replies[0].as_ptr()) };
+        assert_eq!((header.unique, header.error), (1, 0));
+        let header: &fuse_out_header = unsafe { mem::transmute(replies[1].as_ptr()) };
+        assert_eq!((header.unique, header.error), (2, -ENOSYS));
+        // Truncated records and malformed requests fail
+        let err = Session::replay(&records[..records.len() - 1], DestroyFs::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = Session::replay(&[4u8, 0, 0, 0, 0, 0, 0, 0][..], DestroyFs::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
     }
 }
@@ -0,0 +1,187 @@
+//!
+//! Filesystem statistics, as returned by statfs. Passthrough style filesystems
+//! usually report the statistics of their backing filesystem, which they get
+//! with statvfs(3).
+//!
+
+use std::{io, mem};
+use std::path::Path;
+
+pub use self::libc::statvfs;
+
+// Libc's statvfs struct and function (the 64 bit version on Linux, so that the
+// block and file counts are 64 bit on 32 bit systems as well)
+#[allow(non_camel_case_types)]
+mod libc {
+    use libc::{c_char, c_int, c_ulong};
+
+    /// Type of block counts
+    #[cfg(target_os = "macos")]
+    pub type fsblkcnt_t = ::libc::c_uint;
+    /// Type of block counts
+    #[cfg(not(target_os = "macos"))]
+    pub type fsblkcnt_t = u64;
+    /// Type of file counts
+    #[cfg(target_os = "macos")]
+    pub type fsfilcnt_t = ::libc::c_uint;
+    /// Type of file counts
+    #[cfg(not(target_os = "macos"))]
+    pub type fsfilcnt_t = u64;
+
+    /// Filesystem statistics of statvfs(3)
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct statvfs {
+        /// Preferred I/O block size
+        pub f_bsize: c_ulong,
+        /// Fundamental block size (the unit of the block counts)
+        pub f_frsize: c_ulong,
+        pub f_blocks: fsblkcnt_t,
+        pub f_bfree: fsblkcnt_t,
+        pub f_bavail: fsblkcnt_t,
+        pub f_files: fsfilcnt_t,
+        pub f_ffree: fsfilcnt_t,
+        pub f_favail: fsfilcnt_t,
+        pub f_fsid: c_ulong,
+        #[cfg(all(not(target_os = "macos"), target_pointer_width = "32"))]
+        pub f_unused: c_int,
+        pub f_flag: c_ulong,
+        pub f_namemax: c_ulong,
+        #[cfg(not(target_os = "macos"))]
+        pub f_spare: [c_int; 6],
+    }
+
+    extern "system" {
+        #[cfg_attr(target_os = "macos", link_name = "statvfs")]
+        #[cfg_attr(not(target_os = "macos"), link_name = "statvfs64")]
+        pub fn path_statvfs (path: *const c_char, buf: *mut statvfs) -> c_int;
+    }
+}
+
+/// Filesystem statistics (see ReplyStatfs::stat). Block counts are in units of
+/// frsize, the fundamental block size, while bsize is the preferred I/O size. They
+/// often are the same, but not always (e.g. on filesystems with a large bsize for
+/// efficient I/O). Tools like df multiply the block counts by frsize, so mixing
+/// the two up makes them show wrong sizes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatFs {
+    /// Total number of blocks (in units of frsize)
+    pub blocks: u64,
+    /// Number of free blocks
+    pub bfree: u64,
+    /// Number of free blocks available to unprivileged users
+    pub bavail: u64,
+    /// Total number of inodes
+    pub files: u64,
+    /// Number of free inodes
+    pub ffree: u64,
+    /// Preferred I/O block size
+    pub bsize: u32,
+    /// Max length of file names
+    pub namelen: u32,
+    /// Fundamental block size
+    pub frsize: u32,
+}
+
+impl StatFs {
+    /// Statistics of the given statvfs result. If f_frsize is 0 (which some systems
+    /// report), the block counts are in units of f_bsize, like statvfs(3) says.
+    pub fn from_statvfs (st: &statvfs) -> StatFs {
+        let frsize = if st.f_frsize != 0 { st.f_frsize } else { st.f_bsize };
+        StatFs {
+            blocks: st.f_blocks as u64,
+            bfree: st.f_bfree as u64,
+            bavail: st.f_bavail as u64,
+            files: st.f_files as u64,
+            ffree: st.f_ffree as u64,
+            bsize: st.f_bsize as u32,
+            namelen: st.f_namemax as u32,
+            frsize: frsize as u32,
+        }
+    }
+
+    /// Returns the statistics as a statvfs result. Since the statistics have no
+    /// separate count of inodes available to unprivileged users, f_favail is the
+    /// number of free inodes. The flags and the filesystem id are 0.
+    pub fn to_statvfs (&self) -> statvfs {
+        let mut st: statvfs = unsafe { mem::zeroed() };
+        st.f_bsize = self.bsize as ::libc::c_ulong;
+        st.f_frsize = self.frsize as ::libc::c_ulong;
+        st.f_blocks = self.blocks as self::libc::fsblkcnt_t;
+        st.f_bfree = self.bfree as self::libc::fsblkcnt_t;
+        st.f_bavail = self.bavail as self::libc::fsblkcnt_t;
+        st.f_files = self.files as self::libc::fsfilcnt_t;
+        st.f_ffree = self.ffree as self::libc::fsfilcnt_t;
+        st.f_favail = self.ffree as self::libc::fsfilcnt_t;
+        st.f_namemax = self.namelen as ::libc::c_ulong;
+        st
+    }
+
+    /// Returns the statistics of the filesystem the given path is on, e.g. of the
+    /// backing directory of a passthrough filesystem:
+    ///
+    /// ```ignore
+    /// fn statfs (&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+    ///     reply.respond(StatFs::for_path(&self.root).map_err(|e| errno_of(&e)), |reply, st| reply.stat(&st));
+    /// }
+    /// ```
+    pub fn for_path<P: AsRef<Path>> (path: P) -> io::Result<StatFs> {
+        let path = try!(path.as_ref().as_os_str().to_cstring().ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid path")));
+        let mut st: statvfs = unsafe { mem::zeroed() };
+        if unsafe { self::libc::path_statvfs(path.as_ptr(), &mut st) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(StatFs::from_statvfs(&st))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use super::{StatFs, statvfs};
+
+    /// A statvfs result of a filesystem with 4k blocks and a larger I/O size
+    fn known_statvfs () -> statvfs {
+        let mut st: statvfs = unsafe { mem::zeroed() };
+        st.f_bsize = 65536;
+        st.f_frsize = 4096;
+        st.f_blocks = 1000;
+        st.f_bfree = 600;
+        st.f_bavail = 500;
+        st.f_files = 200;
+        st.f_ffree = 150;
+        st.f_favail = 140;
+        st.f_namemax = 255;
+        st
+    }
+
+    #[test]
+    fn from_statvfs () {
+        let st = StatFs::from_statvfs(&known_statvfs());
+        assert_eq!(st, StatFs { blocks: 1000, bfree: 600, bavail: 500, files: 200, ffree: 150, bsize: 65536, namelen: 255, frsize: 4096 });
+        // Without a fundamental block size, the blocks are in units of the I/O size
+        let mut without_frsize = known_statvfs();
+        without_frsize.f_frsize = 0;
+        assert_eq!(StatFs::from_statvfs(&without_frsize).frsize, 65536);
+    }
+
+    #[test]
+    fn to_statvfs () {
+        let st = StatFs::from_statvfs(&known_statvfs()).to_statvfs();
+        assert_eq!((st.f_bsize, st.f_frsize), (65536, 4096));
+        assert_eq!((st.f_blocks, st.f_bfree, st.f_bavail), (1000, 600, 500));
+        assert_eq!((st.f_files, st.f_ffree, st.f_favail), (200, 150, 150));
+        assert_eq!(st.f_namemax, 255);
+    }
+
+    #[test]
+    fn for_path () {
+        let st = StatFs::for_path("/").unwrap();
+        assert!(st.frsize > 0 && st.bsize > 0);
+        assert!(st.bfree <= st.blocks);
+        assert!(StatFs::for_path("/nonexistent/path").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//!
+//! Helpers for extended attribute names. On Linux, extended attributes live
+//! in namespaces and their names must include the namespace prefix (e.g.
+//! "user.comment"). Names without a known prefix are ignored by tools like
+//! getfattr.
+//!
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use libc::{self, c_int};
+
+/// Error for an attribute that doesn't exist, to be replied by getxattr and
+/// removexattr (and setxattr with XATTR_REPLACE). It's ENODATA on Linux, which
+/// has no ENOATTR, and ENOATTR on OS X and FreeBSD, where ENODATA means something
+/// else. Tools like getfattr report other errors as failures instead of a missing
+/// attribute.
+#[cfg(target_os = "linux")]
+pub const ENOATTR: c_int = libc::ENODATA;
+
+/// Error for an attribute that doesn't exist, to be replied by getxattr and
+/// removexattr (and setxattr with XATTR_REPLACE). It's ENODATA on Linux, which
+/// has no ENOATTR, and ENOATTR on OS X and FreeBSD, where ENODATA means something
+/// else. Tools like getfattr report other errors as failures instead of a missing
+/// attribute.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub const ENOATTR: c_int = libc::ENOATTR;
+
+/// Extended attribute namespace prefixes and well-known attribute names
+pub mod namespace {
+    /// Prefix of attributes that can be set by users with permission to the file
+    pub const USER: &'static str = "user.";
+    /// Prefix of attributes used by the kernel (e.g. ACLs)
+    pub const SYSTEM: &'static str = "system.";
+    /// Prefix of attributes used by security modules (e.g. SELinux labels, capabilities)
+    pub const SECURITY: &'static str = "security.";
+    /// Prefix of attributes only accessible by privileged processes
+    pub const TRUSTED: &'static str = "trusted.";
+
+    /// Name of the attribute that stores the access ACL of a file
+    pub const POSIX_ACL_ACCESS: &'static str = "system.posix_acl_access";
+    /// Name of the attribute that stores the default ACL of a directory
+    pub const POSIX_ACL_DEFAULT: &'static str = "system.posix_acl_default";
+}
+
+/// Build the full name of an extended attribute from a namespace prefix and
+/// a name, e.g. xattr_name(namespace::USER, "comment") gives "user.comment"
+pub fn xattr_name<T: AsRef<OsStr>> (prefix: &str, name: T) -> OsString {
+    let mut fullname = OsStr::new(prefix).to_os_string();
+    fullname.push(name);
+    fullname
+}
+
+/// Builder for the list of extended attribute names that is sent as a reply to
+/// listxattr. Names must include their namespace prefix (see xattr_name) and are
+/// stored zero-terminated, one after another.
+///
+/// ```ignore
+/// fn listxattr (&mut self, _req: &Request, _ino: u64, size: u32, reply: ReplyXattr) {
+///     let mut list = XattrList::new();
+///     list.add(xattr_name(namespace::USER, "comment"));
+///     if size == 0 {
+///         reply.size(list.len() as u32);
+///     } else if list.len() > size as usize {
+///         reply.error(ERANGE);
+///     } else {
+///         reply.data(list.as_bytes());
+///     }
+/// }
+/// ```
+pub struct XattrList {
+    data: Vec<u8>,
+}
+
+impl XattrList {
+    /// Create a new empty list
+    pub fn new () -> XattrList {
+        XattrList { data: Vec::new() }
+    }
+
+    /// Add an attribute name to the list
+    pub fn add<T: AsRef<OsStr>> (&mut self, name: T) {
+        self.data.push_all(name.as_ref().as_bytes());
+        self.data.push(0);
+    }
+
+    /// Returns the size of the list in bytes
+    pub fn len (&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if the list contains no names
+    pub fn is_empty (&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the list as bytes as expected by the kernel
+    pub fn as_bytes (&self) -> &[u8] {
+        &self.data
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use super::{namespace, xattr_name, XattrList, ENOATTR};
+
+    #[test]
+    fn name_with_namespace () {
+        assert_eq!(xattr_name(namespace::USER, "comment"), OsStr::new("user.comment"));
+        assert_eq!(xattr_name(namespace::SECURITY, "selinux"), OsStr::new("security.selinux"));
+        assert_eq!(xattr_name(namespace::SYSTEM, "posix_acl_access"), OsStr::new(namespace::POSIX_ACL_ACCESS));
+    }
+
+    #[test]
+    fn list () {
+        let mut list = XattrList::new();
+        assert!(list.is_empty());
+        list.add(xattr_name(namespace::USER, "a"));
+        list.add(namespace::POSIX_ACL_ACCESS);
+        assert_eq!(list.len(), 31);
+        assert_eq!(list.as_bytes(), &b"user.a\0system.posix_acl_access\0"[..]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn missing_attribute_error () {
+        assert_eq!(ENOATTR, ::libc::ENODATA);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    fn missing_attribute_error () {
+        assert_eq!(ENOATTR, ::libc::ENOATTR);
+    }
+}
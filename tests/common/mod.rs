@@ -0,0 +1,23 @@
+//!
+//! Helpers shared by the integration tests that mount the example filesystems
+//!
+
+use std::{env, fs, process};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+/// Returns true if FUSE filesystems can be mounted: the FUSE device can be opened
+/// and the mount helper is installed
+pub fn can_mount () -> bool {
+    OpenOptions::new().read(true).write(true).open("/dev/fuse").is_ok() &&
+        process::Command::new("fusermount").arg("-V").output().is_ok()
+}
+
+/// Create a directory to mount the filesystem of the given test on. It's unique to
+/// this process, so that concurrent test runs don't mount on top of each other.
+pub fn temp_mountpoint (name: &str) -> PathBuf {
+    let pid = fs::read_link("/proc/self").unwrap();
+    let path = env::temp_dir().join(format!("fuse-{}-test-{}", name, pid.display()));
+    fs::create_dir_all(&path).unwrap();
+    path
+}
@@ -0,0 +1,56 @@
+//!
+//! Mounts the hello world example filesystem and reads its file. Needs the
+//! examples feature and a working FUSE setup (/dev/fuse and fusermount), the
+//! test does nothing otherwise.
+//!
+
+#![cfg(all(feature = "examples", target_os = "linux"))]
+
+extern crate fuse;
+
+mod common;
+
+use std::{fs, process, thread};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+use common::{can_mount, temp_mountpoint};
+use fuse::examples::HelloFs;
+
+/// Returns true if the given path is a mount point (according to /proc/mounts)
+fn is_mounted (path: &Path) -> bool {
+    let mut mounts = String::new();
+    File::open("/proc/self/mounts").and_then(|mut f| f.read_to_string(&mut mounts)).unwrap();
+    mounts.lines().any(|line| line.split(' ').nth(1) == path.to_str())
+}
+
+#[test]
+fn hello () {
+    if !can_mount() {
+        return;
+    }
+    let mountpoint = temp_mountpoint("hello");
+    let path = mountpoint.clone();
+    let handle = thread::spawn(move || {
+        fuse::mount(HelloFs::new(), &path, &[]);
+    });
+    let mounted = (0..50).any(|_| {
+        thread::sleep_ms(100);
+        is_mounted(&mountpoint)
+    });
+    assert!(mounted);
+    let names: Vec<String> = fs::read_dir(&mountpoint).unwrap().map(|entry| {
+        entry.unwrap().file_name().into_string().unwrap()
+    }).collect();
+    assert_eq!(names, ["hello.txt"]);
+    let file = mountpoint.join("hello.txt");
+    let mut content = String::new();
+    File::open(&file).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "Hello World!\n");
+    assert_eq!(fs::metadata(&file).unwrap().len(), 13);
+    assert!(OpenOptions::new().write(true).open(&file).is_err());
+    let status = process::Command::new("fusermount").arg("-u").arg(&mountpoint).status().unwrap();
+    assert!(status.success());
+    handle.join().unwrap();
+    fs::remove_dir(&mountpoint).unwrap();
+}
@@ -0,0 +1,215 @@
+//!
+//! Mounts the in-memory example filesystem and runs file operations on it.
+//! Needs the examples feature and a working FUSE setup (/dev/fuse and
+//! fusermount), the test does nothing otherwise.
+//!
+
+#![cfg(all(feature = "examples", target_os = "linux"))]
+
+extern crate fuse;
+
+mod common;
+
+use std::{fs, thread};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use fuse::{ConnectionControl, UnmountMode};
+use fuse::examples::MemFs;
+use common::{can_mount, temp_mountpoint};
+
+/// Returns true if the given path is a mount point (according to /proc/mounts)
+fn is_mounted (path: &Path) -> bool {
+    let mut mounts = String::new();
+    File::open("/proc/self/mounts").and_then(|mut f| f.read_to_string(&mut mounts)).unwrap();
+    mounts.lines().any(|line| line.split(' ').nth(1) == path.to_str())
+}
+
+/// Mount the filesystem in a background thread and wait until the mount shows up
+fn mount (fs: MemFs, mountpoint: &Path) -> thread::JoinHandle<()> {
+    let path = mountpoint.to_path_buf();
+    let handle = thread::spawn(move || {
+        fuse::mount(fs, &path, &[]);
+    });
+    let mounted = (0..50).any(|_| {
+        thread::sleep_ms(100);
+        is_mounted(mountpoint)
+    });
+    assert!(mounted);
+    handle
+}
+
+fn read (path: &Path) -> Vec<u8> {
+    let mut data = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+fn list (path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path).unwrap().map(|entry| {
+        entry.unwrap().file_name().into_string().unwrap()
+    }).collect();
+    names.sort();
+    names
+}
+
+fn file_operations (root: &Path) {
+    // Create and write
+    let file = root.join("a");
+    File::create(&file).unwrap().write_all(b"hello world").unwrap();
+    assert_eq!(read(&file), b"hello world");
+    // Write at offsets, including past the end of the file
+    {
+        let mut f = OpenOptions::new().write(true).open(&file).unwrap();
+        f.write_all(b"HELLO").unwrap();
+        f.seek(SeekFrom::Start(16)).unwrap();
+        f.write_all(b"!").unwrap();
+    }
+    assert_eq!(read(&file), b"HELLO world\0\0\0\0\0!");
+    assert_eq!(fs::metadata(&file).unwrap().len(), 17);
+    // Truncate
+    OpenOptions::new().write(true).open(&file).unwrap().set_len(5).unwrap();
+    assert_eq!(read(&file), b"HELLO");
+    // Rename into a new directory
+    let dir = root.join("dir");
+    fs::create_dir(&dir).unwrap();
+    fs::rename(&file, dir.join("b")).unwrap();
+    assert_eq!(list(root), ["dir"]);
+    assert_eq!(list(&dir), ["b"]);
+    assert_eq!(read(&dir.join("b")), b"HELLO");
+    // Symlink
+    let link = root.join("link");
+    symlink("dir/b", &link).unwrap();
+    assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("dir/b"));
+    assert_eq!(read(&link), b"HELLO");
+    assert_eq!(list(root), ["dir", "link"]);
+    // Remove everything again
+    fs::remove_file(&link).unwrap();
+    fs::remove_file(dir.join("b")).unwrap();
+    fs::remove_dir(&dir).unwrap();
+    assert!(list(root).is_empty());
+}
+
+/// Reading a direct_io file twice through the same handle reaches the filesystem
+/// both times, since nothing is cached
+fn direct_io (root: &Path, reads: Arc<AtomicUsize>) {
+    let file = root.join("stream");
+    File::create(&file).unwrap().write_all(b"hello").unwrap();
+    let mut f = File::open(&file).unwrap();
+    let mut data = Vec::new();
+    let before = reads.load(Ordering::SeqCst);
+    f.read_to_end(&mut data).unwrap();
+    let first = reads.load(Ordering::SeqCst);
+    assert!(first > before);
+    f.seek(SeekFrom::Start(0)).unwrap();
+    f.read_to_end(&mut data).unwrap();
+    assert!(reads.load(Ordering::SeqCst) > first);
+    assert_eq!(data, b"hellohello");
+    fs::remove_file(&file).unwrap();
+}
+
+/// Stat through the handle of a file that is being written reports the grown size
+/// before the writes are flushed
+fn size_while_writing (root: &Path) {
+    let file = root.join("growing");
+    let mut f = File::create(&file).unwrap();
+    f.write_all(b"hello").unwrap();
+    assert_eq!(f.metadata().unwrap().len(), 5);
+    f.write_all(b" world").unwrap();
+    assert_eq!(f.metadata().unwrap().len(), 11);
+    drop(f);
+    // Closing flushed the writes
+    assert_eq!(fs::metadata(&file).unwrap().len(), 11);
+    assert_eq!(read(&file), b"hello world");
+    fs::remove_file(&file).unwrap();
+}
+
+/// Removing and adding entries while a directory is read doesn't break the listing
+fn modify_while_listing (root: &Path) {
+    let dir = root.join("many");
+    fs::create_dir(&dir).unwrap();
+    let mut names: Vec<String> = (0..500).map(|i| format!("a-rather-long-file-name-to-need-several-readdirs-{:03}", i)).collect();
+    for name in names.iter() {
+        File::create(dir.join(name)).unwrap();
+    }
+    let mut listed = Vec::new();
+    for (i, entry) in fs::read_dir(&dir).unwrap().enumerate() {
+        listed.push(entry.unwrap().file_name().into_string().unwrap());
+        if i == 10 {
+            for name in names[100..200].iter() {
+                fs::remove_file(dir.join(name)).unwrap();
+            }
+            File::create(dir.join("new")).unwrap();
+        }
+    }
+    // The listing is the snapshot from when the directory was opened
+    listed.sort();
+    assert_eq!(listed, names);
+    fs::remove_file(dir.join("new")).unwrap();
+    names.drain(100..200);
+    for name in names.iter() {
+        fs::remove_file(dir.join(name)).unwrap();
+    }
+    fs::remove_dir(&dir).unwrap();
+}
+
+fn connection_control (root: &Path) {
+    let control = ConnectionControl::for_mountpoint(root).unwrap();
+    // The fuse control filesystem might not be mounted
+    let max_background = match control.max_background() {
+        Ok(max_background) => max_background,
+        Err(_) => return,
+    };
+    // Writing needs privileges, raising the value might need more
+    match control.set_max_background(max_background + 1) {
+        Ok(()) => {
+            assert_eq!(control.max_background().unwrap(), max_background + 1);
+            control.set_max_background(max_background).unwrap();
+            assert_eq!(control.max_background().unwrap(), max_background);
+        },
+        Err(_) => (),
+    }
+}
+
+/// Drop the handle of a background session while a file is still open. The busy
+/// mount isn't leaked, but unmounted lazily: it's detached right away, and dropping
+/// the handle waits until the file was closed and the session ended.
+fn busy_unmount (mountpoint: &Path) {
+    let session = fuse::spawn_mount(MemFs::new(), &mountpoint, &[]).unwrap();
+    let file = File::create(mountpoint.join("busy")).unwrap();
+    let path = mountpoint.to_path_buf();
+    let closer = thread::spawn(move || {
+        let detached = (0..50).any(|_| {
+            thread::sleep_ms(100);
+            !is_mounted(&path)
+        });
+        drop(file);
+        detached
+    });
+    drop(session);
+    assert!(closer.join().unwrap());
+    assert!(!is_mounted(mountpoint));
+}
+
+#[test]
+fn memfs () {
+    if !can_mount() {
+        return;
+    }
+    let mountpoint = temp_mountpoint("memfs");
+    let memfs = MemFs::new().with_direct_io("stream");
+    let reads = memfs.read_counter();
+    let handle = mount(memfs, &mountpoint);
+    file_operations(&mountpoint);
+    direct_io(&mountpoint, reads);
+    size_while_writing(&mountpoint);
+    modify_while_listing(&mountpoint);
+    connection_control(&mountpoint);
+    fuse::unmount(&mountpoint, UnmountMode::Normal).unwrap();
+    handle.join().unwrap();
+    busy_unmount(&mountpoint);
+    fs::remove_dir(&mountpoint).unwrap();
+}
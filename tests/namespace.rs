@@ -0,0 +1,85 @@
+//!
+//! Mounts the hello world example filesystem in a new mount namespace and checks
+//! that it's only visible in there. Needs the examples feature, a working FUSE
+//! setup (/dev/fuse and fusermount) and the privilege to unshare the mount
+//! namespace (CAP_SYS_ADMIN), the test does nothing otherwise. A rootless mount
+//! (in a new user namespace) isn't an alternative here, since the kernel only
+//! allows it in a process with a single thread, and the test harness has many.
+//!
+
+#![cfg(all(feature = "examples", target_os = "linux"))]
+
+extern crate fuse;
+
+mod common;
+
+use std::{fs, thread};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use fuse::Session;
+use fuse::examples::HelloFs;
+use common::{can_mount, temp_mountpoint};
+
+/// Returns true if the given path is a mount point in the mount namespace of the
+/// calling thread (according to /proc/thread-self/mounts)
+fn is_mounted (path: &Path) -> bool {
+    let mut mounts = String::new();
+    File::open("/proc/thread-self/mounts").and_then(|mut f| f.read_to_string(&mut mounts)).unwrap();
+    mounts.lines().any(|line| line.split(' ').nth(1) == path.to_str())
+}
+
+/// Returns true if the calling thread has CAP_SYS_ADMIN, which it needs to unshare
+/// the mount namespace (according to /proc/thread-self/status)
+fn has_sys_admin () -> bool {
+    const CAP_SYS_ADMIN: u64 = 21;
+    let mut status = String::new();
+    File::open("/proc/thread-self/status").and_then(|mut f| f.read_to_string(&mut status)).unwrap();
+    status.lines().find(|line| line.starts_with("CapEff:"))
+        .and_then(|line| u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok())
+        .map_or(false, |caps| caps & 1 << CAP_SYS_ADMIN != 0)
+}
+
+/// Returns the names of the entries of the given directory
+fn names (path: &Path) -> Vec<String> {
+    fs::read_dir(path).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect()
+}
+
+#[test]
+fn mount_in_namespace () {
+    if !can_mount() || !has_sys_admin() {
+        return;
+    }
+    let mountpoint = temp_mountpoint("namespace");
+    let (mounted_tx, mounted_rx) = channel();
+    let (done_tx, done_rx) = channel::<()>();
+    let path = mountpoint.clone();
+    let handle = thread::spawn(move || {
+        let se = match Session::new_in_namespace(HelloFs::new(), &path, &[], false) {
+            Ok(se) => se,
+            Err(err) => {
+                mounted_tx.send(Err(err.to_string())).unwrap();
+                return;
+            },
+        };
+        assert!(se.mount_namespace().is_some());
+        // The thread that runs the session is started from within the namespace
+        let session = se.spawn().unwrap();
+        mounted_tx.send(Ok((is_mounted(&path), names(&path)))).unwrap();
+        done_rx.recv().unwrap();
+        drop(session);
+    });
+    let (mounted, inside) = match mounted_rx.recv().unwrap() {
+        Ok(mounted) => mounted,
+        Err(err) => panic!("Unable to mount FUSE filesystem in a new namespace: {}", err),
+    };
+    assert!(mounted);
+    assert_eq!(inside, ["hello.txt"]);
+    // This thread is still in the original namespace, where nothing is mounted
+    assert!(!is_mounted(&mountpoint));
+    assert!(names(&mountpoint).is_empty());
+    done_tx.send(()).unwrap();
+    handle.join().unwrap();
+    fs::remove_dir(&mountpoint).unwrap();
+}